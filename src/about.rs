@@ -0,0 +1,138 @@
+use std::{env::consts, thread};
+
+use log::warn;
+use winapi::um::winuser;
+
+use crate::config::{Order, PasteMode, RemoteClipboard};
+use crate::settings::Settings;
+use crate::storage::StorageKind;
+use crate::winapi_functions::message_box_w;
+
+/// The version and the commit it was built from
+pub fn version() -> String {
+    format!(
+        "filo-clipboard {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("FILO_GIT_HASH")
+    )
+}
+
+/// Everything about the build and the settings in use which is worth including in a bug report
+pub fn details(settings: Result<&Settings, String>) -> String {
+    let mut lines = vec![
+        version(),
+        format!(
+            "Built {} ({} build for {}-{})",
+            env!("FILO_BUILD_DATE"),
+            if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+            consts::ARCH,
+            consts::OS
+        ),
+    ];
+    match settings {
+        Ok(settings) => {
+            lines.push(format!("Profile: {}", settings.profile_name));
+            if let Some(path) = &settings.config_path {
+                lines.push(format!("Config: {}", path.display()));
+            }
+            lines.push(format!(
+                "Enabled: {}",
+                enabled_features(settings).join(", ")
+            ));
+        }
+        Err(error) => lines.push(format!("Settings: {}", error)),
+    }
+    lines.join("\n")
+}
+
+/// The optional parts of the program which are switched on
+fn enabled_features(settings: &Settings) -> Vec<&'static str> {
+    let features = [
+        ("ipc", true),
+        ("delayed rendering", true),
+        (
+            "persistence",
+            settings.persist.is_some() && settings.storage != StorageKind::Memory,
+        ),
+        (
+            "encryption",
+            settings.persist.is_some()
+                && settings.storage != StorageKind::Memory
+                && settings.encrypt,
+        ),
+        ("audit log", settings.audit_log.is_some()),
+        ("log file", settings.log_file.is_some()),
+        ("stream deck", settings.stream_deck_port.is_some()),
+        ("shared view", settings.shared_view),
+        ("jump list", settings.jump_list),
+        ("telemetry", settings.telemetry),
+        ("plain paste", settings.plain_paste),
+        ("html to text", settings.html_to_text),
+        ("terminal paste", settings.terminal_paste),
+        (
+            "typed paste",
+            settings.paste_mode == PasteMode::SendinputText,
+        ),
+        ("fifo", settings.mode == Order::Fifo),
+        ("lazy restore", settings.lazy_restore),
+        ("debounce", settings.debounce.is_some()),
+        ("tag restores", settings.tag_restores),
+        ("provenance", settings.provenance),
+        ("auto grow", settings.auto_grow),
+        ("monitor stacks", settings.monitor_stacks),
+        ("max age", settings.max_age.is_some()),
+        ("quiet hours", !settings.quiet_hours.0.is_empty()),
+        (
+            "format filter",
+            settings.capture_formats.is_some() || settings.ignore_formats.is_some(),
+        ),
+        ("adaptive formats", settings.adaptive_formats),
+        ("app exclusions", !settings.exclude_apps.is_empty()),
+        (
+            "remote clipboard filter",
+            settings.remote_clipboard != RemoteClipboard::Capture,
+        ),
+        ("safe restore", !settings.safe_restore_apps.is_empty()),
+        ("policy", settings.policy.is_active()),
+    ];
+    features
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Show `details` in a message box
+pub fn show(details: &str) -> Result<(), String> {
+    message_box_w(
+        details,
+        "About FILO Clipboard",
+        winuser::MB_OK | winuser::MB_ICONINFORMATION,
+    )
+    .map(|_| ())
+    .map_err(|error| error.to_string())
+}
+
+/// Show the about box on its own thread, so the clipboard works while it's open
+pub fn spawn(details: String) {
+    thread::spawn(move || {
+        if let Err(error) = show(&details) {
+            warn!("Could not show the about box: {}", error);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_names_the_commit() {
+        assert!(version().starts_with(&format!("filo-clipboard {} (", env!("CARGO_PKG_VERSION"))));
+        assert!(version().ends_with(')'));
+    }
+}
@@ -0,0 +1,531 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    process,
+};
+
+use log::{info, warn};
+use winapi::um::wincon;
+
+use crate::cli::{
+    AuditCommand, Command, ConfigAction, MigrateCommand, Opts, PickerAction, RunOpts,
+    SnippetAction, StatsCommand,
+};
+use crate::config::Config;
+use crate::debug_overlay::DebugOverlay;
+use crate::instance::InstanceLock;
+use crate::ipc::{self, Request};
+use crate::onboarding::Tour;
+use crate::settings::{Overrides, Settings};
+use crate::storage::{self, StorageKind};
+use crate::telemetry::{self, Telemetry};
+use crate::usage::{self, UsageStats};
+use crate::winapi_functions::attach_console;
+use crate::window::Window;
+use crate::{about, audit, autostart, logging, migrations, repl, selftest, shared_view};
+
+pub fn run(opts: Opts) {
+    if opts.version || opts.about {
+        let _ = attach_console(wincon::ATTACH_PARENT_PROCESS);
+        if opts.version && !opts.verbose {
+            println!("{}", about::version());
+            return;
+        }
+        let settings = Settings::resolve(&opts).map_err(|error| error.to_string());
+        let details = about::details(settings.as_ref().map_err(Clone::clone));
+        if !opts.about {
+            println!("{}", details);
+        } else if let Err(error) = about::show(&details) {
+            eprintln!("Could not show the about box: {}", error);
+            process::exit(1);
+        }
+        return;
+    }
+    match &opts.command {
+        Some(Command::Run) | None => {
+            let pushes = initial_pushes(&opts.run).unwrap_or_else(|error| {
+                let _ = attach_console(wincon::ATTACH_PARENT_PROCESS);
+                eprintln!("Could not read stdin: {}", error);
+                process::exit(1);
+            });
+            let _instance = match InstanceLock::acquire(opts.run.replace) {
+                Ok(lock) => lock,
+                // Seeding a running instance is the point, rather than a mistake
+                Err(_) if !pushes.is_empty() && !opts.run.replace => {
+                    let _ = attach_console(wincon::ATTACH_PARENT_PROCESS);
+                    process::exit(push_to_running(pushes));
+                }
+                Err(message) => {
+                    let _ = attach_console(wincon::ATTACH_PARENT_PROCESS);
+                    eprintln!("{}", message);
+                    process::exit(1);
+                }
+            };
+            let settings = Settings::resolve(&opts).unwrap_or_else(|error| {
+                let _ = attach_console(wincon::ATTACH_PARENT_PROCESS);
+                eprintln!("Invalid settings: {}", error);
+                process::exit(1);
+            });
+            if let Err(error) = logging::init(settings.log_level, settings.log_file.as_deref()) {
+                warn!("Could not open the log file: {}", error);
+            }
+            for result in migrations::upgrade_files(&settings) {
+                match result {
+                    Ok(report) => info!("{}", report),
+                    Err(error) => warn!("{}", error),
+                }
+            }
+
+            let tour = Tour::new(&settings);
+
+            // Create a window and event handler
+            let mut window = Window::new(settings).unwrap_or_else(|error| {
+                let _ = attach_console(wincon::ATTACH_PARENT_PROCESS);
+                eprintln!("{}", error);
+                process::exit(1);
+            });
+            if opts.run.debug_overlay {
+                match DebugOverlay::spawn() {
+                    Ok(mut overlay) => window.subscribe(move |event| overlay.handle_event(event)),
+                    Err(error) => warn!("Could not show the debug overlay: {}", error),
+                }
+            }
+            for text in pushes {
+                window.push_text(&text);
+            }
+            if tour.is_due() {
+                tour.spawn();
+            }
+            window.run_event_loop();
+        }
+        Some(command) => {
+            // Release builds have no console of their own, so borrow the one we were started from
+            let _ = attach_console(wincon::ATTACH_PARENT_PROCESS);
+            process::exit(run_command(&opts, command));
+        }
+    }
+}
+
+/// Run a one-off command, returning the exit code
+fn run_command(opts: &Opts, command: &Command) -> i32 {
+    match command {
+        Command::Run => unreachable!("the run command is handled by run"),
+        Command::Config(config_command) => match config_command.action {
+            ConfigAction::Validate => validate_config(opts),
+            ConfigAction::PrintDefault => {
+                print!("{}", Config::default_file());
+                0
+            }
+            ConfigAction::Get(ref get) => send_request(Request::ConfigGet {
+                key: get.key.clone(),
+            }),
+            ConfigAction::Set(ref set) => send_request(Request::ConfigSet {
+                key: set.key.clone(),
+                value: set.value.clone(),
+                save: set.save,
+            }),
+        },
+        Command::Profile(profile) => send_request(Request::SwitchProfile {
+            name: profile.name.clone(),
+        }),
+        Command::Save(save) => send_request(Request::Save {
+            index: save.index,
+            open: save.open,
+        }),
+        Command::CopyFile(copy_file) => send_request(Request::CopyAsFile {
+            index: copy_file.index,
+        }),
+        Command::Restore(restore) => send_request(Request::Restore {
+            index: restore.index,
+            dry_run: restore.dry_run,
+        }),
+        Command::Expire(expire) => send_request(Request::Expire {
+            index: expire.index,
+            seconds: expire.after,
+        }),
+        Command::Burn(burn) => send_request(Request::Burn { index: burn.index }),
+        Command::Pin(pin) => send_request(Request::Pin { index: pin.index }),
+        Command::Unpin(unpin) => send_request(Request::Unpin { index: unpin.index }),
+        Command::Batch(batch) => send_request(Request::Batch {
+            revision: batch.if_revision,
+            operations: batch.operations.clone(),
+        }),
+        Command::Pop => send_request(Request::Pop),
+        Command::PasteRecent(paste) => send_request(Request::PasteRecent {
+            fingerprint: paste.fingerprint,
+        }),
+        Command::Clear => send_request(Request::Clear),
+        Command::Compact => send_request(Request::Compact),
+        Command::Recapture => send_request(Request::Recapture),
+        Command::List(list) if list.shared => match shared_view::read() {
+            Ok((revision, list)) => {
+                println!("Revision: {}", revision);
+                if !list.is_empty() {
+                    println!("{}", list);
+                }
+                0
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                1
+            }
+        },
+        Command::List(_) => send_request(Request::List),
+        Command::Status(status) => send_request(Request::Status { json: status.json }),
+        Command::Selftest => selftest::run(),
+        Command::Tour => match Settings::resolve(opts) {
+            Ok(settings) => match Tour::new(&settings).show() {
+                Ok(()) => 0,
+                Err(error) => {
+                    eprintln!("Could not show the tour: {}", error);
+                    1
+                }
+            },
+            Err(error) => {
+                eprintln!("{}", error);
+                1
+            }
+        },
+        Command::Audit(audit) => show_audit_log(opts, audit),
+        Command::Stats(stats) => show_stats(opts, stats),
+        Command::Export(export) => export_history(&export.path),
+        Command::Migrate(migrate) => migrate_storage(opts, migrate),
+        Command::Upgrade => upgrade_files(opts),
+        Command::Repl => repl::run(),
+        Command::InstallAutostart => autostart::install(),
+        Command::UninstallAutostart => autostart::uninstall(),
+        Command::Import(import) => match fs::read_to_string(&import.path) {
+            Ok(json) => send_request(Request::Import { json }),
+            Err(error) => {
+                eprintln!("{}: {}", import.path.display(), error);
+                1
+            }
+        },
+        Command::Picker(picker) => send_request(match picker.action {
+            PickerAction::Open => Request::PickerOpen,
+            PickerAction::Up(ref up) => Request::PickerMove {
+                by: -(up.count as isize),
+            },
+            PickerAction::Down(ref down) => Request::PickerMove {
+                by: down.count as isize,
+            },
+            PickerAction::Confirm => Request::PickerConfirm,
+            PickerAction::Cancel => Request::PickerCancel,
+        }),
+        Command::Snippet(snippet) => send_request(match snippet.action {
+            SnippetAction::Save(ref save) => Request::SnippetSave {
+                index: save.index,
+                name: save.name.clone(),
+            },
+            SnippetAction::Paste(ref paste) => Request::SnippetPaste {
+                name: paste.name.clone(),
+            },
+            SnippetAction::Delete(ref delete) => Request::SnippetDelete {
+                name: delete.name.clone(),
+            },
+            SnippetAction::List => Request::SnippetList,
+        }),
+        Command::Stack(stack) => send_request(match &stack.name {
+            Some(name) => Request::SwitchStack { name: name.clone() },
+            None => Request::StackList,
+        }),
+    }
+}
+
+/// The text given with --push, followed by stdin with --push-stdin. A single trailing line break
+/// is dropped from stdin, as `echo` adds one
+fn initial_pushes(run: &RunOpts) -> io::Result<Vec<String>> {
+    let mut pushes = run.push.clone();
+    if run.push_stdin {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        let trimmed = text
+            .strip_suffix("\r\n")
+            .or_else(|| text.strip_suffix('\n'))
+            .unwrap_or(&text);
+        pushes.push(trimmed.to_owned());
+    }
+    Ok(pushes)
+}
+
+/// Add each text to the running instance's history in turn, returning the exit code
+fn push_to_running(pushes: Vec<String>) -> i32 {
+    for text in pushes {
+        let code = send_request(Request::Push { text });
+        if code != 0 {
+            return code;
+        }
+    }
+    0
+}
+
+/// Forward a request to the running instance and print its response
+fn send_request(request: Request) -> i32 {
+    match ipc::send(&request) {
+        Ok(response) if response.ok => {
+            println!("{}", response.message);
+            0
+        }
+        Ok(response) => {
+            eprintln!("{}", response.message);
+            1
+        }
+        Err(error) => {
+            eprintln!("Could not reach the running instance: {}", error);
+            1
+        }
+    }
+}
+
+/// Write the running instance's history to `path`, returning the exit code
+fn export_history(path: &Path) -> i32 {
+    let json = match ipc::send(&Request::Export) {
+        Ok(response) if response.ok => response.message,
+        Ok(response) => {
+            eprintln!("{}", response.message);
+            return 1;
+        }
+        Err(error) => {
+            eprintln!("Could not reach the running instance: {}", error);
+            return 1;
+        }
+    };
+    match fs::write(path, json) {
+        Ok(()) => {
+            println!("Exported the history to {}", path.display());
+            0
+        }
+        Err(error) => {
+            eprintln!("{}: {}", path.display(), error);
+            1
+        }
+    }
+}
+
+/// Copy the history into another kind of storage. The instance lock is held throughout, so the
+/// clipboard can't start and write the history part way
+fn migrate_storage(opts: &Opts, command: &MigrateCommand) -> i32 {
+    let _instance = match InstanceLock::acquire(false) {
+        Ok(instance) => instance,
+        Err(error) => {
+            eprintln!("{}", error);
+            return 1;
+        }
+    };
+    let settings = match Settings::resolve(opts) {
+        Ok(settings) => settings,
+        Err(error) => {
+            eprintln!("{}", error);
+            return 1;
+        }
+    };
+    let from_path = match &settings.persist {
+        Some(path) if settings.storage != StorageKind::Memory => path,
+        _ => {
+            eprintln!("There's no stored history to migrate, as it isn't persisted");
+            return 1;
+        }
+    };
+    if command.to == StorageKind::Memory {
+        eprintln!("Migrating to memory storage would lose the history");
+        return 1;
+    }
+    let to_path = command
+        .path
+        .clone()
+        .unwrap_or_else(|| from_path.with_extension(command.to.extension()));
+    if to_path == *from_path {
+        eprintln!("{} is already the history file", to_path.display());
+        return 1;
+    }
+
+    let copied =
+        storage::open(settings.storage, from_path, settings.encrypt).and_then(|mut from| {
+            let mut to = storage::open(command.to, &to_path, settings.encrypt)?;
+            storage::migrate(&mut *from, &mut *to)
+        });
+    match copied {
+        Ok(copied) => {
+            println!(
+                "Copied {} items to {}. Set storage = \"{}\" and persist = {:?} in the config to \
+                 use it",
+                copied,
+                to_path.display(),
+                command.to,
+                to_path
+            );
+            0
+        }
+        Err(error) => {
+            eprintln!("Could not migrate the history: {}", error);
+            1
+        }
+    }
+}
+
+/// Upgrade the files older versions wrote, while nothing is running, returning the exit code
+fn upgrade_files(opts: &Opts) -> i32 {
+    let _instance = match InstanceLock::acquire(false) {
+        Ok(instance) => instance,
+        Err(error) => {
+            eprintln!("{}", error);
+            return 1;
+        }
+    };
+    let settings = match Settings::resolve(opts) {
+        Ok(settings) => settings,
+        Err(error) => {
+            eprintln!("{}", error);
+            return 1;
+        }
+    };
+    let results = migrations::upgrade_files(&settings);
+    if results.is_empty() {
+        println!("Everything is up to date");
+    }
+    let mut code = 0;
+    for result in results {
+        match result {
+            Ok(report) => println!("{}", report),
+            Err(error) => {
+                eprintln!("{}", error);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+/// Print the audit log, oldest first, with the filters from the command applied
+fn show_audit_log(opts: &Opts, command: &AuditCommand) -> i32 {
+    let path = match Settings::resolve(opts) {
+        Ok(Settings {
+            audit_log: Some(path),
+            ..
+        }) => path,
+        Ok(_) => {
+            eprintln!("No audit log is configured. Set audit_log in the config or use --audit-log");
+            return 1;
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            return 1;
+        }
+    };
+    let records = match audit::read(&path) {
+        Ok(records) => records,
+        Err(error) => {
+            eprintln!("{}: {}", path.display(), error);
+            return 1;
+        }
+    };
+
+    let records: Vec<_> = records
+        .into_iter()
+        .filter(|record| match &command.app {
+            Some(app) => record
+                .app
+                .as_ref()
+                .map(|name| name.eq_ignore_ascii_case(app))
+                .unwrap_or(false),
+            None => true,
+        })
+        .collect();
+    let skip = command
+        .limit
+        .map(|limit| records.len().saturating_sub(limit))
+        .unwrap_or(0);
+    for record in &records[skip..] {
+        println!(
+            "{}\t{}\t{}\t{:016x}",
+            audit::format_timestamp(record.timestamp),
+            record.action,
+            record.app.as_deref().unwrap_or("-"),
+            record.hash
+        );
+    }
+    0
+}
+
+fn show_stats(opts: &Opts, command: &StatsCommand) -> i32 {
+    let settings = match Settings::resolve(opts) {
+        Ok(settings) => settings,
+        Err(error) => {
+            eprintln!("{}", error);
+            return 1;
+        }
+    };
+    let config_path = match settings.config_path.as_deref() {
+        Some(path) => path,
+        None => {
+            eprintln!("No config file given and %APPDATA% isn't set");
+            return 1;
+        }
+    };
+    let usage = UsageStats::load(&usage::stats_path(config_path));
+    println!("Items copied: {}", usage.copies);
+    match usage.average_entry_size() {
+        Some(size) => println!("Average item size: {} bytes", size),
+        None => println!("Average item size: -"),
+    }
+    println!("Typical history depth: {}", usage.typical_depth);
+
+    if !command.detailed && command.export.is_none() {
+        return 0;
+    }
+    let path = telemetry::telemetry_path(config_path);
+    if !path.exists() {
+        eprintln!(
+            "No telemetry has been kept. Set telemetry = true in the config or use --telemetry"
+        );
+        return if command.detailed { 0 } else { 1 };
+    }
+    let telemetry = Telemetry::load(&path);
+    if command.detailed {
+        println!("{}", telemetry.report());
+    }
+    if let Some(export) = &command.export {
+        if let Err(error) = fs::write(export, telemetry.to_json()) {
+            eprintln!("{}: {}", export.display(), error);
+            return 1;
+        }
+    }
+    0
+}
+
+fn validate_config(opts: &Opts) -> i32 {
+    let overrides = match Overrides::from_env() {
+        Ok(env) => Overrides::from_opts(opts).or(env),
+        Err(error) => {
+            eprintln!("{}", error);
+            return 1;
+        }
+    };
+    let path = match overrides.config_path() {
+        Some(path) => path,
+        None => {
+            eprintln!("No config file given and %APPDATA% isn't set");
+            return 1;
+        }
+    };
+
+    match Config::load(&path) {
+        Ok(mut config) => {
+            let issues = config.validate();
+            for issue in &issues {
+                eprintln!("{}: {}", path.display(), issue);
+            }
+            if issues.is_empty() {
+                println!("{}: OK", path.display());
+                0
+            } else {
+                1
+            }
+        }
+        Err(error) => {
+            eprintln!("{}: {}", path.display(), error);
+            1
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::path::{Path, PathBuf};
+
+use winapi::shared::windef::HWND__;
+
+use crate::winapi_functions::{
+    get_clipboard_owner, get_window_thread_process_id, query_full_process_image_name,
+};
+
+/// The programs which copy the clipboard between a remote desktop or Citrix session and this one
+const REMOTE_CLIPBOARD_APPS: &[&str] = &["rdpclip.exe", "wfica32.exe"];
+
+/// The executable of the process which created `h_wnd`
+pub fn window_app(h_wnd: &mut HWND__) -> Option<PathBuf> {
+    match get_window_thread_process_id(h_wnd) {
+        0 => None,
+        process_id => query_full_process_image_name(process_id).ok(),
+    }
+}
+
+/// The executable of the program which last wrote the clipboard. Some programs write it without a
+/// window, in which case there's no way to tell
+pub fn clipboard_owner_app() -> Option<PathBuf> {
+    get_clipboard_owner().and_then(window_app)
+}
+
+/// Whether the executable at `path` is one of `names`, such as "keepass.exe". Names are compared
+/// without regard to case, and ".exe" can be left off
+pub fn matches_app(path: &Path, names: &[impl AsRef<str>]) -> bool {
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().to_lowercase(),
+        None => return false,
+    };
+    let stem = file_name.strip_suffix(".exe").unwrap_or(&file_name);
+    names.iter().any(|name| {
+        let name = name.as_ref().trim().to_lowercase();
+        name == file_name || name == stem
+    })
+}
+
+/// Whether the executable at `path` copies the clipboard from a remote session
+pub fn is_remote_clipboard(path: &Path) -> bool {
+    matches_app(path, REMOTE_CLIPBOARD_APPS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_file_name() {
+        let names = ["KeePass.exe".to_owned(), "bitwarden".to_owned()];
+        let keepass = Path::new(r"C:\Program Files\KeePass Password Safe 2\KeePass.exe");
+        assert!(matches_app(keepass, &names));
+        assert!(matches_app(Path::new(r"C:\Apps\Bitwarden.exe"), &names));
+        assert!(!matches_app(Path::new(r"C:\Windows\notepad.exe"), &names));
+        assert!(!matches_app(
+            Path::new(r"C:\Apps\bitwarden-helper.exe"),
+            &names
+        ));
+    }
+
+    #[test]
+    fn remote_clipboard_apps() {
+        assert!(is_remote_clipboard(Path::new(
+            r"C:\Windows\System32\rdpclip.exe"
+        )));
+        assert!(!is_remote_clipboard(Path::new(r"C:\Windows\notepad.exe")));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_round_trip() {
+        let record = Record {
+            timestamp: 1_700_000_000,
+            action: Action::PlainPaste,
+            app: Some("WINWORD.EXE".to_owned()),
+            hash: 0x0123_4567_89ab_cdef,
+        };
+        assert_eq!(record.to_string().parse(), Ok(record.clone()));
+        let popped = Record {
+            app: None,
+            action: Action::Pop,
+            ..record
+        };
+        assert_eq!(popped.to_string().parse(), Ok(popped));
+    }
+
+    #[test]
+    fn invalid_records() {
+        assert!("".parse::<Record>().is_err());
+        assert!("1\tcut\t-\t00".parse::<Record>().is_err());
+        assert!("x\tpaste\t-\t00".parse::<Record>().is_err());
+    }
+
+    #[test]
+    fn format_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00Z");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13:20Z");
+    }
+}
+
+/// What was done with an item
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Paste,
+    PlainPaste,
+    Peek,
+    Pick,
+    Pop,
+    PastePinned,
+    PasteSnippet,
+}
+
+const ACTIONS: &[(Action, &str)] = &[
+    (Action::Paste, "paste"),
+    (Action::PlainPaste, "plain-paste"),
+    (Action::Peek, "peek"),
+    (Action::Pick, "pick"),
+    (Action::Pop, "pop"),
+    (Action::PastePinned, "paste-pinned"),
+    (Action::PasteSnippet, "paste-snippet"),
+];
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name) = ACTIONS.iter().find(|(action, _)| action == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        ACTIONS
+            .iter()
+            .find(|(_, name)| *name == value)
+            .map(|(action, _)| *action)
+            .ok_or_else(|| format!("unknown action \"{}\"", value))
+    }
+}
+
+/// One line of the audit log. Only a hash of the item is kept, never its contents
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub action: Action,
+    /// The executable of the program pasted into, if there was one
+    pub app: Option<String>,
+    pub hash: u64,
+}
+
+impl Record {
+    pub fn now(action: Action, app: Option<String>, hash: u64) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        Self {
+            timestamp,
+            action,
+            app,
+            hash,
+        }
+    }
+}
+
+/// Tab separated, with "-" for a missing app
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{:016x}",
+            self.timestamp,
+            self.action,
+            self.app.as_deref().unwrap_or("-"),
+            self.hash
+        )
+    }
+}
+
+impl FromStr for Record {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid audit record \"{}\"", line);
+        match line.split('\t').collect::<Vec<_>>().as_slice() {
+            [timestamp, action, app, hash] => Ok(Self {
+                timestamp: timestamp.parse().map_err(|_| invalid())?,
+                action: action.parse()?,
+                app: Some(app.to_string()).filter(|app| app != "-"),
+                hash: u64::from_str_radix(hash, 16).map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Add a record to the end of the log, creating it if needed. Existing records are never rewritten
+pub fn append(path: &Path, record: &Record) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    writeln!(file, "{}", record)
+}
+
+/// Read every record in the log, skipping lines which can't be parsed
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| line.parse().ok())
+        .collect())
+}
+
+/// Format seconds since the Unix epoch as a UTC date and time
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+    // Convert days to a civil date, from Howard Hinnant's days_from_civil algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
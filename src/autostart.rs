@@ -0,0 +1,195 @@
+use std::env;
+use std::path::Path;
+
+use crate::winapi_functions::{reg_delete_current_user_value, reg_set_current_user_string};
+
+/// Where programs started at sign in are listed for the current user
+const RUN_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+const VALUE_NAME: &str = "FILO Clipboard";
+/// The subcommands which aren't passed on to the registered command line
+const SUBCOMMANDS: [&str; 2] = ["install-autostart", "uninstall-autostart"];
+/// The options whose values are paths, which are made absolute since sign in starts the clipboard
+/// in a different directory
+const PATH_OPTIONS: [&str; 4] = ["--config", "--persist", "--log-file", "--audit-log"];
+
+/// Quote `arg` so the C runtime splits it back out of a command line unchanged
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(&[' ', '\t', '"'][..]) {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // Backslashes before a quote escape each other, and the quote needs one of its own
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                backslashes = 0;
+            }
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+            }
+        }
+        if c != '\\' {
+            quoted.push(c);
+        }
+    }
+    // The closing quote mustn't be escaped by trailing backslashes
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// `path` relative to `dir` if it isn't absolute already
+fn absolute(dir: &Path, path: &str) -> String {
+    dir.join(path).to_string_lossy().into_owned()
+}
+
+/// The command line which runs `exe` with `args`, leaving out the autostart subcommand and making
+/// the paths given to `PATH_OPTIONS` absolute, relative to `dir`
+fn command_line(exe: &str, dir: &Path, args: &[String]) -> String {
+    let mut skipped = false;
+    let mut path_follows = false;
+    std::iter::once(quote(exe))
+        .chain(
+            args.iter()
+                .filter(|arg| {
+                    let subcommand = !skipped && SUBCOMMANDS.contains(&arg.as_str());
+                    skipped |= subcommand;
+                    !subcommand
+                })
+                .map(|arg| {
+                    let arg = if std::mem::take(&mut path_follows) {
+                        absolute(dir, arg)
+                    } else if let Some((option, path)) = arg.split_once('=') {
+                        if PATH_OPTIONS.contains(&option) {
+                            format!("{}={}", option, absolute(dir, path))
+                        } else {
+                            arg.clone()
+                        }
+                    } else {
+                        path_follows = PATH_OPTIONS.contains(&arg.as_str());
+                        arg.clone()
+                    };
+                    quote(&arg)
+                }),
+        )
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Start the clipboard when the current user signs in, with the options given on this command
+/// line. Returns the exit code
+pub fn install() -> i32 {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(error) => {
+            eprintln!("Could not find the executable: {}", error);
+            return 1;
+        }
+    };
+    let dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(error) => {
+            eprintln!("Could not find the current directory: {}", error);
+            return 1;
+        }
+    };
+    let args: Vec<_> = env::args().skip(1).collect();
+    let command = command_line(&exe.to_string_lossy(), &dir, &args);
+    match reg_set_current_user_string(RUN_KEY, VALUE_NAME, &command) {
+        Ok(()) => {
+            println!(
+                "FILO Clipboard will start when you sign in, with: {}",
+                command
+            );
+            0
+        }
+        Err(error) => {
+            eprintln!(
+                "Could not add FILO Clipboard to the startup programs: {}",
+                error
+            );
+            1
+        }
+    }
+}
+
+/// Stop the clipboard starting when the current user signs in. Returns the exit code
+pub fn uninstall() -> i32 {
+    match reg_delete_current_user_value(RUN_KEY, VALUE_NAME) {
+        Ok(true) => {
+            println!("FILO Clipboard will no longer start when you sign in");
+            0
+        }
+        Ok(false) => {
+            println!("FILO Clipboard wasn't set to start when you sign in");
+            0
+        }
+        Err(error) => {
+            eprintln!(
+                "Could not remove FILO Clipboard from the startup programs: {}",
+                error
+            );
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn quote_arguments() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("two words"), "\"two words\"");
+        assert_eq!(quote(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote(r"C:\some dir\"), r#""C:\some dir\\""#);
+    }
+
+    #[test]
+    fn command_line_keeps_options() {
+        assert_eq!(
+            command_line(
+                r"C:\Program Files\filo-clipboard.exe",
+                Path::new(r"C:\Users\me"),
+                &args(&[
+                    "--max-history",
+                    "50",
+                    "install-autostart",
+                    "--hotkey",
+                    "ctrl+shift+v"
+                ])
+            ),
+            r#""C:\Program Files\filo-clipboard.exe" --max-history 50 --hotkey ctrl+shift+v"#
+        );
+    }
+
+    #[test]
+    fn command_line_makes_paths_absolute() {
+        assert_eq!(
+            command_line(
+                r"C:\filo.exe",
+                Path::new(r"C:\Users\me"),
+                &args(&[
+                    "--persist",
+                    "history.bin",
+                    "--config=cfg\\config.toml",
+                    "--log-file",
+                    r"D:\filo.log",
+                    "--profile",
+                    "work"
+                ])
+            ),
+            r"C:\filo.exe --persist C:\Users\me\history.bin --config=C:\Users\me\cfg\config.toml --log-file D:\filo.log --profile work"
+        );
+    }
+}
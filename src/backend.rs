@@ -0,0 +1,383 @@
+use std::{
+    env,
+    io::Write,
+    iter,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+#[cfg(windows)]
+use clipboard_win::{empty, formats, Clipboard, EnumFormats, Getter};
+
+#[cfg(windows)]
+use crate::clipboard_extras::{get_formats_ordered, set_all, set_all_with_timeout};
+use crate::clipboard_extras::{read_text, write_unicode_text, ClipboardItem, CF_UNICODETEXT};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_clipboard() {
+        let mut clipboard = MemoryClipboard::default();
+        assert!(clipboard.read_all(|_| true).is_empty());
+        clipboard
+            .set(&[
+                write_unicode_text("text"),
+                ClipboardItem {
+                    format: 49_000,
+                    content: Vec::new(),
+                },
+            ])
+            .unwrap();
+        assert_eq!(clipboard.formats(), [13, 49_000]);
+        assert_eq!(clipboard.sequence(), 1);
+        // Empty formats are left out, as they are on the real clipboard
+        let items = clipboard.read_all(|_| true);
+        assert_eq!(items.len(), 1);
+        assert_eq!(read_text(&items), Some("text".to_owned()));
+        assert!(clipboard.read_all(|format| format != 13).is_empty());
+        clipboard.empty().unwrap();
+        assert!(clipboard.formats().is_empty());
+        assert_eq!(clipboard.sequence(), 2);
+    }
+
+    #[test]
+    fn pick_the_command_clipboard() {
+        let vars = |names: &'static [&'static str]| {
+            move |name: &str| names.contains(&name).then(|| "1".to_owned())
+        };
+        assert_eq!(
+            CommandClipboard::detect_with(vars(&["WAYLAND_DISPLAY", "DISPLAY"]), false),
+            Some(CommandClipboard::WAYLAND)
+        );
+        assert_eq!(
+            CommandClipboard::detect_with(vars(&["DISPLAY"]), false),
+            Some(CommandClipboard::X11)
+        );
+        assert_eq!(CommandClipboard::detect_with(vars(&[]), false), None);
+        assert_eq!(
+            CommandClipboard::detect_with(vars(&["DISPLAY"]), true),
+            Some(CommandClipboard::MACOS)
+        );
+    }
+}
+
+/// The clipboard operations the history needs, so what it does with the clipboard can be tried
+/// against `MemoryClipboard` without a desktop
+pub trait ClipboardBackend {
+    /// The formats on the clipboard, in the order they were put there
+    fn formats(&self) -> Vec<u32>;
+
+    /// The contents of `format`, if it's on the clipboard and could be read
+    fn read(&self, format: u32) -> Option<Vec<u8>>;
+
+    /// Replace the clipboard's contents with `items`
+    fn set(&mut self, items: &[ClipboardItem]) -> Result<(), String>;
+
+    fn empty(&mut self) -> Result<(), String>;
+
+    /// Read the non-empty formats for which `allows` is true, without reading the others at all
+    fn read_all(&self, allows: impl Fn(u32) -> bool) -> Vec<ClipboardItem>
+    where
+        Self: Sized,
+    {
+        self.read_ordered(allows, |_| {})
+    }
+
+    /// Like `read_all`, but the formats are read in the order `order` sorts them into
+    fn read_ordered(
+        &self,
+        allows: impl Fn(u32) -> bool,
+        order: impl FnOnce(&mut [u32]),
+    ) -> Vec<ClipboardItem>
+    where
+        Self: Sized,
+    {
+        let mut formats: Vec<_> = self
+            .formats()
+            .into_iter()
+            .filter(|format| allows(*format))
+            .collect();
+        order(&mut formats);
+        formats
+            .into_iter()
+            .filter_map(|format| {
+                self.read(format)
+                    .filter(|content| !content.is_empty())
+                    .map(|content| ClipboardItem { format, content })
+            })
+            .collect()
+    }
+
+    /// Like `set`, but gives up once `timeout` has passed, returning `None`. Otherwise says for
+    /// each item whether its format was set
+    fn set_with_timeout(
+        &mut self,
+        items: Vec<ClipboardItem>,
+        _timeout: Duration,
+    ) -> Option<Vec<Result<(), String>>>
+    where
+        Self: Sized,
+    {
+        let result = self.set(&items);
+        Some(iter::repeat_n(result, items.len()).collect())
+    }
+}
+
+/// The Windows clipboard, opened for each operation
+#[cfg(windows)]
+#[derive(Debug, Default)]
+pub struct WindowsClipboard;
+
+#[cfg(windows)]
+fn open() -> Result<Clipboard, String> {
+    Clipboard::new_attempts(10).map_err(|error| format!("Could not open the clipboard: {}", error))
+}
+
+#[cfg(windows)]
+impl ClipboardBackend for WindowsClipboard {
+    fn formats(&self) -> Vec<u32> {
+        match open() {
+            Ok(_clip) => EnumFormats::new().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn read(&self, format: u32) -> Option<Vec<u8>> {
+        let _clip = open().ok()?;
+        let mut content = Vec::new();
+        formats::RawData(format)
+            .read_clipboard(&mut content)
+            .ok()
+            .map(|_| content)
+    }
+
+    fn set(&mut self, items: &[ClipboardItem]) -> Result<(), String> {
+        let _clip = open()?;
+        set_all(items)
+            .into_iter()
+            .zip(items)
+            .try_for_each(|(result, item)| {
+                result.map_err(|error| format!("Could not set format {}: {}", item.format, error))
+            })
+    }
+
+    fn empty(&mut self) -> Result<(), String> {
+        let _clip = open()?;
+        empty().map_err(|error| format!("Could not empty the clipboard: {}", error))
+    }
+
+    // Opening the clipboard once for every format would let other programs change it in between
+    fn read_ordered(
+        &self,
+        allows: impl Fn(u32) -> bool,
+        order: impl FnOnce(&mut [u32]),
+    ) -> Vec<ClipboardItem> {
+        match open() {
+            Ok(_clip) => get_formats_ordered(allows, order),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Written from another thread, so a program which holds the clipboard can't freeze us
+    fn set_with_timeout(
+        &mut self,
+        items: Vec<ClipboardItem>,
+        timeout: Duration,
+    ) -> Option<Vec<Result<(), String>>> {
+        let formats: Vec<_> = items.iter().map(|item| item.format).collect();
+        set_all_with_timeout(items, timeout).map(|results| {
+            results
+                .into_iter()
+                .zip(formats)
+                .map(|(result, format)| {
+                    result.map_err(|error| format!("Could not set format {}: {}", format, error))
+                })
+                .collect()
+        })
+    }
+}
+
+/// A clipboard kept in memory, for tests and for embedding the history where there's no desktop
+#[derive(Debug, Default, Clone)]
+pub struct MemoryClipboard {
+    items: Vec<ClipboardItem>,
+    sequence: u32,
+}
+
+impl MemoryClipboard {
+    /// How many times the contents have changed, like GetClipboardSequenceNumber
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+}
+
+impl ClipboardBackend for MemoryClipboard {
+    fn formats(&self) -> Vec<u32> {
+        self.items.iter().map(|item| item.format).collect()
+    }
+
+    fn read(&self, format: u32) -> Option<Vec<u8>> {
+        self.items
+            .iter()
+            .find(|item| item.format == format)
+            .map(|item| item.content.clone())
+    }
+
+    fn set(&mut self, items: &[ClipboardItem]) -> Result<(), String> {
+        self.items = items.to_vec();
+        self.sequence += 1;
+        Ok(())
+    }
+
+    fn empty(&mut self) -> Result<(), String> {
+        self.items.clear();
+        self.sequence += 1;
+        Ok(())
+    }
+}
+
+/// A text-only clipboard reached through the command line tools of each desktop, for Linux and
+/// macOS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandClipboard {
+    /// Prints the clipboard's text
+    paste: &'static [&'static str],
+    /// Puts the text it's given on the clipboard
+    copy: &'static [&'static str],
+    /// Empties the clipboard, if the tools can. Otherwise it's set to no text
+    clear: Option<&'static [&'static str]>,
+    /// Presses the paste shortcut in the focused program
+    keys: &'static [&'static str],
+}
+
+impl CommandClipboard {
+    /// wl-clipboard, for Wayland
+    pub const WAYLAND: Self = Self {
+        paste: &["wl-paste", "--no-newline", "--type", "text"],
+        copy: &["wl-copy", "--type", "text/plain;charset=utf-8"],
+        clear: Some(&["wl-copy", "--clear"]),
+        keys: &["wtype", "-M", "ctrl", "v", "-m", "ctrl"],
+    };
+    /// xclip, for X11
+    pub const X11: Self = Self {
+        paste: &["xclip", "-selection", "clipboard", "-out"],
+        copy: &["xclip", "-selection", "clipboard", "-in"],
+        clear: None,
+        // The shortcut which ran us may still be held, so its keys are let go first
+        keys: &["xdotool", "key", "--clearmodifiers", "ctrl+v"],
+    };
+    pub const MACOS: Self = Self {
+        paste: &["pbpaste"],
+        copy: &["pbcopy"],
+        clear: None,
+        keys: &[
+            "osascript",
+            "-e",
+            "tell application \"System Events\" to keystroke \"v\" using command down",
+        ],
+    };
+
+    /// The tools for the desktop this is running on, if it's one they're known for
+    pub fn detect() -> Option<Self> {
+        Self::detect_with(|name| env::var(name).ok(), cfg!(target_os = "macos"))
+    }
+
+    fn detect_with(lookup: impl Fn(&str) -> Option<String>, macos: bool) -> Option<Self> {
+        let set = |name| lookup(name).is_some_and(|value| !value.is_empty());
+        if macos {
+            Some(Self::MACOS)
+        } else if set("WAYLAND_DISPLAY") {
+            Some(Self::WAYLAND)
+        } else if set("DISPLAY") {
+            Some(Self::X11)
+        } else {
+            None
+        }
+    }
+
+    /// Paste what's on the clipboard into the focused program
+    pub fn send_paste(&self) -> Result<(), String> {
+        self.run(self.keys, None)
+    }
+
+    fn text(&self) -> Option<String> {
+        let output = Command::new(self.paste[0])
+            .args(&self.paste[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+            .filter(|text| output.status.success() && !text.is_empty())
+    }
+
+    fn run(&self, command: &[&str], input: Option<&str>) -> Result<(), String> {
+        let failed =
+            |error: &dyn std::fmt::Display| format!("Could not run {}: {}", command[0], error);
+        // The copy tools stay running to serve the text, so their output mustn't be waited on
+        let mut child = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|error| failed(&error))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(input.unwrap_or_default().as_bytes())
+                .map_err(|error| failed(&error))?;
+        }
+        match child.wait() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(failed(&status)),
+            Err(error) => Err(failed(&error)),
+        }
+    }
+}
+
+impl ClipboardBackend for CommandClipboard {
+    fn formats(&self) -> Vec<u32> {
+        match self.text() {
+            Some(_) => vec![CF_UNICODETEXT],
+            None => Vec::new(),
+        }
+    }
+
+    fn read(&self, format: u32) -> Option<Vec<u8>> {
+        if format != CF_UNICODETEXT {
+            return None;
+        }
+        self.text().map(|text| write_unicode_text(&text).content)
+    }
+
+    fn set(&mut self, items: &[ClipboardItem]) -> Result<(), String> {
+        let text = read_text(items).ok_or_else(|| {
+            format!(
+                "Only text can be put on the clipboard with {}",
+                self.copy[0]
+            )
+        })?;
+        self.run(self.copy, Some(&text))
+    }
+
+    fn empty(&mut self) -> Result<(), String> {
+        match self.clear {
+            Some(clear) => self.run(clear, None),
+            None => self.run(self.copy, Some("")),
+        }
+    }
+
+    // The text is all there is, so it's only fetched once and there's nothing to order
+    fn read_ordered(
+        &self,
+        allows: impl Fn(u32) -> bool,
+        _order: impl FnOnce(&mut [u32]),
+    ) -> Vec<ClipboardItem> {
+        match self.text() {
+            Some(text) if allows(CF_UNICODETEXT) => vec![write_unicode_text(&text)],
+            _ => Vec::new(),
+        }
+    }
+}
@@ -1,12 +1,592 @@
+use std::path::PathBuf;
+
 use clap::{AppSettings, Clap};
 
+use crate::config::{
+    Age, ByteSize, Dedup, LogLevel, Order, PasteMode, QuietHours, RemoteClipboard,
+};
+use crate::formats::FormatList;
+use crate::hotkey::{Hotkey, Keystroke};
+use crate::ipc::BatchOperation;
+use crate::persistence::FlushStrategy;
+use crate::storage::StorageKind;
+
 /// This program provides a FILO queue from values copies to the clipboard,
 /// which can be used with Ctrl+Shift+V
 #[derive(Clap)]
 #[clap(version = "1.0", author = "David A. <github.com/davystrong>")]
 #[clap(setting = AppSettings::ColoredHelp)]
+#[clap(setting = AppSettings::DisableVersionFlag)]
 pub struct Opts {
-    /// The maximum number of items to keep in the clipboard history
-    #[clap(long, default_value = "50")]
-    pub max_history: usize,
+    /// Print the version. With --verbose, also print the build and the features in use
+    #[clap(short = 'V', long)]
+    pub version: bool,
+    /// Print more detail with --version
+    #[clap(long, requires = "version")]
+    pub verbose: bool,
+    /// Show the version, build and features in use in a window
+    #[clap(long)]
+    pub about: bool,
+    /// The config file to load profiles from. Defaults to %APPDATA%\filo-clipboard\config.toml
+    #[clap(long, parse(from_os_str), global = true)]
+    pub config: Option<PathBuf>,
+    /// The profile to start with. Defaults to the config's active_profile, or "default"
+    #[clap(long, global = true)]
+    pub profile: Option<String>,
+    #[clap(flatten)]
+    pub run: RunOpts,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Settings for the background instance, which can be given before or after `run`
+#[derive(Clap)]
+pub struct RunOpts {
+    /// The maximum number of items to keep in the clipboard history. Overrides the profile value
+    #[clap(long, global = true)]
+    pub max_history: Option<usize>,
+    /// Keep the history in this file so it survives restarts
+    #[clap(long, parse(from_os_str), global = true)]
+    pub persist: Option<PathBuf>,
+    /// When to write the history to disk: "change", "exit" or an interval such as "30s"
+    #[clap(long, global = true)]
+    pub flush: Option<FlushStrategy>,
+    /// Where the history is kept: "file", "journal", "sqlite" or "memory"
+    #[clap(long, global = true)]
+    pub storage: Option<StorageKind>,
+    /// Encrypt the history file so only the current Windows user can read it
+    #[clap(long, global = true)]
+    pub encrypt: bool,
+    /// The key combination which pastes and pops the next item, such as "ctrl+alt+v"
+    #[clap(long, global = true)]
+    pub hotkey: Option<Hotkey>,
+    /// The key combination which pastes the next item without removing it. Defaults to "ctrl+shift+c"
+    #[clap(long, global = true)]
+    pub peek_hotkey: Option<Hotkey>,
+    /// The key combination which opens a list of the history to choose from. Defaults to "ctrl+shift+h"
+    #[clap(long, global = true)]
+    pub picker_hotkey: Option<Hotkey>,
+    /// The key combination which puts the last popped item back. Defaults to "ctrl+shift+z"
+    #[clap(long, global = true)]
+    pub undo_hotkey: Option<Hotkey>,
+    /// The key combination which turns the history so the next item is at the front, without
+    /// removing anything. Defaults to "ctrl+shift+pagedown"
+    #[clap(long, global = true)]
+    pub next_hotkey: Option<Hotkey>,
+    /// The key combination which turns the history back to the previous item. Defaults to
+    /// "ctrl+shift+pageup"
+    #[clap(long, global = true)]
+    pub previous_hotkey: Option<Hotkey>,
+    /// The key combination which pastes the pinned items in turn. Defaults to "ctrl+alt+shift+p"
+    #[clap(long, global = true)]
+    pub pinned_hotkey: Option<Hotkey>,
+    /// The key combination which opens the history search. Defaults to "ctrl+alt+shift+f"
+    #[clap(long, global = true)]
+    pub search_hotkey: Option<Hotkey>,
+    /// The key combination which opens the list of snippets. Defaults to "ctrl+alt+shift+s"
+    #[clap(long, global = true)]
+    pub snippet_hotkey: Option<Hotkey>,
+    /// The key combination which switches to the next stack. Defaults to "ctrl+alt+shift+n"
+    #[clap(long, global = true)]
+    pub stack_hotkey: Option<Hotkey>,
+    /// The key combination which pastes the oldest item then presses the queue keys, for filling in
+    /// forms. Defaults to "ctrl+alt+shift+q"
+    #[clap(long, global = true)]
+    pub queue_hotkey: Option<Hotkey>,
+    /// A key to press after each paste from the queue hotkey, such as "tab" or "enter". Defaults
+    /// to "tab"
+    #[clap(
+        long,
+        global = true,
+        use_delimiter = true,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub queue_key: Vec<Keystroke>,
+    /// A key such as "f13" or "capslock" which pastes the next item when tapped and opens the
+    /// picker when held, for pasting without pressing several keys at once
+    #[clap(long, global = true)]
+    pub tap_key: Option<Keystroke>,
+    /// The key combination which reads the clipboard again into the front item, for programs which
+    /// offer more formats after the copy. Unset by default
+    #[clap(long, global = true)]
+    pub recapture_hotkey: Option<Hotkey>,
+    /// Only put the plain text of items back on the clipboard, dropping fonts, colours and images
+    #[clap(long, global = true)]
+    pub plain_paste: bool,
+    /// Paste items copied as HTML, such as from web pages, as the plain text of the HTML
+    #[clap(long, global = true)]
+    pub html_to_text: bool,
+    /// Paste into terminals such as Windows Terminal and mintty with the keys they paste with
+    /// rather than Ctrl+V
+    #[clap(long, global = true)]
+    pub terminal_paste: bool,
+    /// How items are pasted: "keys" presses Ctrl+V, "sendinput-text" types the item's text, for
+    /// virtual machines, remote desktops and games where Ctrl+V doesn't paste
+    #[clap(long, global = true)]
+    pub paste_mode: Option<PasteMode>,
+    /// Which item the paste hotkey takes next: "filo" the newest, "fifo" the oldest, for pasting a
+    /// list in the order it was copied
+    #[clap(long, global = true)]
+    pub mode: Option<Order>,
+    /// When a new item matches an older one: "off" keeps both, "drop" ignores the new copy and
+    /// "move" brings the older one to the front
+    #[clap(long, global = true)]
+    pub dedup: Option<Dedup>,
+    /// Leave a pasted item on the clipboard and only put the next one there when it's pasted, so
+    /// the clipboard changes less often
+    #[clap(long, global = true)]
+    pub lazy_restore: bool,
+    /// Mark items put back on the clipboard so other clipboard managers don't record them again
+    #[clap(long, global = true)]
+    pub tag_restores: bool,
+    /// Mark items put back on the clipboard with where they were copied from, when and on which machine
+    #[clap(long, global = true)]
+    pub provenance: bool,
+    /// The key combination which pastes and pops the next item as plain text. Defaults to "ctrl+alt+shift+v"
+    #[clap(long, global = true)]
+    pub plain_paste_hotkey: Option<Hotkey>,
+    /// How long to wait for the clipboard when putting an item back on it before retrying later
+    #[clap(long, global = true)]
+    pub restore_timeout_ms: Option<u64>,
+    /// Wait this long after the clipboard changes before reading it, so the several updates some
+    /// programs send for one copy are read once
+    #[clap(long, global = true)]
+    pub debounce_ms: Option<u64>,
+    /// Raise the history limit for a while instead of dropping items when lots are copied at once.
+    /// The memory limit still applies
+    #[clap(long, global = true)]
+    pub auto_grow: bool,
+    /// Keep a stack for each monitor: copies go to the stack of the monitor showing the window
+    /// they were made in, and the paste hotkeys use the stack of the monitor being pasted into
+    #[clap(long, global = true)]
+    pub monitor_stacks: bool,
+    /// Don't record formats larger than this, such as "5MB". Large images are shrunk to fit instead
+    #[clap(long, global = true)]
+    pub max_item_size: Option<ByteSize>,
+    /// Drop the oldest items while the history takes up more than this, such as "200MB"
+    #[clap(long, global = true)]
+    pub max_total_memory: Option<ByteSize>,
+    /// Wipe items from the history once they're this old, such as "30m", which suits copied
+    /// passwords. Pinned items are kept
+    #[clap(long, global = true)]
+    pub max_age: Option<Age>,
+    /// Times of day when nothing is recorded and the next item isn't shown in the tray, such as
+    /// "09:00-10:00,22:00-07:00"
+    #[clap(long, global = true)]
+    pub quiet_hours: Option<QuietHours>,
+    /// Never record copies made by these programs, such as "keepass.exe,bitwarden.exe"
+    #[clap(
+        long,
+        global = true,
+        use_delimiter = true,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub exclude_app: Vec<String>,
+    /// Wipe copies made by these programs from the history and the clipboard once they've been
+    /// pasted, such as "keepassxc.exe"
+    #[clap(
+        long,
+        global = true,
+        use_delimiter = true,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub burn_app: Vec<String>,
+    /// Release the hotkeys while these programs are in the foreground, so they get the keys
+    /// themselves, such as "vmware.exe,vmconnect.exe"
+    #[clap(
+        long,
+        global = true,
+        use_delimiter = true,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub passthrough_app: Vec<String>,
+    /// Release the hotkeys while a window of these classes is in the foreground
+    #[clap(
+        long,
+        global = true,
+        use_delimiter = true,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub passthrough_class: Vec<String>,
+    /// What to do with copies synchronised from a remote desktop or Citrix session: "capture" records
+    /// them, "skip" ignores them and "tag" marks them as remote
+    #[clap(long, global = true)]
+    pub remote_clipboard: Option<RemoteClipboard>,
+    /// Only paste text, HTML, RTF and images into these programs, leaving out private formats which
+    /// can crash them. Defaults to the Office programs
+    #[clap(
+        long,
+        global = true,
+        use_delimiter = true,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub safe_restore_app: Vec<String>,
+    /// Only record these formats, such as "unicode,html,bitmap". Names can be aliases, standard
+    /// formats such as "CF_DIB", registered format names or numbers
+    #[clap(long, global = true)]
+    pub capture_formats: Option<FormatList>,
+    /// Never record these formats, such as Office's large private ones
+    #[clap(long, global = true)]
+    pub ignore_formats: Option<FormatList>,
+    /// Learn which large formats programs read when pasting, record those first, and stop
+    /// recording the ones nothing reads while the history is near --max-total-memory
+    #[clap(long, global = true)]
+    pub adaptive_formats: bool,
+    /// Don't record anything until monitoring is resumed from the tray menu
+    #[clap(long, global = true)]
+    pub start_paused: bool,
+    /// Ask an instance which is already running to exit, and take over from it
+    #[clap(long, global = true)]
+    pub replace: bool,
+    /// Add this text to the history at launch, or to the running instance's history. Can be given
+    /// more than once, and the last is pasted first
+    #[clap(long, global = true, multiple_occurrences = true, number_of_values = 1)]
+    pub push: Vec<String>,
+    /// Add the text read from stdin to the history at launch, after any --push
+    #[clap(long, global = true)]
+    pub push_stdin: bool,
+    /// Show a small window with how the last copy compared with the history, for diagnosing
+    /// duplicates
+    #[clap(long, global = true)]
+    pub debug_overlay: bool,
+    /// Log every paste and pop to this file, without the item's contents
+    #[clap(long, parse(from_os_str), global = true)]
+    pub audit_log: Option<PathBuf>,
+    /// How much to log: "off", "error", "warn", "info", "debug" or "trace"
+    #[clap(long, global = true)]
+    pub log_level: Option<LogLevel>,
+    /// Append the log to this file as well as printing it
+    #[clap(long, parse(from_os_str), global = true)]
+    pub log_file: Option<PathBuf>,
+    /// Accept Stream Deck plugins and other local tools on this port, so their buttons can paste,
+    /// peek and clear and their keys can show the front items
+    #[clap(long, global = true)]
+    pub stream_deck_port: Option<u16>,
+    /// Publish the history previews in shared memory, for companion tools which redraw often
+    #[clap(long, global = true)]
+    pub shared_view: bool,
+    /// Offer the most recent items in the jump list of the program's taskbar button or Start entry
+    #[clap(long, global = true)]
+    pub jump_list: bool,
+    /// Count which features are used and which errors happen, on this computer only, for
+    /// `stats --detailed`
+    #[clap(long, global = true)]
+    pub telemetry: bool,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Run the clipboard history in the background. This is the default
+    Run,
+    /// Print a summary of each item in the running instance's history
+    List(ListCommand),
+    /// Remove every item from the running instance's history
+    Clear,
+    /// Remove duplicate and blank items from the running instance's history, and older copies of
+    /// the item in front of them, reporting the bytes reclaimed
+    Compact,
+    /// Read the clipboard again into the front item of the running instance's history, for
+    /// programs which offer more formats once they've finished copying
+    Recapture,
+    /// Remove the next item from the running instance's history and print its text
+    Pop,
+    /// Show the state of the running instance
+    Status(StatusCommand),
+    /// Check which clipboard formats can be written and read back unchanged on this system
+    Selftest,
+    /// Walk through the hotkeys, tray menu and privacy options, as shown on first run
+    Tour,
+    /// Inspect the config file, or the settings of the running instance
+    Config(ConfigCommand),
+    /// Switch the running instance to another profile
+    Profile(ProfileCommand),
+    /// Save an item's text, HTML or image to a file
+    Save(SaveCommand),
+    /// Put an item on the clipboard as a file, so pasting attaches it instead of inserting it
+    CopyFile(CopyFileCommand),
+    /// Put an item on the clipboard without pasting it, or check what would be put there
+    Restore(RestoreCommand),
+    /// Wipe an item from the history and the clipboard after a while, such as a one-time code
+    Expire(ExpireCommand),
+    /// Wipe an item from the history and the clipboard once it's been pasted
+    Burn(BurnCommand),
+    /// Pin an item so it's never trimmed or popped
+    Pin(PinCommand),
+    /// Put a pinned item back at the front of the history
+    Unpin(UnpinCommand),
+    /// Delete, promote and label several items of the running instance's history at once
+    Batch(BatchCommand),
+    /// Show the audit log of pastes and pops
+    Audit(AuditCommand),
+    /// Show how much is copied and, with telemetry on, which features are used and which errors
+    /// happen
+    Stats(StatsCommand),
+    /// Write the running instance's history to a JSON file
+    Export(ExportCommand),
+    /// Add the items in a file written by `export` to the front of the running instance's history
+    Import(ImportCommand),
+    /// Open and steer the picker, for macro pads and other tools
+    Picker(PickerCommand),
+    /// Save items under a name and paste them again later
+    Snippet(SnippetCommand),
+    /// Switch the running instance to another stack, or list the stacks
+    Stack(StackCommand),
+    /// Copy the history from the configured storage into another kind, while nothing is running
+    Migrate(MigrateCommand),
+    /// Upgrade the config and history files written by older versions, keeping a copy of each.
+    /// This also happens on startup
+    Upgrade,
+    /// Paste the item with this fingerprint into the focused program without removing it, as the
+    /// jump list's tasks do
+    PasteRecent(PasteRecentCommand),
+    /// Run commands such as ls, pop, push "text", inspect 3 and grep foo against the running
+    /// instance, at a prompt or one per line of redirected input
+    Repl,
+    /// Start the clipboard when you sign in, with the other options given here
+    InstallAutostart,
+    /// Stop the clipboard starting when you sign in
+    UninstallAutostart,
+}
+
+#[derive(Clap)]
+pub struct ConfigCommand {
+    #[clap(subcommand)]
+    pub action: ConfigAction,
+}
+
+#[derive(Clap)]
+pub enum ConfigAction {
+    /// Check the config file for errors
+    Validate,
+    /// Print a config file containing the default settings
+    PrintDefault,
+    /// Print a setting of the running instance
+    Get(GetCommand),
+    /// Change a setting of the running instance
+    Set(SetCommand),
+}
+
+#[derive(Clap)]
+pub struct GetCommand {
+    /// The name of the setting, such as max_history
+    pub key: String,
+}
+
+#[derive(Clap)]
+pub struct SetCommand {
+    /// The name of the setting, such as max_history
+    pub key: String,
+    pub value: String,
+    /// Also write the change to the active profile in the config file
+    #[clap(long)]
+    pub save: bool,
+}
+
+#[derive(Clap)]
+pub struct ProfileCommand {
+    /// The name of a profile in the config file
+    pub name: String,
+}
+
+#[derive(Clap)]
+pub struct SaveCommand {
+    /// The item's number, as shown by `list`
+    #[clap(default_value = "0")]
+    pub index: usize,
+    /// Open the file once it's saved
+    #[clap(long)]
+    pub open: bool,
+}
+
+#[derive(Clap)]
+pub struct RestoreCommand {
+    /// The item's number, as shown by `list`
+    pub index: usize,
+    /// Check each format and report which would be skipped or converted, without touching the
+    /// clipboard
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clap)]
+pub struct CopyFileCommand {
+    /// The item's number, as shown by `list`
+    #[clap(default_value = "0")]
+    pub index: usize,
+}
+
+#[derive(Clap)]
+pub struct ExpireCommand {
+    /// The item's number, as shown by `list`
+    #[clap(default_value = "0")]
+    pub index: usize,
+    /// How many seconds to keep the item for
+    #[clap(long, default_value = "300")]
+    pub after: u64,
+}
+
+#[derive(Clap)]
+pub struct PasteRecentCommand {
+    pub fingerprint: u64,
+}
+
+#[derive(Clap)]
+pub struct BurnCommand {
+    /// The item's number, as shown by `list`
+    #[clap(default_value = "0")]
+    pub index: usize,
+}
+
+#[derive(Clap)]
+pub struct PinCommand {
+    /// The item's number, as shown by `list`
+    #[clap(default_value = "0")]
+    pub index: usize,
+}
+
+#[derive(Clap)]
+pub struct UnpinCommand {
+    /// The position of the item among the pinned items, as shown by `list`
+    pub index: usize,
+}
+
+#[derive(Clap)]
+pub struct BatchCommand {
+    /// Operations such as "delete 3", "promote 1" or "label 0 invoice", numbered as the history
+    /// was before any of them
+    #[clap(required = true)]
+    pub operations: Vec<BatchOperation>,
+    /// Only change anything if the history is still at this revision, as shown by `status`
+    #[clap(long)]
+    pub if_revision: Option<u64>,
+}
+
+#[derive(Clap)]
+pub struct ExportCommand {
+    /// The file to write, such as history.json
+    #[clap(parse(from_os_str))]
+    pub path: PathBuf,
+}
+
+#[derive(Clap)]
+pub struct ListCommand {
+    /// Read the previews from shared memory rather than asking over the pipe, as companion tools
+    /// do. Needs the running instance to be started with --shared-view
+    #[clap(long)]
+    pub shared: bool,
+}
+
+#[derive(Clap)]
+pub struct ImportCommand {
+    /// A file written by `export`
+    #[clap(parse(from_os_str))]
+    pub path: PathBuf,
+}
+
+#[derive(Clap)]
+pub struct MigrateCommand {
+    /// The storage to copy into: "file", "journal" or "sqlite"
+    pub to: StorageKind,
+    /// Where to put the copy. Defaults to the history file with the storage's extension
+    #[clap(long, parse(from_os_str))]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct PickerCommand {
+    #[clap(subcommand)]
+    pub action: PickerAction,
+}
+
+#[derive(Clap)]
+pub enum PickerAction {
+    /// Open the picker
+    Open,
+    /// Move the selection towards the top of the list
+    Up(MoveCommand),
+    /// Move the selection towards the bottom of the list
+    Down(MoveCommand),
+    /// Paste the selected item, as Enter does
+    Confirm,
+    /// Close the picker without pasting anything
+    Cancel,
+}
+
+#[derive(Clap)]
+pub struct SnippetCommand {
+    #[clap(subcommand)]
+    pub action: SnippetAction,
+}
+
+#[derive(Clap)]
+pub enum SnippetAction {
+    /// Save a copy of an item under a name, replacing any snippet with that name
+    Save(SnippetSaveCommand),
+    /// Paste a snippet into the focused program
+    Paste(SnippetNameCommand),
+    /// Delete a snippet
+    Delete(SnippetNameCommand),
+    /// Print the name and a summary of each snippet
+    List,
+}
+
+#[derive(Clap)]
+pub struct SnippetSaveCommand {
+    pub name: String,
+    /// The item's number, as shown by `list`
+    #[clap(default_value = "0")]
+    pub index: usize,
+}
+
+#[derive(Clap)]
+pub struct SnippetNameCommand {
+    /// The snippet's name, ignoring case
+    pub name: String,
+}
+
+#[derive(Clap)]
+pub struct StackCommand {
+    /// The stack to switch to, such as "work", which is created if it doesn't exist. Without one,
+    /// the stacks are listed
+    pub name: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct MoveCommand {
+    /// How many items to move by
+    #[clap(default_value = "1")]
+    pub count: usize,
+}
+
+#[derive(Clap)]
+pub struct StatusCommand {
+    /// Print the status as JSON, for scripts
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Clap)]
+pub struct StatsCommand {
+    /// Also show the feature, error and timing counts kept when telemetry is on
+    #[clap(long)]
+    pub detailed: bool,
+    /// Write the telemetry counts to this JSON file, to share them in a bug report
+    #[clap(long)]
+    pub export: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct AuditCommand {
+    /// Only show pastes into this program, such as "notepad.exe"
+    #[clap(long)]
+    pub app: Option<String>,
+    /// Only show the most recent records
+    #[clap(long)]
+    pub limit: Option<usize>,
 }
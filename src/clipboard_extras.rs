@@ -1,22 +1,254 @@
-use clipboard_win::{empty, SysResult};
+#[cfg(windows)]
+use clipboard_win::{empty, formats, Clipboard, EnumFormats, Getter, SysResult};
+#[cfg(windows)]
+use crossbeam::channel;
+#[cfg(windows)]
 use winapi::um::winuser::SetClipboardData;
 
+#[cfg(windows)]
 use core::{mem, ptr};
+use std::{convert::TryInto, iter, path::Path};
+#[cfg(windows)]
+use std::{os::windows::ffi::OsStrExt, thread, time::Duration};
 
+#[cfg(windows)]
 use winapi::ctypes::c_void;
 
+#[cfg(windows)]
+use crate::delayed_rendering::{self, DELAYED_RENDERING_SIZE};
+#[cfg(windows)]
+use crate::winapi_functions::is_clipboard_format_available;
+
+// The standard formats, numbered as on Windows so items are stored the same way everywhere
+pub const CF_TEXT: u32 = 1;
+pub const CF_OEMTEXT: u32 = 7;
+pub const CF_DIB: u32 = 8;
+pub const CF_UNICODETEXT: u32 = 13;
+pub const CF_HDROP: u32 = 15;
+pub const CF_DIBV5: u32 = 17;
+
+#[cfg(windows)]
 const GHND: winapi::ctypes::c_uint = 0x42;
 
+#[cfg(windows)]
 const BYTES_LAYOUT: std::alloc::Layout = std::alloc::Layout::new::<u8>();
 
+/// BI_BITFIELDS compression, where colour masks follow a plain BITMAPINFOHEADER
+const BI_BITFIELDS: u32 = 3;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wide(content: &[u8]) -> Vec<u16> {
+        content
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn unicode_text_round_trip() {
+        let item = write_unicode_text("héllo ✓");
+        assert_eq!(item.format, CF_UNICODETEXT);
+        assert_eq!(wide(&item.content).last(), Some(&0));
+        assert_eq!(read_unicode_text(&item).as_deref(), Some("héllo ✓"));
+    }
+
+    #[test]
+    fn unicode_text_stops_at_null() {
+        let mut item = write_unicode_text("first");
+        item.content.extend_from_slice(&[b'x', 0, 0, 0]);
+        assert_eq!(read_unicode_text(&item).as_deref(), Some("first"));
+        item.format = CF_TEXT;
+        assert_eq!(read_unicode_text(&item), None);
+    }
+
+    #[test]
+    fn only_listed_formats() {
+        let items = [
+            write_unicode_text("text"),
+            ClipboardItem {
+                format: 49_300,
+                content: vec![1, 2, 3],
+            },
+        ];
+        assert_eq!(
+            only_formats(&items, &[CF_UNICODETEXT, CF_DIB]),
+            [items[0].clone()]
+        );
+        assert!(only_formats(&items, &[]).is_empty());
+    }
+
+    #[test]
+    fn unicode_text_is_preferred() {
+        let ansi = ClipboardItem {
+            format: CF_TEXT,
+            content: b"caf\xe9\0".to_vec(),
+        };
+        assert_eq!(
+            read_text(std::slice::from_ref(&ansi)).as_deref(),
+            Some("caf\u{FFFD}")
+        );
+        let items = [ansi, write_unicode_text("café")];
+        assert_eq!(read_text(&items).as_deref(), Some("café"));
+        assert_eq!(read_text(&[]), None);
+    }
+
+    #[test]
+    fn dib_pixel_offset() {
+        let mut content = vec![0u8; 40 + 12 + 4];
+        content[..4].copy_from_slice(&40u32.to_le_bytes());
+        content[4..8].copy_from_slice(&1i32.to_le_bytes());
+        content[8..12].copy_from_slice(&(-1i32).to_le_bytes());
+        content[14..16].copy_from_slice(&32u16.to_le_bytes());
+        content[16..20].copy_from_slice(&BI_BITFIELDS.to_le_bytes());
+        let item = ClipboardItem {
+            format: CF_DIB,
+            content,
+        };
+        let dib = read_dib(&item).unwrap();
+        assert_eq!((dib.width, dib.height, dib.bit_count), (1, -1, 32));
+        assert_eq!(dib.pixel_offset(), 52);
+        assert_eq!(dib.pixels().map(<[u8]>::len), Some(4));
+    }
+
+    /// A bottom-up 32 bit DIB where each pixel's blue channel is its index
+    fn numbered_dib(width: i32, height: i32) -> ClipboardItem {
+        let mut content = vec![0u8; 40];
+        content[..4].copy_from_slice(&40u32.to_le_bytes());
+        content[4..8].copy_from_slice(&width.to_le_bytes());
+        content[8..12].copy_from_slice(&height.to_le_bytes());
+        content[12..14].copy_from_slice(&1u16.to_le_bytes());
+        content[14..16].copy_from_slice(&32u16.to_le_bytes());
+        for index in 0..width * height {
+            content.extend_from_slice(&[index as u8, 0, 0, 0]);
+        }
+        ClipboardItem {
+            format: CF_DIB,
+            content,
+        }
+    }
+
+    #[test]
+    fn shrink_halves_dib() {
+        let item = numbered_dib(8, 8);
+        let shrunk = shrink_dib(&read_dib(&item).unwrap(), 40 + 4 * 4 * 4).unwrap();
+        assert_eq!(shrunk.len(), 40 + 4 * 4 * 4);
+        let dib = Dib::parse(&shrunk).unwrap();
+        assert_eq!((dib.width, dib.height), (4, 4));
+        // Every other pixel of every other row
+        let blues: Vec<u8> = dib
+            .pixels()
+            .unwrap()
+            .chunks(4)
+            .map(|pixel| pixel[0])
+            .collect();
+        assert_eq!(&blues[..5], &[0, 2, 4, 6, 16]);
+    }
+
+    #[test]
+    fn image_info_of_dib() {
+        let item = numbered_dib(8, 6);
+        assert_eq!(
+            item.image_info(),
+            Some(ImageInfo {
+                width: 8,
+                height: 6,
+                bit_count: 32,
+            })
+        );
+        assert_eq!(write_unicode_text("text").image_info(), None);
+    }
+
+    #[test]
+    fn thumbnail_fits_within_size() {
+        let item = numbered_dib(8, 4);
+        let shrunk = thumbnail(&read_dib(&item).unwrap(), 4).unwrap();
+        let dib = Dib::parse(&shrunk).unwrap();
+        assert_eq!((dib.width, dib.height, dib.bit_count), (4, 2, 32));
+        let blues: Vec<u8> = dib
+            .pixels()
+            .unwrap()
+            .chunks(4)
+            .map(|pixel| pixel[0])
+            .collect();
+        assert_eq!(blues, [0, 2, 4, 6, 16, 18, 20, 22]);
+        // Small images keep their size
+        let small = thumbnail(&read_dib(&item).unwrap(), 100).unwrap();
+        assert_eq!(Dib::parse(&small).unwrap().width, 8);
+    }
+
+    #[test]
+    fn limit_size_drops_large_formats() {
+        let text = write_unicode_text("small");
+        let items = vec![
+            text.clone(),
+            ClipboardItem {
+                format: 49_000,
+                content: vec![0; 1000],
+            },
+            numbered_dib(16, 16),
+        ];
+        let limited = limit_size(items, 200);
+        assert_eq!(limited.len(), 2);
+        assert_eq!(limited[0], text);
+        assert_eq!(limited[1].format, CF_DIB);
+        assert!(limited[1].content.len() <= 200);
+    }
+
+    #[test]
+    fn short_dib() {
+        assert!(Dib::parse(&[0; 10]).is_none());
+    }
+
+    #[test]
+    fn hdrop_layout() {
+        let item = write_hdrop(&["a", "bc"]);
+        assert_eq!(item.format, CF_HDROP);
+        assert_eq!(
+            u32::from_le_bytes(item.content[..4].try_into().unwrap()),
+            20
+        );
+        // Wide characters
+        assert_eq!(
+            u32::from_le_bytes(item.content[16..20].try_into().unwrap()),
+            1
+        );
+        assert_eq!(
+            wide(&item.content[20..]),
+            [b'a' as u16, 0, b'b' as u16, b'c' as u16, 0, 0]
+        );
+        assert_eq!(read_hdrop(&item), ["a", "bc"]);
+    }
+
+    #[test]
+    fn ansi_hdrop_rewritten_as_wide() {
+        let mut content = Vec::new();
+        content.extend_from_slice(&20u32.to_le_bytes());
+        content.extend_from_slice(&[0; 16]);
+        content.extend_from_slice(b"C:\\a.txt\0C:\\b.txt\0\0");
+        let item = ClipboardItem {
+            format: CF_HDROP,
+            content,
+        };
+        let rewritten = normalize_hdrop(&item).unwrap();
+        assert_eq!(rewritten, write_hdrop(&["C:\\a.txt", "C:\\b.txt"]));
+        assert_eq!(normalize_hdrop(&write_unicode_text("C:\\a.txt")), None);
+    }
+}
+
+#[cfg(windows)]
 #[inline]
 fn noop(_: *mut c_void) {}
 
+#[cfg(windows)]
 #[inline]
 fn free_rust_mem(data: *mut c_void) {
     unsafe { std::alloc::dealloc(data as _, BYTES_LAYOUT) }
 }
 
+#[cfg(windows)]
 #[inline]
 fn unlock_data(data: *mut c_void) {
     unsafe {
@@ -24,6 +256,7 @@ fn unlock_data(data: *mut c_void) {
     }
 }
 
+#[cfg(windows)]
 #[inline]
 fn free_global_mem(data: *mut c_void) {
     unsafe {
@@ -31,8 +264,10 @@ fn free_global_mem(data: *mut c_void) {
     }
 }
 
+#[cfg(windows)]
 pub struct Scope<T: Copy>(pub T, pub fn(T));
 
+#[cfg(windows)]
 impl<T: Copy> Drop for Scope<T> {
     #[inline(always)]
     fn drop(&mut self) {
@@ -40,8 +275,10 @@ impl<T: Copy> Drop for Scope<T> {
     }
 }
 
+#[cfg(windows)]
 pub struct RawMem(Scope<*mut c_void>);
 
+#[cfg(windows)]
 impl RawMem {
     #[inline(always)]
     pub fn new_rust_mem(size: usize) -> Self {
@@ -97,27 +334,441 @@ pub struct ClipboardItem {
     pub content: Vec<u8>,
 }
 
-///Copies raw bytes onto clipboard with specified `format`, returning whether it was successful.
-pub fn set_all(clipbard_items: &[ClipboardItem]) -> Vec<SysResult<()>> {
-    let _ = empty();
+impl ClipboardItem {
+    /// The size of the image, if the item is a CF_DIB or CF_DIBV5 bitmap
+    pub fn image_info(&self) -> Option<ImageInfo> {
+        if self.format != CF_DIB && self.format != CF_DIBV5 {
+            return None;
+        }
+        let dib = Dib::parse(&self.content)?;
+        if dib.width <= 0 || dib.height == 0 {
+            return None;
+        }
+        Some(ImageInfo {
+            width: dib.width as u32,
+            height: dib.height.unsigned_abs(),
+            bit_count: dib.bit_count,
+        })
+    }
+}
 
-    clipbard_items
+/// What's known about a bitmap from its header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bit_count: u16,
+}
+
+/// Only the plain text formats of an item, or the whole item if it has no text
+pub fn plain_text(clipboard_items: &[ClipboardItem]) -> Vec<ClipboardItem> {
+    let text: Vec<_> = clipboard_items
         .iter()
-        .map(|item| {
-            let data = &item.content;
-            let format = item.format;
+        .filter(|item| item.format == CF_UNICODETEXT || item.format == CF_TEXT)
+        .cloned()
+        .collect();
+    if text.is_empty() {
+        clipboard_items.to_vec()
+    } else {
+        text
+    }
+}
 
-            let size = data.len();
-            debug_assert!(size > 0);
+/// Only the formats of an item which are in `formats`
+pub fn only_formats(clipboard_items: &[ClipboardItem], formats: &[u32]) -> Vec<ClipboardItem> {
+    clipboard_items
+        .iter()
+        .filter(|item| formats.contains(&item.format))
+        .cloned()
+        .collect()
+}
 
-            let mem = RawMem::new_global_mem(size)?;
+/// The text of a CF_UNICODETEXT item, up to its null terminator
+pub fn read_unicode_text(item: &ClipboardItem) -> Option<String> {
+    if item.format != CF_UNICODETEXT {
+        return None;
+    }
+    let wide: Vec<u16> = item
+        .content
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    Some(String::from_utf16_lossy(&wide))
+}
 
-            {
-                let (ptr, _lock) = mem.lock()?;
-                unsafe { ptr::copy_nonoverlapping(data.as_ptr(), ptr.as_ptr() as _, size) };
+/// The text of a CF_TEXT item, up to its null terminator. It's in the system's ANSI code page, so
+/// anything outside ASCII is replaced rather than guessed at
+pub fn read_ansi_text(item: &ClipboardItem) -> Option<String> {
+    if item.format != CF_TEXT {
+        return None;
+    }
+    let text = item.content.split(|c| *c == 0).next().unwrap_or_default();
+    Some(
+        text.iter()
+            .map(|c| if c.is_ascii() { *c as char } else { '\u{FFFD}' })
+            .collect(),
+    )
+}
+
+/// The best available text of an item: CF_UNICODETEXT if it has it, otherwise CF_TEXT
+pub fn read_text(items: &[ClipboardItem]) -> Option<String> {
+    items
+        .iter()
+        .find_map(read_unicode_text)
+        .or_else(|| items.iter().find_map(read_ansi_text))
+}
+
+/// A null terminated CF_UNICODETEXT item holding `text`
+pub fn write_unicode_text(text: &str) -> ClipboardItem {
+    ClipboardItem {
+        format: CF_UNICODETEXT,
+        content: text
+            .encode_utf16()
+            .chain(iter::once(0))
+            .flat_map(u16::to_le_bytes)
+            .collect(),
+    }
+}
+
+/// The fields of a device independent bitmap's header which are needed to find its pixels
+#[derive(Debug)]
+pub struct Dib<'a> {
+    pub header_size: u32,
+    pub width: i32,
+    /// Positive for bottom-up images and negative for top-down ones
+    pub height: i32,
+    pub bit_count: u16,
+    pub compression: u32,
+    pub colors_used: u32,
+    /// The whole DIB, starting with the header
+    pub data: &'a [u8],
+}
+
+impl<'a> Dib<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let read_u16 = |offset: usize| {
+            Some(u16::from_le_bytes(
+                data.get(offset..offset + 2)?.try_into().ok()?,
+            ))
+        };
+        let read_u32 = |offset: usize| {
+            Some(u32::from_le_bytes(
+                data.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+
+        Some(Self {
+            header_size: read_u32(0)?,
+            width: read_u32(4)? as i32,
+            height: read_u32(8)? as i32,
+            bit_count: read_u16(14)?,
+            compression: read_u32(16)?,
+            colors_used: read_u32(32)?,
+            data,
+        })
+    }
+
+    /// Where the pixels start, after the header, any BI_BITFIELDS masks and the colour table
+    pub fn pixel_offset(&self) -> usize {
+        let colors = match (self.colors_used, self.bit_count) {
+            (0, bits) if bits <= 8 => 1 << bits,
+            (colors, _) => colors,
+        };
+        // The masks are part of the larger headers, but follow a plain BITMAPINFOHEADER
+        let masks = if self.compression == BI_BITFIELDS && self.header_size == 40 {
+            12
+        } else {
+            0
+        };
+        (self.header_size + masks + colors * 4) as usize
+    }
+
+    pub fn pixels(&self) -> Option<&'a [u8]> {
+        self.data.get(self.pixel_offset()..)
+    }
+}
+
+/// The bitmap of a CF_DIB item
+pub fn read_dib(item: &ClipboardItem) -> Option<Dib<'_>> {
+    if item.format != CF_DIB {
+        return None;
+    }
+    Dib::parse(&item.content)
+}
+
+/// Shrink an uncompressed 24 or 32 bit DIB by halving its size until it takes up at most
+/// `max_size` bytes, keeping every other pixel. Returns `None` for other kinds of bitmap, or if
+/// even a single pixel is too large
+pub fn shrink_dib(dib: &Dib, max_size: usize) -> Option<Vec<u8>> {
+    const HEADER_SIZE: usize = 40;
+    if dib.header_size as usize != HEADER_SIZE
+        || dib.compression != 0
+        || dib.colors_used != 0
+        || !(dib.bit_count == 24 || dib.bit_count == 32)
+        || dib.width <= 0
+        || dib.height == 0
+    {
+        return None;
+    }
+    let stride = |width: usize| (width * dib.bit_count as usize).div_ceil(32) * 4;
+    let bytes_per_pixel = dib.bit_count as usize / 8;
+    let (width, height) = (dib.width as usize, dib.height.unsigned_abs() as usize);
+    let pixels = dib.pixels()?;
+    if pixels.len() < stride(width) * height {
+        return None;
+    }
+
+    let mut step = 2;
+    loop {
+        let new_width = width.div_ceil(step);
+        let new_height = height.div_ceil(step);
+        let new_stride = stride(new_width);
+        if HEADER_SIZE + new_stride * new_height <= max_size {
+            let mut shrunk = dib.data[..HEADER_SIZE].to_vec();
+            shrunk[4..8].copy_from_slice(&(new_width as i32).to_le_bytes());
+            // Keep the sign, which says whether the rows are stored top-down
+            shrunk[8..12].copy_from_slice(&(new_height as i32 * dib.height.signum()).to_le_bytes());
+            shrunk[20..24].copy_from_slice(&((new_stride * new_height) as u32).to_le_bytes());
+            for y in 0..new_height {
+                let row = &pixels[y * step * stride(width)..];
+                let mut new_row = vec![0u8; new_stride];
+                for x in 0..new_width {
+                    let offset = x * step * bytes_per_pixel;
+                    new_row[x * bytes_per_pixel..(x + 1) * bytes_per_pixel]
+                        .copy_from_slice(&row[offset..offset + bytes_per_pixel]);
+                }
+                shrunk.extend_from_slice(&new_row);
+            }
+            return Some(shrunk);
+        }
+        if new_width == 1 && new_height == 1 {
+            return None;
+        }
+        step *= 2;
+    }
+}
+
+/// A 32 bit copy of an uncompressed 24 or 32 bit DIB, scaled down to fit within `max_side` pixels
+/// each way by keeping the nearest pixels. Images which already fit keep their size
+pub fn thumbnail(dib: &Dib, max_side: usize) -> Option<Vec<u8>> {
+    const HEADER_SIZE: usize = 40;
+    // BI_RGB, or BI_BITFIELDS with masks which are assumed to be the usual ones
+    if dib.width <= 0
+        || dib.height == 0
+        || !(dib.bit_count == 24 || dib.bit_count == 32)
+        || !(dib.compression == 0 || dib.compression == BI_BITFIELDS)
+        || max_side == 0
+    {
+        return None;
+    }
+    let bytes_per_pixel = dib.bit_count as usize / 8;
+    let (width, height) = (dib.width as usize, dib.height.unsigned_abs() as usize);
+    let stride = (width * dib.bit_count as usize).div_ceil(32) * 4;
+    let pixels = dib.pixels()?;
+    if pixels.len() < stride * height {
+        return None;
+    }
+
+    let longest = width.max(height);
+    let (new_width, new_height) = if longest > max_side {
+        (
+            (width * max_side / longest).max(1),
+            (height * max_side / longest).max(1),
+        )
+    } else {
+        (width, height)
+    };
+    let mut thumbnail = Vec::with_capacity(HEADER_SIZE + new_width * new_height * 4);
+    thumbnail.extend_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+    thumbnail.extend_from_slice(&(new_width as i32).to_le_bytes());
+    // Rows are copied in the order they're stored, so the sign says which way up they are
+    thumbnail.extend_from_slice(&(new_height as i32 * dib.height.signum()).to_le_bytes());
+    thumbnail.extend_from_slice(&1u16.to_le_bytes());
+    thumbnail.extend_from_slice(&32u16.to_le_bytes());
+    thumbnail.extend_from_slice(&0u32.to_le_bytes());
+    thumbnail.extend_from_slice(&((new_width * new_height * 4) as u32).to_le_bytes());
+    thumbnail.resize(HEADER_SIZE, 0);
+    for y in 0..new_height {
+        let row = &pixels[y * height / new_height * stride..];
+        for x in 0..new_width {
+            let offset = x * width / new_width * bytes_per_pixel;
+            thumbnail.extend_from_slice(&row[offset..offset + 3]);
+            thumbnail.push(0);
+        }
+    }
+    Some(thumbnail)
+}
+
+/// Drop the formats larger than `max_size` bytes. A CF_DIB is shrunk to fit rather than dropped,
+/// and since Windows can produce the other bitmap formats from it, screenshots still paste
+pub fn limit_size(items: Vec<ClipboardItem>, max_size: usize) -> Vec<ClipboardItem> {
+    items
+        .into_iter()
+        .filter_map(|item| {
+            if item.content.len() <= max_size {
+                return Some(item);
+            }
+            let shrunk = read_dib(&item).and_then(|dib| shrink_dib(&dib, max_size))?;
+            Some(ClipboardItem {
+                format: item.format,
+                content: shrunk,
+            })
+        })
+        .collect()
+}
+
+/// A CF_HDROP item listing `paths`, the format Explorer uses when files are copied
+pub fn write_hdrop<P: AsRef<Path>>(paths: &[P]) -> ClipboardItem {
+    // DROPFILES: the offset of the file list, the drop point, whether the point is in the
+    // non-client area and whether the names are wide strings
+    const HEADER_SIZE: u32 = 20;
+    let mut content = Vec::new();
+    content.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+    content.extend_from_slice(&[0; 12]);
+    content.extend_from_slice(&1u32.to_le_bytes());
+    // Each name is null terminated, and the list ends with an extra null
+    for path in paths {
+        for c in wide_path(path.as_ref()).into_iter().chain(iter::once(0)) {
+            content.extend_from_slice(&c.to_le_bytes());
+        }
+    }
+    content.extend_from_slice(&[0; 2]);
+
+    ClipboardItem {
+        format: CF_HDROP,
+        content,
+    }
+}
+
+/// A path in UTF-16, as Windows stores file names
+#[cfg(windows)]
+fn wide_path(path: &Path) -> Vec<u16> {
+    path.as_os_str().encode_wide().collect()
+}
+
+#[cfg(not(windows))]
+fn wide_path(path: &Path) -> Vec<u16> {
+    path.to_string_lossy().encode_utf16().collect()
+}
+
+/// The paths listed in a CF_HDROP item, in either wide or ANSI characters
+pub fn read_hdrop(item: &ClipboardItem) -> Vec<String> {
+    let header = |offset: usize| {
+        item.content
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let (offset, wide) = match (item.format, header(0), header(16)) {
+        (CF_HDROP, Some(offset), Some(wide)) => (offset as usize, wide != 0),
+        _ => return Vec::new(),
+    };
+    let names = item.content.get(offset..).unwrap_or_default();
+    let names = if wide {
+        let names: Vec<u16> = names
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&names)
+    } else {
+        String::from_utf8_lossy(names).into_owned()
+    };
+    names
+        .split('\0')
+        .take_while(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// A CF_HDROP item rewritten with a standard DROPFILES header, or None if `item` isn't one or lists
+/// no files. Some programs write the names in ANSI or leave the header's offset wrong, which
+/// Explorer refuses to paste
+pub fn normalize_hdrop(item: &ClipboardItem) -> Option<ClipboardItem> {
+    let paths = read_hdrop(item);
+    if paths.is_empty() {
+        None
+    } else {
+        Some(write_hdrop(&paths))
+    }
+}
+
+/// Read every non-empty format on the clipboard, which must already be open
+#[cfg(windows)]
+pub fn get_all() -> Vec<ClipboardItem> {
+    get_formats(|_| true)
+}
+
+/// Read the non-empty formats on the clipboard for which `allows` is true, without reading the
+/// others at all. The clipboard must already be open
+#[cfg(windows)]
+pub fn get_formats(allows: impl Fn(u32) -> bool) -> Vec<ClipboardItem> {
+    get_formats_ordered(allows, |_| {})
+}
+
+/// Like `get_formats`, but the formats are read in the order `order` sorts them into
+#[cfg(windows)]
+pub fn get_formats_ordered(
+    allows: impl Fn(u32) -> bool,
+    order: impl FnOnce(&mut [u32]),
+) -> Vec<ClipboardItem> {
+    let mut formats: Vec<_> = EnumFormats::new()
+        .filter(|format| allows(*format))
+        .collect();
+    order(&mut formats);
+    formats
+        .into_iter()
+        .filter_map(|format| {
+            let mut clipboard_data = Vec::new();
+            if let Ok(bytes) = formats::RawData(format).read_clipboard(&mut clipboard_data) {
+                if bytes != 0 {
+                    return Some(ClipboardItem {
+                        format,
+                        content: clipboard_data,
+                    });
+                }
             }
+            None
+        })
+        .collect()
+}
+
+/// Copy an item's bytes into movable global memory, as SetClipboardData needs
+#[cfg(windows)]
+pub(crate) fn to_global_mem(item: &ClipboardItem) -> SysResult<RawMem> {
+    let normalized = normalize_hdrop(item);
+    let data = &normalized.as_ref().unwrap_or(item).content;
+    let size = data.len();
+    debug_assert!(size > 0);
+
+    let mem = RawMem::new_global_mem(size)?;
+    {
+        let (ptr, _lock) = mem.lock()?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), ptr.as_ptr() as _, size) };
+    }
+    Ok(mem)
+}
 
-            if unsafe { !SetClipboardData(format, mem.get()).is_null() } {
+///Copies raw bytes onto clipboard with specified `format`, returning whether it was successful.
+///Every item is copied into global memory before the clipboard is emptied, so if any allocation
+///fails the clipboard is left as it was and every item reports the error.
+#[cfg(windows)]
+pub fn set_all(clipbard_items: &[ClipboardItem]) -> Vec<SysResult<()>> {
+    let memory = match clipbard_items
+        .iter()
+        .map(to_global_mem)
+        .collect::<SysResult<Vec<_>>>()
+    {
+        Ok(memory) => memory,
+        Err(error) => return clipbard_items.iter().map(|_| Err(error)).collect(),
+    };
+
+    let _ = empty();
+
+    clipbard_items
+        .iter()
+        .zip(memory)
+        .map(|(item, mem)| {
+            if unsafe { !SetClipboardData(item.format, mem.get()).is_null() } {
                 //SetClipboardData takes ownership
                 mem.release();
                 return Ok(());
@@ -127,3 +778,59 @@ pub fn set_all(clipbard_items: &[ClipboardItem]) -> Vec<SysResult<()>> {
         })
         .collect()
 }
+
+/// Like `set_all`, but formats of at least `DELAYED_RENDERING_SIZE` are only promised, and copied
+/// onto the clipboard when a program asks for them. The clipboard must have been opened for the
+/// renderer's window. If the renderer hasn't been started everything is copied straight away
+#[cfg(windows)]
+fn set_all_delayed(clipboard_items: Vec<ClipboardItem>) -> Vec<SysResult<()>> {
+    if delayed_rendering::owner().is_none() {
+        return set_all(&clipboard_items);
+    }
+    let (delayed, immediate): (Vec<_>, Vec<_>) = clipboard_items
+        .into_iter()
+        .enumerate()
+        .partition(|(_, item)| item.content.len() >= DELAYED_RENDERING_SIZE);
+    let (indices, immediate): (Vec<_>, Vec<_>) = immediate.into_iter().unzip();
+    // Emptying the clipboard here voids any earlier promises
+    let mut results: Vec<_> = indices.into_iter().zip(set_all(&immediate)).collect();
+    for (index, item) in delayed.iter() {
+        unsafe { SetClipboardData(item.format, ptr::null_mut()) };
+        // A null handle is returned whether or not it worked, so check the format is there
+        let result = if is_clipboard_format_available(item.format) {
+            Ok(())
+        } else {
+            Err(error_code::SystemError::last())
+        };
+        results.push((*index, result));
+    }
+    delayed_rendering::promise(delayed.into_iter().map(|(_, item)| item).collect());
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Open the clipboard and copy `clipboard_items` onto it from another thread, so a program which
+/// keeps the clipboard open or stops responding can't freeze us. Large formats are only promised,
+/// if the renderer has been started. Returns `None` if it took longer than `timeout`, in which
+/// case the clipboard may still be written once the other program recovers
+#[cfg(windows)]
+pub fn set_all_with_timeout(
+    clipboard_items: Vec<ClipboardItem>,
+    timeout: Duration,
+) -> Option<Vec<SysResult<()>>> {
+    let (sender, receiver) = channel::bounded(1);
+    // Window handles can be used from any thread, but raw pointers can't be sent between them
+    let owner = delayed_rendering::owner().map(|owner| owner as *mut _ as usize);
+    thread::spawn(move || {
+        let clipboard = match owner {
+            Some(owner) => Clipboard::new_attempts_for(owner as _, 10),
+            None => Clipboard::new_attempts(10),
+        };
+        let results = match clipboard {
+            Ok(_clip) => set_all_delayed(clipboard_items),
+            Err(error) => vec![Err(error)],
+        };
+        let _ = sender.send(results);
+    });
+    receiver.recv_timeout(timeout).ok()
+}
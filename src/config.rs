@@ -0,0 +1,1142 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    env, fmt, fs, io, mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::formats::FormatList;
+use crate::hotkey::{Hotkey, Keystroke};
+use crate::migrations::{self, CONFIG_VERSION};
+use crate::persistence::FlushStrategy;
+use crate::stacks;
+use crate::storage::StorageKind;
+
+// The engine and history don't need Windows, so their settings live with them
+pub use crate::engine::{Dedup, DEFAULT_SIMILARITY_THRESHOLD};
+pub use crate::history::{Order, DEFAULT_MAX_HISTORY};
+
+pub const DEFAULT_PROFILE: &str = "default";
+pub const DEFAULT_RESTORE_TIMEOUT_MS: u64 = 500;
+/// Office programs, which can crash when pasting private formats from another program or session
+pub const DEFAULT_SAFE_RESTORE_APPS: &[&str] = &[
+    "excel.exe",
+    "winword.exe",
+    "powerpnt.exe",
+    "outlook.exe",
+    "onenote.exe",
+    "msaccess.exe",
+    "mspub.exe",
+    "visio.exe",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_default_profile_uses_defaults() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.profile(DEFAULT_PROFILE).unwrap(), Profile::default());
+    }
+
+    #[test]
+    fn partial_profile_inherits_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            active_profile = "streaming"
+
+            [profiles.streaming]
+            capture = false
+            "#,
+        )
+        .unwrap();
+        let profile = config.profile("streaming").unwrap();
+        assert!(!profile.capture);
+        assert_eq!(profile.max_history, DEFAULT_MAX_HISTORY);
+    }
+
+    #[test]
+    fn validate_resets_invalid_values() {
+        let mut config: Config = toml::from_str(
+            r#"
+            active_profile = "missing"
+
+            [profiles.work]
+            max_history = 0
+
+            [stacks.Code]
+            max_history = 0
+
+            [stacks."not/valid"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.validate().len(), 4);
+        assert_eq!(config.stacks.keys().collect::<Vec<_>>(), vec!["code"]);
+        assert_eq!(config.stacks["code"].max_history, None);
+        assert_eq!(config.active_profile, None);
+        assert_eq!(
+            config.profile("work").unwrap().max_history,
+            DEFAULT_MAX_HISTORY
+        );
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn default_file_round_trips() {
+        let config: Config = toml::from_str(&Config::default_file()).unwrap();
+        assert_eq!(config.profile(DEFAULT_PROFILE).unwrap(), Profile::default());
+        assert_eq!(config.version, Some(CONFIG_VERSION));
+    }
+
+    #[test]
+    fn set_by_key() {
+        let mut profile = Profile::default();
+        profile.set("max_history", "10").unwrap();
+        assert_eq!(profile.get("max_history").unwrap(), "10");
+        assert!(profile.set("max_history", "0").is_err());
+        assert!(profile.set("capture", "maybe").is_err());
+        assert!(matches!(
+            profile.set("colour", "red"),
+            Err(ConfigError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn count_from_either_end() {
+        assert_eq!(HistoryEnd::Newest.position(1, 3), 1);
+        assert_eq!(HistoryEnd::Oldest.position(0, 3), 2);
+        assert_eq!(HistoryEnd::Oldest.position(2, 3), 0);
+        assert_eq!(HistoryEnd::Oldest.position(3, 3), 3);
+        assert_eq!(HistoryEnd::Oldest.positions(3), [2, 1, 0]);
+        let mut profile = Profile::default();
+        profile.set("index_from", "oldest").unwrap();
+        assert_eq!(profile.index_from, HistoryEnd::Oldest);
+        assert!(profile.set("picker_order", "middle").is_err());
+    }
+
+    #[test]
+    fn parse_byte_size() {
+        assert_eq!("1024".parse(), Ok(ByteSize(1024)));
+        assert_eq!("16KB".parse(), Ok(ByteSize(16 * 1024)));
+        assert_eq!("5 mb".parse(), Ok(ByteSize(5 * 1024 * 1024)));
+        assert_eq!("2g".parse(), Ok(ByteSize(2 * 1024 * 1024 * 1024)));
+        assert!("0".parse::<ByteSize>().is_err());
+        assert!("lots".parse::<ByteSize>().is_err());
+        assert_eq!(ByteSize(5 * 1024 * 1024).to_string(), "5MB");
+        assert_eq!(ByteSize(1500).to_string(), "1500");
+    }
+
+    #[test]
+    fn parse_age() {
+        assert_eq!("30m".parse(), Ok(Age(Duration::from_secs(30 * 60))));
+        assert_eq!("2 H".parse(), Ok(Age(Duration::from_secs(2 * 60 * 60))));
+        assert_eq!("45".parse(), Ok(Age(Duration::from_secs(45))));
+        assert!("0s".parse::<Age>().is_err());
+        assert!("soon".parse::<Age>().is_err());
+        assert_eq!(Age(Duration::from_secs(90 * 60)).to_string(), "90m");
+        assert_eq!(Age(Duration::from_secs(86_400)).to_string(), "1d");
+    }
+
+    #[test]
+    fn parse_quiet_hours() {
+        let hours: QuietHours = "09:00-10:00, 22:30-7:00".parse().unwrap();
+        assert_eq!(hours.to_string(), "09:00-10:00,22:30-07:00");
+        assert!(hours.contains(9 * 60));
+        assert!(!hours.contains(10 * 60));
+        // Ranges which end before they start carry on past midnight
+        assert!(hours.contains(23 * 60));
+        assert!(hours.contains(6 * 60 + 59));
+        assert!(!hours.contains(12 * 60));
+        assert!("".parse::<QuietHours>().unwrap().0.is_empty());
+        assert!("9-10".parse::<QuietHours>().is_err());
+        assert!("09:00-24:00".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn parse_log_level() {
+        assert_eq!("debug".parse(), Ok(LogLevel(LevelFilter::Debug)));
+        assert_eq!("WARN".parse(), Ok(LogLevel(LevelFilter::Warn)));
+        assert!("loud".parse::<LogLevel>().is_err());
+        assert_eq!(LogLevel(LevelFilter::Off).to_string(), "off");
+    }
+
+    #[test]
+    fn parse_remote_clipboard() {
+        assert_eq!("tag".parse(), Ok(RemoteClipboard::Tag));
+        assert!("block".parse::<RemoteClipboard>().is_err());
+        for remote in [
+            RemoteClipboard::Capture,
+            RemoteClipboard::Skip,
+            RemoteClipboard::Tag,
+        ]
+        .iter()
+        {
+            assert_eq!(remote.to_string().parse(), Ok(*remote));
+        }
+    }
+
+    #[test]
+    fn unknown_profile() {
+        let config = Config::default();
+        assert!(matches!(
+            config.profile("work"),
+            Err(ConfigError::UnknownProfile(_))
+        ));
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    UnknownProfile(String),
+    InvalidEnvVar {
+        name: String,
+        value: String,
+    },
+    UnknownKey(String),
+    InvalidValue {
+        key: String,
+        value: String,
+    },
+    /// The change isn't allowed by the administrator's policy
+    Policy(String),
+    /// A hotkey the change needs couldn't be registered
+    Hotkey(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "could not read config file: {}", error),
+            ConfigError::Parse(error) => write!(f, "could not parse config file: {}", error),
+            ConfigError::UnknownProfile(name) => write!(f, "no profile named \"{}\"", name),
+            ConfigError::InvalidEnvVar { name, value } => {
+                write!(f, "invalid value \"{}\" for {}", value, name)
+            }
+            ConfigError::UnknownKey(key) => write!(
+                f,
+                "unknown setting \"{}\", expected one of {:?}",
+                key,
+                Profile::KEYS
+            ),
+            ConfigError::InvalidValue { key, value } => {
+                write!(f, "invalid value \"{}\" for {}", value, key)
+            }
+            ConfigError::Policy(reason) => write!(f, "{}", reason),
+            ConfigError::Hotkey(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// A number of bytes, written with an optional KB, MB or GB suffix such as "20MB"
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteSize(pub u64);
+
+const BYTE_UNITS: &[(&str, u64)] = &[("GB", 1 << 30), ("MB", 1 << 20), ("KB", 1 << 10)];
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let upper = value.trim().to_ascii_uppercase();
+        let (number, multiplier) = BYTE_UNITS
+            .iter()
+            .find_map(|(unit, multiplier)| {
+                upper
+                    .strip_suffix(unit)
+                    .or_else(|| upper.strip_suffix(&unit[..1]))
+                    .map(|number| (number, *multiplier))
+            })
+            .unwrap_or((&upper, 1));
+        match number.trim().parse::<u64>() {
+            Ok(number) if number > 0 => number
+                .checked_mul(multiplier)
+                .map(ByteSize)
+                .ok_or_else(|| format!("\"{}\" is too large", value)),
+            _ => Err(format!(
+                "expected a size such as \"500KB\" or \"20MB\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match BYTE_UNITS
+            .iter()
+            .find(|(_, multiplier)| self.0.is_multiple_of(*multiplier))
+        {
+            Some((unit, multiplier)) => write!(f, "{}{}", self.0 / multiplier, unit),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ByteSize> for String {
+    fn from(size: ByteSize) -> Self {
+        size.to_string()
+    }
+}
+
+/// A length of time, written with an s, m, h or d suffix such as "30m". Plain numbers are seconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Age(pub Duration);
+
+const AGE_UNITS: &[(&str, u64)] = &[("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+
+impl FromStr for Age {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let lower = value.trim().to_ascii_lowercase();
+        let (number, multiplier) = AGE_UNITS
+            .iter()
+            .find_map(|(unit, multiplier)| {
+                lower.strip_suffix(unit).map(|number| (number, *multiplier))
+            })
+            .unwrap_or((&lower, 1));
+        match number.trim().parse::<u64>() {
+            Ok(number) if number > 0 => number
+                .checked_mul(multiplier)
+                .map(|seconds| Age(Duration::from_secs(seconds)))
+                .ok_or_else(|| format!("\"{}\" is too long", value)),
+            _ => Err(format!(
+                "expected a length of time such as \"90s\" or \"30m\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Age {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        let (unit, multiplier) = AGE_UNITS
+            .iter()
+            .find(|(_, multiplier)| seconds.is_multiple_of(*multiplier))
+            .expect("every number of seconds to be a multiple of one");
+        write!(f, "{}{}", seconds / multiplier, unit)
+    }
+}
+
+impl TryFrom<String> for Age {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Age> for String {
+    fn from(age: Age) -> Self {
+        age.to_string()
+    }
+}
+
+/// A daily period of local time, written as "09:00-10:00". Periods which end before they start,
+/// such as "22:00-07:00", carry on past midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    /// Minutes after midnight
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeRange {
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let minute = |time: &str| {
+            let (hours, minutes) = time.trim().split_once(':')?;
+            match (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?) {
+                (hours, minutes) if hours < 24 && minutes < 60 => Some(hours * 60 + minutes),
+                _ => None,
+            }
+        };
+        value
+            .split_once('-')
+            .and_then(|(start, end)| {
+                Some(TimeRange {
+                    start: minute(start)?,
+                    end: minute(end)?,
+                })
+            })
+            .ok_or_else(|| {
+                format!(
+                    "expected a period such as \"09:00-10:00\", found \"{}\"",
+                    value.trim()
+                )
+            })
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// A comma separated list of periods during which nothing is recorded, such as
+/// "09:00-10:00,13:00-13:30"
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct QuietHours(pub Vec<TimeRange>);
+
+impl QuietHours {
+    /// Whether `minute`, counted from midnight, falls in any of the periods
+    pub fn contains(&self, minute: u32) -> bool {
+        self.0.iter().any(|range| range.contains(minute))
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(QuietHours)
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<_> = self.0.iter().map(TimeRange::to_string).collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<QuietHours> for String {
+    fn from(hours: QuietHours) -> Self {
+        hours.to_string()
+    }
+}
+
+/// How much to log, written as "off", "error", "warn", "info", "debug" or "trace"
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogLevel(pub LevelFilter);
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value.parse().map(LogLevel).map_err(|_| {
+            format!(
+                "expected \"off\", \"error\", \"warn\", \"info\", \"debug\" or \"trace\", found \"{}\"",
+                value
+            )
+        })
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_str().to_ascii_lowercase())
+    }
+}
+
+impl TryFrom<String> for LogLevel {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<LogLevel> for String {
+    fn from(level: LogLevel) -> Self {
+        level.to_string()
+    }
+}
+
+/// What to do with copies synchronised from a remote desktop or Citrix session
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RemoteClipboard {
+    /// Record them like any other copy
+    #[default]
+    Capture,
+    /// Don't record them
+    Skip,
+    /// Record them, marked as remote in the list and the picker
+    Tag,
+}
+
+impl FromStr for RemoteClipboard {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "capture" => Ok(RemoteClipboard::Capture),
+            "skip" => Ok(RemoteClipboard::Skip),
+            "tag" => Ok(RemoteClipboard::Tag),
+            _ => Err(format!(
+                "expected \"capture\", \"skip\" or \"tag\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RemoteClipboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteClipboard::Capture => write!(f, "capture"),
+            RemoteClipboard::Skip => write!(f, "skip"),
+            RemoteClipboard::Tag => write!(f, "tag"),
+        }
+    }
+}
+
+impl TryFrom<String> for RemoteClipboard {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RemoteClipboard> for String {
+    fn from(remote: RemoteClipboard) -> Self {
+        remote.to_string()
+    }
+}
+
+/// How an item is pasted once it's on the clipboard
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PasteMode {
+    /// Press Ctrl+V, or the keys the terminal pastes with
+    #[default]
+    Keys,
+    /// Type the item's text as Unicode key events, for programs which don't paste synthetic Ctrl+V
+    /// or can't read the clipboard, such as virtual machines and remote desktops
+    SendinputText,
+}
+
+impl FromStr for PasteMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "keys" => Ok(PasteMode::Keys),
+            "sendinput-text" => Ok(PasteMode::SendinputText),
+            _ => Err(format!(
+                "expected \"keys\" or \"sendinput-text\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PasteMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasteMode::Keys => write!(f, "keys"),
+            PasteMode::SendinputText => write!(f, "sendinput-text"),
+        }
+    }
+}
+
+impl TryFrom<String> for PasteMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PasteMode> for String {
+    fn from(mode: PasteMode) -> Self {
+        mode.to_string()
+    }
+}
+
+/// An end of the history, which indices count from or the picker lists first
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum HistoryEnd {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+impl HistoryEnd {
+    /// The position in a history of `len` items, newest first, of the item numbered `index`
+    /// counting from this end. As the numbering is its own inverse, this also gives an item's
+    /// number from its position. Numbers past the end are left alone, so they're still missing
+    pub fn position(self, index: usize, len: usize) -> usize {
+        match self {
+            HistoryEnd::Oldest if index < len => len - 1 - index,
+            _ => index,
+        }
+    }
+
+    /// Positions in a history of `len` items, in the order they're shown starting from this end
+    pub fn positions(self, len: usize) -> Vec<usize> {
+        match self {
+            HistoryEnd::Newest => (0..len).collect(),
+            HistoryEnd::Oldest => (0..len).rev().collect(),
+        }
+    }
+}
+
+impl FromStr for HistoryEnd {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "newest" => Ok(HistoryEnd::Newest),
+            "oldest" => Ok(HistoryEnd::Oldest),
+            _ => Err(format!(
+                "expected \"newest\" or \"oldest\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HistoryEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryEnd::Newest => write!(f, "newest"),
+            HistoryEnd::Oldest => write!(f, "oldest"),
+        }
+    }
+}
+
+impl TryFrom<String> for HistoryEnd {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<HistoryEnd> for String {
+    fn from(end: HistoryEnd) -> Self {
+        end.to_string()
+    }
+}
+
+/// The settings which can be changed by switching profile
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// The maximum number of items to keep in the clipboard history
+    pub max_history: usize,
+    /// The proportion of formats (out of 255) which must match for two items to be merged
+    pub similarity_threshold: u8,
+    /// Whether new clipboard items are recorded at all
+    pub capture: bool,
+    /// Which end `list`, `status` and the commands taking an item number count from
+    pub index_from: HistoryEnd,
+    /// Which end of the history the picker and search list first
+    pub picker_order: HistoryEnd,
+    // The rest replace the config's value of the same name while the profile is active
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peek_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub picker_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plain_paste_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub undo_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recapture_hotkey: Option<Hotkey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tap_key: Option<Keystroke>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<Age>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHours>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_apps: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burn_apps: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passthrough_apps: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passthrough_classes: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture_formats: Option<FormatList>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_formats: Option<FormatList>,
+}
+
+impl Profile {
+    /// The settings which can be read and changed by name
+    pub const KEYS: &'static [&'static str] = &[
+        "max_history",
+        "similarity_threshold",
+        "capture",
+        "index_from",
+        "picker_order",
+    ];
+
+    /// Read a setting by name
+    pub fn get(&self, key: &str) -> Result<String, ConfigError> {
+        match key {
+            "max_history" => Ok(self.max_history.to_string()),
+            "similarity_threshold" => Ok(self.similarity_threshold.to_string()),
+            "capture" => Ok(self.capture.to_string()),
+            "index_from" => Ok(self.index_from.to_string()),
+            "picker_order" => Ok(self.picker_order.to_string()),
+            _ => Err(ConfigError::UnknownKey(key.to_owned())),
+        }
+    }
+
+    /// Change a setting by name, parsing the value from a string
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        match key {
+            "max_history" => match value.parse() {
+                Ok(max_history) if max_history > 0 => self.max_history = max_history,
+                _ => return Err(invalid()),
+            },
+            "similarity_threshold" => {
+                self.similarity_threshold = value.parse().map_err(|_| invalid())?
+            }
+            "capture" => self.capture = value.parse().map_err(|_| invalid())?,
+            "index_from" => self.index_from = value.parse().map_err(|_| invalid())?,
+            "picker_order" => self.picker_order = value.parse().map_err(|_| invalid())?,
+            _ => return Err(ConfigError::UnknownKey(key.to_owned())),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            max_history: DEFAULT_MAX_HISTORY,
+            similarity_threshold: DEFAULT_SIMILARITY_THRESHOLD,
+            capture: true,
+            index_from: HistoryEnd::Newest,
+            picker_order: HistoryEnd::Newest,
+            hotkey: None,
+            peek_hotkey: None,
+            picker_hotkey: None,
+            plain_paste_hotkey: None,
+            undo_hotkey: None,
+            next_hotkey: None,
+            previous_hotkey: None,
+            pinned_hotkey: None,
+            search_hotkey: None,
+            snippet_hotkey: None,
+            stack_hotkey: None,
+            queue_hotkey: None,
+            recapture_hotkey: None,
+            tap_key: None,
+            max_age: None,
+            quiet_hours: None,
+            exclude_apps: None,
+            burn_apps: None,
+            passthrough_apps: None,
+            passthrough_classes: None,
+            capture_formats: None,
+            ignore_formats: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The version of the config format, which is upgraded on startup. Files without one are
+    /// version 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// The profile to use when none is given on the command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_profile: Option<String>,
+    /// Where items are saved by the save action. Defaults to %TEMP%\filo-clipboard
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub save_directory: Option<PathBuf>,
+    /// Open saved items with their default program
+    pub open_saved_files: bool,
+    /// Where to keep the history between restarts. The history isn't saved if this isn't set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persist: Option<PathBuf>,
+    /// When to write the history: "change", "exit" or an interval such as "30s"
+    pub flush: FlushStrategy,
+    /// Where the history is kept: "file", "journal", "sqlite" or "memory"
+    pub storage: StorageKind,
+    /// Encrypt the history file with DPAPI, so only the current Windows user can read it
+    pub encrypt: bool,
+    /// The key combination which pastes and pops the next item, such as "ctrl+shift+v"
+    pub hotkey: Hotkey,
+    /// The key combination which pastes the next item without removing it. Defaults to "ctrl+shift+c"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peek_hotkey: Option<Hotkey>,
+    /// The key combination which opens a list of the history to choose from. Defaults to "ctrl+shift+h"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub picker_hotkey: Option<Hotkey>,
+    /// The key combination which puts the last popped item back. Defaults to "ctrl+shift+z"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub undo_hotkey: Option<Hotkey>,
+    /// The key combination which turns the history so the next item is at the front. Defaults to
+    /// "ctrl+shift+pagedown"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_hotkey: Option<Hotkey>,
+    /// The key combination which turns the history back to the previous item. Defaults to
+    /// "ctrl+shift+pageup"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_hotkey: Option<Hotkey>,
+    /// The key combination which pastes the pinned items in turn. Defaults to "ctrl+alt+shift+p"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_hotkey: Option<Hotkey>,
+    /// The key combination which opens the history search. Defaults to "ctrl+alt+shift+f"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search_hotkey: Option<Hotkey>,
+    /// The key combination which opens the list of snippets. Defaults to "ctrl+alt+shift+s"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet_hotkey: Option<Hotkey>,
+    /// The key combination which switches to the next stack. Defaults to "ctrl+alt+shift+n"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack_hotkey: Option<Hotkey>,
+    /// The key combination which pastes the oldest item then presses `queue_keys`, for filling in
+    /// forms. Defaults to "ctrl+alt+shift+q"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_hotkey: Option<Hotkey>,
+    /// The keys pressed after each paste from the queue hotkey, such as ["tab"] or ["enter"].
+    /// Defaults to ["tab"], and an empty list presses nothing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_keys: Option<Vec<Keystroke>>,
+    /// A key such as "f13" or "capslock" which pastes the next item when tapped and opens the
+    /// picker when held. Unset by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tap_key: Option<Keystroke>,
+    /// The key combination which replaces the front item with what's on the clipboard now, for
+    /// programs which offer more formats once they've finished copying. Unset by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recapture_hotkey: Option<Hotkey>,
+    /// What to do when a new item matches one further down the history: "off", "drop" or "move"
+    pub dedup: Dedup,
+    /// Turn the history so the item chosen in the picker is at the front, rather than leaving it in place
+    pub rotate_on_pick: bool,
+    /// Only put the plain text of items back on the clipboard, dropping fonts, colours and images
+    pub plain_paste: bool,
+    /// Paste items copied from web pages and other rich text as the plain text of their HTML
+    pub html_to_text: bool,
+    /// Paste with Ctrl+Shift+V or Shift+Insert into terminals such as Windows Terminal and mintty,
+    /// where Ctrl+V doesn't paste
+    pub terminal_paste: bool,
+    /// How items are pasted: "keys" presses Ctrl+V, "sendinput-text" types the text instead
+    pub paste_mode: PasteMode,
+    /// Which item the paste hotkey takes next: "filo" the newest, "fifo" the oldest
+    pub mode: Order,
+    /// Start with monitoring paused, so nothing is recorded until it's resumed from the tray menu
+    pub start_paused: bool,
+    /// Leave a pasted item on the clipboard and only put the next one there when it's pasted, for
+    /// programs which react to every clipboard change
+    pub lazy_restore: bool,
+    /// Add the "Clipboard Viewer Ignore" format to items put back on the clipboard, so other
+    /// clipboard managers don't record them a second time
+    pub tag_restores: bool,
+    /// Add a private format to items put back on the clipboard recording where they came from, so
+    /// other tools can recognise restored items
+    pub provenance: bool,
+    /// The key combination which pastes and pops the next item as plain text. Defaults to "ctrl+alt+shift+v"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plain_paste_hotkey: Option<Hotkey>,
+    /// How long to wait for the clipboard when putting an item back on it before retrying later.
+    /// Defaults to 500
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restore_timeout_ms: Option<u64>,
+    /// Wait this long after the clipboard changes before reading it, so the several updates some
+    /// programs send for one copy are read once. Defaults to 0, reading it straight away
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debounce_ms: Option<u64>,
+    /// Raise the history limit for a while instead of dropping items when lots are copied at once,
+    /// up to four times max_history. The memory limit still applies
+    pub auto_grow: bool,
+    /// Keep a stack for each monitor, named after its number such as "monitor-2". Copies go to the
+    /// stack of the monitor showing the foreground window, and the paste hotkeys use it too
+    pub monitor_stacks: bool,
+    /// Formats larger than this aren't recorded, except images, which are shrunk to fit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_item_size: Option<ByteSize>,
+    /// The oldest items are dropped while the history takes up more than this
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total_memory: Option<ByteSize>,
+    /// Items older than this, such as "30m", are wiped from the history. Pinned items are kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<Age>,
+    /// Times of day when nothing is recorded and the next item isn't shown, such as
+    /// "09:00-10:00" for a daily meeting where the screen is shared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHours>,
+    /// Programs whose copies are never recorded, such as password managers
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude_apps: Vec<String>,
+    /// Programs whose copies are wiped once they've been pasted, such as a password manager's
+    /// one-time codes
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub burn_apps: Vec<String>,
+    /// Programs which get the hotkeys' keys themselves while they're in the foreground, such as
+    /// virtual machines and games which capture the keyboard
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub passthrough_apps: Vec<String>,
+    /// Window classes which get the hotkeys' keys themselves while they're in the foreground
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub passthrough_classes: Vec<String>,
+    /// What to do with copies synchronised from a remote desktop or Citrix session: "capture",
+    /// "skip" or "tag"
+    pub remote_clipboard: RemoteClipboard,
+    /// Programs which only get text, HTML, RTF and images pasted into them. Defaults to the Office
+    /// programs, and an empty list restores every format everywhere
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safe_restore_apps: Option<Vec<String>>,
+    /// Only these clipboard formats are recorded, such as "unicode,html,bitmap"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture_formats: Option<FormatList>,
+    /// These clipboard formats are never recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_formats: Option<FormatList>,
+    /// Learn which large formats programs read when pasting, record those first, and stop
+    /// recording the ones nothing reads while the history is near `max_total_memory`
+    pub adaptive_formats: bool,
+    /// Append a line to this file for every paste and pop, recording when, where and a hash of the
+    /// item, but never its contents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<PathBuf>,
+    /// How much to log: "off", "error", "warn", "info", "debug" or "trace". Defaults to "info"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LogLevel>,
+    /// Append the log to this file as well as printing it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+    /// Accept Stream Deck plugins on this port of the loopback interface
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_deck_port: Option<u16>,
+    /// Publish the history previews in a shared memory section, so companion tools can read them
+    /// without asking over the pipe
+    pub shared_view: bool,
+    /// Offer the most recent items in the jump list, so they can be pasted from the taskbar
+    pub jump_list: bool,
+    /// Count feature use, error categories and capture timings locally. Nothing copied is recorded
+    /// and nothing is sent anywhere
+    pub telemetry: bool,
+    // Tables have to come after plain values in TOML
+    pub profiles: BTreeMap<String, Profile>,
+    /// Settings for named stacks, which don't need an entry here to be used
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub stacks: BTreeMap<String, StackConfig>,
+}
+
+/// Settings for one of the named stacks, from a `[stacks.<name>]` table
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StackConfig {
+    /// The most items the stack keeps, in place of the profile's max_history
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_history: Option<usize>,
+}
+
+impl Config {
+    /// Load the config from `path`, upgrading it if an older version wrote it. A missing file is
+    /// treated as an empty config
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => match migrations::upgrade_config(&contents)? {
+                Some(upgrade) => toml::from_str(&upgrade.text),
+                None => toml::from_str(&contents),
+            }
+            .map_err(ConfigError::Parse),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(ConfigError::Io(error)),
+        }
+    }
+
+    /// Write the config to `path`, creating the parent directory if needed
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(ConfigError::Io)?;
+        }
+        let config = Self {
+            version: Some(CONFIG_VERSION),
+            ..self.clone()
+        };
+        let contents = toml::to_string(&config).expect("The config to serialize");
+        fs::write(path, contents).map_err(ConfigError::Io)
+    }
+
+    /// Look up a profile by name. The default profile always exists, even if it isn't in the file
+    pub fn profile(&self, name: &str) -> Result<Profile, ConfigError> {
+        match self.profiles.get(name) {
+            Some(profile) => Ok(profile.clone()),
+            None if name == DEFAULT_PROFILE => Ok(Profile::default()),
+            None => Err(ConfigError::UnknownProfile(name.to_owned())),
+        }
+    }
+
+    /// Check for values which parse but can't be used, resetting each to its default.
+    /// Returns a description of every problem found
+    pub fn validate(&mut self) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        if let Some(version) = self.version.filter(|version| *version > CONFIG_VERSION) {
+            issues.push(format!(
+                "version: {} was written by a newer version, which may read it differently",
+                version
+            ));
+        }
+
+        for (name, profile) in self.profiles.iter_mut() {
+            if profile.max_history == 0 {
+                issues.push(format!(
+                    "profiles.{}.max_history: expected a value greater than 0, using {}",
+                    name, DEFAULT_MAX_HISTORY
+                ));
+                profile.max_history = DEFAULT_MAX_HISTORY;
+            }
+        }
+
+        let mut stacks = BTreeMap::new();
+        for (name, mut stack) in mem::take(&mut self.stacks) {
+            let name = match stacks::check_name(&name) {
+                Ok(name) => name,
+                Err(error) => {
+                    issues.push(format!("stacks.{}: {}, ignoring it", name, error));
+                    continue;
+                }
+            };
+            if stack.max_history == Some(0) {
+                issues.push(format!(
+                    "stacks.{}.max_history: expected a value greater than 0, using the profile's",
+                    name
+                ));
+                stack.max_history = None;
+            }
+            stacks.insert(name, stack);
+        }
+        self.stacks = stacks;
+
+        if self.restore_timeout_ms == Some(0) {
+            issues.push(format!(
+                "restore_timeout_ms: expected a value greater than 0, using {}",
+                DEFAULT_RESTORE_TIMEOUT_MS
+            ));
+            self.restore_timeout_ms = None;
+        }
+
+        if let Some(active_profile) = &self.active_profile {
+            if self.profile(active_profile).is_err() {
+                issues.push(format!(
+                    "active_profile: expected one of {:?}, using \"{}\"",
+                    self.profile_names(),
+                    DEFAULT_PROFILE
+                ));
+                self.active_profile = None;
+            }
+        }
+
+        issues
+    }
+
+    /// A config file containing the default profile with every setting at its default value
+    pub fn default_file() -> String {
+        let mut profiles = BTreeMap::new();
+        profiles.insert(DEFAULT_PROFILE.to_owned(), Profile::default());
+        let config = Self {
+            version: Some(CONFIG_VERSION),
+            active_profile: Some(DEFAULT_PROFILE.to_owned()),
+            profiles,
+            ..Default::default()
+        };
+        toml::to_string(&config).expect("The default config to serialize")
+    }
+
+    /// The directory items are saved to by the save action
+    pub fn save_directory(&self) -> PathBuf {
+        self.save_directory
+            .clone()
+            .unwrap_or_else(|| env::temp_dir().join("filo-clipboard"))
+    }
+
+    /// The names of all available profiles, including the implicit default profile
+    pub fn profile_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.profiles.keys().cloned().collect();
+        if !self.profiles.contains_key(DEFAULT_PROFILE) {
+            names.insert(0, DEFAULT_PROFILE.to_owned());
+        }
+        names
+    }
+}
+
+/// The config file location used when `--config` isn't given: %APPDATA%\filo-clipboard\config.toml
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(|app_data| {
+        PathBuf::from(app_data)
+            .join("filo-clipboard")
+            .join("config.toml")
+    })
+}
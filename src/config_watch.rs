@@ -0,0 +1,55 @@
+use std::{fs::File, os::windows::io::FromRawHandle, path::PathBuf, thread};
+
+use log::warn;
+use winapi::um::{winnt, winuser};
+
+use crate::winapi_functions::{open_directory, post_message_a, read_directory_changes_w};
+
+/// Sent to the message window when the config file may have changed
+pub const WM_CONFIG_CHANGED: u32 = winuser::WM_APP + 4;
+
+/// Watch the directory holding the config file on another thread, posting WM_CONFIG_CHANGED to
+/// `h_wnd` whenever the file is written, created or renamed into place. Editors often save in
+/// several steps, so the window should wait a moment before reloading
+pub fn spawn(h_wnd: &mut winapi::shared::windef::HWND__, config_path: PathBuf) {
+    // Window handles can be used from any thread, but raw pointers can't be sent between them
+    let h_wnd = h_wnd as *mut _ as usize;
+    let (directory, file_name) = match (config_path.parent(), config_path.file_name()) {
+        (Some(directory), Some(file_name)) => (directory.to_owned(), file_name.to_owned()),
+        _ => return,
+    };
+
+    thread::spawn(move || {
+        let handle = match open_directory(&directory) {
+            Ok(handle) => handle,
+            Err(error) => {
+                warn!("Could not watch {}: {}", directory.display(), error);
+                return;
+            }
+        };
+        // The file takes ownership of the handle, closing it when the thread ends
+        let directory = unsafe { File::from_raw_handle(handle) };
+        let file_name = file_name.to_string_lossy().to_lowercase();
+        loop {
+            let names = match read_directory_changes_w(
+                &directory,
+                winnt::FILE_NOTIFY_CHANGE_FILE_NAME | winnt::FILE_NOTIFY_CHANGE_LAST_WRITE,
+            ) {
+                Ok(names) => names,
+                Err(error) => {
+                    warn!("Stopped watching the config file: {}", error);
+                    return;
+                }
+            };
+            // An empty list means too much changed to say what, so reload to be safe
+            if names.is_empty()
+                || names
+                    .iter()
+                    .any(|name| name.to_string_lossy().to_lowercase() == file_name)
+            {
+                let h_wnd = unsafe { &mut *(h_wnd as *mut winapi::shared::windef::HWND__) };
+                let _ = post_message_a(h_wnd, WM_CONFIG_CHANGED, 0, 0);
+            }
+        }
+    });
+}
@@ -0,0 +1,193 @@
+use std::{
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use crossbeam::channel;
+use winapi::um::{wingdi, winuser};
+
+use crate::events::{Decision, Event, SkipReason};
+use crate::winapi_functions::{
+    create_window_ex_w, get_stock_object, get_system_metrics, send_message_w, set_timer,
+    set_window_text_w, show_window, SystemError,
+};
+
+const WIDTH: i32 = 260;
+const HEIGHT: i32 = 52;
+/// The overlay is redrawn at most this often, however quickly copies arrive
+const REFRESH_INTERVAL_MS: u32 = 100;
+const REFRESH_TIMER_ID: usize = 1;
+
+/// Text waiting to be shown, or None if the overlay is up to date
+static PENDING: Mutex<Option<String>> = Mutex::new(None);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_last_decision() {
+        let mut state = OverlayState::default();
+        assert!(state.update(&Event::CaptureDecided {
+            decision: Decision::Similar,
+            depth: 3,
+            read: Duration::from_micros(1500),
+            compare: Duration::from_micros(250),
+        }));
+        assert!(state.update(&Event::EntryPushed {
+            depth: 4,
+            preview: String::new(),
+        }));
+        assert!(!state.update(&Event::Notice {
+            message: String::new(),
+        }));
+        assert_eq!(
+            state.text(),
+            "Similar, depth 4\nRead 1.50 ms, compared 0.25 ms"
+        );
+
+        state.update(&Event::CaptureSkipped {
+            reason: SkipReason::Duplicate,
+        });
+        assert!(state.text().ends_with("\nSkipped: Duplicate"));
+    }
+}
+
+/// What the overlay shows, kept up to date from the events
+#[derive(Debug, Default, Clone, PartialEq)]
+struct OverlayState {
+    decision: Option<Decision>,
+    depth: usize,
+    read: Duration,
+    compare: Duration,
+    skipped: Option<SkipReason>,
+}
+
+impl OverlayState {
+    /// Apply an event, returning whether anything shown changed
+    fn update(&mut self, event: &Event) -> bool {
+        let previous = self.clone();
+        match event {
+            Event::CaptureDecided {
+                decision,
+                depth,
+                read,
+                compare,
+            } => {
+                self.decision = Some(*decision);
+                self.depth = *depth;
+                self.read = *read;
+                self.compare = *compare;
+                self.skipped = None;
+            }
+            Event::CaptureSkipped { reason } => self.skipped = Some(*reason),
+            Event::EntryPushed { depth, .. }
+            | Event::HistoryLoaded { depth, .. }
+            | Event::EntryUpdated { depth, .. }
+            | Event::EntryPopped { depth, .. }
+            | Event::PopUndone { depth, .. }
+            | Event::HistoryTrimmed { depth, .. }
+            | Event::EntriesExpired { depth, .. }
+            | Event::HistoryRotated { depth, .. }
+            | Event::HistoryEdited { depth, .. }
+            | Event::StackSwitched { depth, .. }
+            | Event::PinsChanged { depth, .. } => self.depth = *depth,
+            Event::HistoryCleared => self.depth = 0,
+            _ => {}
+        }
+        *self != previous
+    }
+
+    fn text(&self) -> String {
+        let decision = self
+            .decision
+            .map(|decision| format!("{:?}", decision))
+            .unwrap_or_else(|| "Nothing copied yet".to_owned());
+        let mut text = format!(
+            "{}, depth {}\nRead {:.2} ms, compared {:.2} ms",
+            decision,
+            self.depth,
+            self.read.as_secs_f64() * 1000.0,
+            self.compare.as_secs_f64() * 1000.0
+        );
+        if let Some(reason) = self.skipped {
+            text.push_str(&format!("\nSkipped: {:?}", reason));
+        }
+        text
+    }
+}
+
+fn pending() -> MutexGuard<'static, Option<String>> {
+    // The text is only ever replaced whole, so a panic part way through leaves nothing broken
+    PENDING.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// A small always-on-top window in the corner of the screen showing how the last copy compared with
+/// the history. It runs on a thread of its own and is redrawn on a timer, so a burst of copies
+/// doesn't slow the event loop down
+pub struct DebugOverlay {
+    state: OverlayState,
+}
+
+impl DebugOverlay {
+    pub fn spawn() -> Result<Self, SystemError> {
+        let state = OverlayState::default();
+        *pending() = Some(state.text());
+
+        let (sender, receiver) = channel::bounded(1);
+        thread::spawn(move || {
+            let window = match create_window() {
+                Ok(window) => window,
+                Err(error) => {
+                    let _ = sender.send(Err(error));
+                    return;
+                }
+            };
+            let _ = sender.send(Ok(()));
+
+            let mut lp_msg = winuser::MSG::default();
+            while unsafe { winuser::GetMessageW(&mut lp_msg, std::ptr::null_mut(), 0, 0) } > 0 {
+                if lp_msg.message == winuser::WM_TIMER && lp_msg.wParam == REFRESH_TIMER_ID {
+                    if let Some(text) = pending().take() {
+                        let _ = set_window_text_w(window, &text);
+                    }
+                    continue;
+                }
+                unsafe { winuser::DispatchMessageW(&lp_msg) };
+            }
+        });
+        receiver
+            .recv()
+            .expect("The overlay thread to report whether it started")
+            .map(|_| Self { state })
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        if self.state.update(event) {
+            *pending() = Some(self.state.text());
+        }
+    }
+}
+
+/// Show a static text control as a window of its own in the top right corner, without taking the
+/// focus
+fn create_window() -> Result<&'static mut winapi::shared::windef::HWND__, SystemError> {
+    let x = get_system_metrics(winuser::SM_CXSCREEN) - WIDTH - 16;
+    let window = create_window_ex_w(
+        winuser::WS_EX_TOPMOST | winuser::WS_EX_TOOLWINDOW | winuser::WS_EX_NOACTIVATE,
+        "STATIC",
+        "",
+        winuser::WS_POPUP | winuser::WS_BORDER,
+        x,
+        16,
+        WIDTH,
+        HEIGHT,
+        None,
+    )?;
+    let font = get_stock_object(wingdi::DEFAULT_GUI_FONT as i32);
+    send_message_w(window, winuser::WM_SETFONT, font as usize, 0);
+    set_timer(window, REFRESH_TIMER_ID, REFRESH_INTERVAL_MS)?;
+    show_window(window, winuser::SW_SHOWNOACTIVATE);
+    Ok(window)
+}
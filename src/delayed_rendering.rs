@@ -0,0 +1,214 @@
+use std::{
+    mem,
+    path::PathBuf,
+    process, ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    thread,
+};
+
+use crossbeam::channel;
+use winapi::shared::{
+    minwindef::{LPARAM, LRESULT, UINT, WPARAM},
+    windef::{HWND, HWND__},
+    winerror,
+};
+use winapi::um::winuser;
+
+use crate::apps::window_app;
+use crate::clipboard_extras::{to_global_mem, ClipboardItem};
+use crate::winapi_functions::{
+    close_clipboard, create_window_ex_w, get_clipboard_owner, get_foreground_window,
+    get_open_clipboard_window, get_window_thread_process_id, open_clipboard, register_class_ex_w,
+    send_message_w, to_wide, SystemError,
+};
+
+/// Formats at least this large are only copied onto the clipboard once a program asks for them
+pub const DELAYED_RENDERING_SIZE: usize = 1024 * 1024;
+
+const CLASS_NAME: &str = "filo-clipboard_renderer";
+
+/// The window which owns the clipboard while it holds promised formats, or 0 before `start`
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+/// The formats promised to the clipboard which no program has asked for yet
+static PENDING: Mutex<Vec<ClipboardItem>> = Mutex::new(Vec::new());
+/// Every format of the latest promise, whether or not it's been asked for
+static PROMISED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+/// The formats other programs have asked for since `take_reads`, and the program which asked
+static READS: Mutex<Vec<(u32, Option<PathBuf>)>> = Mutex::new(Vec::new());
+
+unsafe extern "system" fn window_proc(
+    h_wnd: HWND,
+    msg: UINT,
+    w_param: WPARAM,
+    l_param: LPARAM,
+) -> LRESULT {
+    if let Some(result) = handle_message(&mut *h_wnd, msg, w_param) {
+        return result;
+    }
+    match msg {
+        winuser::WM_DESTROY => {
+            winuser::PostQuitMessage(0);
+            0
+        }
+        _ => winuser::DefWindowProcW(h_wnd, msg, w_param, l_param),
+    }
+}
+
+/// Answer the clipboard's messages to whichever window owns its promised formats, returning None
+/// for any other message
+pub fn handle_message(h_wnd: &mut HWND__, msg: UINT, w_param: WPARAM) -> Option<LRESULT> {
+    match msg {
+        // A program is reading a promised format. The clipboard is already open
+        winuser::WM_RENDERFORMAT => {
+            let format = w_param as u32;
+            if render(format) {
+                if let Some(reader) = reader() {
+                    lock(&READS).push((format, reader));
+                }
+            }
+        }
+        // The window is closing while formats are still promised, so hand them all over now
+        winuser::WM_RENDERALLFORMATS => {
+            let h_wnd = h_wnd as *mut HWND__;
+            if open_clipboard(unsafe { &mut *h_wnd }).is_ok() {
+                if get_clipboard_owner().map(|owner| owner as *mut _) == Some(h_wnd) {
+                    let formats: Vec<_> = pending().iter().map(|item| item.format).collect();
+                    for format in formats {
+                        render(format);
+                    }
+                }
+                let _ = close_clipboard();
+            }
+        }
+        // Another program replaced the clipboard's contents, so the promises are void
+        winuser::WM_DESTROYCLIPBOARD => {
+            pending().clear();
+            lock(&PROMISED).clear();
+        }
+        _ => return None,
+    }
+    Some(0)
+}
+
+fn lock<T>(mutex: &'static Mutex<T>) -> MutexGuard<'static, T> {
+    // The contents are only ever replaced or added to whole, so a panic part way through leaves
+    // nothing broken
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+fn pending() -> MutexGuard<'static, Vec<ClipboardItem>> {
+    lock(&PENDING)
+}
+
+/// The program reading a promised format: the one with the clipboard open, or the one in the
+/// foreground if it opened the clipboard without a window. `None` when it's this one, such as when
+/// a copy is compared with the clipboard
+fn reader() -> Option<Option<PathBuf>> {
+    match get_open_clipboard_window() {
+        Some(window) => {
+            if get_window_thread_process_id(window) == process::id() {
+                None
+            } else {
+                Some(window_app(window))
+            }
+        }
+        None => Some(get_foreground_window().and_then(window_app)),
+    }
+}
+
+/// Copy a promised format onto the clipboard, which must already be open, returning whether it was
+fn render(format: u32) -> bool {
+    let mut pending = pending();
+    let index = match pending.iter().position(|item| item.format == format) {
+        Some(index) => index,
+        None => return false,
+    };
+    let item = pending.remove(index);
+    if let Ok(mem) = to_global_mem(&item) {
+        if unsafe { !winuser::SetClipboardData(format, mem.get()).is_null() } {
+            //SetClipboardData takes ownership
+            mem.release();
+            return true;
+        }
+    }
+    false
+}
+
+fn register_class() -> Result<(), SystemError> {
+    let class_name = to_wide(CLASS_NAME);
+    let class = winuser::WNDCLASSEXW {
+        cbSize: mem::size_of::<winuser::WNDCLASSEXW>() as u32,
+        lpfnWndProc: Some(window_proc),
+        lpszClassName: class_name.as_ptr(),
+        ..Default::default()
+    };
+    match register_class_ex_w(&class) {
+        Err(error) if error.raw_code() as u32 == winerror::ERROR_CLASS_ALREADY_EXISTS => Ok(()),
+        result => result.map(|_| ()),
+    }
+}
+
+/// Create the window which answers requests for promised formats, on a thread of its own so it can
+/// answer while the message loop waits on the clipboard
+pub fn start() -> Result<(), SystemError> {
+    let (sender, receiver) = channel::bounded(1);
+    thread::spawn(move || {
+        let window = register_class().and_then(|_| {
+            create_window_ex_w(
+                0,
+                CLASS_NAME,
+                "",
+                0,
+                0,
+                0,
+                0,
+                0,
+                Some(unsafe { &mut *winuser::HWND_MESSAGE }),
+            )
+        });
+        let created = window.map(|window| OWNER.store(window as *mut _ as usize, Ordering::SeqCst));
+        let _ = sender.send(created);
+
+        let mut lp_msg = winuser::MSG::default();
+        while unsafe { winuser::GetMessageW(&mut lp_msg, ptr::null_mut(), 0, 0) } > 0 {
+            unsafe { winuser::DispatchMessageW(&lp_msg) };
+        }
+    });
+    receiver
+        .recv()
+        .expect("The renderer thread to report whether it started")
+}
+
+/// The window to open the clipboard with when promising formats, if it's been started
+pub fn owner() -> Option<&'static mut HWND__> {
+    unsafe { (OWNER.load(Ordering::SeqCst) as *mut HWND__).as_mut() }
+}
+
+/// Keep `items` to hand to the clipboard when they're asked for, replacing any earlier promises.
+/// Call once the clipboard has been emptied, as emptying it voids them
+pub fn promise(items: Vec<ClipboardItem>) {
+    *lock(&PROMISED) = items.iter().map(|item| item.format).collect();
+    *pending() = items;
+}
+
+/// The formats of the latest promise, including any which have since been asked for
+pub fn promised() -> Vec<u32> {
+    lock(&PROMISED).clone()
+}
+
+/// The promised formats other programs have read since this was last called, with the program
+/// which read each if it could be told
+pub fn take_reads() -> Vec<(u32, Option<PathBuf>)> {
+    mem::take(&mut *lock(&READS))
+}
+
+/// Close the window, copying any formats still promised onto the clipboard so they outlive us
+pub fn stop() {
+    if let Some(owner) = owner() {
+        OWNER.store(0, Ordering::SeqCst);
+        send_message_w(owner, winuser::WM_CLOSE, 0, 0);
+    }
+}
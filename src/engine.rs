@@ -0,0 +1,406 @@
+use std::{borrow::Cow, collections::VecDeque, convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::entry::{Entry, SIMILAR_IMAGE_DISTANCE};
+use crate::events::Decision;
+use crate::normalize;
+
+pub const DEFAULT_SIMILARITY_THRESHOLD: u8 = 230;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clipboard_extras::{write_unicode_text, ClipboardItem, CF_TEXT};
+
+    fn text(text: &str) -> Entry {
+        Entry::new(vec![write_unicode_text(text)])
+    }
+
+    fn history(texts: &[&str]) -> VecDeque<Entry> {
+        texts.iter().map(|t| text(t)).collect()
+    }
+
+    fn texts(history: &VecDeque<Entry>) -> Vec<String> {
+        history.iter().map(Entry::text).collect()
+    }
+
+    #[test]
+    fn compare_text() {
+        assert_eq!(
+            compare_data(&text("a"), &text("a"), 255),
+            ComparisonResult::Same
+        );
+        assert_eq!(
+            compare_data(&text("a"), &text("b"), 255),
+            ComparisonResult::Different
+        );
+        // The same text copied from a program which adds a format of its own
+        let mut other_program = text("a ");
+        other_program.items.push(ClipboardItem {
+            format: CF_TEXT,
+            content: b"a\0".to_vec(),
+        });
+        assert_eq!(
+            compare_data(&other_program, &text("a"), 255),
+            ComparisonResult::Similar
+        );
+        assert_eq!(
+            compare_data(&Entry::default(), &text("a"), 255),
+            ComparisonResult::Different
+        );
+    }
+
+    #[test]
+    fn decide_against_front_and_last_restored() {
+        let engine = ClipboardEngine::new(255, Dedup::Off);
+        let history = history(&["b", "a"]);
+        assert_eq!(engine.decide(&text("b"), None, &history), Decision::Same);
+        // Putting an item back on the clipboard shouldn't record it again
+        assert_eq!(
+            engine.decide(&text("a"), Some(&text("a")), &history),
+            Decision::Same
+        );
+        assert_eq!(
+            engine.decide(&text("c"), None, &history),
+            Decision::Different
+        );
+    }
+
+    #[test]
+    fn apply_decisions() {
+        let engine = ClipboardEngine::new(255, Dedup::Off);
+        let mut entries = history(&["b", "a"]);
+        assert_eq!(
+            engine.apply(Decision::Similar, text("c"), &mut entries),
+            Capture::Replaced
+        );
+        assert_eq!(texts(&entries), ["c", "a"]);
+        assert_eq!(
+            engine.apply(Decision::Different, text("a"), &mut entries),
+            Capture::Pushed
+        );
+        assert_eq!(texts(&entries), ["a", "c", "a"]);
+        assert_eq!(
+            engine.apply(Decision::Similar, text("a"), &mut VecDeque::new()),
+            Capture::Ignored
+        );
+    }
+
+    #[test]
+    fn dedup_older_copies() {
+        let mut entries = history(&["b", "a"]);
+        let drop = ClipboardEngine::new(255, Dedup::Drop);
+        assert_eq!(
+            drop.apply(Decision::Different, text("a"), &mut entries),
+            Capture::Duplicate
+        );
+        assert_eq!(texts(&entries), ["b", "a"]);
+        let move_to_front = ClipboardEngine::new(255, Dedup::MoveToFront);
+        assert_eq!(
+            move_to_front.apply(Decision::Different, text("a"), &mut entries),
+            Capture::Pushed
+        );
+        assert_eq!(texts(&entries), ["a", "b"]);
+    }
+
+    #[test]
+    fn compact_history() {
+        let engine = ClipboardEngine::new(255, Dedup::Off);
+        let mut revised = text("b ");
+        revised.items.push(ClipboardItem {
+            format: CF_TEXT,
+            content: b"b\0".to_vec(),
+        });
+        let mut entries = history(&["a", " \r\n", "c", "a", "c"]);
+        entries.insert(1, revised);
+        entries.insert(2, text("b"));
+        entries.push_back(Entry::default());
+        let before: usize = entries.iter().map(Entry::size).sum();
+        let compaction = engine.compact(&mut entries);
+        assert_eq!(texts(&entries), ["a", "b ", "c"]);
+        assert_eq!(
+            compaction,
+            Compaction {
+                duplicates: 2,
+                blank: 2,
+                revisions: 1,
+                bytes: before - entries.iter().map(Entry::size).sum::<usize>(),
+            }
+        );
+        assert_eq!(compaction.removed(), 5);
+    }
+
+    #[test]
+    fn parse_dedup() {
+        assert_eq!("drop".parse(), Ok(Dedup::Drop));
+        assert_eq!("move".parse(), Ok(Dedup::MoveToFront));
+        assert!("both".parse::<Dedup>().is_err());
+        for dedup in [Dedup::Off, Dedup::Drop, Dedup::MoveToFront].iter() {
+            assert_eq!(dedup.to_string().parse(), Ok(*dedup));
+        }
+    }
+}
+
+/// What to do when a new item matches one further down the history than the front
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Dedup {
+    /// Record it anyway, so the history can hold repeats
+    #[default]
+    Off,
+    /// Don't record the new copy, leaving the history as it was
+    Drop,
+    /// Remove the older copy and record the new one at the front
+    MoveToFront,
+}
+
+impl FromStr for Dedup {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Dedup::Off),
+            "drop" => Ok(Dedup::Drop),
+            "move" => Ok(Dedup::MoveToFront),
+            _ => Err(format!(
+                "expected \"off\", \"drop\" or \"move\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Dedup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dedup::Off => write!(f, "off"),
+            Dedup::Drop => write!(f, "drop"),
+            Dedup::MoveToFront => write!(f, "move"),
+        }
+    }
+}
+
+impl TryFrom<String> for Dedup {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Dedup> for String {
+    fn from(dedup: Dedup) -> Self {
+        dedup.to_string()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ComparisonResult {
+    Same,
+    Similar,
+    Different,
+}
+
+/// Compare two entries format by format, as `normalize::comparable` sees them, so differences
+/// which programs add on every copy don't make the same content look new
+pub fn compare_data(cb_data: &Entry, prev_cb_data: &Entry, threshold: u8) -> ComparisonResult {
+    match (cb_data.len(), prev_cb_data.len()) {
+        (0, 0) => ComparisonResult::Same,
+        (0, _) | (_, 0) => ComparisonResult::Different,
+        _ => {
+            let (items, prev_items) = (comparable_items(cb_data), comparable_items(prev_cb_data));
+            let count_eq = items
+                .iter()
+                .filter(|x| match prev_items.iter().find(|y| x.0 == y.0) {
+                    Some(y) => x.1 == y.1,
+                    None => false,
+                })
+                .count();
+
+            let max_eq = *[items.len(), prev_items.len()].iter().max().unwrap();
+
+            if count_eq == max_eq {
+                ComparisonResult::Same
+            } else if let (Some(hash), Some(prev_hash)) =
+                (cb_data.image_hash, prev_cb_data.image_hash)
+            {
+                // Counting matching formats says little about images, as a screenshot's formats all
+                // change together, so compare what the pictures look like instead
+                if (hash ^ prev_hash).count_ones() <= SIMILAR_IMAGE_DISTANCE {
+                    ComparisonResult::Similar
+                } else {
+                    ComparisonResult::Different
+                }
+            } else if same_text(cb_data, prev_cb_data) {
+                // The same text copied from a different program, which adds its own formats
+                ComparisonResult::Similar
+            } else if count_eq * 255 >= max_eq * threshold as usize {
+                ComparisonResult::Similar
+            } else {
+                ComparisonResult::Different
+            }
+        }
+    }
+}
+
+/// Each of the entry's formats which is compared, with the data it's compared by
+fn comparable_items(entry: &Entry) -> Vec<(u32, Cow<'_, [u8]>)> {
+    entry
+        .iter()
+        .filter_map(|item| normalize::comparable(item).map(|data| (item.format, data)))
+        .collect()
+}
+
+/// Whether both entries have text and it's identical once trailing whitespace is ignored
+fn same_text(cb_data: &Entry, prev_cb_data: &Entry) -> bool {
+    let (text, prev_text) = (cb_data.text(), prev_cb_data.text());
+    !text.trim_end().is_empty() && text.trim_end() == prev_text.trim_end()
+}
+
+/// What recording a copy did to the history
+#[derive(Debug, PartialEq)]
+pub enum Capture {
+    /// It was already there, so nothing changed
+    Duplicate,
+    /// It replaced the similar item at the front
+    Replaced,
+    /// It was added to the front
+    Pushed,
+    /// It was similar to the last item put back on the clipboard, but the history is empty
+    Ignored,
+}
+
+/// What `compact` removed from a history
+#[derive(Debug, Default, PartialEq)]
+pub struct Compaction {
+    /// Items identical to a newer one
+    pub duplicates: usize,
+    /// Items holding nothing but whitespace
+    pub blank: usize,
+    /// Older copies of the item in front of them
+    pub revisions: usize,
+    /// The size of the removed items' formats
+    pub bytes: usize,
+}
+
+impl Compaction {
+    pub fn removed(&self) -> usize {
+        self.duplicates + self.blank + self.revisions
+    }
+}
+
+/// Decides how each copy is recorded, apart from reading the clipboard and everything around it
+/// which the window does, so the decisions can be tested without a clipboard
+pub struct ClipboardEngine {
+    /// How alike two items have to be to count as similar, out of 255
+    threshold: u8,
+    dedup: Dedup,
+}
+
+impl ClipboardEngine {
+    pub fn new(threshold: u8, dedup: Dedup) -> Self {
+        Self { threshold, dedup }
+    }
+
+    /// Compare a new copy with the item last put back on the clipboard and the front of the history
+    pub fn decide(
+        &self,
+        cb_data: &Entry,
+        last_internal_update: Option<&Entry>,
+        cb_history: &VecDeque<Entry>,
+    ) -> Decision {
+        let threshold = self.threshold;
+        let compare = |entry: Option<&Entry>| {
+            entry
+                .map(|entry| compare_data(cb_data, entry, threshold))
+                .unwrap_or(ComparisonResult::Different)
+        };
+        let (prev_item_similarity, current_item_similarity) = crossbeam::scope(|scope| {
+            let prev_item_similarity_handle = scope.spawn(|_| compare(last_internal_update));
+            let current_item_similarity_handle = scope.spawn(|_| compare(cb_history.front()));
+            (
+                prev_item_similarity_handle.join().unwrap(),
+                current_item_similarity_handle.join().unwrap(),
+            )
+        })
+        .unwrap();
+
+        match (prev_item_similarity, current_item_similarity) {
+            (_, ComparisonResult::Same) | (ComparisonResult::Same, _) => Decision::Same,
+            (_, ComparisonResult::Similar) | (ComparisonResult::Similar, _) => Decision::Similar,
+            (ComparisonResult::Different, ComparisonResult::Different) => Decision::Different,
+        }
+    }
+
+    /// Remove blank items, older copies of the item in front of them and items identical to a newer
+    /// one, wiping what's removed. Older copies are the ones a similar copy would have replaced had
+    /// it been recorded straight after them
+    pub fn compact(&self, cb_history: &mut VecDeque<Entry>) -> Compaction {
+        let mut compaction = Compaction::default();
+        let mut kept: VecDeque<Entry> = VecDeque::with_capacity(cb_history.len());
+        for mut entry in cb_history.drain(..) {
+            let removed = if entry.is_blank() {
+                Some(&mut compaction.blank)
+            } else if kept
+                .iter()
+                .any(|newer| compare_data(&entry, newer, self.threshold) == ComparisonResult::Same)
+            {
+                Some(&mut compaction.duplicates)
+            } else if kept.back().is_some_and(|newer| {
+                compare_data(newer, &entry, self.threshold) == ComparisonResult::Similar
+            }) {
+                Some(&mut compaction.revisions)
+            } else {
+                None
+            };
+            match removed {
+                Some(count) => {
+                    *count += 1;
+                    compaction.bytes += entry.size();
+                    entry.wipe();
+                }
+                None => kept.push_back(entry),
+            }
+        }
+        *cb_history = kept;
+        compaction
+    }
+
+    /// Record `cb_data` in the history as `decision` says. The history isn't trimmed
+    pub fn apply(
+        &self,
+        decision: Decision,
+        cb_data: Entry,
+        cb_history: &mut VecDeque<Entry>,
+    ) -> Capture {
+        match decision {
+            Decision::Same => Capture::Duplicate,
+            Decision::Similar => match cb_history.front_mut() {
+                Some(front) => {
+                    *front = cb_data;
+                    Capture::Replaced
+                }
+                None => Capture::Ignored,
+            },
+            Decision::Different => {
+                let older = match self.dedup {
+                    Dedup::Off => None,
+                    _ => cb_history.iter().skip(1).position(|entry| {
+                        compare_data(&cb_data, entry, self.threshold) != ComparisonResult::Different
+                    }),
+                };
+                match (self.dedup, older) {
+                    (Dedup::Drop, Some(_)) => return Capture::Duplicate,
+                    (Dedup::MoveToFront, Some(position)) => {
+                        cb_history.remove(position + 1);
+                    }
+                    _ => {}
+                }
+                cb_history.push_front(cb_data);
+                Capture::Pushed
+            }
+        }
+    }
+}
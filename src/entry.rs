@@ -0,0 +1,411 @@
+use std::{
+    ops::Deref,
+    ptr,
+    time::{Duration, Instant},
+};
+
+use crate::clipboard_extras::{
+    read_dib, read_hdrop, read_text, thumbnail, ClipboardItem, Dib, ImageInfo,
+};
+
+/// Image hashes which differ in at most this many bits are treated as the same picture
+pub const SIMILAR_IMAGE_DISTANCE: u32 = 6;
+
+/// How long an item marked from the picker lasts, long enough to paste a one-time code
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard_extras::{write_hdrop, write_unicode_text, CF_DIB, CF_TEXT};
+
+    fn hash(dib: Vec<u8>) -> Option<u64> {
+        Dib::parse(&dib).as_ref().and_then(dhash)
+    }
+
+    /// A top-down 32 bit DIB with each pixel's brightness given by `pixel(x, y)`
+    fn dib(width: i32, height: i32, pixel: impl Fn(i32, i32) -> u8) -> Vec<u8> {
+        let mut dib = vec![0u8; 40];
+        dib[..4].copy_from_slice(&40u32.to_le_bytes());
+        dib[4..8].copy_from_slice(&width.to_le_bytes());
+        dib[8..12].copy_from_slice(&(-height).to_le_bytes());
+        dib[12..14].copy_from_slice(&1u16.to_le_bytes());
+        dib[14..16].copy_from_slice(&32u16.to_le_bytes());
+        for y in 0..height {
+            for x in 0..width {
+                let value = pixel(x, y);
+                dib.extend_from_slice(&[value, value, value, 0]);
+            }
+        }
+        dib
+    }
+
+    #[test]
+    fn gradient_hash() {
+        // Brightness increases to the right, so every comparison is true
+        assert_eq!(hash(dib(90, 80, |x, _| x as u8 * 2)), Some(u64::MAX));
+        assert_eq!(hash(dib(90, 80, |x, _| 200 - x as u8 * 2)), Some(0));
+    }
+
+    #[test]
+    fn near_identical_images_are_close() {
+        let original = hash(dib(200, 100, |x, y| ((x * 7 + y * 13) % 256) as u8)).unwrap();
+        // A small change, such as a cursor moving, barely affects the hash
+        let changed = hash(dib(200, 100, |x, y| {
+            if x < 4 && y < 4 {
+                255
+            } else {
+                ((x * 7 + y * 13) % 256) as u8
+            }
+        }))
+        .unwrap();
+        let different = hash(dib(200, 100, |x, y| ((x * y) % 256) as u8)).unwrap();
+        assert!((original ^ changed).count_ones() <= SIMILAR_IMAGE_DISTANCE);
+        assert!((original ^ different).count_ones() > SIMILAR_IMAGE_DISTANCE);
+    }
+
+    #[test]
+    fn summary_uses_first_line() {
+        let entry = Entry::new(vec![ClipboardItem {
+            format: CF_TEXT,
+            content: b"\r\n  first line\r\nsecond line\0".to_vec(),
+        }]);
+        assert_eq!(entry.summary(80), "first line");
+        assert_eq!(entry.summary(5), "first…");
+    }
+
+    #[test]
+    fn summary_of_unicode_text() {
+        let entry = Entry::new(vec![
+            ClipboardItem {
+                format: CF_TEXT,
+                content: b"??\0".to_vec(),
+            },
+            write_unicode_text("日本語のテキスト"),
+        ]);
+        assert_eq!(entry.summary(3), "日本語…");
+    }
+
+    #[test]
+    fn summary_without_text() {
+        let entry = Entry::new(vec![ClipboardItem {
+            format: 49_000,
+            content: vec![1],
+        }]);
+        assert_eq!(entry.summary(80), "(1 format, no text)");
+        let image = Entry {
+            image_hash: Some(0),
+            ..Entry::default()
+        };
+        assert_eq!(image.summary(80), "(image)");
+        let screenshot = Entry::new(vec![ClipboardItem {
+            format: CF_DIB,
+            content: dib(16, 9, |_, _| 0),
+        }]);
+        assert_eq!(screenshot.summary(80), "(16×9 image)");
+    }
+
+    #[test]
+    fn summary_marks_remote_entries() {
+        let mut entry = Entry::new(vec![write_unicode_text("from the server")]);
+        entry.remote = true;
+        assert_eq!(entry.summary(4), "[remote] from…");
+        entry.label = Some("server".to_owned());
+        assert_eq!(entry.summary(4), "server: [remote] from…");
+        entry.burn = true;
+        assert_eq!(entry.summary(4), "server: [burn] [remote] from…");
+    }
+
+    #[test]
+    fn summary_of_files() {
+        let entry = Entry::new(vec![write_hdrop(&["C:\\a.txt", "C:\\b.png"])]);
+        assert_eq!(entry.files(), ["C:\\a.txt", "C:\\b.png"]);
+        assert_eq!(entry.summary(100), "C:\\a.txt and 1 more file");
+        assert_eq!(entry.preview(), "C:\\a.txt\nC:\\b.png");
+    }
+
+    #[test]
+    fn fingerprint_depends_on_content() {
+        let entry = Entry::new(vec![write_unicode_text("a")]);
+        assert_eq!(Entry::default().fingerprint(), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(
+            entry.fingerprint(),
+            Entry::new(vec![write_unicode_text("a")]).fingerprint()
+        );
+        assert_ne!(
+            entry.fingerprint(),
+            Entry::new(vec![write_unicode_text("b")]).fingerprint()
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let now = Instant::now();
+        let mut entry = Entry::new(vec![write_unicode_text("123456")]);
+        assert!(!entry.is_expired(now));
+        entry.expires_at = Some(now);
+        assert!(entry.is_expired(now));
+        assert!(!entry.is_expired(now - Duration::from_secs(1)));
+        entry.wipe();
+        assert!(entry.is_empty());
+    }
+
+    #[test]
+    fn checksum() {
+        let mut entry = Entry::new(vec![write_unicode_text("a")]);
+        assert!(entry.is_intact());
+        entry.checksum = Some(entry.fingerprint());
+        assert!(entry.is_intact());
+        entry.items[0].content[0] = b'b';
+        assert!(!entry.is_intact());
+    }
+
+    #[test]
+    fn unsupported_dib() {
+        assert_eq!(hash(vec![0; 10]), None);
+        let mut paletted = dib(16, 16, |_, _| 0);
+        paletted[14..16].copy_from_slice(&8u16.to_le_bytes());
+        assert_eq!(hash(paletted), None);
+    }
+}
+
+/// An item in the history: every format which was on the clipboard, along with anything worked
+/// out from them when they were captured
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct Entry {
+    pub items: Vec<ClipboardItem>,
+    /// Perceptual hash of the CF_DIB image, if there is one
+    pub image_hash: Option<u64>,
+    /// The size of the first bitmap format, if there is one
+    pub image: Option<ImageInfo>,
+    /// When the entry should be wiped from the history, if it was marked to expire
+    pub expires_at: Option<Instant>,
+    /// The executable of the program the entry was copied from, if it could be told
+    pub source_app: Option<String>,
+    /// The file copied or the document the entry was copied from, if it could be told
+    pub source_document: Option<String>,
+    /// Seconds since the Unix epoch when the entry was copied. Not kept across restarts
+    pub copied_at: Option<u64>,
+    /// Pinned entries are kept apart from the history, so they're never trimmed or popped
+    pub pinned: bool,
+    /// Copied in a remote desktop or Citrix session, marked when remote_clipboard is "tag"
+    pub remote: bool,
+    /// Wiped from the history and the clipboard once it's been pasted, such as a one-time password
+    pub burn: bool,
+    /// A name given to the entry with the batch command. Not kept across restarts
+    pub label: Option<String>,
+    /// The fingerprint stored with the entry in the history file, if it was loaded from one
+    pub checksum: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(items: Vec<ClipboardItem>) -> Self {
+        let image_hash = items.iter().find_map(read_dib).as_ref().and_then(dhash);
+        let image = items.iter().find_map(ClipboardItem::image_info);
+        Self {
+            items,
+            image_hash,
+            image,
+            expires_at: None,
+            source_app: None,
+            source_document: None,
+            copied_at: None,
+            pinned: false,
+            remote: false,
+            burn: false,
+            label: None,
+            checksum: None,
+        }
+    }
+
+    /// A 32 bit bitmap of the entry's image scaled to fit within `max_side` pixels, if it has an
+    /// uncompressed one
+    pub fn thumbnail(&self, max_side: usize) -> Option<Vec<u8>> {
+        self.items
+            .iter()
+            .find_map(read_dib)
+            .and_then(|dib| thumbnail(&dib, max_side))
+    }
+
+    /// A 64 bit FNV-1a hash of every format and its contents, which stays the same between runs
+    pub fn fingerprint(&self) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+        let mut hash = OFFSET_BASIS;
+        for item in self.items.iter() {
+            for byte in item.format.to_le_bytes().iter().chain(item.content.iter()) {
+                hash = (hash ^ *byte as u64).wrapping_mul(PRIME);
+            }
+        }
+        hash
+    }
+
+    /// Whether the entry still matches the checksum it was stored with, if it has one
+    pub fn is_intact(&self) -> bool {
+        self.checksum
+            .map(|checksum| checksum == self.fingerprint())
+            .unwrap_or(true)
+    }
+
+    /// The number of bytes taken up by the entry's formats
+    pub fn size(&self) -> usize {
+        self.items.iter().map(|item| item.content.len()).sum()
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.map(|at| at <= now).unwrap_or(false)
+    }
+
+    /// Overwrite every byte of the entry's data before it's dropped, so it doesn't linger in freed
+    /// memory
+    pub fn wipe(&mut self) {
+        for item in self.items.iter_mut() {
+            for byte in item.content.iter_mut() {
+                // Volatile so the writes aren't optimised away as dead stores
+                unsafe { ptr::write_volatile(byte, 0) };
+            }
+        }
+        self.items.clear();
+    }
+
+    /// The entry's text, preferring CF_UNICODETEXT, or an empty string if it has none
+    pub fn text(&self) -> String {
+        read_text(&self.items).unwrap_or_default()
+    }
+
+    /// Whether the entry holds nothing, or nothing but whitespace text
+    pub fn is_blank(&self) -> bool {
+        match read_text(&self.items) {
+            Some(text) => {
+                text.trim_matches(|c: char| c.is_whitespace() || c == '\0')
+                    .is_empty()
+                    && self.image_hash.is_none()
+                    && self.files().is_empty()
+            }
+            None => self.items.is_empty(),
+        }
+    }
+
+    /// The paths of the files copied, if the entry was copied in Explorer
+    pub fn files(&self) -> Vec<String> {
+        self.items.iter().flat_map(read_hdrop).collect()
+    }
+
+    /// The entry's text, or the paths of its files one per line if it has none, for previews
+    pub fn preview(&self) -> String {
+        match read_text(&self.items) {
+            Some(text) => text,
+            None => self.files().join("\n"),
+        }
+    }
+
+    /// A single line describing the entry: the start of its first line of text, or what kind of
+    /// data it holds. Text longer than `length` characters is cut short
+    pub fn summary(&self, length: usize) -> String {
+        let text = self.text();
+        let mut summary = text
+            .lines()
+            .map(|line| line.trim_matches(|c: char| c.is_whitespace() || c == '\0'))
+            .find(|line| !line.is_empty())
+            .unwrap_or_default()
+            .to_owned();
+        if summary.is_empty() {
+            let files = self.files();
+            if let Some(file) = files.first() {
+                summary = match files.len() {
+                    1 => file.clone(),
+                    2 => format!("{} and 1 more file", file),
+                    count => format!("{} and {} more files", file, count - 1),
+                };
+            } else if let Some(image) = self.image {
+                summary = format!("({}×{} image)", image.width, image.height);
+            } else if self.image_hash.is_some() {
+                summary = "(image)".to_owned();
+            } else {
+                summary = format!(
+                    "({} format{}, no text)",
+                    self.len(),
+                    if self.len() == 1 { "" } else { "s" }
+                );
+            }
+        } else if let Some((end, _)) = summary.char_indices().nth(length) {
+            summary.truncate(end);
+            summary.push('…');
+        }
+        if self.remote {
+            summary.insert_str(0, "[remote] ");
+        }
+        if self.burn {
+            summary.insert_str(0, "[burn] ");
+        }
+        if let Some(label) = &self.label {
+            summary.insert_str(0, &format!("{}: ", label));
+        }
+        summary
+    }
+}
+
+impl Deref for Entry {
+    type Target = [ClipboardItem];
+
+    fn deref(&self) -> &Self::Target {
+        &self.items
+    }
+}
+
+/// Difference hash of an uncompressed 24 or 32 bit DIB: the image is shrunk to 9x8 greyscale
+/// and each bit records whether a pixel is darker than its right hand neighbour
+fn dhash(dib: &Dib) -> Option<u64> {
+    let (width, height, bit_count) = (dib.width, dib.height, dib.bit_count);
+    if width <= 0 || height == 0 || !(bit_count == 24 || bit_count == 32) {
+        return None;
+    }
+    // BI_RGB, or BI_BITFIELDS with masks which are assumed to be the usual ones
+    if !(dib.compression == 0 || dib.compression == 3) {
+        return None;
+    }
+
+    let width = width as usize;
+    let bottom_up = height > 0;
+    let height = height.unsigned_abs() as usize;
+    let bytes_per_pixel = bit_count as usize / 8;
+    let stride = (width * bit_count as usize).div_ceil(32) * 4;
+    let pixels = dib.pixels()?;
+    if pixels.len() < stride * height {
+        return None;
+    }
+
+    let brightness = |x: usize, y: usize| {
+        let row = if bottom_up { height - 1 - y } else { y };
+        let offset = row * stride + x * bytes_per_pixel;
+        let (b, g, r) = (pixels[offset], pixels[offset + 1], pixels[offset + 2]);
+        // Integer approximation of the usual luma weights
+        (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000
+    };
+
+    // Average a 4x4 grid of samples in each cell, rather than every pixel, so large screenshots
+    // are still quick to hash
+    const SAMPLES: usize = 4;
+    let mut cells = [[0u32; 9]; 8];
+    for (cell_y, row) in cells.iter_mut().enumerate() {
+        for (cell_x, cell) in row.iter_mut().enumerate() {
+            let mut total = 0;
+            for sample_y in 0..SAMPLES {
+                for sample_x in 0..SAMPLES {
+                    let x = ((cell_x * SAMPLES + sample_x) * 2 + 1) * width / (9 * SAMPLES * 2);
+                    let y = ((cell_y * SAMPLES + sample_y) * 2 + 1) * height / (8 * SAMPLES * 2);
+                    total += brightness(x, y);
+                }
+            }
+            *cell = total;
+        }
+    }
+
+    let mut hash = 0u64;
+    for row in cells.iter() {
+        for pair in row.windows(2) {
+            hash = hash << 1 | (pair[0] < pair[1]) as u64;
+        }
+    }
+    Some(hash)
+}
@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use log::{debug, error, info};
+
+/// How a new copy compared with the front of the history and the item last put on the clipboard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    /// It matched one of them exactly, so it isn't recorded again
+    Same,
+    /// It's a newer copy of the front item, which it replaces
+    Similar,
+    /// It's new, so it's added to the front
+    Different,
+}
+
+/// Why a clipboard update wasn't recorded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipReason {
+    /// The update was caused by us restoring an older item
+    OwnRestore,
+    /// The clipboard contained the "Clipboard Viewer Ignore" format
+    ViewerIgnore,
+    /// The clipboard was written by a program on the exclusion list
+    ExcludedApp,
+    /// The clipboard was synchronised from a remote session, and remote_clipboard is "skip"
+    RemoteSession,
+    /// Capture is turned off in the current profile
+    CaptureDisabled,
+    /// Monitoring was paused from the tray menu
+    Paused,
+    /// It's one of the quiet hours
+    QuietHours,
+    /// The clipboard couldn't be opened or had no readable formats
+    Unreadable,
+    /// The new item was the same as the front of the history or the item just popped
+    Duplicate,
+    /// Every format of the new item was larger than the size limit
+    TooLarge,
+    /// None of the new item's formats are ones capture_formats and ignore_formats allow
+    FormatsFiltered,
+}
+
+/// Changes to the history, emitted so that frontends don't need to poll it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A new item was added to the front of the history
+    EntryPushed {
+        depth: usize,
+        preview: String,
+    },
+    /// The history was read from disk at startup
+    HistoryLoaded {
+        depth: usize,
+        preview: String,
+    },
+    /// The front item was replaced by a similar, newer copy of itself
+    EntryUpdated {
+        depth: usize,
+        preview: String,
+    },
+    /// The next item was pasted and removed. `preview` is the item to be pasted after it
+    EntryPopped {
+        depth: usize,
+        preview: String,
+    },
+    /// The last popped item was put back at the front
+    PopUndone {
+        depth: usize,
+        preview: String,
+    },
+    /// Items were dropped from the back when a temporarily raised limit went back down
+    HistoryTrimmed {
+        depth: usize,
+        preview: String,
+    },
+    /// Items marked to expire were wiped. `preview` is the item now at the front
+    EntriesExpired {
+        depth: usize,
+        preview: String,
+    },
+    /// The history was turned like a ring so that another item is at the front
+    HistoryRotated {
+        depth: usize,
+        preview: String,
+    },
+    /// Items were deleted, moved or labelled by a batch request, or removed by compacting
+    HistoryEdited {
+        depth: usize,
+        preview: String,
+    },
+    /// An item was pinned or unpinned. `pinned` is how many items are pinned now
+    PinsChanged {
+        depth: usize,
+        preview: String,
+        pinned: usize,
+    },
+    HistoryCleared,
+    /// A new copy was compared with the history. `read` is how long reading the clipboard took and
+    /// `compare` how long the comparison took
+    CaptureDecided {
+        decision: Decision,
+        depth: usize,
+        read: Duration,
+        compare: Duration,
+    },
+    CaptureSkipped {
+        reason: SkipReason,
+    },
+    ProfileSwitched {
+        name: String,
+    },
+    /// Another stack became the active one. `preview` is the item at its front
+    StackSwitched {
+        name: String,
+        depth: usize,
+        preview: String,
+    },
+    /// Capture was turned on or off, by pausing, switching profile or changing the setting
+    CaptureToggled {
+        enabled: bool,
+    },
+    /// Quiet hours started or ended. `preview` is hidden while they're `active`
+    QuietHoursChanged {
+        active: bool,
+        depth: usize,
+        preview: String,
+    },
+    /// A suggestion worth showing the user, which isn't an error
+    Notice {
+        message: String,
+    },
+    /// Something went wrong which the user should know about
+    Error {
+        message: String,
+    },
+}
+
+impl Event {
+    /// Whether the history's contents were changed
+    pub fn changes_history(&self) -> bool {
+        matches!(
+            self,
+            Event::EntryPushed { .. }
+                | Event::EntryUpdated { .. }
+                | Event::EntryPopped { .. }
+                | Event::PopUndone { .. }
+                | Event::HistoryTrimmed { .. }
+                | Event::HistoryRotated { .. }
+                | Event::EntriesExpired { .. }
+                | Event::PinsChanged { .. }
+                | Event::HistoryEdited { .. }
+                | Event::HistoryCleared
+                | Event::ProfileSwitched { .. }
+                | Event::StackSwitched { .. }
+        )
+    }
+}
+
+pub type Subscriber = Box<dyn FnMut(&Event)>;
+
+/// Passes every event to each subscriber in the order they subscribed
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Vec<Subscriber>,
+}
+
+impl EventBus {
+    pub fn subscribe(&mut self, subscriber: impl FnMut(&Event) + 'static) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber(&event);
+        }
+    }
+}
+
+/// Log an event without any of the history's contents. Captures, merges and pops are logged under
+/// targets of their own, so a missed capture can be traced in the log file
+pub fn log_event(event: &Event) {
+    match event {
+        Event::EntryPushed { depth, .. } => {
+            info!(target: "capture", "Recorded a new item, depth {}", depth)
+        }
+        Event::CaptureSkipped { reason } => info!(target: "capture", "Skipped: {:?}", reason),
+        Event::CaptureDecided {
+            decision,
+            depth,
+            read,
+            compare,
+        } => debug!(
+            target: "capture",
+            "{:?} to the history at depth {}, read in {:?}, compared in {:?}",
+            decision,
+            depth,
+            read,
+            compare
+        ),
+        Event::EntryUpdated { depth, .. } => {
+            info!(target: "merge", "Replaced the front item with a newer copy, depth {}", depth)
+        }
+        Event::EntryPopped { depth, .. } => info!(target: "pop", "Popped an item, depth {}", depth),
+        Event::PopUndone { depth, .. } => info!(target: "pop", "Undid a pop, depth {}", depth),
+        Event::HistoryLoaded { depth, .. } => info!("Loaded the history, depth {}", depth),
+        Event::HistoryTrimmed { depth, .. } => info!("Trimmed the history, depth {}", depth),
+        Event::EntriesExpired { depth, .. } => info!("Wiped expired items, depth {}", depth),
+        Event::HistoryRotated { depth, .. } => debug!("Turned the history, depth {}", depth),
+        Event::PinsChanged { depth, pinned, .. } => {
+            info!("{} pinned items, depth {}", pinned, depth)
+        }
+        Event::HistoryEdited { depth, .. } => info!("Edited the history, depth {}", depth),
+        Event::HistoryCleared => info!("Cleared the history"),
+        Event::ProfileSwitched { name } => info!("Switched to profile {}", name),
+        Event::StackSwitched { name, depth, .. } => {
+            info!("Switched to the {} stack, depth {}", name, depth)
+        }
+        Event::CaptureToggled { enabled } => {
+            info!("Capture {}", if *enabled { "resumed" } else { "paused" })
+        }
+        Event::QuietHoursChanged { active, .. } => {
+            info!("Quiet hours {}", if *active { "started" } else { "ended" })
+        }
+        Event::Notice { message } => info!("{}", message),
+        Event::Error { message } => error!("{}", message),
+    }
+}
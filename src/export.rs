@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+
+use crate::audit::format_timestamp;
+use crate::clipboard_extras::ClipboardItem;
+use crate::config::HistoryEnd;
+use crate::entry::Entry;
+
+/// Bumped whenever the layout changes in a way older versions can't read
+const VERSION: u32 = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard_extras::write_unicode_text;
+
+    fn name(format: u32) -> String {
+        format!("format {}", format)
+    }
+
+    fn resolve(name: &str) -> Result<u32, String> {
+        name.trim_start_matches("format ")
+            .parse()
+            .map_err(|_| name.to_owned())
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut entry = Entry::new(vec![
+            write_unicode_text("hello"),
+            ClipboardItem {
+                format: 49_300,
+                content: vec![0, 1, 2, 255],
+            },
+        ]);
+        entry.source_app = Some("notepad.exe".to_owned());
+        entry.source_document = Some("C:\\Notes\\plan.docx".to_owned());
+        let mut pinned = Entry::new(vec![write_unicode_text("pinned")]);
+        pinned.pinned = true;
+
+        let json = to_json(&[entry.clone()], &[pinned.clone()], name);
+        assert!(json.contains("\"text\": \"hello\""));
+        let entries = from_json(&json, resolve).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].items, entry.items);
+        assert_eq!(entries[0].source_app, entry.source_app);
+        assert_eq!(entries[0].source_document, entry.source_document);
+        assert!(!entries[0].pinned);
+        assert_eq!(entries[1].items, pinned.items);
+        assert!(entries[1].pinned);
+    }
+
+    #[test]
+    fn describe_entry() {
+        let mut entry = Entry::new(vec![write_unicode_text("hello\nworld")]);
+        entry.source_app = Some("notepad.exe".to_owned());
+        entry.copied_at = Some(0);
+        let json = to_json(&[entry], &[], name);
+        let description = describe(&json, 0, HistoryEnd::Newest).unwrap();
+        assert!(description.contains("Text: hello\n  world\n"));
+        assert!(description.contains("Copied: 1970-01-01 00:00:00Z from notepad.exe"));
+        assert!(description.ends_with("format 13\t24 bytes"));
+        assert!(describe(&json, 1, HistoryEnd::Newest).is_err());
+        let older = Entry::new(vec![write_unicode_text("older")]);
+        let json = to_json(&[older, Entry::new(Vec::new())], &[], name);
+        assert!(describe(&json, 0, HistoryEnd::Oldest)
+            .unwrap()
+            .contains("Text: older"));
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(from_json("[]", resolve).is_err());
+        assert!(from_json(r#"{"version": 2, "entries": []}"#, resolve).is_err());
+        let bad_data = r#"{"version": 1, "entries": [
+            {"text": "", "formats": [{"name": "format 13", "data": "not base64!"}]}
+        ]}"#;
+        assert!(from_json(bad_data, resolve).is_err());
+    }
+}
+
+/// The JSON written by `filo-clipboard export`
+#[derive(Serialize, Deserialize)]
+struct ExportFile {
+    version: u32,
+    /// The history from the front, followed by the pinned entries
+    entries: Vec<ExportedEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedEntry {
+    /// The entry's text, so the file can be read without decoding the formats. Ignored on import
+    text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_app: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_document: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    copied_at: Option<u64>,
+    #[serde(default)]
+    pinned: bool,
+    formats: Vec<ExportedFormat>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedFormat {
+    /// The format's name, as ids of registered formats change between restarts
+    name: String,
+    /// The raw contents in base64
+    data: String,
+}
+
+/// Write the history and pinned entries as JSON, naming each format with `name`
+pub fn to_json<'a>(
+    history: impl IntoIterator<Item = &'a Entry>,
+    pinned: &'a [Entry],
+    name: impl Fn(u32) -> String,
+) -> String {
+    let entries = history
+        .into_iter()
+        .chain(pinned.iter())
+        .map(|entry| ExportedEntry {
+            text: entry.text(),
+            source_app: entry.source_app.clone(),
+            source_document: entry.source_document.clone(),
+            label: entry.label.clone(),
+            copied_at: entry.copied_at,
+            pinned: entry.pinned,
+            formats: entry
+                .items
+                .iter()
+                .map(|item| ExportedFormat {
+                    name: name(item.format),
+                    data: base64::encode(&item.content),
+                })
+                .collect(),
+        })
+        .collect();
+    let file = ExportFile {
+        version: VERSION,
+        entries,
+    };
+    serde_json::to_string_pretty(&file).expect("The export to serialize")
+}
+
+/// Read entries written by `to_json`, in the same order, turning format names back into ids with
+/// `resolve`
+pub fn from_json(
+    json: &str,
+    resolve: impl Fn(&str) -> Result<u32, String>,
+) -> Result<Vec<Entry>, String> {
+    let file: ExportFile = serde_json::from_str(json).map_err(|error| error.to_string())?;
+    if file.version != VERSION {
+        return Err(format!("unsupported export version {}", file.version));
+    }
+    file.entries
+        .into_iter()
+        .map(|exported| {
+            let items = exported
+                .formats
+                .iter()
+                .map(|format| {
+                    Ok(ClipboardItem {
+                        format: resolve(&format.name)?,
+                        content: base64::decode(&format.data).map_err(|error| {
+                            format!("the data of format \"{}\": {}", format.name, error)
+                        })?,
+                    })
+                })
+                .collect::<Result<_, String>>()?;
+            let mut entry = Entry::new(items);
+            entry.source_app = exported.source_app;
+            entry.source_document = exported.source_document;
+            entry.label = exported.label;
+            entry.copied_at = exported.copied_at;
+            entry.pinned = exported.pinned;
+            Ok(entry)
+        })
+        .collect()
+}
+
+/// A description of item `index` of JSON written by `to_json`, numbered from `from` as `list`
+/// numbers it: its text, where it came from, and the name and size of each format
+pub fn describe(json: &str, index: usize, from: HistoryEnd) -> Result<String, String> {
+    let file: ExportFile = serde_json::from_str(json).map_err(|error| error.to_string())?;
+    // Pinned items follow the history and keep their order whichever end it's numbered from
+    let history = file.entries.iter().filter(|entry| !entry.pinned).count();
+    let entry = file
+        .entries
+        .get(from.position(index, history))
+        .ok_or_else(|| format!("There is no item {}", index))?;
+    let mut lines = vec![format!(
+        "Text: {}",
+        entry.text.lines().collect::<Vec<_>>().join("\n  ")
+    )];
+    match (entry.copied_at, &entry.source_app) {
+        (Some(at), Some(app)) => {
+            lines.push(format!("Copied: {} from {}", format_timestamp(at), app))
+        }
+        (Some(at), None) => lines.push(format!("Copied: {}", format_timestamp(at))),
+        (None, Some(app)) => lines.push(format!("Copied from {}", app)),
+        (None, None) => {}
+    }
+    if let Some(document) = &entry.source_document {
+        lines.push(format!("Document: {}", document));
+    }
+    if let Some(label) = &entry.label {
+        lines.push(format!("Label: {}", label));
+    }
+    if entry.pinned {
+        lines.push("Pinned".to_owned());
+    }
+    lines.push("Formats:".to_owned());
+    for format in &entry.formats {
+        let size = base64::decode(&format.data).map_or(0, |data| data.len());
+        lines.push(format!("  {}\t{} bytes", format.name, size));
+    }
+    Ok(lines.join("\n"))
+}
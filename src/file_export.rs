@@ -0,0 +1,90 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use winapi::um::winuser;
+
+use crate::clipboard_extras::{read_dib, read_unicode_text, ClipboardItem, Dib};
+use crate::html;
+use crate::winapi_functions::register_clipboard_format;
+
+/// Pick the most useful representation of an entry, returning its file extension and contents
+fn primary_content(entry: &[ClipboardItem]) -> Option<(&'static str, Vec<u8>)> {
+    let find = |format| entry.iter().find(|item| item.format == format);
+    let find_named = |name| register_clipboard_format(name).ok().and_then(find);
+
+    if let Some(item) = find_named("PNG") {
+        return Some(("png", item.content.clone()));
+    }
+    if let Some(dib) = find(winuser::CF_DIB).and_then(read_dib) {
+        return Some(("bmp", dib_to_bmp(&dib)));
+    }
+    if let Some(item) = find_named(html::FORMAT_NAME) {
+        return Some(("html", html::document(&item.content).to_vec()));
+    }
+    if let Some(text) = find(winuser::CF_UNICODETEXT).and_then(read_unicode_text) {
+        return Some(("txt", text.into_bytes()));
+    }
+    find(winuser::CF_TEXT).map(|item| {
+        let text = item.content.split(|c| *c == 0).next().unwrap_or_default();
+        ("txt", text.to_vec())
+    })
+}
+
+/// Prefix a CF_DIB with the file header it needs to be a .bmp file
+fn dib_to_bmp(dib: &Dib) -> Vec<u8> {
+    let pixel_offset = 14 + dib.pixel_offset() as u32;
+    let file_size = 14 + dib.data.len() as u32;
+
+    let mut bmp = Vec::with_capacity(file_size as usize);
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&file_size.to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&pixel_offset.to_le_bytes());
+    bmp.extend_from_slice(dib.data);
+    bmp
+}
+
+/// Write the primary content of `entry` to a new file in `directory`, returning its path
+pub fn save_entry(entry: &[ClipboardItem], directory: &Path) -> io::Result<PathBuf> {
+    let (extension, contents) = primary_content(entry).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the item has no text, HTML or image to save",
+        )
+    })?;
+
+    fs::create_dir_all(directory)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let mut path = directory.join(format!("clipboard-{}.{}", timestamp, extension));
+    let mut suffix = 1;
+    while path.exists() {
+        path = directory.join(format!("clipboard-{}-{}.{}", timestamp, suffix, extension));
+        suffix += 1;
+    }
+
+    fs::write(&path, contents)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn bmp_header_for_32_bit_dib() {
+        let mut dib = vec![0u8; 40 + 4];
+        dib[..4].copy_from_slice(&40u32.to_le_bytes());
+        dib[14..16].copy_from_slice(&32u16.to_le_bytes());
+        let bmp = dib_to_bmp(&Dib::parse(&dib).unwrap());
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(u32::from_le_bytes(bmp[2..6].try_into().unwrap()), 58);
+        assert_eq!(u32::from_le_bytes(bmp[10..14].try_into().unwrap()), 54);
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Written next to the config file when the clipboard exits
+const FILE_NAME: &str = "formats.toml";
+/// How many times a format has to be pasted without being read before it's thought unused
+const MIN_OFFERS: u64 = 5;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(usage: &mut FormatUsage, app: &str, format: &str, read: bool) {
+        usage.record_offered(app, &[format.to_owned()]);
+        if read {
+            usage.record_read(app, format);
+        }
+    }
+
+    #[test]
+    fn unread_formats_are_unused() {
+        let mut usage = FormatUsage::default();
+        for _ in 0..MIN_OFFERS {
+            offer(&mut usage, "excel.exe", "Biff12", true);
+            offer(&mut usage, "notepad.exe", "Biff12", false);
+            offer(&mut usage, "notepad.exe", "PNG", false);
+        }
+        // Read by one program is enough to keep it
+        assert!(!usage.unused("Biff12"));
+        assert!(usage.unused("PNG"));
+        // Nothing is known about a format which was never offered
+        assert!(!usage.unused("CF_DIB"));
+    }
+
+    #[test]
+    fn too_few_offers_say_nothing() {
+        let mut usage = FormatUsage::default();
+        offer(&mut usage, "notepad.exe", "PNG", false);
+        assert!(!usage.unused("PNG"));
+    }
+
+    #[test]
+    fn ranked_by_share_read() {
+        let mut usage = FormatUsage::default();
+        offer(&mut usage, "word.exe", "PNG", true);
+        offer(&mut usage, "word.exe", "CF_DIB", true);
+        offer(&mut usage, "paint.exe", "CF_DIB", true);
+        offer(&mut usage, "paint.exe", "PNG", false);
+        offer(&mut usage, "paint.exe", "Biff12", false);
+        assert_eq!(usage.ranked(), ["CF_DIB", "PNG"]);
+    }
+
+    #[test]
+    fn read_formats_come_first() {
+        let mut usage = FormatUsage::default();
+        offer(&mut usage, "word.exe", "PNG", true);
+        let mut formats = [1, 2, 3];
+        usage.prioritize(&mut formats, |format| {
+            if format == 3 { "PNG" } else { "other" }.to_owned()
+        });
+        assert_eq!(formats, [3, 1, 2]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut usage = FormatUsage::default();
+        offer(&mut usage, "word.exe", "PNG", true);
+        let text = toml::to_string(&usage).unwrap();
+        assert_eq!(toml::from_str::<FormatUsage>(&text).unwrap(), usage);
+    }
+}
+
+/// How often a format was on the clipboard when something was pasted into a program, and how
+/// often the program read it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct FormatCount {
+    pub offered: u64,
+    pub read: u64,
+}
+
+/// Which of the formats only copied onto the clipboard when they're asked for each program has
+/// read when pasting, by format name, kept between runs so capture can favour the formats in use
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FormatUsage {
+    pub apps: BTreeMap<String, BTreeMap<String, FormatCount>>,
+}
+
+impl FormatUsage {
+    /// Load the counts from `path`. Missing or unreadable counts start again from nothing
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = toml::to_string(self).expect("The format usage to serialize");
+        fs::write(path, contents)
+    }
+
+    fn count(&mut self, app: &str, format: &str) -> &mut FormatCount {
+        self.apps
+            .entry(app.to_lowercase())
+            .or_default()
+            .entry(format.to_owned())
+            .or_default()
+    }
+
+    /// Note that `formats` were on the clipboard for a paste into `app`
+    pub fn record_offered(&mut self, app: &str, formats: &[String]) {
+        for format in formats {
+            self.count(app, format).offered += 1;
+        }
+    }
+
+    /// Note that `app` read `format` from the clipboard
+    pub fn record_read(&mut self, app: &str, format: &str) {
+        let count = self.count(app, format);
+        count.read += 1;
+        // A program can read a format it was offered before we started counting
+        count.offered = count.offered.max(count.read);
+    }
+
+    /// Every program's counts for `format` added together
+    fn total(&self, format: &str) -> FormatCount {
+        self.apps
+            .values()
+            .filter_map(|formats| formats.get(format))
+            .fold(FormatCount::default(), |total, count| FormatCount {
+                offered: total.offered + count.offered,
+                read: total.read + count.read,
+            })
+    }
+
+    /// Whether `format` has been pasted often enough to tell, and no program has ever read it
+    pub fn unused(&self, format: &str) -> bool {
+        let total = self.total(format);
+        total.offered >= MIN_OFFERS && total.read == 0
+    }
+
+    /// The formats which have been read, the most often read for each time offered first
+    pub fn ranked(&self) -> Vec<String> {
+        let names: BTreeSet<_> = self
+            .apps
+            .values()
+            .flat_map(|formats| formats.keys())
+            .collect();
+        let mut formats: Vec<_> = names
+            .into_iter()
+            .map(|format| (format.clone(), self.total(format)))
+            .filter(|(_, total)| total.read > 0)
+            .collect();
+        formats.sort_by(|(a_name, a), (b_name, b)| {
+            // Comparing a.read / a.offered with b.read / b.offered without dividing
+            (b.read * a.offered)
+                .cmp(&(a.read * b.offered))
+                .then_with(|| b.read.cmp(&a.read))
+                .then_with(|| a_name.cmp(b_name))
+        });
+        formats.into_iter().map(|(format, _)| format).collect()
+    }
+
+    /// Sort `formats` so the most read come first, leaving the others in their order
+    pub fn prioritize(&self, formats: &mut [u32], name: impl Fn(u32) -> String) {
+        let ranked = self.ranked();
+        // The sort is stable, so formats which have never been read keep their order
+        formats.sort_by_cached_key(|format| {
+            let name = name(*format);
+            ranked
+                .iter()
+                .position(|ranked| *ranked == name)
+                .unwrap_or(ranked.len())
+        });
+    }
+}
+
+/// Where the counts are kept, next to the config file
+pub fn usage_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(FILE_NAME)
+}
@@ -0,0 +1,256 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use winapi::um::winuser;
+
+use crate::winapi_functions::{get_clipboard_format_name_w, register_clipboard_format};
+
+/// The predefined formats, which can be named with or without the "CF_" prefix
+const STANDARD_FORMATS: &[(&str, u32)] = &[
+    ("CF_TEXT", winuser::CF_TEXT),
+    ("CF_BITMAP", winuser::CF_BITMAP),
+    ("CF_METAFILEPICT", winuser::CF_METAFILEPICT),
+    ("CF_SYLK", winuser::CF_SYLK),
+    ("CF_DIF", winuser::CF_DIF),
+    ("CF_TIFF", winuser::CF_TIFF),
+    ("CF_OEMTEXT", winuser::CF_OEMTEXT),
+    ("CF_DIB", winuser::CF_DIB),
+    ("CF_PALETTE", winuser::CF_PALETTE),
+    ("CF_PENDATA", winuser::CF_PENDATA),
+    ("CF_RIFF", winuser::CF_RIFF),
+    ("CF_WAVE", winuser::CF_WAVE),
+    ("CF_UNICODETEXT", winuser::CF_UNICODETEXT),
+    ("CF_ENHMETAFILE", winuser::CF_ENHMETAFILE),
+    ("CF_HDROP", winuser::CF_HDROP),
+    ("CF_LOCALE", winuser::CF_LOCALE),
+    ("CF_DIBV5", winuser::CF_DIBV5),
+];
+
+/// Short names for the formats which usually appear together
+const ALIASES: &[(&str, &[Format])] = &[
+    (
+        "text",
+        &[
+            Format::Standard(winuser::CF_TEXT),
+            Format::Standard(winuser::CF_OEMTEXT),
+            Format::Standard(winuser::CF_LOCALE),
+        ],
+    ),
+    ("unicode", &[Format::Standard(winuser::CF_UNICODETEXT)]),
+    ("html", &[Format::Registered("HTML Format")]),
+    ("rtf", &[Format::Registered("Rich Text Format")]),
+    (
+        "bitmap",
+        &[
+            Format::Standard(winuser::CF_BITMAP),
+            Format::Standard(winuser::CF_DIB),
+            Format::Standard(winuser::CF_DIBV5),
+        ],
+    ),
+    ("png", &[Format::Registered("PNG")]),
+    ("files", &[Format::Standard(winuser::CF_HDROP)]),
+];
+
+/// The formats every program understands, which are all that's pasted into `safe_restore_apps`
+const SAFE_FORMATS: &[&str] = &["unicode", "text", "html", "rtf", "CF_DIB"];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_names() {
+        assert_eq!(
+            builtin("CF_UNICODETEXT"),
+            Some(vec![Format::Standard(winuser::CF_UNICODETEXT)])
+        );
+        assert_eq!(
+            builtin("dib"),
+            Some(vec![Format::Standard(winuser::CF_DIB)])
+        );
+        assert_eq!(builtin("49161"), Some(vec![Format::Standard(49161)]));
+        assert_eq!(
+            builtin("HTML"),
+            Some(vec![Format::Registered("HTML Format")])
+        );
+        assert_eq!(builtin("bitmap").map(|formats| formats.len()), Some(3));
+        // Anything else is registered by name when it's resolved
+        assert_eq!(builtin("HTML Format"), None);
+        assert_eq!(builtin("0"), None);
+    }
+
+    #[test]
+    fn format_list_round_trip() {
+        let list: FormatList = "unicode, HTML Format,png".parse().unwrap();
+        assert_eq!(list.0, ["unicode", "HTML Format", "png"]);
+        assert_eq!(list.to_string(), "unicode,HTML Format,png");
+        assert!(",".parse::<FormatList>().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn filter_formats() {
+        assert!(FormatFilter::default().allows(49_000));
+        let filter = FormatFilter {
+            capture: Some(vec![winuser::CF_UNICODETEXT, 49_000]),
+            ignore: vec![49_000],
+        };
+        assert!(filter.allows(winuser::CF_UNICODETEXT));
+        assert!(!filter.allows(49_000));
+        assert!(!filter.allows(winuser::CF_DIB));
+    }
+}
+
+/// A format known without asking Windows: a predefined id, or a registered name
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Standard(u32),
+    Registered(&'static str),
+}
+
+/// The formats a name refers to if it's a predefined format, an alias or a number
+fn builtin(name: &str) -> Option<Vec<Format>> {
+    let name = name.trim();
+    if let Ok(id) = name.parse::<u32>() {
+        return Some(vec![Format::Standard(id)]).filter(|_| id != 0);
+    }
+    // Aliases come first so "text" and "bitmap" cover the related formats too
+    if let Some((_, formats)) = ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+    {
+        return Some(formats.to_vec());
+    }
+    let upper = name.to_ascii_uppercase();
+    STANDARD_FORMATS
+        .iter()
+        .find(|(standard, _)| {
+            *standard == upper || standard.strip_prefix("CF_") == Some(upper.as_str())
+        })
+        .map(|(_, id)| vec![Format::Standard(*id)])
+}
+
+/// Turn a format name into the ids it refers to. Names which aren't predefined formats, aliases
+/// or numbers are registered, as other programs do, so custom formats such as "HTML Format" get
+/// the same id here as in the program which wrote them
+pub fn resolve(name: &str) -> Result<Vec<u32>, String> {
+    let register = |name: &str| {
+        register_clipboard_format(name)
+            .map_err(|error| format!("could not register format \"{}\": {}", name, error))
+    };
+    match builtin(name) {
+        Some(formats) => formats
+            .into_iter()
+            .map(|format| match format {
+                Format::Standard(id) => Ok(id),
+                Format::Registered(name) => register(name),
+            })
+            .collect(),
+        None => register(name.trim()).map(|id| vec![id]),
+    }
+}
+
+/// The ids of `SAFE_FORMATS`. Registered formats which can't be looked up are left out
+pub fn safe_formats() -> Vec<u32> {
+    SAFE_FORMATS
+        .iter()
+        .filter_map(|name| resolve(name).ok())
+        .flatten()
+        .collect()
+}
+
+/// A name for the format which `resolve` turns back into it, even after a restart changes the ids of
+/// registered formats
+pub fn name(format: u32) -> String {
+    STANDARD_FORMATS
+        .iter()
+        .find(|(_, id)| *id == format)
+        .map(|(name, _)| (*name).to_owned())
+        .or_else(|| get_clipboard_format_name_w(format))
+        .unwrap_or_else(|| format.to_string())
+}
+
+/// A comma separated list of format names, as written in the settings. Names are kept as they
+/// were written, so the config stays readable and portable, and only turned into ids at startup
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FormatList(pub Vec<String>);
+
+impl FormatList {
+    /// The ids of every format in the list
+    pub fn resolve(&self) -> Result<Vec<u32>, String> {
+        let mut ids = Vec::new();
+        for name in self.0.iter() {
+            ids.extend(resolve(name)?);
+        }
+        Ok(ids)
+    }
+}
+
+impl FromStr for FormatList {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+}
+
+impl fmt::Display for FormatList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join(","))
+    }
+}
+
+impl TryFrom<String> for FormatList {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<FormatList> for String {
+    fn from(list: FormatList) -> Self {
+        list.to_string()
+    }
+}
+
+/// Which formats are recorded, from the capture_formats and ignore_formats settings
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FormatFilter {
+    /// Only these formats are recorded, if set
+    capture: Option<Vec<u32>>,
+    /// These formats are never recorded, even if they're in `capture`
+    ignore: Vec<u32>,
+}
+
+impl FormatFilter {
+    pub fn new(capture: Option<&FormatList>, ignore: Option<&FormatList>) -> Result<Self, String> {
+        Ok(Self {
+            capture: capture.map(FormatList::resolve).transpose()?,
+            ignore: ignore
+                .map(FormatList::resolve)
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Whether every format is recorded
+    pub fn is_empty(&self) -> bool {
+        self.capture.is_none() && self.ignore.is_empty()
+    }
+
+    pub fn allows(&self, format: u32) -> bool {
+        let captured = match &self.capture {
+            Some(capture) => capture.contains(&format),
+            None => true,
+        };
+        captured && !self.ignore.contains(&format)
+    }
+}
@@ -0,0 +1,331 @@
+use std::{collections::VecDeque, convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::ClipboardBackend;
+use crate::clipboard_extras::write_unicode_text;
+use crate::engine::{Capture, ClipboardEngine};
+use crate::entry::Entry;
+use crate::events::{Event, EventBus};
+
+pub const DEFAULT_MAX_HISTORY: usize = 50;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::backend::MemoryClipboard;
+    use crate::clipboard_extras::{ClipboardItem, CF_TEXT};
+    use crate::engine::Dedup;
+
+    fn texts(history: &HistoryHandle) -> Vec<String> {
+        history.iter().map(Entry::text).collect()
+    }
+
+    #[test]
+    fn push_and_pop_in_order() {
+        let mut history = HistoryHandle::new(10);
+        history.push_text("one");
+        history.push_text("two");
+        assert_eq!(texts(&history), ["two", "one"]);
+        assert_eq!(
+            history.pop().map(|entry| entry.text()),
+            Some("two".to_owned())
+        );
+
+        let mut queue = HistoryHandle::with_order(10, Order::Fifo);
+        queue.push_text("one");
+        queue.push_text("two");
+        assert_eq!(queue.peek().map(Entry::text), Some("one".to_owned()));
+        assert_eq!(
+            queue.pop().map(|entry| entry.text()),
+            Some("one".to_owned())
+        );
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn oldest_items_are_dropped() {
+        let mut history = HistoryHandle::new(2);
+        for text in &["one", "two", "three"] {
+            history.push_text(text);
+        }
+        assert_eq!(texts(&history), ["three", "two"]);
+    }
+
+    #[test]
+    fn subscribers_see_changes() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut history = HistoryHandle::new(10);
+        let events = Rc::clone(&seen);
+        history.subscribe(move |event| events.borrow_mut().push(event.clone()));
+        history.push_text("one");
+        history.pop();
+        history.clear();
+        assert_eq!(
+            *seen.borrow(),
+            [
+                Event::EntryPushed {
+                    depth: 1,
+                    preview: "one".to_owned(),
+                },
+                Event::EntryPopped {
+                    depth: 0,
+                    preview: String::new(),
+                },
+                Event::HistoryCleared,
+            ]
+        );
+    }
+
+    #[test]
+    fn capture_from_the_clipboard() {
+        let engine = ClipboardEngine::new(255, Dedup::Off);
+        let mut clipboard = MemoryClipboard::default();
+        let mut history = HistoryHandle::new(10);
+        assert_eq!(history.capture(&clipboard, &engine), Capture::Ignored);
+        for (text, capture) in &[
+            ("one", Capture::Pushed),
+            ("one", Capture::Duplicate),
+            // Trailing whitespace isn't compared
+            ("one ", Capture::Duplicate),
+            ("two", Capture::Pushed),
+        ] {
+            clipboard.set(&[write_unicode_text(text)]).unwrap();
+            assert_eq!(&history.capture(&clipboard, &engine), capture);
+        }
+        // The same text copied from a program which adds a format of its own
+        let other_program = ClipboardItem {
+            format: CF_TEXT,
+            content: b"two\0".to_vec(),
+        };
+        clipboard
+            .set(&[write_unicode_text("two"), other_program])
+            .unwrap();
+        assert_eq!(history.capture(&clipboard, &engine), Capture::Replaced);
+        assert_eq!(texts(&history), ["two", "one"]);
+    }
+
+    #[test]
+    fn pop_onto_the_clipboard() {
+        let engine = ClipboardEngine::new(255, Dedup::Off);
+        let mut clipboard = MemoryClipboard::default();
+        let mut history = HistoryHandle::new(10);
+        assert_eq!(history.pop_to(&mut clipboard).unwrap(), None);
+        history.push_text("one");
+        history.push_text("two");
+        let popped = history.pop_to(&mut clipboard).unwrap();
+        assert_eq!(popped.map(|entry| entry.text()), Some("two".to_owned()));
+        assert_eq!(
+            crate::clipboard_extras::read_text(&clipboard.read_all(|_| true)),
+            Some("two".to_owned())
+        );
+        // What was put there isn't recorded again when the clipboard reports the change
+        assert_eq!(history.capture(&clipboard, &engine), Capture::Duplicate);
+        assert_eq!(texts(&history), ["one"]);
+    }
+
+    #[test]
+    fn parse_order() {
+        assert_eq!("fifo".parse(), Ok(Order::Fifo));
+        assert!("lifo".parse::<Order>().is_err());
+        for order in [Order::Filo, Order::Fifo].iter() {
+            assert_eq!(order.to_string().parse(), Ok(*order));
+        }
+    }
+}
+
+/// Which end of the history the paste hotkey takes items from
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Order {
+    /// The newest item first, like a stack
+    #[default]
+    Filo,
+    /// The oldest item first, like a queue, so a list copied in order is pasted in the same order
+    Fifo,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "filo" => Ok(Order::Filo),
+            "fifo" => Ok(Order::Fifo),
+            _ => Err(format!(
+                "expected \"filo\" or \"fifo\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Order::Filo => write!(f, "filo"),
+            Order::Fifo => write!(f, "fifo"),
+        }
+    }
+}
+
+impl TryFrom<String> for Order {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Order> for String {
+    fn from(order: Order) -> Self {
+        order.to_string()
+    }
+}
+
+/// The position of the item pasted next in a history of `len` items
+pub(crate) fn next_index(len: usize, order: Order) -> usize {
+    match order {
+        Order::Filo => 0,
+        Order::Fifo => len.saturating_sub(1),
+    }
+}
+
+/// Take the item pasted next out of `entries`
+pub(crate) fn take_next(entries: &mut VecDeque<Entry>, order: Order) -> Option<Entry> {
+    match order {
+        Order::Filo => entries.pop_front(),
+        Order::Fifo => entries.pop_back(),
+    }
+}
+
+/// A clipboard history which isn't tied to the clipboard or a message window, for programs which
+/// embed the FILO engine. New items go on the front, the oldest are dropped beyond `max_history`,
+/// and `pop` takes the newest or, in FIFO order, the oldest. Subscribers are told of every change
+/// with the same events the clipboard emits
+pub struct HistoryHandle {
+    entries: VecDeque<Entry>,
+    max_history: usize,
+    order: Order,
+    events: EventBus,
+    /// The item `pop_to` last put on the clipboard, which isn't recorded again
+    restored: Option<Entry>,
+}
+
+impl HistoryHandle {
+    /// An empty history which pops the newest item first
+    pub fn new(max_history: usize) -> Self {
+        Self::with_order(max_history, Order::Filo)
+    }
+
+    pub fn with_order(max_history: usize, order: Order) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            max_history,
+            order,
+            events: EventBus::default(),
+            restored: None,
+        }
+    }
+
+    /// The items from the front, which is the newest, to the back
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The item `pop` would take, without taking it
+    pub fn peek(&self) -> Option<&Entry> {
+        self.entries.get(next_index(self.entries.len(), self.order))
+    }
+
+    /// Add `entry` to the front, dropping the oldest items beyond the maximum
+    pub fn push(&mut self, entry: Entry) {
+        self.entries.push_front(entry);
+        self.entries.truncate(self.max_history.max(1));
+        self.emit_change(|depth, preview| Event::EntryPushed { depth, preview });
+    }
+
+    /// Add `text` to the front as though it had been copied
+    pub fn push_text(&mut self, text: &str) {
+        self.push(Entry::new(vec![write_unicode_text(text)]));
+    }
+
+    /// Take the next item out of the history
+    pub fn pop(&mut self) -> Option<Entry> {
+        let popped = take_next(&mut self.entries, self.order)?;
+        self.emit_change(|depth, preview| Event::EntryPopped { depth, preview });
+        Some(popped)
+    }
+
+    /// Record what's on `clipboard` as a copy, the way the clipboard history does when it changes
+    pub fn capture(
+        &mut self,
+        clipboard: &impl ClipboardBackend,
+        engine: &ClipboardEngine,
+    ) -> Capture {
+        let entry = Entry::new(clipboard.read_all(|_| true));
+        if entry.is_empty() {
+            return Capture::Ignored;
+        }
+        let decision = engine.decide(&entry, self.restored.as_ref(), &self.entries);
+        let capture = engine.apply(decision, entry, &mut self.entries);
+        match capture {
+            Capture::Pushed => {
+                self.entries.truncate(self.max_history.max(1));
+                self.restored = None;
+                self.emit_change(|depth, preview| Event::EntryPushed { depth, preview });
+            }
+            Capture::Replaced => {
+                self.restored = None;
+                self.emit_change(|depth, preview| Event::EntryUpdated { depth, preview });
+            }
+            Capture::Duplicate | Capture::Ignored => {}
+        }
+        capture
+    }
+
+    /// Take the next item out of the history and put it on `clipboard`, ready to be pasted. If it
+    /// can't be put there it stays in the history
+    pub fn pop_to(
+        &mut self,
+        clipboard: &mut impl ClipboardBackend,
+    ) -> Result<Option<Entry>, String> {
+        let next = match self.peek() {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        clipboard.set(&next.items)?;
+        self.restored = Some(next.clone());
+        Ok(self.pop())
+    }
+
+    /// Remove every item, wiping their contents from memory
+    pub fn clear(&mut self) {
+        for mut entry in self.entries.drain(..) {
+            entry.wipe();
+        }
+        self.events.emit(Event::HistoryCleared);
+    }
+
+    /// Call `subscriber` whenever the history changes
+    pub fn subscribe(&mut self, subscriber: impl FnMut(&Event) + 'static) {
+        self.events.subscribe(subscriber);
+    }
+
+    /// Emit the event made from the depth and the preview of the next item
+    fn emit_change(&mut self, event: impl FnOnce(usize, String) -> Event) {
+        let preview = self.peek().map(Entry::preview).unwrap_or_default();
+        self.events.emit(event(self.entries.len(), preview));
+    }
+}
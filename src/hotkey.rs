@@ -0,0 +1,770 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use winapi::shared::windef::HWND__;
+use winapi::um::winuser;
+
+use crate::key_utils::PasteKeys;
+use crate::winapi_functions::{register_hotkey, unregister_hotkey, SystemError};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_held(_: u16) -> bool {
+        true
+    }
+
+    #[test]
+    fn parse_hotkey() {
+        assert_eq!(
+            "ctrl+alt+v".parse(),
+            Ok(Hotkey {
+                modifiers: (winuser::MOD_CONTROL | winuser::MOD_ALT) as u32,
+                key: 'V' as u16,
+            })
+        );
+        assert_eq!(
+            " Win + Shift + F12 ".parse(),
+            Ok(Hotkey {
+                modifiers: (winuser::MOD_WIN | winuser::MOD_SHIFT) as u32,
+                key: winuser::VK_F12 as u16,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_hotkeys() {
+        assert!("v".parse::<Hotkey>().is_err());
+        assert!("ctrl+shift".parse::<Hotkey>().is_err());
+        assert!("ctrl+v+b".parse::<Hotkey>().is_err());
+        assert!("ctrl+ctrl+v".parse::<Hotkey>().is_err());
+        assert!("ctrl+f25".parse::<Hotkey>().is_err());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let hotkey: Hotkey = "win+shift+insert".parse().unwrap();
+        assert_eq!(hotkey.to_string(), "shift+win+insert");
+        assert_eq!(Hotkey::default().to_string(), "ctrl+shift+v");
+        assert_eq!(hotkey.to_string().parse(), Ok(hotkey));
+    }
+
+    #[test]
+    fn default_paste_sequence() {
+        // The sequence Ctrl+Shift+V has always sent
+        let (keys, events) = Hotkey::default().paste_sequence(PasteKeys::CtrlV, all_held);
+        assert_eq!(
+            keys,
+            [
+                winuser::VK_SHIFT as u16,
+                winuser::VK_CONTROL as u16,
+                'V' as u16,
+                winuser::VK_CONTROL as u16,
+                'V' as u16,
+                winuser::VK_SHIFT as u16,
+            ]
+        );
+        assert_eq!(
+            events,
+            [
+                winuser::KEYEVENTF_KEYUP,
+                winuser::KEYEVENTF_KEYUP,
+                winuser::KEYEVENTF_KEYUP,
+                0,
+                0,
+                0,
+            ]
+        );
+    }
+
+    #[test]
+    fn other_paste_sequence_releases_v() {
+        let hotkey: Hotkey = "alt+b".parse().unwrap();
+        let (keys, events) = hotkey.paste_sequence(PasteKeys::CtrlV, all_held);
+        assert_eq!(
+            keys,
+            [
+                winuser::VK_MENU as u16,
+                'B' as u16,
+                winuser::VK_CONTROL as u16,
+                'V' as u16,
+                'V' as u16,
+                winuser::VK_CONTROL as u16,
+                winuser::VK_MENU as u16,
+            ]
+        );
+        assert_eq!(
+            events,
+            [
+                winuser::KEYEVENTF_KEYUP,
+                winuser::KEYEVENTF_KEYUP,
+                0,
+                0,
+                winuser::KEYEVENTF_KEYUP,
+                winuser::KEYEVENTF_KEYUP,
+                0,
+            ]
+        );
+    }
+
+    #[test]
+    fn terminal_paste_sequence() {
+        let (keys, events) = Hotkey::default().paste_sequence(PasteKeys::ShiftInsert, all_held);
+        assert_eq!(
+            keys,
+            [
+                winuser::VK_SHIFT as u16,
+                winuser::VK_CONTROL as u16,
+                'V' as u16,
+                winuser::VK_SHIFT as u16,
+                winuser::VK_INSERT as u16,
+                winuser::VK_INSERT as u16,
+                winuser::VK_CONTROL as u16,
+            ]
+        );
+        assert_eq!(
+            events,
+            [
+                winuser::KEYEVENTF_KEYUP,
+                winuser::KEYEVENTF_KEYUP,
+                winuser::KEYEVENTF_KEYUP,
+                0,
+                0,
+                winuser::KEYEVENTF_KEYUP,
+                0,
+            ]
+        );
+    }
+
+    #[test]
+    fn released_keys_are_left_alone() {
+        // Shift and V were let go before the hotkey was handled, so only Ctrl is released, and V is
+        // released after pasting rather than left down
+        let held = |key| key == winuser::VK_CONTROL as u16;
+        let (keys, events) = Hotkey::default().paste_sequence(PasteKeys::CtrlV, held);
+        assert_eq!(
+            keys,
+            [
+                winuser::VK_CONTROL as u16,
+                winuser::VK_CONTROL as u16,
+                'V' as u16,
+                'V' as u16,
+            ]
+        );
+        assert_eq!(
+            events,
+            [winuser::KEYEVENTF_KEYUP, 0, 0, winuser::KEYEVENTF_KEYUP,]
+        );
+        let (keys, _) = Hotkey::default().hold_sequence(held);
+        assert_eq!(keys, [winuser::VK_CONTROL as u16]);
+        // Ctrl is pressed for the paste then let go again, as it's no longer held
+        let (keys, events) = Hotkey::default().paste_sequence(PasteKeys::CtrlV, |_| false);
+        assert_eq!(
+            keys,
+            [
+                winuser::VK_CONTROL as u16,
+                'V' as u16,
+                'V' as u16,
+                winuser::VK_CONTROL as u16,
+            ]
+        );
+        assert_eq!(
+            events,
+            [0, 0, winuser::KEYEVENTF_KEYUP, winuser::KEYEVENTF_KEYUP,]
+        );
+    }
+
+    #[test]
+    fn keystroke_sequence() {
+        let keystroke: Keystroke = "shift+tab".parse().unwrap();
+        assert_eq!(keystroke.to_string(), "shift+tab");
+        assert_eq!("tab".parse(), Ok(Keystroke::tab()));
+        assert!("shift".parse::<Keystroke>().is_err());
+        let (keys, events) = keystroke.press_sequence();
+        assert_eq!(
+            keys,
+            [
+                winuser::VK_SHIFT as u16,
+                winuser::VK_TAB as u16,
+                winuser::VK_TAB as u16,
+                winuser::VK_SHIFT as u16,
+            ]
+        );
+        assert_eq!(
+            events,
+            [0, 0, winuser::KEYEVENTF_KEYUP, winuser::KEYEVENTF_KEYUP]
+        );
+    }
+
+    /// The lParam of a WM_HOTKEY message for `hotkey`
+    fn l_param(hotkey: Hotkey) -> isize {
+        ((hotkey.key as isize) << 16) | hotkey.modifiers as isize
+    }
+
+    #[test]
+    fn registry_checks_ids_and_keys() {
+        let paste = Hotkey::default();
+        let peek = Hotkey::default_peek();
+        let mut registry = HotkeyRegistry::default();
+        let paste_id = registry.add(HotkeyAction::Paste, paste);
+        let peek_id = registry.add(HotkeyAction::Peek, peek);
+        assert_ne!(paste_id, peek_id);
+        assert_eq!(
+            registry.action(paste_id, l_param(paste)),
+            Some(HotkeyAction::Paste)
+        );
+        assert_eq!(
+            registry.action(peek_id, l_param(peek)),
+            Some(HotkeyAction::Peek)
+        );
+        // A message with one of our ids but someone else's keys isn't ours
+        assert_eq!(registry.action(paste_id, l_param(peek)), None);
+        assert_eq!(registry.action(99, l_param(paste)), None);
+        registry.remove(peek_id);
+        assert_eq!(registry.action(peek_id, l_param(peek)), None);
+    }
+
+    #[test]
+    fn tap_key_on_its_own() {
+        let keystroke: Keystroke = "capslock".parse().unwrap();
+        let tap = keystroke.as_hotkey();
+        assert_eq!(tap.to_string(), "capslock");
+        let mut registry = HotkeyRegistry::default();
+        let tap_id = registry.add(HotkeyAction::Tap, tap);
+        // WM_HOTKEY doesn't report MOD_NOREPEAT
+        assert_eq!(
+            registry.action(tap_id, (winuser::VK_CAPITAL as isize) << 16),
+            Some(HotkeyAction::Tap)
+        );
+    }
+}
+
+/// The modifier flags which WM_HOTKEY reports in the low word of lParam
+const REPORTED_MODIFIERS: u32 =
+    (winuser::MOD_ALT | winuser::MOD_CONTROL | winuser::MOD_SHIFT | winuser::MOD_WIN) as u32;
+
+/// Modifier names, their RegisterHotKey flag and the key which has to be released to clear them,
+/// in the order they're released
+const MODIFIERS: [(&str, isize, i32); 4] = [
+    ("shift", winuser::MOD_SHIFT, winuser::VK_SHIFT),
+    ("ctrl", winuser::MOD_CONTROL, winuser::VK_CONTROL),
+    ("alt", winuser::MOD_ALT, winuser::VK_MENU),
+    ("win", winuser::MOD_WIN, winuser::VK_LWIN),
+];
+
+/// Named keys which aren't a single letter, digit or function key
+const NAMED_KEYS: [(&str, i32); 15] = [
+    ("space", winuser::VK_SPACE),
+    ("tab", winuser::VK_TAB),
+    ("enter", winuser::VK_RETURN),
+    ("backspace", winuser::VK_BACK),
+    ("insert", winuser::VK_INSERT),
+    ("delete", winuser::VK_DELETE),
+    ("home", winuser::VK_HOME),
+    ("end", winuser::VK_END),
+    ("pageup", winuser::VK_PRIOR),
+    ("pagedown", winuser::VK_NEXT),
+    ("up", winuser::VK_UP),
+    ("down", winuser::VK_DOWN),
+    ("capslock", winuser::VK_CAPITAL),
+    ("scrolllock", winuser::VK_SCROLL),
+    ("pause", winuser::VK_PAUSE),
+];
+
+/// A key combination such as "ctrl+shift+v": one or more modifiers and a single key
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Hotkey {
+    /// MOD_* flags, as passed to RegisterHotKey
+    pub modifiers: u32,
+    /// The virtual key code
+    pub key: u16,
+}
+
+impl Default for Hotkey {
+    fn default() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_SHIFT) as u32,
+            key: 'V' as u16,
+        }
+    }
+}
+
+impl Hotkey {
+    /// The default hotkey for pasting the next item without removing it
+    pub fn default_peek() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_SHIFT) as u32,
+            key: 'C' as u16,
+        }
+    }
+
+    /// The default hotkey for pasting and popping the next item as plain text
+    pub fn default_plain_paste() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_ALT | winuser::MOD_SHIFT) as u32,
+            key: 'V' as u16,
+        }
+    }
+
+    /// The default hotkey for putting the last popped item back
+    pub fn default_undo() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_SHIFT) as u32,
+            key: 'Z' as u16,
+        }
+    }
+
+    /// The default hotkey for turning the history to the next item
+    pub fn default_next() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_SHIFT) as u32,
+            key: winuser::VK_NEXT as u16,
+        }
+    }
+
+    /// The default hotkey for turning the history back to the previous item
+    pub fn default_previous() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_SHIFT) as u32,
+            key: winuser::VK_PRIOR as u16,
+        }
+    }
+
+    /// The default hotkey for pasting the pinned items in turn
+    pub fn default_pinned() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_ALT | winuser::MOD_SHIFT) as u32,
+            key: 'P' as u16,
+        }
+    }
+
+    /// The default hotkey for searching the history
+    pub fn default_search() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_ALT | winuser::MOD_SHIFT) as u32,
+            key: 'F' as u16,
+        }
+    }
+
+    /// The default hotkey for choosing a snippet to paste
+    pub fn default_snippet() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_ALT | winuser::MOD_SHIFT) as u32,
+            key: 'S' as u16,
+        }
+    }
+
+    /// The default hotkey for switching to the next stack
+    pub fn default_stack() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_ALT | winuser::MOD_SHIFT) as u32,
+            key: 'N' as u16,
+        }
+    }
+
+    /// The default hotkey for pasting the oldest item and moving to the next field
+    pub fn default_queue() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_ALT | winuser::MOD_SHIFT) as u32,
+            key: 'Q' as u16,
+        }
+    }
+
+    /// The default hotkey for opening the history picker
+    pub fn default_picker() -> Self {
+        Self {
+            modifiers: (winuser::MOD_CONTROL | winuser::MOD_SHIFT) as u32,
+            key: 'H' as u16,
+        }
+    }
+
+    /// The virtual key codes of the modifiers in this hotkey
+    fn modifier_keys(&self) -> impl Iterator<Item = u16> + '_ {
+        MODIFIERS
+            .iter()
+            .filter(move |(_, flag, _)| self.modifiers & *flag as u32 != 0)
+            .map(|(_, _, key)| *key as u16)
+    }
+
+    /// The keys of the hotkey which `is_pressed` says are still down. The user may well have let go
+    /// of some by the time the hotkey is handled
+    fn held_keys<'a>(
+        &'a self,
+        is_pressed: &'a impl Fn(u16) -> bool,
+    ) -> impl Iterator<Item = u16> + 'a {
+        self.modifier_keys()
+            .chain(std::iter::once(self.key))
+            .filter(move |key| is_pressed(*key))
+    }
+
+    /// The keys and events which release the parts of the hotkey that are still held
+    pub fn release_sequence(&self, is_pressed: impl Fn(u16) -> bool) -> (Vec<u16>, Vec<u32>) {
+        let keys: Vec<_> = self.held_keys(&is_pressed).collect();
+        let events = vec![winuser::KEYEVENTF_KEYUP; keys.len()];
+        (keys, events)
+    }
+
+    /// The keys and events which press the hotkey's modifiers which are still held again after it's
+    /// been released
+    pub fn hold_sequence(&self, is_pressed: impl Fn(u16) -> bool) -> (Vec<u16>, Vec<u32>) {
+        let keys: Vec<_> = self
+            .modifier_keys()
+            .filter(|key| is_pressed(*key))
+            .collect();
+        let events = vec![0; keys.len()];
+        (keys, events)
+    }
+
+    /// The keys and events which release the hotkey, send `paste`, then press the modifiers again so
+    /// the keyboard is left in the state the user expects while they're still holding them. Only the
+    /// keys `is_pressed` says are down are released and pressed again, so none are left stuck
+    pub fn paste_sequence(
+        &self,
+        paste: PasteKeys,
+        is_pressed: impl Fn(u16) -> bool,
+    ) -> (Vec<u16>, Vec<u32>) {
+        let (paste_modifiers, paste_key) = paste.keys();
+        let held: Vec<_> = self
+            .modifier_keys()
+            .filter(|key| is_pressed(*key))
+            .collect();
+        let key_held = is_pressed(self.key);
+        let (mut keys, mut events) = self.release_sequence(&is_pressed);
+        let mut send = |key: i32, event: u32| {
+            keys.push(key as u16);
+            events.push(event);
+        };
+
+        for modifier in paste_modifiers {
+            send(*modifier, 0);
+        }
+        send(paste_key, 0);
+        // The key is only held down afterwards if it's part of the hotkey and still pressed
+        if self.key != paste_key as u16 || !key_held {
+            send(paste_key, winuser::KEYEVENTF_KEYUP);
+        }
+        for modifier in paste_modifiers {
+            if !held.contains(&(*modifier as u16)) {
+                send(*modifier, winuser::KEYEVENTF_KEYUP);
+            }
+        }
+        for key in held {
+            if !paste_modifiers.contains(&(key as i32)) {
+                send(key as i32, 0);
+            }
+        }
+        (keys, events)
+    }
+}
+
+fn parse_key(name: &str) -> Option<u16> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_alphanumeric() {
+            return Some(c.to_ascii_uppercase() as u16);
+        }
+    }
+    if let Some(Ok(number)) = name.strip_prefix('f').map(str::parse::<i32>) {
+        if (1..=24).contains(&number) {
+            return Some((winuser::VK_F1 + number - 1) as u16);
+        }
+    }
+    NAMED_KEYS
+        .iter()
+        .find(|(key_name, _)| *key_name == name)
+        .map(|(_, key)| *key as u16)
+}
+
+fn key_name(key: u16) -> String {
+    let code = key as i32;
+    if (winuser::VK_F1..=winuser::VK_F24).contains(&code) {
+        format!("f{}", code - winuser::VK_F1 + 1)
+    } else if let Some((name, _)) = NAMED_KEYS.iter().find(|(_, named)| *named == code) {
+        name.to_string()
+    } else {
+        (key as u8 as char).to_ascii_lowercase().to_string()
+    }
+}
+
+/// Parse the MOD_* flags and key of a combination such as "ctrl+shift+v", if it has a key
+fn parse_combination(value: &str) -> Result<(u32, Option<u16>), String> {
+    let mut modifiers = 0;
+    let mut key = None;
+    for part in value.split('+') {
+        let part = part.trim().to_ascii_lowercase();
+        let part = match part.as_str() {
+            "control" => "ctrl",
+            "windows" => "win",
+            other => other,
+        };
+        if let Some((_, flag, _)) = MODIFIERS.iter().find(|(name, _, _)| *name == part) {
+            if modifiers & *flag as u32 != 0 {
+                return Err(format!("\"{}\" appears twice in \"{}\"", part, value));
+            }
+            modifiers |= *flag as u32;
+        } else if key.is_some() {
+            return Err(format!("\"{}\" has more than one key", value));
+        } else {
+            key = Some(parse_key(part).ok_or_else(|| format!("unknown key \"{}\"", part))?);
+        }
+    }
+    Ok((modifiers, key))
+}
+
+/// Write a combination in the usual order, rather than the order it's released in
+fn write_combination(f: &mut fmt::Formatter<'_>, modifiers: u32, key: u16) -> fmt::Result {
+    for name in ["ctrl", "alt", "shift", "win"].iter() {
+        let (_, flag, _) = MODIFIERS
+            .iter()
+            .find(|(modifier, _, _)| modifier == name)
+            .unwrap();
+        if modifiers & *flag as u32 != 0 {
+            write!(f, "{}+", name)?;
+        }
+    }
+    write!(f, "{}", key_name(key))
+}
+
+impl FromStr for Hotkey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match parse_combination(value)? {
+            (modifiers, Some(key)) if modifiers != 0 => Ok(Self { modifiers, key }),
+            _ => Err(format!(
+                "expected modifiers and a key such as \"ctrl+shift+v\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Hotkey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_combination(f, self.modifiers, self.key)
+    }
+}
+
+impl TryFrom<String> for Hotkey {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Hotkey> for String {
+    fn from(hotkey: Hotkey) -> Self {
+        hotkey.to_string()
+    }
+}
+
+/// A key to press, with any modifiers, such as "tab" or "shift+tab"
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Keystroke {
+    /// MOD_* flags for the modifiers held while the key is pressed
+    pub modifiers: u32,
+    /// The virtual key code
+    pub key: u16,
+}
+
+impl Keystroke {
+    /// Tab, which moves to the next field of most forms
+    pub fn tab() -> Self {
+        Self {
+            modifiers: 0,
+            key: winuser::VK_TAB as u16,
+        }
+    }
+
+    /// The keystroke as a hotkey, which unlike a typed in one may be a key on its own. Holding it
+    /// down only reports it once
+    pub fn as_hotkey(&self) -> Hotkey {
+        Hotkey {
+            modifiers: self.modifiers | winuser::MOD_NOREPEAT as u32,
+            key: self.key,
+        }
+    }
+
+    /// The keys and events which press and release the key, holding its modifiers around it
+    pub fn press_sequence(&self) -> (Vec<u16>, Vec<u32>) {
+        let modifiers: Vec<_> = MODIFIERS
+            .iter()
+            .filter(|(_, flag, _)| self.modifiers & *flag as u32 != 0)
+            .map(|(_, _, key)| *key as u16)
+            .collect();
+        let keys: Vec<_> = modifiers
+            .iter()
+            .chain([self.key, self.key].iter())
+            .chain(modifiers.iter().rev())
+            .copied()
+            .collect();
+        let mut events = vec![0; modifiers.len() + 1];
+        events.resize(keys.len(), winuser::KEYEVENTF_KEYUP);
+        (keys, events)
+    }
+}
+
+impl FromStr for Keystroke {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match parse_combination(value)? {
+            (modifiers, Some(key)) => Ok(Self { modifiers, key }),
+            _ => Err(format!(
+                "expected a key such as \"tab\" or \"shift+tab\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Keystroke {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_combination(f, self.modifiers, self.key)
+    }
+}
+
+impl TryFrom<String> for Keystroke {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Keystroke> for String {
+    fn from(keystroke: Keystroke) -> Self {
+        keystroke.to_string()
+    }
+}
+
+/// Something a hotkey does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HotkeyAction {
+    Paste,
+    Peek,
+    Picker,
+    PlainPaste,
+    UndoPop,
+    Next,
+    Previous,
+    Pinned,
+    Search,
+    Snippet,
+    NextStack,
+    Queue,
+    Tap,
+    Recapture,
+}
+
+struct Binding {
+    id: i32,
+    action: HotkeyAction,
+    hotkey: Hotkey,
+}
+
+/// The hotkeys registered for the message window, so WM_HOTKEY is only acted on when both its id
+/// and its keys are ones we registered
+#[derive(Default)]
+pub struct HotkeyRegistry {
+    bindings: Vec<Binding>,
+    next_id: i32,
+    /// Whether the bindings are kept but not registered with Windows
+    suspended: bool,
+}
+
+impl HotkeyRegistry {
+    /// Record a binding without registering it with Windows, returning its id
+    fn add(&mut self, action: HotkeyAction, hotkey: Hotkey) -> i32 {
+        // Ids start at 1, as 0 is easily sent by mistake
+        self.next_id += 1;
+        self.bindings.push(Binding {
+            id: self.next_id,
+            action,
+            hotkey,
+        });
+        self.next_id
+    }
+
+    fn remove(&mut self, id: i32) {
+        self.bindings.retain(|binding| binding.id != id);
+    }
+
+    /// Register `hotkey` with Windows to trigger `action`
+    pub fn register(
+        &mut self,
+        h_wnd: &mut HWND__,
+        action: HotkeyAction,
+        hotkey: Hotkey,
+    ) -> Result<(), SystemError> {
+        let id = self.add(action, hotkey);
+        let result = register_hotkey(h_wnd, id, hotkey.modifiers, hotkey.key as u32);
+        if result.is_err() {
+            self.remove(id);
+        }
+        result
+    }
+
+    /// Unregister every hotkey
+    pub fn unregister_all(&mut self, h_wnd: &mut HWND__) {
+        for binding in self.bindings.drain(..) {
+            let _ = unregister_hotkey(h_wnd, binding.id);
+        }
+        self.suspended = false;
+    }
+
+    /// Unregister every hotkey with Windows so their keys reach other programs, keeping the
+    /// bindings for `resume`
+    pub fn suspend(&mut self, h_wnd: &mut HWND__) {
+        if self.suspended {
+            return;
+        }
+        for binding in &self.bindings {
+            let _ = unregister_hotkey(h_wnd, binding.id);
+        }
+        self.suspended = true;
+    }
+
+    /// Register the hotkeys again after `suspend`, dropping and returning any which another
+    /// program took in the meantime
+    pub fn resume(&mut self, h_wnd: &mut HWND__) -> Vec<(Hotkey, SystemError)> {
+        let mut failures = Vec::new();
+        if !self.suspended {
+            return failures;
+        }
+        self.bindings.retain(|binding| {
+            let hotkey = binding.hotkey;
+            match register_hotkey(h_wnd, binding.id, hotkey.modifiers, hotkey.key as u32) {
+                Ok(()) => true,
+                Err(error) => {
+                    failures.push((hotkey, error));
+                    false
+                }
+            }
+        });
+        self.suspended = false;
+        failures
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// The action for a WM_HOTKEY message, if it's for one of our hotkeys
+    pub fn action(&self, id: i32, l_param: isize) -> Option<HotkeyAction> {
+        let modifiers = (l_param & 0xffff) as u32;
+        let key = ((l_param >> 16) & 0xffff) as u16;
+        self.bindings
+            .iter()
+            .find(|binding| {
+                binding.id == id
+                    && binding.hotkey.key == key
+                    && binding.hotkey.modifiers & REPORTED_MODIFIERS == modifiers
+            })
+            .map(|binding| binding.action)
+    }
+}
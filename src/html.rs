@@ -0,0 +1,308 @@
+use std::ops::Range;
+
+use crate::clipboard_extras::{write_unicode_text, ClipboardItem};
+
+/// The registered format browsers and Office copy rich content in
+pub const FORMAT_NAME: &str = "HTML Format";
+
+const START_MARKER: &[u8] = b"<!--StartFragment-->";
+const END_MARKER: &[u8] = b"<!--EndFragment-->";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CF_HTML item whose offsets count characters rather than bytes, as some programs write them
+    fn miscounted() -> Vec<u8> {
+        let html = "<html><body><!--StartFragment--><b>café</b><!--EndFragment--></body></html>";
+        let header = "Version:0.9\r\nStartHTML:0000000055\r\nEndHTML:0000000130\r\n\
+                      SourceURL:https://example.com/\r\n";
+        let mut data = format!("{}{}", header, html).into_bytes();
+        data.extend_from_slice(&[0, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn document_uses_offsets() {
+        let header = "Version:0.9\r\nStartHTML:0000000055\r\nEndHTML:0000000068\r\n";
+        let data = format!("{}<html></html>trailing", header);
+        assert_eq!(document(data.as_bytes()), b"<html></html>");
+        assert_eq!(document(b"<b>no header</b>"), b"<b>no header</b>");
+    }
+
+    #[test]
+    fn normalize_rewrites_offsets() {
+        let fixed = normalize(&miscounted()).unwrap();
+        let parsed = parse(&fixed).unwrap();
+        assert!(parsed.document.starts_with(b"<html>"));
+        assert!(parsed.document.ends_with(b"</html>"));
+        assert_eq!(&parsed.document[parsed.fragment], "<b>café</b>".as_bytes());
+        assert_eq!(parsed.source_url, Some("https://example.com/"));
+        assert_eq!(fixed.last(), Some(&0));
+        // Already correct offsets are left alone
+        assert_eq!(normalize(&fixed), None);
+        assert_eq!(normalize(b"not html"), None);
+    }
+
+    #[test]
+    fn html_to_text() {
+        let html = "Version:0.9\r\nStartHTML:0000000055\r\nEndHTML:0000000000\r\n<html><body>\
+                    <style>p { color: red }</style><p>Fish &amp; chips</p><ul><li>one</li>\
+                    <li>two&nbsp;<i>three</i></li></ul><table><tr><td>a</td><td>b</td></tr></table>\
+                    </body></html>";
+        assert_eq!(
+            to_text(html.as_bytes()),
+            "Fish & chips\r\none\r\ntwo three\r\na\tb"
+        );
+    }
+}
+
+/// The parts of a CF_HTML item: a description header followed by an HTML document, part of which
+/// is the fragment that was actually copied
+#[derive(Debug)]
+struct CfHtml<'a> {
+    document: &'a [u8],
+    /// Where the fragment is in `document`
+    fragment: Range<usize>,
+    source_url: Option<&'a str>,
+}
+
+/// Split a CF_HTML item into its parts, or None if it doesn't start with a description header.
+/// Programs often count the offsets in characters rather than bytes or pad the item with nulls, so
+/// offsets which don't land on a tag are ignored in favour of the tags themselves
+fn parse(data: &[u8]) -> Option<CfHtml<'_>> {
+    if !data.starts_with(b"Version:") {
+        return None;
+    }
+    let header_end = data.iter().position(|byte| *byte == b'<')?;
+    let header = std::str::from_utf8(&data[..header_end]).ok()?;
+    let field = |key: &str| {
+        header.lines().find_map(|line| {
+            line.strip_prefix(key)
+                .and_then(|line| line.strip_prefix(':'))
+                .map(str::trim)
+        })
+    };
+    let offset = |key: &str| field(key).and_then(|value| value.parse::<usize>().ok());
+
+    let start = offset("StartHTML")
+        .filter(|start| *start >= header_end && data.get(*start) == Some(&b'<'))
+        .unwrap_or(header_end);
+    let end = offset("EndHTML")
+        .filter(|end| *end > start && data.get(end - 1) == Some(&b'>'))
+        .unwrap_or_else(|| {
+            data.iter()
+                .rposition(|byte| *byte != 0)
+                .map_or(start, |last| last + 1)
+        });
+    let document = data.get(start..end)?;
+
+    let fragment = match (find(document, START_MARKER), find(document, END_MARKER)) {
+        (Some(fragment_start), Some(fragment_end))
+            if fragment_start + START_MARKER.len() <= fragment_end =>
+        {
+            fragment_start + START_MARKER.len()..fragment_end
+        }
+        _ => match (offset("StartFragment"), offset("EndFragment")) {
+            (Some(fragment_start), Some(fragment_end))
+                if start <= fragment_start
+                    && fragment_start <= fragment_end
+                    && fragment_end <= end =>
+            {
+                fragment_start - start..fragment_end - start
+            }
+            _ => 0..document.len(),
+        },
+    };
+    Some(CfHtml {
+        document,
+        fragment,
+        source_url: field("SourceURL").filter(|url| !url.is_empty()),
+    })
+}
+
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+/// The HTML document in a CF_HTML item, without its description header. If the header can't be
+/// parsed, the whole data is returned
+pub fn document(data: &[u8]) -> &[u8] {
+    parse(data).map_or(data, |html| html.document)
+}
+
+/// A copy of a CF_HTML item with its header rewritten so the offsets match the document, or None
+/// if it isn't CF_HTML or is already correct. Restoring an item with wrong offsets byte for byte
+/// makes the program it's pasted into cut the content short or paste the header
+pub fn normalize(data: &[u8]) -> Option<Vec<u8>> {
+    let html = parse(data)?;
+    let source = html
+        .source_url
+        .map(|url| format!("SourceURL:{}\r\n", url))
+        .unwrap_or_default();
+    let header_length = "Version:0.9\r\nStartHTML:0000000000\r\nEndHTML:0000000000\r\n\
+                         StartFragment:0000000000\r\nEndFragment:0000000000\r\n"
+        .len()
+        + source.len();
+    let header = format!(
+        "Version:0.9\r\nStartHTML:{:010}\r\nEndHTML:{:010}\r\nStartFragment:{:010}\r\n\
+         EndFragment:{:010}\r\n{}",
+        header_length,
+        header_length + html.document.len(),
+        header_length + html.fragment.start,
+        header_length + html.fragment.end,
+        source
+    );
+    let mut normalized = Vec::with_capacity(header.len() + html.document.len() + 1);
+    normalized.extend_from_slice(header.as_bytes());
+    normalized.extend_from_slice(html.document);
+    normalized.push(0);
+    if normalized == data {
+        None
+    } else {
+        Some(normalized)
+    }
+}
+
+/// Rewrite the headers of any `format` items, as for `normalize`
+pub fn normalize_items(items: &mut [ClipboardItem], format: u32) {
+    for item in items.iter_mut().filter(|item| item.format == format) {
+        if let Some(normalized) = normalize(&item.content) {
+            item.content = normalized;
+        }
+    }
+}
+
+/// The text of the fragment in a CF_HTML item, with lines for paragraphs, line breaks and list
+/// items, and tabs between table cells
+pub fn to_text(data: &[u8]) -> String {
+    let fragment = match parse(data) {
+        Some(html) => &html.document[html.fragment],
+        None => data,
+    };
+    let html = String::from_utf8_lossy(fragment);
+    let mut text = String::new();
+    let mut rest = &*html;
+    // Scripts and styles have contents which aren't text
+    let mut skipping: Option<String> = None;
+    while let Some(open) = rest.find('<') {
+        if skipping.is_none() {
+            push_text(&mut text, &rest[..open]);
+        }
+        rest = &rest[open..];
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+            continue;
+        }
+        let close = match rest.find('>') {
+            Some(close) => close,
+            None => break,
+        };
+        let tag = &rest[1..close];
+        rest = &rest[close + 1..];
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(char::is_ascii_alphanumeric)
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if let Some(skipped) = &skipping {
+            if closing && name == *skipped {
+                skipping = None;
+            }
+            continue;
+        }
+        match name.as_str() {
+            "script" | "style" if !closing => skipping = Some(name),
+            "br" => {
+                trim_spaces(&mut text);
+                text.push('\n');
+            }
+            "p" | "div" | "li" | "tr" | "table" | "ul" | "ol" | "blockquote" | "pre" | "h1"
+            | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                trim_spaces(&mut text);
+                if !text.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+            }
+            "td" | "th" if !closing => {
+                trim_spaces(&mut text);
+                if !text.is_empty() && !text.ends_with('\n') {
+                    text.push('\t');
+                }
+            }
+            _ => {}
+        }
+    }
+    if skipping.is_none() {
+        push_text(&mut text, rest);
+    }
+    text.lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\r\n")
+        .trim_matches(|c| c == '\r' || c == '\n')
+        .to_owned()
+}
+
+/// Append text from between tags, collapsing runs of whitespace as a browser does
+fn push_text(text: &mut String, html: &str) {
+    for (index, word) in html.split(|c: char| c.is_ascii_whitespace()).enumerate() {
+        if index > 0 && !text.is_empty() && !text.ends_with(char::is_whitespace) {
+            text.push(' ');
+        }
+        text.push_str(&decode_entities(word));
+    }
+}
+
+fn trim_spaces(text: &mut String) {
+    text.truncate(text.trim_end_matches(' ').len());
+}
+
+/// Replace character references such as "&amp;" and "&#233;" with the characters they stand for
+fn decode_entities(html: &str) -> String {
+    let mut decoded = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(ampersand) = rest.find('&') {
+        decoded.push_str(&rest[..ampersand]);
+        rest = &rest[ampersand..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#')?.parse().ok())
+                .and_then(char::from_u32),
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The plain text of an item's HTML, in place of all its formats, or None if it has no HTML
+pub fn as_text(items: &[ClipboardItem], format: u32) -> Option<Vec<ClipboardItem>> {
+    let html = items.iter().find(|item| item.format == format)?;
+    Some(vec![write_unicode_text(&to_text(&html.content))])
+}
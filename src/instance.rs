@@ -0,0 +1,61 @@
+use std::{fs::File, os::windows::io::FromRawHandle, thread, time::Duration};
+
+use crate::ipc::{self, Request};
+use crate::winapi_functions::create_mutex_w;
+
+/// Held by the running instance for as long as it runs. Local to the session, so each user who's
+/// logged in can run their own
+const MUTEX_NAME: &str = "Local\\filo-clipboard-instance";
+/// How long to wait for a replaced instance to exit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLACE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Proof that this is the only instance running. The mutex is released when it's dropped
+pub struct InstanceLock {
+    _mutex: File,
+}
+
+impl InstanceLock {
+    /// Claim the mutex, or return None if another instance holds it
+    fn try_acquire() -> Result<Option<Self>, String> {
+        let (handle, already_exists) = create_mutex_w(MUTEX_NAME)
+            .map_err(|error| format!("Could not check for a running instance: {}", error))?;
+        // The file takes ownership of the handle, closing it on drop, even if it isn't ours to keep
+        let mutex = unsafe { File::from_raw_handle(handle) };
+        Ok(if already_exists {
+            None
+        } else {
+            Some(Self { _mutex: mutex })
+        })
+    }
+
+    /// Make sure this is the only instance. If another is running it's asked to exit when
+    /// `replace` is set, otherwise the error describes it
+    pub fn acquire(replace: bool) -> Result<Self, String> {
+        if let Some(lock) = Self::try_acquire()? {
+            return Ok(lock);
+        }
+        if !replace {
+            let status = match ipc::send(&Request::Status { json: false }) {
+                Ok(response) if response.ok => format!(":\n{}", response.message),
+                _ => String::new(),
+            };
+            return Err(format!(
+                "FILO Clipboard is already running. Use --replace to restart it{}",
+                status
+            ));
+        }
+
+        ipc::send(&Request::Shutdown)
+            .map_err(|error| format!("Could not ask the running instance to exit: {}", error))?;
+        let mut waited = Duration::from_secs(0);
+        while waited < REPLACE_TIMEOUT {
+            thread::sleep(REPLACE_POLL_INTERVAL);
+            waited += REPLACE_POLL_INTERVAL;
+            if let Some(lock) = Self::try_acquire()? {
+                return Ok(lock);
+            }
+        }
+        Err("The running instance didn't exit in time".to_owned())
+    }
+}
@@ -0,0 +1,768 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    iter,
+    os::windows::io::FromRawHandle,
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use log::warn;
+use winapi::shared::winerror;
+use winapi::um::{winbase, winuser};
+
+use crate::picker;
+use crate::winapi_functions::{
+    connect_named_pipe, create_named_pipe_a, current_session_id, post_message_a,
+};
+
+/// The start of the pipe's name, which ends with the session ID. Pipe names are shared by the
+/// whole machine, so each signed in user's instance needs its own, like the instance lock
+const PIPE_PREFIX: &str = r"\\.\pipe\filo-clipboard-";
+/// Posted to the message window whenever a request is waiting in the channel
+pub const WM_IPC_REQUEST: u32 = winuser::WM_APP + 1;
+/// Posted to the message window when the pipe can't be created, with the error code in wParam, or
+/// with 0 once it's been created again
+pub const WM_IPC_ERROR: u32 = winuser::WM_APP + 10;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const BUFFER_SIZE: u32 = 4096;
+/// How long to wait before trying to create the pipe again, doubling up to the maximum
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_round_trip() {
+        let request = Request::ConfigSet {
+            key: "max_history".to_owned(),
+            value: "a\tb\\c\nd".to_owned(),
+            save: true,
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+    }
+
+    #[test]
+    fn expire_round_trip() {
+        let request = Request::Expire {
+            index: 2,
+            seconds: 300,
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(Request::decode("expire\t0\tsoon\n"), None);
+    }
+
+    #[test]
+    fn restore_round_trip() {
+        let request = Request::Restore {
+            index: 1,
+            dry_run: true,
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(
+            Request::decode("restore\t0\t\n"),
+            Some(Request::Restore {
+                index: 0,
+                dry_run: false
+            })
+        );
+    }
+
+    #[test]
+    fn pin_round_trip() {
+        let request = Request::Unpin { index: 3 };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(Request::decode("pin\t1\n"), Some(Request::Pin { index: 1 }));
+        assert_eq!(Request::decode("pin\n"), None);
+        assert_eq!(
+            Request::decode("burn\t0\n"),
+            Some(Request::Burn { index: 0 })
+        );
+    }
+
+    #[test]
+    fn snippet_round_trip() {
+        let request = Request::SnippetSave {
+            index: 2,
+            name: "home address".to_owned(),
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(
+            Request::decode("snippet-paste\tsig\n"),
+            Some(Request::SnippetPaste {
+                name: "sig".to_owned()
+            })
+        );
+        assert_eq!(Request::decode("snippets\n"), Some(Request::SnippetList));
+        assert_eq!(Request::decode("snippet-save\tsig\n"), None);
+    }
+
+    #[test]
+    fn stack_round_trip() {
+        let request = Request::SwitchStack {
+            name: "work".to_owned(),
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(Request::decode("stacks\n"), Some(Request::StackList));
+        assert_eq!(Request::decode("stack\n"), None);
+    }
+
+    #[test]
+    fn picker_round_trip() {
+        let request = Request::PickerMove { by: -3 };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(Request::decode("picker-open\n"), Some(Request::PickerOpen));
+        assert_eq!(Request::decode("picker-move\tup\n"), None);
+    }
+
+    #[test]
+    fn import_round_trip() {
+        let request = Request::Import {
+            json: "{\n\t\"version\": 1\n}".to_owned(),
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(Request::decode("export\n"), Some(Request::Export));
+    }
+
+    #[test]
+    fn push_round_trip() {
+        let request = Request::Push {
+            text: "line one\r\n\tline two".to_owned(),
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let request = Request::Batch {
+            revision: Some(12),
+            operations: vec![
+                BatchOperation::Delete { index: 3 },
+                BatchOperation::Promote { index: 1 },
+                BatchOperation::Label {
+                    index: 0,
+                    label: "invoice\tnumbers".to_owned(),
+                },
+            ],
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(
+            Request::decode("batch\t\tlabel 2\n"),
+            Some(Request::Batch {
+                revision: None,
+                operations: vec![BatchOperation::Label {
+                    index: 2,
+                    label: String::new(),
+                }],
+            })
+        );
+        assert_eq!(Request::decode("batch\t\tmove 2\n"), None);
+        assert!("delete first".parse::<BatchOperation>().is_err());
+    }
+
+    #[test]
+    fn paste_recent_round_trip() {
+        let request = Request::PasteRecent {
+            fingerprint: u64::MAX,
+        };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(Request::decode("paste-recent\t-1\n"), None);
+    }
+
+    #[test]
+    fn request_without_fields() {
+        assert_eq!(Request::decode(&Request::Pop.encode()), Some(Request::Pop));
+        assert_eq!(Request::decode("paste\n"), Some(Request::Paste));
+        assert_eq!(Request::decode("peek\n"), Some(Request::Peek));
+        assert_eq!(Request::decode("list\n"), Some(Request::List));
+        assert_eq!(
+            Request::decode("status\n"),
+            Some(Request::Status { json: false })
+        );
+        let request = Request::Status { json: true };
+        assert_eq!(Request::decode(&request.encode()), Some(request));
+        assert_eq!(Request::decode("shutdown\n"), Some(Request::Shutdown));
+        assert_eq!(Request::decode("clear\textra\n"), None);
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let response = Response::error("first line\nsecond line");
+        assert_eq!(Response::decode(&response.encode()), response);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Request {
+    ConfigGet {
+        key: String,
+    },
+    ConfigSet {
+        key: String,
+        value: String,
+        save: bool,
+    },
+    SwitchProfile {
+        name: String,
+    },
+    Save {
+        index: usize,
+        open: bool,
+    },
+    CopyAsFile {
+        index: usize,
+    },
+    /// Put an item on the clipboard without pasting it, or with `dry_run` only report what would be
+    /// put there
+    Restore {
+        index: usize,
+        dry_run: bool,
+    },
+    /// Wipe an item from the history and the clipboard once `seconds` have passed
+    Expire {
+        index: usize,
+        seconds: u64,
+    },
+    /// Wipe an item from the history and the clipboard once it's been pasted
+    Burn {
+        index: usize,
+    },
+    /// Move an item out of the history into the pinned items
+    Pin {
+        index: usize,
+    },
+    /// Move a pinned item back to the front of the history
+    Unpin {
+        index: usize,
+    },
+    /// Add text to the front of the history, as though it had been copied
+    Push {
+        text: String,
+    },
+    /// Reply with the history and pinned items as JSON
+    Export,
+    /// Add the entries in JSON written by `Export` to the front of the history
+    Import {
+        json: String,
+    },
+    /// Apply every operation or none of them, before any other clipboard change is recorded. If
+    /// `revision` is given, nothing is changed unless the history is still at that revision
+    Batch {
+        revision: Option<u64>,
+        operations: Vec<BatchOperation>,
+    },
+    /// Paste the front item into the focused program and remove it, as the hotkey does
+    Paste,
+    /// Paste the front item into the focused program without removing it
+    Peek,
+    /// Paste the item with this fingerprint into the focused program without removing it
+    PasteRecent {
+        fingerprint: u64,
+    },
+    /// Remove the front item without pasting it, replying with its text
+    Pop,
+    Clear,
+    /// Remove duplicate, blank and superseded items, replying with what was reclaimed
+    Compact,
+    /// Replace the front item with what's on the clipboard now
+    Recapture,
+    /// Reply with a summary of every item, one per line
+    List,
+    /// Reply with the state of the instance and the size and origin of every item, as JSON if
+    /// `json` is set
+    Status {
+        json: bool,
+    },
+    /// Exit, so another instance can take over
+    Shutdown,
+    PickerOpen,
+    /// Move the picker's selection down by `by` items, or up if it's negative
+    PickerMove {
+        by: isize,
+    },
+    /// Paste the item selected in the picker
+    PickerConfirm,
+    PickerCancel,
+    /// Save a copy of an item under a name, replacing any snippet with that name
+    SnippetSave {
+        index: usize,
+        name: String,
+    },
+    /// Paste a snippet into the focused program, leaving the history as it was
+    SnippetPaste {
+        name: String,
+    },
+    SnippetDelete {
+        name: String,
+    },
+    /// Reply with the name and a summary of each snippet, one per line
+    SnippetList,
+    /// Make another stack the active one, creating it if needed
+    SwitchStack {
+        name: String,
+    },
+    /// Reply with each stack's name and how many items it holds, the active one marked with '*'
+    StackList,
+}
+
+/// One change in a `Batch` request, written as "delete 3", "promote 1" or "label 0 text". Indexes
+/// count from the front of the history as it was before the batch, so earlier operations don't
+/// shift later ones
+#[derive(Debug, PartialEq, Clone)]
+pub enum BatchOperation {
+    Delete {
+        index: usize,
+    },
+    /// Move an item to the front. Of several promoted items, the last ends up in front
+    Promote {
+        index: usize,
+    },
+    /// Label an item, or remove its label if `label` is empty
+    Label {
+        index: usize,
+        label: String,
+    },
+}
+
+impl BatchOperation {
+    pub fn index(&self) -> usize {
+        match self {
+            BatchOperation::Delete { index }
+            | BatchOperation::Promote { index }
+            | BatchOperation::Label { index, .. } => *index,
+        }
+    }
+}
+
+impl FromStr for BatchOperation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.splitn(3, ' ');
+        let verb = parts.next().unwrap_or_default();
+        let index = parts
+            .next()
+            .and_then(|index| index.parse().ok())
+            .ok_or_else(|| format!("expected an item number after \"{}\"", verb))?;
+        match (verb, parts.next()) {
+            ("delete", None) => Ok(BatchOperation::Delete { index }),
+            ("promote", None) => Ok(BatchOperation::Promote { index }),
+            ("label", label) => Ok(BatchOperation::Label {
+                index,
+                label: label.unwrap_or_default().to_owned(),
+            }),
+            _ => Err(format!(
+                "expected \"delete <n>\", \"promote <n>\" or \"label <n> <text>\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BatchOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchOperation::Delete { index } => write!(f, "delete {}", index),
+            BatchOperation::Promote { index } => write!(f, "promote {}", index),
+            BatchOperation::Label { index, label } => write!(f, "label {} {}", index, label),
+        }
+    }
+}
+
+impl Request {
+    /// Whether the request pastes or shows a window, which can't be done without an interactive
+    /// desktop
+    pub fn needs_desktop(&self) -> bool {
+        matches!(
+            self,
+            Request::Paste
+                | Request::Peek
+                | Request::PasteRecent { .. }
+                | Request::SnippetPaste { .. }
+                | Request::PickerOpen
+                | Request::PickerMove { .. }
+                | Request::PickerConfirm
+                | Request::PickerCancel
+        )
+    }
+
+    /// Encode the request as a single line of tab separated fields
+    fn encode(&self) -> String {
+        let flag = |set: bool, name: &str| if set { name.to_owned() } else { String::new() };
+        let fields = match self {
+            Request::ConfigGet { key } => vec!["config-get".to_owned(), key.clone()],
+            Request::ConfigSet { key, value, save } => vec![
+                "config-set".to_owned(),
+                key.clone(),
+                value.clone(),
+                flag(*save, "save"),
+            ],
+            Request::SwitchProfile { name } => vec!["profile".to_owned(), name.clone()],
+            Request::Save { index, open } => {
+                vec!["save".to_owned(), index.to_string(), flag(*open, "open")]
+            }
+            Request::CopyAsFile { index } => vec!["copy-file".to_owned(), index.to_string()],
+            Request::Restore { index, dry_run } => vec![
+                "restore".to_owned(),
+                index.to_string(),
+                flag(*dry_run, "dry-run"),
+            ],
+            Request::Expire { index, seconds } => {
+                vec!["expire".to_owned(), index.to_string(), seconds.to_string()]
+            }
+            Request::Burn { index } => vec!["burn".to_owned(), index.to_string()],
+            Request::Pin { index } => vec!["pin".to_owned(), index.to_string()],
+            Request::Unpin { index } => vec!["unpin".to_owned(), index.to_string()],
+            Request::Push { text } => vec!["push".to_owned(), text.clone()],
+            Request::Export => vec!["export".to_owned()],
+            Request::Import { json } => vec!["import".to_owned(), json.clone()],
+            Request::Batch {
+                revision,
+                operations,
+            } => iter::once("batch".to_owned())
+                .chain(iter::once(
+                    revision
+                        .map(|revision| revision.to_string())
+                        .unwrap_or_default(),
+                ))
+                .chain(operations.iter().map(BatchOperation::to_string))
+                .collect(),
+            Request::Paste => vec!["paste".to_owned()],
+            Request::Peek => vec!["peek".to_owned()],
+            Request::PasteRecent { fingerprint } => {
+                vec!["paste-recent".to_owned(), fingerprint.to_string()]
+            }
+            Request::Pop => vec!["pop".to_owned()],
+            Request::Clear => vec!["clear".to_owned()],
+            Request::Compact => vec!["compact".to_owned()],
+            Request::Recapture => vec!["recapture".to_owned()],
+            Request::List => vec!["list".to_owned()],
+            Request::Status { json: false } => vec!["status".to_owned()],
+            Request::Status { json: true } => vec!["status".to_owned(), "json".to_owned()],
+            Request::Shutdown => vec!["shutdown".to_owned()],
+            Request::PickerOpen => vec!["picker-open".to_owned()],
+            Request::PickerMove { by } => vec!["picker-move".to_owned(), by.to_string()],
+            Request::PickerConfirm => vec!["picker-confirm".to_owned()],
+            Request::PickerCancel => vec!["picker-cancel".to_owned()],
+            Request::SnippetSave { index, name } => {
+                vec!["snippet-save".to_owned(), index.to_string(), name.clone()]
+            }
+            Request::SnippetPaste { name } => vec!["snippet-paste".to_owned(), name.clone()],
+            Request::SnippetDelete { name } => vec!["snippet-delete".to_owned(), name.clone()],
+            Request::SnippetList => vec!["snippets".to_owned()],
+            Request::SwitchStack { name } => vec!["stack".to_owned(), name.clone()],
+            Request::StackList => vec!["stacks".to_owned()],
+        };
+        let fields: Vec<_> = fields.iter().map(|field| escape(field)).collect();
+        format!("{}\n", fields.join("\t"))
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        let fields: Vec<_> = line
+            .trim_end_matches('\n')
+            .split('\t')
+            .map(unescape)
+            .collect();
+        let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+        match fields.as_slice() {
+            ["config-get", key] => Some(Request::ConfigGet {
+                key: key.to_string(),
+            }),
+            ["config-set", key, value, save] => Some(Request::ConfigSet {
+                key: key.to_string(),
+                value: value.to_string(),
+                save: *save == "save",
+            }),
+            ["profile", name] => Some(Request::SwitchProfile {
+                name: name.to_string(),
+            }),
+            ["save", index, open] => Some(Request::Save {
+                index: index.parse().ok()?,
+                open: *open == "open",
+            }),
+            ["copy-file", index] => Some(Request::CopyAsFile {
+                index: index.parse().ok()?,
+            }),
+            ["restore", index, dry_run] => Some(Request::Restore {
+                index: index.parse().ok()?,
+                dry_run: *dry_run == "dry-run",
+            }),
+            ["expire", index, seconds] => Some(Request::Expire {
+                index: index.parse().ok()?,
+                seconds: seconds.parse().ok()?,
+            }),
+            ["burn", index] => Some(Request::Burn {
+                index: index.parse().ok()?,
+            }),
+            ["pin", index] => Some(Request::Pin {
+                index: index.parse().ok()?,
+            }),
+            ["unpin", index] => Some(Request::Unpin {
+                index: index.parse().ok()?,
+            }),
+            ["push", text] => Some(Request::Push {
+                text: text.to_string(),
+            }),
+            ["export"] => Some(Request::Export),
+            ["import", json] => Some(Request::Import {
+                json: json.to_string(),
+            }),
+            ["batch", revision, operations @ ..] if !operations.is_empty() => {
+                Some(Request::Batch {
+                    revision: match *revision {
+                        "" => None,
+                        revision => Some(revision.parse().ok()?),
+                    },
+                    operations: operations
+                        .iter()
+                        .map(|operation| operation.parse().ok())
+                        .collect::<Option<_>>()?,
+                })
+            }
+            ["paste"] => Some(Request::Paste),
+            ["peek"] => Some(Request::Peek),
+            ["paste-recent", fingerprint] => Some(Request::PasteRecent {
+                fingerprint: fingerprint.parse().ok()?,
+            }),
+            ["pop"] => Some(Request::Pop),
+            ["clear"] => Some(Request::Clear),
+            ["compact"] => Some(Request::Compact),
+            ["recapture"] => Some(Request::Recapture),
+            ["list"] => Some(Request::List),
+            ["status"] => Some(Request::Status { json: false }),
+            ["status", "json"] => Some(Request::Status { json: true }),
+            ["shutdown"] => Some(Request::Shutdown),
+            ["picker-open"] => Some(Request::PickerOpen),
+            ["picker-move", by] => Some(Request::PickerMove {
+                by: by.parse().ok()?,
+            }),
+            ["picker-confirm"] => Some(Request::PickerConfirm),
+            ["picker-cancel"] => Some(Request::PickerCancel),
+            ["snippet-save", index, name] => Some(Request::SnippetSave {
+                index: index.parse().ok()?,
+                name: name.to_string(),
+            }),
+            ["snippet-paste", name] => Some(Request::SnippetPaste {
+                name: name.to_string(),
+            }),
+            ["snippet-delete", name] => Some(Request::SnippetDelete {
+                name: name.to_string(),
+            }),
+            ["snippets"] => Some(Request::SnippetList),
+            ["stack", name] => Some(Request::SwitchStack {
+                name: name.to_string(),
+            }),
+            ["stacks"] => Some(Request::StackList),
+            _ => None,
+        }
+    }
+}
+
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Response {
+    pub ok: bool,
+    pub message: String,
+}
+
+impl Response {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: message.into(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: message.into(),
+        }
+    }
+
+    /// A status line followed by the message, which runs until the pipe is closed
+    fn encode(&self) -> String {
+        format!("{}\n{}", if self.ok { "ok" } else { "error" }, self.message)
+    }
+
+    fn decode(data: &str) -> Self {
+        match data.split_once('\n') {
+            Some(("ok", message)) => Self::ok(message),
+            Some((_, message)) => Self::error(message),
+            None => Self::error(format!("Malformed response: {}", data)),
+        }
+    }
+}
+
+/// A request from a client, along with the channel to send its response to
+pub type PendingRequest = (Request, Sender<Response>);
+
+/// Listen for clients on a background thread. Each request is queued on the returned channel and
+/// WM_IPC_REQUEST is posted to `h_wnd` so the message loop can answer it
+pub fn spawn_server(h_wnd: &mut winapi::shared::windef::HWND__) -> Receiver<PendingRequest> {
+    let (sender, receiver) = channel::unbounded();
+    // Window handles can be used from any thread, but raw pointers can't be sent between them
+    let h_wnd = h_wnd as *mut _ as usize;
+
+    let name = match pipe_name() {
+        Ok(name) => name,
+        Err(error) => {
+            warn!("Could not create IPC pipe: {}", error);
+            return receiver;
+        }
+    };
+
+    thread::spawn(move || {
+        let mut first = true;
+        let mut delay = RETRY_DELAY;
+        let mut failed = false;
+        loop {
+            let window = unsafe { &mut *(h_wnd as *mut winapi::shared::windef::HWND__) };
+            let pipe = match create_named_pipe_a(
+                &name,
+                winbase::PIPE_ACCESS_DUPLEX | winbase::FILE_FLAG_FIRST_PIPE_INSTANCE,
+                winbase::PIPE_TYPE_BYTE | winbase::PIPE_WAIT | winbase::PIPE_REJECT_REMOTE_CLIENTS,
+                1,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+            ) {
+                Ok(pipe) => pipe,
+                // Only one instance exists at a time, so being refused the first means another
+                // process has taken the name
+                Err(error) if first && error.raw_code() == winerror::ERROR_ACCESS_DENIED as i32 => {
+                    warn!(
+                        "Could not create IPC pipe, as another process has it: {}",
+                        error
+                    );
+                    let _ = post_message_a(window, WM_IPC_ERROR, error.raw_code() as usize, 0);
+                    return;
+                }
+                Err(error) => {
+                    warn!(
+                        "Could not create IPC pipe, trying again in {}s: {}",
+                        delay.as_secs(),
+                        error
+                    );
+                    if !failed {
+                        let _ = post_message_a(window, WM_IPC_ERROR, error.raw_code() as usize, 0);
+                        failed = true;
+                    }
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    continue;
+                }
+            };
+            if failed {
+                let _ = post_message_a(window, WM_IPC_ERROR, 0, 0);
+                failed = false;
+            }
+            first = false;
+            delay = RETRY_DELAY;
+            // The file takes ownership of the handle, closing it (and disconnecting the client) on
+            // drop
+            let pipe = unsafe { File::from_raw_handle(pipe) };
+
+            if connect_named_pipe(&pipe).is_ok() {
+                if let Err(error) = serve_client(pipe, window, &sender) {
+                    warn!("IPC client failed: {}", error);
+                }
+            }
+        }
+    });
+
+    receiver
+}
+
+fn serve_client(
+    pipe: File,
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    sender: &Sender<PendingRequest>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(pipe);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let response = match Request::decode(&line) {
+        // The picker holds back messages for the message window until it closes, so it's steered
+        // from here instead
+        Some(Request::PickerMove { by }) => picker_response(picker::move_selection(by), "Moved"),
+        Some(Request::PickerConfirm) => picker_response(picker::confirm(), "Confirmed"),
+        Some(Request::PickerCancel) => picker_response(picker::cancel(), "Cancelled"),
+        Some(request) => {
+            let (reply_sender, reply_receiver) = channel::bounded(1);
+            sender
+                .send((request, reply_sender))
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "message loop stopped"))?;
+            post_message_a(h_wnd, WM_IPC_REQUEST, 0, 0)
+                .map_err(|error| io::Error::from_raw_os_error(error.raw_code()))?;
+            reply_receiver
+                .recv_timeout(REPLY_TIMEOUT)
+                .unwrap_or_else(|_| Response::error("Timed out waiting for a response"))
+        }
+        None => Response::error(format!("Unknown request: {}", line.trim_end())),
+    };
+
+    let mut pipe = reader.into_inner();
+    pipe.write_all(response.encode().as_bytes())?;
+    // Make sure the client has read everything before the pipe is closed
+    pipe.sync_all()
+}
+
+fn picker_response(open: bool, message: &str) -> Response {
+    if open {
+        Response::ok(message)
+    } else {
+        Response::error("The picker isn't open")
+    }
+}
+
+/// The name of the pipe for the current session
+fn pipe_name() -> io::Result<String> {
+    let session_id =
+        current_session_id().map_err(|error| io::Error::from_raw_os_error(error.raw_code()))?;
+    Ok(format!("{}{}", PIPE_PREFIX, session_id))
+}
+
+/// Send a request to the running instance and wait for its response
+pub fn send(request: &Request) -> io::Result<Response> {
+    let mut pipe = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(pipe_name()?)?;
+    pipe.write_all(request.encode().as_bytes())?;
+
+    let mut data = String::new();
+    pipe.read_to_string(&mut data)?;
+    Ok(Response::decode(&data))
+}
@@ -0,0 +1,284 @@
+use std::{env, ffi::c_void, mem, ptr};
+
+use winapi::shared::{
+    guiddef::{REFCLSID, REFIID},
+    minwindef::UINT,
+    winerror::{FAILED, HRESULT, RPC_E_CHANGED_MODE},
+    wtypes::VT_LPWSTR,
+    wtypesbase::CLSCTX_INPROC_SERVER,
+};
+use winapi::um::{
+    combaseapi::{CoCreateInstance, CoInitializeEx, CoUninitialize},
+    objbase::COINIT_APARTMENTTHREADED,
+    propidl::PROPVARIANT,
+    propkey::PKEY_Title,
+    propsys::IPropertyStore,
+    shobjidl_core::{DestinationList, EnumerableObjectCollection, IShellLinkW, ShellLink},
+    unknwnbase::{IUnknown, IUnknownVtbl},
+    winnt::LPCWSTR,
+};
+use winapi::{Class, Interface, RIDL};
+
+use crate::winapi_functions::to_wide;
+
+/// The most items offered, although the taskbar may have room for fewer
+pub const MAX_TASKS: usize = 5;
+/// The subcommand the tasks run, which asks the running instance to paste the item
+const PASTE_COMMAND: &str = "paste-recent";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tasks_skip_items_without_text() {
+        let items: Vec<_> = (0..8)
+            .map(|n| (n, if n == 1 { String::new() } else { n.to_string() }))
+            .collect();
+        let tasks = tasks(items);
+        assert_eq!(tasks.len(), MAX_TASKS);
+        assert_eq!(tasks[0].title, "0");
+        assert_eq!(tasks[1].title, "2");
+        assert_eq!(tasks[1].arguments(), "paste-recent 2");
+    }
+}
+
+// The interfaces winapi doesn't have, from ShObjIdl_core.h
+#[allow(non_snake_case)]
+mod interfaces {
+    use super::*;
+
+    RIDL! {#[uuid(0x92ca9dcd, 0x5622, 0x4bba, 0xa8, 0x05, 0x5e, 0x9f, 0x54, 0x1b, 0xd8, 0xc9)]
+    interface IObjectArray(IObjectArrayVtbl): IUnknown(IUnknownVtbl) {
+        fn GetCount(pcObjects: *mut UINT,) -> HRESULT,
+        fn GetAt(uiIndex: UINT, riid: REFIID, ppv: *mut *mut c_void,) -> HRESULT,
+    }}
+
+    RIDL! {#[uuid(0x5632b1a4, 0xe38a, 0x400a, 0x92, 0x8a, 0xd4, 0xcd, 0x63, 0x23, 0x02, 0x95)]
+    interface IObjectCollection(IObjectCollectionVtbl): IObjectArray(IObjectArrayVtbl) {
+        fn AddObject(punk: *mut IUnknown,) -> HRESULT,
+        fn AddFromArray(poaSource: *mut IObjectArray,) -> HRESULT,
+        fn RemoveObjectAt(uiIndex: UINT,) -> HRESULT,
+        fn Clear() -> HRESULT,
+    }}
+
+    RIDL! {#[uuid(0x6332debf, 0x87b5, 0x4670, 0x90, 0xc0, 0x5e, 0x57, 0xb4, 0x08, 0xa4, 0x9e)]
+    interface ICustomDestinationList(ICustomDestinationListVtbl): IUnknown(IUnknownVtbl) {
+        fn SetAppID(pszAppID: LPCWSTR,) -> HRESULT,
+        fn BeginList(pcMinSlots: *mut UINT, riid: REFIID, ppv: *mut *mut c_void,) -> HRESULT,
+        fn AppendCategory(pszCategory: LPCWSTR, poa: *mut IObjectArray,) -> HRESULT,
+        fn AppendKnownCategory(category: i32,) -> HRESULT,
+        fn AddUserTasks(poa: *mut IObjectArray,) -> HRESULT,
+        fn CommitList() -> HRESULT,
+        fn GetRemovedDestinations(riid: REFIID, ppv: *mut *mut c_void,) -> HRESULT,
+        fn DeleteList(pszAppID: LPCWSTR,) -> HRESULT,
+        fn AbortList() -> HRESULT,
+    }}
+}
+
+use interfaces::{ICustomDestinationList, IObjectArray, IObjectCollection};
+
+/// An entry in the jump list, which pastes the item with `fingerprint` when it's clicked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Task {
+    pub fingerprint: u64,
+    pub title: String,
+}
+
+impl Task {
+    /// The command line the task runs this program with
+    pub fn arguments(&self) -> String {
+        format!("{} {}", PASTE_COMMAND, self.fingerprint)
+    }
+}
+
+/// The tasks for the first few `items`, given as fingerprints and summaries in history order.
+/// Items without text are left out
+pub fn tasks(items: impl IntoIterator<Item = (u64, String)>) -> Vec<Task> {
+    items
+        .into_iter()
+        .filter(|(_, title)| !title.is_empty())
+        .take(MAX_TASKS)
+        .map(|(fingerprint, title)| Task { fingerprint, title })
+        .collect()
+}
+
+/// A COM interface pointer, released on drop
+struct Com<T: Interface>(*mut T);
+
+impl<T: Interface> Com<T> {
+    fn create(class: REFCLSID) -> Result<Self, String> {
+        let mut object = ptr::null_mut();
+        check(
+            unsafe {
+                CoCreateInstance(
+                    class,
+                    ptr::null_mut(),
+                    CLSCTX_INPROC_SERVER,
+                    &T::uuidof(),
+                    &mut object,
+                )
+            },
+            "create a COM object",
+        )?;
+        Ok(Self(object as *mut T))
+    }
+
+    fn query<U: Interface>(&self) -> Result<Com<U>, String> {
+        let mut object = ptr::null_mut();
+        check(
+            unsafe { (*(self.0 as *mut IUnknown)).QueryInterface(&U::uuidof(), &mut object) },
+            "query a COM interface",
+        )?;
+        Ok(Com(object as *mut U))
+    }
+
+    fn get(&self) -> &T {
+        unsafe { &*self.0 }
+    }
+}
+
+impl<T: Interface> Drop for Com<T> {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { (*(self.0 as *mut IUnknown)).Release() };
+        }
+    }
+}
+
+fn check(result: HRESULT, action: &str) -> Result<(), String> {
+    if FAILED(result) {
+        Err(format!("Could not {} ({:#010x})", action, result))
+    } else {
+        Ok(())
+    }
+}
+
+/// The tasks shown when the program's taskbar button or Start entry is right clicked. The list is
+/// removed again when this is dropped, as Windows keeps it on disk
+pub struct JumpList {
+    list: Option<Com<ICustomDestinationList>>,
+    shown: Vec<Task>,
+    uninitialize: bool,
+}
+
+impl JumpList {
+    pub fn create() -> Result<Self, String> {
+        let result = unsafe { CoInitializeEx(ptr::null_mut(), COINIT_APARTMENTTHREADED) };
+        // Another part of the thread set COM up differently, which is fine as long as it's left up
+        let uninitialize = result != RPC_E_CHANGED_MODE;
+        if uninitialize {
+            check(result, "set up COM for the jump list")?;
+        }
+        let list = Com::create(&DestinationList::uuidof()).inspect_err(|_| {
+            if uninitialize {
+                unsafe { CoUninitialize() };
+            }
+        })?;
+        Ok(Self {
+            list: Some(list),
+            shown: Vec::new(),
+            uninitialize,
+        })
+    }
+
+    /// Show `tasks` in place of the last ones, if they've changed
+    pub fn update(&mut self, tasks: Vec<Task>) -> Result<(), String> {
+        if tasks == self.shown {
+            return Ok(());
+        }
+        let list = match &self.list {
+            Some(list) => list.get(),
+            None => return Ok(()),
+        };
+        if tasks.is_empty() {
+            check(
+                unsafe { list.DeleteList(ptr::null()) },
+                "clear the jump list",
+            )?;
+            self.shown = tasks;
+            return Ok(());
+        }
+
+        let mut slots = 0;
+        let mut removed = ptr::null_mut();
+        check(
+            unsafe { list.BeginList(&mut slots, &IObjectArray::uuidof(), &mut removed) },
+            "start the jump list",
+        )?;
+        // The destinations the user removed only matter to lists of documents, which this isn't
+        drop(Com(removed as *mut IObjectArray));
+        let result = add_tasks(list, &tasks, slots as usize)
+            .and_then(|_| check(unsafe { list.CommitList() }, "save the jump list"));
+        if result.is_err() {
+            unsafe { list.AbortList() };
+        } else {
+            self.shown = tasks;
+        }
+        result
+    }
+}
+
+impl Drop for JumpList {
+    fn drop(&mut self) {
+        if let Some(list) = self.list.take() {
+            unsafe { list.get().DeleteList(ptr::null()) };
+        }
+        if self.uninitialize {
+            unsafe { CoUninitialize() };
+        }
+    }
+}
+
+/// Add as many of `tasks` as there are `slots` to the list being built
+fn add_tasks(list: &ICustomDestinationList, tasks: &[Task], slots: usize) -> Result<(), String> {
+    let exe =
+        env::current_exe().map_err(|error| format!("Could not find the executable: {}", error))?;
+    let exe = to_wide(&exe.to_string_lossy());
+    let collection = Com::<IObjectCollection>::create(&EnumerableObjectCollection::uuidof())?;
+    for task in tasks.iter().take(slots.max(1)) {
+        let link = shell_link(&exe, task)?;
+        check(
+            unsafe { collection.get().AddObject(link.0 as *mut IUnknown) },
+            "add a task to the jump list",
+        )?;
+    }
+    let array = collection.query::<IObjectArray>()?;
+    check(
+        unsafe { list.AddUserTasks(array.0) },
+        "add the tasks to the jump list",
+    )
+}
+
+/// A shortcut which runs `exe` with the task's arguments, titled with its summary
+fn shell_link(exe: &[u16], task: &Task) -> Result<Com<IShellLinkW>, String> {
+    let link = Com::<IShellLinkW>::create(&ShellLink::uuidof())?;
+    let arguments = to_wide(&task.arguments());
+    let mut title = to_wide(&task.title);
+    unsafe {
+        check(link.get().SetPath(exe.as_ptr()), "set the task's program")?;
+        check(
+            link.get().SetArguments(arguments.as_ptr()),
+            "set the task's arguments",
+        )?;
+        check(
+            link.get().SetIconLocation(exe.as_ptr(), 0),
+            "set the task's icon",
+        )?;
+    }
+
+    // Tasks show the shortcut's title property rather than its description
+    let store = link.query::<IPropertyStore>()?;
+    let mut value: PROPVARIANT = unsafe { mem::zeroed() };
+    value.vt = VT_LPWSTR as u16;
+    // SetValue copies the string, so it can stay owned here
+    unsafe { *value.data.pwszVal_mut() = title.as_mut_ptr() };
+    unsafe {
+        check(
+            store.get().SetValue(&PKEY_Title, &value),
+            "set the task's title",
+        )?;
+        check(store.get().Commit(), "save the task's title")?;
+    }
+    Ok(link)
+}
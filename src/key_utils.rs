@@ -1,8 +1,10 @@
-use std::mem;
+use std::{iter, mem};
 
 use winapi::um::winuser;
 
-use crate::winapi_functions::{get_async_key_state, send_input, system_parameters_info_a};
+use crate::winapi_functions::{
+    block_input, get_async_key_state, keybd_event, send_input, system_parameters_info_a,
+};
 
 #[cfg(test)]
 mod tests {
@@ -17,6 +19,80 @@ mod tests {
     fn raw_speed_to_millis_max() {
         assert_eq!(raw_speed_to_millis(31), 33u16);
     }
+
+    #[test]
+    fn terminal_classes() {
+        assert_eq!(
+            PasteKeys::for_window_class("CASCADIA_HOSTING_WINDOW_CLASS"),
+            PasteKeys::CtrlShiftV
+        );
+        assert_eq!(
+            PasteKeys::for_window_class("mintty"),
+            PasteKeys::ShiftInsert
+        );
+        assert_eq!(PasteKeys::for_window_class("Notepad"), PasteKeys::CtrlV);
+    }
+
+    #[test]
+    fn text_key_events() {
+        let unicode = winuser::KEYEVENTF_UNICODE;
+        let up = winuser::KEYEVENTF_KEYUP;
+        let enter = winuser::VK_RETURN as u16;
+        assert_eq!(
+            text_keys("é\r\n\n😀"),
+            vec![
+                (0, 0xE9, unicode),
+                (0, 0xE9, unicode | up),
+                (enter, 0, 0),
+                (enter, 0, up),
+                (enter, 0, 0),
+                (enter, 0, up),
+                (0, 0xD83D, unicode),
+                (0, 0xD83D, unicode | up),
+                (0, 0xDE00, unicode),
+                (0, 0xDE00, unicode | up),
+            ]
+        );
+    }
+}
+
+/// The keys which paste in the program being pasted into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PasteKeys {
+    CtrlV,
+    /// Windows Terminal and other terminals which send Ctrl+V to the shell
+    CtrlShiftV,
+    /// mintty, PuTTY and other terminals which follow X11
+    ShiftInsert,
+}
+
+/// Window classes of terminals where Ctrl+V doesn't paste
+const TERMINAL_CLASSES: &[(&str, PasteKeys)] = &[
+    ("CASCADIA_HOSTING_WINDOW_CLASS", PasteKeys::CtrlShiftV),
+    ("org.wezfurlong.wezterm", PasteKeys::CtrlShiftV),
+    ("mintty", PasteKeys::ShiftInsert),
+    ("PuTTY", PasteKeys::ShiftInsert),
+    ("KiTTY", PasteKeys::ShiftInsert),
+    ("VirtualConsoleClass", PasteKeys::ShiftInsert),
+];
+
+impl PasteKeys {
+    /// The keys which paste into a window of class `class`
+    pub fn for_window_class(class: &str) -> Self {
+        TERMINAL_CLASSES
+            .iter()
+            .find(|(terminal, _)| *terminal == class)
+            .map_or(PasteKeys::CtrlV, |(_, keys)| *keys)
+    }
+
+    /// The modifiers, in the order they're pressed, and the key
+    pub fn keys(self) -> (&'static [i32], i32) {
+        match self {
+            PasteKeys::CtrlV => (&[winuser::VK_CONTROL], 'V' as i32),
+            PasteKeys::CtrlShiftV => (&[winuser::VK_CONTROL, winuser::VK_SHIFT], 'V' as i32),
+            PasteKeys::ShiftInsert => (&[winuser::VK_SHIFT], winuser::VK_INSERT),
+        }
+    }
 }
 
 /// Create an input struct from the key code and event
@@ -39,6 +115,69 @@ fn create_input(key_code: u16, event: u32) -> winuser::INPUT {
     }
 }
 
+/// The virtual key, scan code and flags of the events which type `text`. Characters are sent as
+/// Unicode, so they don't depend on the keyboard layout, but line breaks and tabs are sent as the
+/// Enter and Tab keys, which programs handle more reliably than the characters
+fn text_keys(text: &str) -> Vec<(u16, u16, u32)> {
+    let mut keys = Vec::new();
+    for character in text.replace("\r\n", "\n").chars() {
+        let key = match character {
+            '\n' | '\r' => Some(winuser::VK_RETURN),
+            '\t' => Some(winuser::VK_TAB),
+            _ => None,
+        };
+        match key {
+            Some(key) => {
+                keys.push((key as u16, 0, 0));
+                keys.push((key as u16, 0, winuser::KEYEVENTF_KEYUP));
+            }
+            None => {
+                for unit in character.encode_utf16(&mut [0; 2]) {
+                    keys.push((0, *unit, winuser::KEYEVENTF_UNICODE));
+                    keys.push((
+                        0,
+                        *unit,
+                        winuser::KEYEVENTF_UNICODE | winuser::KEYEVENTF_KEYUP,
+                    ));
+                }
+            }
+        }
+    }
+    keys
+}
+
+/// Send the key events in `before`, then type `text`, then send those in `after`, all at once so
+/// the user's own typing can't land in the middle
+pub fn type_text(
+    before: (&[u16], &[u32]),
+    text: &str,
+    after: (&[u16], &[u32]),
+) -> Result<u32, error_code::ErrorCode<error_code::SystemCategory>> {
+    let keys = |(key_codes, events): (&[u16], &[u32])| {
+        key_codes
+            .iter()
+            .zip(events.iter())
+            .map(|(key_code, event)| (*key_code, 0, *event))
+            .collect::<Vec<_>>()
+    };
+    let mut inputs: Vec<_> = keys(before)
+        .into_iter()
+        .chain(text_keys(text))
+        .chain(keys(after))
+        .map(|(key_code, scan_code, event)| {
+            let mut input = create_input(key_code, event);
+            unsafe { input.u.ki_mut().wScan = scan_code };
+            input
+        })
+        .collect();
+
+    send_input(
+        inputs.len() as u32,
+        &mut inputs,
+        mem::size_of::<winuser::INPUT>() as i32,
+    )
+}
+
 /// Trigger thef list o key events through the Windows api
 pub fn trigger_keys(
     key_codes: &[u16],
@@ -58,6 +197,36 @@ pub fn trigger_keys(
     )
 }
 
+/// Send key events one at a time with keybd_event, for when SendInput keeps failing. The user's
+/// own input is held back meanwhile where Windows allows it, so it can't come between the events
+pub fn trigger_keys_fallback(key_codes: &[u16], events: &[u32]) {
+    assert_eq!(key_codes.len(), events.len());
+    let blocked = block_input(true).is_ok();
+    for (key_code, event) in key_codes.iter().zip(events.iter()) {
+        keybd_event(*key_code, *event);
+    }
+    if blocked {
+        let _ = block_input(false);
+    }
+}
+
+/// Press and release `paste`, for pasting when none of our hotkeys are still held down
+pub fn send_paste(
+    paste: PasteKeys,
+) -> Result<u32, error_code::ErrorCode<error_code::SystemCategory>> {
+    let (modifiers, key) = paste.keys();
+    let keys: Vec<_> = modifiers
+        .iter()
+        .chain(iter::once(&key))
+        .chain(iter::once(&key))
+        .chain(modifiers.iter().rev())
+        .map(|key| *key as u16)
+        .collect();
+    let mut events = vec![0; modifiers.len() + 1];
+    events.resize(keys.len(), winuser::KEYEVENTF_KEYUP);
+    trigger_keys(&keys, &events)
+}
+
 /// Get the speed at which the keyboard repeats a keystroke
 pub fn get_keyboard_speed() -> Result<u32, error_code::ErrorCode<error_code::SystemCategory>> {
     let mut raw_speed = 0u32;
@@ -89,3 +258,8 @@ pub fn is_key_pressed(
     let mask = 1i16 << 15;
     get_async_key_state(v_key).map(|state| state & mask != 0)
 }
+
+/// Whether `key` is down, taking it to be if that can't be told
+pub fn key_held(key: u16) -> bool {
+    is_key_pressed(key as i32).unwrap_or(true)
+}
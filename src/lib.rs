@@ -1,14 +1,105 @@
+#[cfg(windows)]
+pub mod about;
+#[cfg(windows)]
+mod app;
+#[cfg(windows)]
+pub mod apps;
+#[cfg(windows)]
+pub mod audit;
+#[cfg(windows)]
+pub mod autostart;
+pub mod backend;
+#[cfg(windows)]
 pub mod cli;
 pub mod clipboard_extras;
+#[cfg(windows)]
+pub mod config;
+#[cfg(windows)]
+pub mod config_watch;
+#[cfg(windows)]
+pub mod debug_overlay;
+#[cfg(windows)]
+pub mod delayed_rendering;
+pub mod engine;
+pub mod entry;
+pub mod events;
+#[cfg(windows)]
+pub mod export;
+#[cfg(windows)]
+pub mod file_export;
+#[cfg(windows)]
+pub mod format_usage;
+#[cfg(windows)]
+pub mod formats;
+pub mod history;
+#[cfg(windows)]
+pub mod hotkey;
+pub mod html;
+#[cfg(windows)]
+pub mod instance;
+#[cfg(windows)]
+pub mod ipc;
+#[cfg(windows)]
+pub mod jump_list;
+#[cfg(windows)]
 pub mod key_utils;
+#[cfg(windows)]
+pub mod logging;
+#[cfg(windows)]
+pub mod migrations;
+#[cfg(windows)]
+pub mod monitors;
+pub mod normalize;
+#[cfg(windows)]
+pub mod onboarding;
+#[cfg(windows)]
+pub mod persistence;
+#[cfg(windows)]
+pub mod picker;
+#[cfg(windows)]
+pub mod policy;
+#[cfg(windows)]
+pub mod provenance;
+#[cfg(windows)]
+pub mod repl;
+#[cfg(windows)]
+pub mod restore_check;
+#[cfg(windows)]
+pub mod search;
+#[cfg(windows)]
+pub mod selftest;
+#[cfg(windows)]
+pub mod settings;
+#[cfg(windows)]
+pub mod shared_view;
+#[cfg(windows)]
+pub mod snippets;
+#[cfg(windows)]
+pub mod source_document;
+#[cfg(windows)]
+pub mod stacks;
+#[cfg(windows)]
+pub mod status;
+#[cfg(windows)]
+pub mod storage;
+#[cfg(windows)]
+pub mod stream_deck;
+#[cfg(windows)]
+pub mod telemetry;
+#[cfg(windows)]
+pub mod tray;
+#[cfg(unix)]
+pub mod unix;
+#[cfg(windows)]
+pub mod usage;
+#[cfg(windows)]
+pub mod watchdog;
+#[cfg(windows)]
+pub mod websocket;
+#[cfg(windows)]
 pub mod winapi_functions;
+#[cfg(windows)]
 pub mod window;
 
-use crate::window::Window;
-use cli::Opts;
-
-pub fn run(opts: Opts) {
-    // Create a window and event handler
-    let mut window = Window::new(opts.max_history);
-    window.run_event_loop();
-}
+#[cfg(windows)]
+pub use app::run;
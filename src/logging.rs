@@ -0,0 +1,90 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::audit::format_timestamp;
+use crate::provenance::unix_time;
+
+pub const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// A line of the log: when, how important and where, followed by the message
+fn format_line(timestamp: u64, level: Level, target: &str, message: &fmt::Arguments) -> String {
+    format!(
+        "{} {:<5} {}: {}",
+        format_timestamp(timestamp),
+        level,
+        target,
+        message
+    )
+}
+
+/// Prints each record, also appending it to the log file if there is one
+struct Logger {
+    file: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_line(unix_time(), record.level(), record.target(), record.args());
+        // Release builds have no console, so this only shows when started from a terminal
+        eprintln!("{}", line);
+        if let Some(file) = &self.file {
+            // A panic while writing leaves at worst a partial line, so carry on
+            let mut file = file.lock().unwrap_or_else(|error| error.into_inner());
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file
+                .lock()
+                .unwrap_or_else(|error| error.into_inner())
+                .flush();
+        }
+    }
+}
+
+/// Start logging records up to `level`, appending them to `file` too if it's given. If the file
+/// can't be opened, logging starts without it and the error is returned
+pub fn init(level: LevelFilter, file: Option<&Path>) -> io::Result<()> {
+    let (file, result) = match file
+        .map(|path| OpenOptions::new().create(true).append(true).open(path))
+        .transpose()
+    {
+        Ok(file) => (file, Ok(())),
+        Err(error) => (None, Err(error)),
+    };
+    log::set_max_level(level);
+    // Only the first logger is kept, which is fine as init is only called once at startup
+    let _ = log::set_boxed_logger(Box::new(Logger {
+        file: file.map(Mutex::new),
+    }));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_format() {
+        assert_eq!(
+            format_line(0, Level::Warn, "capture", &format_args!("Skipped {}", 1)),
+            "1970-01-01 00:00:00Z WARN  capture: Skipped 1"
+        );
+    }
+}
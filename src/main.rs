@@ -1,9 +1,26 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
+#[cfg(any(windows, unix))]
 use clap::Clap;
+#[cfg(unix)]
+use filo_clipboard::unix::{self, UnixOpts};
+#[cfg(windows)]
 use filo_clipboard::{cli::Opts, run};
 
+#[cfg(windows)]
 fn main() {
     let opts = Opts::parse();
 
     run(opts);
 }
+
+/// Linux and macOS get the text-only clipboard, driven by commands bound to shortcuts
+#[cfg(unix)]
+fn main() {
+    unix::run(UnixOpts::parse());
+}
+
+#[cfg(not(any(windows, unix)))]
+fn main() {
+    eprintln!("FILO Clipboard only runs on Windows, Linux and macOS");
+    std::process::exit(1);
+}
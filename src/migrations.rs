@@ -0,0 +1,257 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use toml::{value::Table, Value};
+
+use crate::config::{ConfigError, Profile, DEFAULT_PROFILE};
+use crate::persistence;
+use crate::settings::Settings;
+use crate::stacks;
+use crate::storage::{self, StorageKind};
+
+/// The config format this version writes. Files without a version are version 1
+pub const CONFIG_VERSION: u32 = 2;
+/// The changes which bring a config up to the next version, starting from version 1
+const CONFIG_MIGRATIONS: &[fn(&mut Table, &mut Vec<String>)] = &[move_profile_keys];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_level_profile_keys_are_moved() {
+        let upgrade = upgrade_config(
+            r#"
+            active_profile = "work"
+            max_history = 20
+            capture = false
+
+            [profiles.work]
+            capture = true
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(upgrade.from, 1);
+        assert_eq!(upgrade.changes.len(), 2);
+        let value: Value = toml::from_str(&upgrade.text).unwrap();
+        assert_eq!(value["version"].as_integer(), Some(CONFIG_VERSION.into()));
+        assert_eq!(
+            value["profiles"]["work"]["max_history"].as_integer(),
+            Some(20)
+        );
+        assert_eq!(value["profiles"]["work"]["capture"].as_bool(), Some(true));
+        assert!(value.get("capture").is_none());
+    }
+
+    #[test]
+    fn unchanged_config_keeps_its_comments() {
+        let contents = "# My settings\nencrypt = true\n";
+        let upgrade = upgrade_config(contents).unwrap().unwrap();
+        assert!(upgrade.changes.is_empty());
+        assert!(upgrade.text.ends_with(contents));
+        assert!(upgrade_config(&upgrade.text).unwrap().is_none());
+    }
+
+    #[test]
+    fn backup_path_keeps_the_extension() {
+        assert_eq!(
+            backup_path(Path::new("dir/config.toml"), 1),
+            Path::new("dir/config.toml.v1.bak")
+        );
+    }
+}
+
+/// Config text brought up to `CONFIG_VERSION`
+#[derive(Debug)]
+pub struct Upgrade {
+    /// The version the text was written for
+    pub from: u32,
+    pub text: String,
+    /// A description of each setting which was moved or dropped
+    pub changes: Vec<String>,
+}
+
+/// Bring config text written by an older version up to date, or return `None` if it already is
+pub fn upgrade_config(contents: &str) -> Result<Option<Upgrade>, ConfigError> {
+    let mut value: Value = toml::from_str(contents).map_err(ConfigError::Parse)?;
+    let table = match value.as_table_mut() {
+        Some(table) => table,
+        None => return Ok(None),
+    };
+    let from = match table.get("version") {
+        None => 1,
+        Some(Value::Integer(version)) if *version >= 1 => *version as u32,
+        // Left for the config's own parsing to report
+        Some(_) => return Ok(None),
+    };
+    if from >= CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    let mut changes = Vec::new();
+    for migrate in &CONFIG_MIGRATIONS[from as usize - 1..] {
+        migrate(table, &mut changes);
+    }
+    let text = if changes.is_empty() {
+        // Keys at the very start always belong to the top level, so the comments can be kept
+        format!("version = {}\n{}", CONFIG_VERSION, contents)
+    } else {
+        table.insert("version".to_owned(), Value::Integer(CONFIG_VERSION.into()));
+        toml::to_string(&value).expect("The upgraded config to serialize")
+    };
+    Ok(Some(Upgrade {
+        from,
+        text,
+        changes,
+    }))
+}
+
+/// Version 2 only reads profile settings from the `[profiles]` tables, so any given at the top
+/// level are moved into the active profile, unless it sets them itself
+fn move_profile_keys(config: &mut Table, changes: &mut Vec<String>) {
+    let name = config
+        .get("active_profile")
+        .and_then(Value::as_str)
+        .unwrap_or(DEFAULT_PROFILE)
+        .to_owned();
+    for key in Profile::KEYS {
+        let value = match config.remove(*key) {
+            Some(value) => value,
+            None => continue,
+        };
+        let profile = config
+            .entry("profiles")
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .map(|profiles| {
+                profiles
+                    .entry(name.clone())
+                    .or_insert_with(|| Value::Table(Table::new()))
+            })
+            .and_then(Value::as_table_mut);
+        match profile {
+            Some(profile) if profile.contains_key(*key) => changes.push(format!(
+                "{}: dropped, as profiles.{}.{} is set",
+                key, name, key
+            )),
+            Some(profile) => {
+                profile.insert((*key).to_owned(), value);
+                changes.push(format!("{}: moved to profiles.{}.{}", key, name, key));
+            }
+            // Not tables, which the config's own parsing reports
+            None => {
+                config.insert((*key).to_owned(), value);
+            }
+        }
+    }
+}
+
+/// Where the copy of `path` from before it was upgraded from `version` is kept
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(".v{}.bak", version));
+    path.with_file_name(file_name)
+}
+
+/// Copy `path` aside before it's upgraded from `version`. An existing backup is left alone, so the
+/// oldest copy is kept
+fn back_up(path: &Path, version: u32) -> io::Result<PathBuf> {
+    let backup = backup_path(path, version);
+    if !backup.exists() {
+        fs::copy(path, &backup)?;
+    }
+    Ok(backup)
+}
+
+/// Upgrade the config file at `path` in place, describing what changed
+fn upgrade_config_file(path: &Path) -> Result<Option<String>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(format!("{}: {}", path.display(), error)),
+    };
+    let upgrade = match upgrade_config(&contents) {
+        Ok(Some(upgrade)) => upgrade,
+        Ok(None) => return Ok(None),
+        Err(error) => return Err(format!("{}: {}", path.display(), error)),
+    };
+    let backup = back_up(path, upgrade.from)
+        .and_then(|backup| persistence::replace(path, upgrade.text.as_bytes()).map(|_| backup))
+        .map_err(|error| {
+            format!(
+                "{}: could not upgrade the config: {}",
+                path.display(),
+                error
+            )
+        })?;
+    let mut report = format!(
+        "{}: upgraded from version {} to {}, keeping the old file as {}",
+        path.display(),
+        upgrade.from,
+        CONFIG_VERSION,
+        backup.display()
+    );
+    for change in upgrade.changes {
+        report.push_str("\n  ");
+        report.push_str(&change);
+    }
+    Ok(Some(report))
+}
+
+/// Rewrite the history file at `path` in the current format if an older version wrote it
+fn upgrade_history_file(path: &Path, encrypt: bool) -> Result<Option<String>, String> {
+    let upgrade = || -> io::Result<Option<String>> {
+        let from = match persistence::file_version(path)? {
+            Some(version) if version < persistence::VERSION => version,
+            _ => return Ok(None),
+        };
+        let backup = back_up(path, from)?;
+        let mut storage = storage::open(StorageKind::File, path, encrypt)?;
+        let history = storage.load()?;
+        // The pinned entries are marked and last, which is where saving puts them
+        storage.save(&history, &[])?;
+        Ok(Some(format!(
+            "{}: upgraded {} items from version {} to {}, keeping the old file as {}",
+            path.display(),
+            history.len(),
+            from,
+            persistence::VERSION,
+            backup.display()
+        )))
+    };
+    upgrade().map_err(|error| {
+        format!(
+            "{}: could not upgrade the history: {}",
+            path.display(),
+            error
+        )
+    })
+}
+
+/// Upgrade the config file and the history files of the configured stacks which older versions
+/// wrote, returning a description of each file upgraded or the reason it couldn't be
+pub fn upgrade_files(settings: &Settings) -> Vec<Result<String, String>> {
+    let mut results = Vec::new();
+    if let Some(path) = &settings.config_path {
+        results.extend(upgrade_config_file(path).transpose());
+    }
+    // The other kinds of storage have always written the current format
+    if let (Some(persist), StorageKind::File) = (&settings.persist, settings.storage) {
+        let names = Some(stacks::DEFAULT_STACK).into_iter().chain(
+            settings
+                .config
+                .stacks
+                .keys()
+                .map(String::as_str)
+                .filter(|name| *name != stacks::DEFAULT_STACK),
+        );
+        for name in names {
+            let path = stacks::stack_path(persist, name);
+            results.extend(upgrade_history_file(&path, settings.encrypt).transpose());
+        }
+    }
+    results
+}
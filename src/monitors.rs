@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+/// The start of the name of each monitor's stack, which ends with the monitor's number
+const STACK_PREFIX: &str = "monitor-";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stacks_are_named_by_number() {
+        assert_eq!(stack_name(r"\\.\DISPLAY2"), Some("monitor-2".to_owned()));
+        assert_eq!(stack_name(r"\\.\DISPLAY12"), Some("monitor-12".to_owned()));
+        assert_eq!(stack_name("DISPLAY"), None);
+    }
+
+    #[test]
+    fn names_are_looked_up_once() {
+        let mut stacks = MonitorStacks::default();
+        assert_eq!(
+            stacks.stack(1, || Some(r"\\.\DISPLAY1".to_owned())),
+            Some("monitor-1".to_owned())
+        );
+        assert_eq!(
+            stacks.stack(1, || panic!("looked up again")),
+            Some("monitor-1".to_owned())
+        );
+        assert_eq!(stacks.stack(2, || None), None);
+        stacks.clear();
+        assert_eq!(
+            stacks.stack(1, || Some(r"\\.\DISPLAY3".to_owned())),
+            Some("monitor-3".to_owned())
+        );
+    }
+}
+
+/// Which stack each monitor uses, keyed by monitor handle so the monitor's name is only looked up
+/// the first time it's seen. Windows hands out new handles when displays are added, removed or
+/// rearranged, so the registry should be cleared then
+#[derive(Debug, Default)]
+pub struct MonitorStacks {
+    stacks: HashMap<isize, String>,
+}
+
+impl MonitorStacks {
+    /// The stack of the monitor with handle `monitor`, calling `device_name` for its name if it
+    /// hasn't been seen yet. Returns `None` if the name can't be found or has no number
+    pub fn stack(
+        &mut self,
+        monitor: isize,
+        device_name: impl FnOnce() -> Option<String>,
+    ) -> Option<String> {
+        if let Some(stack) = self.stacks.get(&monitor) {
+            return Some(stack.clone());
+        }
+        let stack = stack_name(&device_name()?)?;
+        self.stacks.insert(monitor, stack.clone());
+        Some(stack)
+    }
+
+    pub fn clear(&mut self) {
+        self.stacks.clear();
+    }
+}
+
+/// The stack for a monitor device such as `\\.\DISPLAY2`, named after its number: "monitor-2"
+fn stack_name(device: &str) -> Option<String> {
+    let number = device.trim_start_matches(|c: char| !c.is_ascii_digit());
+    (!number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+        .then(|| format!("{}{}", STACK_PREFIX, number))
+}
@@ -0,0 +1,171 @@
+use std::borrow::Cow;
+
+use crate::clipboard_extras::{
+    read_unicode_text, ClipboardItem, CF_OEMTEXT, CF_TEXT, CF_UNICODETEXT,
+};
+use crate::html;
+#[cfg(windows)]
+use crate::winapi_functions::register_clipboard_format;
+
+/// Registered formats which some programs fill with something new on every copy, such as a token
+/// for the page it came from or a pointer to their own data object, so they say nothing about
+/// whether two items hold the same thing
+#[cfg(windows)]
+const VOLATILE_FORMATS: &[&str] = &[
+    "Ole Private Data",
+    "DataObject",
+    "Chromium internal source RFH token",
+];
+
+thread_local! {
+    /// The ids of the formats compared specially, looked up the first time they're needed
+    static FORMATS: Formats = Formats::lookup();
+}
+
+/// The formats compared specially
+struct Formats {
+    html: Option<u32>,
+    volatile: Vec<u32>,
+}
+
+impl Formats {
+    #[cfg(windows)]
+    fn lookup() -> Self {
+        Self {
+            html: register_clipboard_format(html::FORMAT_NAME).ok(),
+            volatile: VOLATILE_FORMATS
+                .iter()
+                .filter_map(|name| register_clipboard_format(name).ok())
+                .collect(),
+        }
+    }
+
+    /// Other systems have no registered formats to look up
+    #[cfg(not(windows))]
+    fn lookup() -> Self {
+        Self {
+            html: None,
+            volatile: Vec::new(),
+        }
+    }
+}
+
+/// What an item is compared by when looking for repeats: text with its line endings and trailing
+/// whitespace evened out, HTML without its description header, and anything else as it is. None
+/// for volatile formats, which are left out of comparisons. The item itself is never changed, so
+/// what's stored and pasted keeps the exact bytes
+pub fn comparable(item: &ClipboardItem) -> Option<Cow<'_, [u8]>> {
+    FORMATS.with(|formats| comparable_with(item, formats))
+}
+
+fn comparable_with<'a>(item: &'a ClipboardItem, formats: &Formats) -> Option<Cow<'a, [u8]>> {
+    if formats.volatile.contains(&item.format) {
+        return None;
+    }
+    let text = match item.format {
+        CF_UNICODETEXT => normalize_text(read_unicode_text(item).unwrap_or_default().as_bytes()),
+        CF_TEXT | CF_OEMTEXT => normalize_text(&item.content),
+        format if Some(format) == formats.html => normalize_text(html::document(&item.content)),
+        _ => return Some(Cow::Borrowed(&item.content)),
+    };
+    Some(Cow::Owned(text))
+}
+
+/// Text up to its null terminator, with every line ending as "\n" and the whitespace at the ends of
+/// lines and of the text dropped. Only ASCII bytes are changed, so UTF-8 stays valid
+fn normalize_text(text: &[u8]) -> Vec<u8> {
+    let text = text.split(|byte| *byte == 0).next().unwrap_or_default();
+    let mut unified = Vec::with_capacity(text.len());
+    let mut bytes = text.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'\r' if bytes.peek() == Some(&&b'\n') => {}
+            b'\r' => unified.push(b'\n'),
+            byte => unified.push(byte),
+        }
+    }
+    let mut normalized = unified
+        .split(|byte| *byte == b'\n')
+        .map(|line| {
+            let end = line
+                .iter()
+                .rposition(|byte| !byte.is_ascii_whitespace())
+                .map_or(0, |last| last + 1);
+            &line[..end]
+        })
+        .collect::<Vec<_>>()
+        .join(&b'\n');
+    while normalized.last() == Some(&b'\n') {
+        normalized.pop();
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: u32 = 0xC100;
+    const VOLATILE: u32 = 0xC101;
+
+    fn formats() -> Formats {
+        Formats {
+            html: Some(HTML),
+            volatile: vec![VOLATILE],
+        }
+    }
+
+    fn item(format: u32, content: &[u8]) -> ClipboardItem {
+        ClipboardItem {
+            format,
+            content: content.to_vec(),
+        }
+    }
+
+    #[test]
+    fn line_endings_and_trailing_whitespace_are_ignored() {
+        assert_eq!(
+            normalize_text(b"one \r\ntwo\t\rthree\n\n\0junk"),
+            b"one\ntwo\nthree"
+        );
+        assert_eq!(normalize_text(b"  indented\n"), b"  indented");
+        assert_eq!(normalize_text(b""), b"");
+    }
+
+    #[test]
+    fn text_formats_are_normalized() {
+        let formats = formats();
+        let unicode: Vec<u8> = "text\r\n\0"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let unicode = item(CF_UNICODETEXT, &unicode);
+        let comparable = comparable_with(&unicode, &formats);
+        assert_eq!(comparable.as_deref(), Some(&b"text"[..]));
+        let ansi = item(CF_TEXT, b"text \n\0");
+        let comparable = comparable_with(&ansi, &formats);
+        assert_eq!(comparable.as_deref(), Some(&b"text"[..]));
+    }
+
+    #[test]
+    fn html_header_is_ignored() {
+        let formats = formats();
+        let first = b"Version:0.9\r\nStartHTML:0000000030\r\n<html>a</html>\r\n";
+        let second = b"Version:1.0\r\nStartHTML:31\r\n<html>a</html>";
+        assert_eq!(
+            comparable_with(&item(HTML, first), &formats),
+            comparable_with(&item(HTML, second), &formats)
+        );
+    }
+
+    #[test]
+    fn volatile_formats_are_left_out() {
+        let formats = formats();
+        assert_eq!(comparable_with(&item(VOLATILE, b"1234"), &formats), None);
+        let other = item(0xC102, b"data \r\n");
+        assert_eq!(
+            comparable_with(&other, &formats).as_deref(),
+            Some(&b"data \r\n"[..])
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use log::warn;
+use winapi::um::winuser;
+
+use crate::settings::Settings;
+use crate::winapi_functions::message_box_w;
+
+/// Written next to the config file once the user asks not to see the tour again
+const MARKER_FILE_NAME: &str = "tour-seen";
+
+pub struct Page {
+    pub title: String,
+    pub text: String,
+}
+
+/// A few pages explaining what's going on, as the clipboard has no window of its own
+pub struct Tour {
+    pages: Vec<Page>,
+    /// Where to record that the tour shouldn't be shown again, if anywhere
+    marker: Option<PathBuf>,
+}
+
+impl Tour {
+    pub fn new(settings: &Settings) -> Self {
+        let pages = vec![
+            Page {
+                title: "Welcome to FILO Clipboard".to_owned(),
+                text: "FILO Clipboard is now running in the background. Everything you copy is \
+                       added to a stack, and the icon in the notification area shows how many \
+                       items are waiting to be pasted."
+                    .to_owned(),
+            },
+            Page {
+                title: "Hotkeys".to_owned(),
+                text: format!(
+                    "{}\tpaste the next item and remove it\n\
+                     {}\tpaste the next item and keep it\n\
+                     {}\tchoose an item from a list\n\
+                     {}\tpaste the next item as plain text\n\
+                     {}\tput the last pasted item back\n\
+                     {} / {}\tturn to the next or previous item\n\
+                     {}\tpaste the pinned items in turn\n\
+                     {}\tsearch the history\n\
+                     {}\tpaste a snippet\n\
+                     {}\tswitch to the next stack\n\
+                     {}\tpaste the oldest item and move to the next field",
+                    settings.hotkey,
+                    settings.peek_hotkey,
+                    settings.picker_hotkey,
+                    settings.plain_paste_hotkey,
+                    settings.undo_hotkey,
+                    settings.next_hotkey,
+                    settings.previous_hotkey,
+                    settings.pinned_hotkey,
+                    settings.search_hotkey,
+                    settings.snippet_hotkey,
+                    settings.stack_hotkey,
+                    settings.queue_hotkey
+                ),
+            },
+            Page {
+                title: "Tray menu".to_owned(),
+                text: "Right click the icon in the notification area to pause or resume \
+                       recording, clear the history, save the next item to a file, switch \
+                       profile or exit."
+                    .to_owned(),
+            },
+            Page {
+                title: "Privacy".to_owned(),
+                text: "The history stays on this computer and, unless persist is set in the \
+                       config, only in memory. Pause recording from the tray menu before copying \
+                       anything sensitive, keep password managers out with exclude_apps, or press \
+                       E in the picker to wipe an item after five minutes."
+                    .to_owned(),
+            },
+        ];
+        Self {
+            pages,
+            marker: settings.config_path.as_deref().map(marker_path),
+        }
+    }
+
+    /// Whether the tour should be shown when the clipboard starts
+    pub fn is_due(&self) -> bool {
+        self.marker
+            .as_ref()
+            .map(|marker| !marker.exists())
+            .unwrap_or(false)
+    }
+
+    /// Show each page in turn, then ask whether to show the tour again next time. Cancelling skips
+    /// straight to the question
+    pub fn show(&self) -> Result<(), String> {
+        for page in self.pages.iter() {
+            let button = message_box_w(
+                &page.text,
+                &page.title,
+                winuser::MB_OKCANCEL | winuser::MB_ICONINFORMATION,
+            )
+            .map_err(|error| error.to_string())?;
+            if button != winuser::IDOK {
+                break;
+            }
+        }
+        let button = message_box_w(
+            "Show this tour next time FILO Clipboard starts?",
+            "FILO Clipboard",
+            winuser::MB_YESNO | winuser::MB_ICONQUESTION,
+        )
+        .map_err(|error| error.to_string())?;
+        let result = match (&self.marker, button) {
+            (Some(marker), winuser::IDNO) => marker
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(marker, "")),
+            (Some(marker), _) if marker.exists() => fs::remove_file(marker),
+            _ => Ok(()),
+        };
+        result.map_err(|error| error.to_string())
+    }
+
+    /// Show the tour on its own thread, so the clipboard works while it's open
+    pub fn spawn(self) {
+        thread::spawn(move || {
+            if let Err(error) = self.show() {
+                warn!("Could not show the tour: {}", error);
+            }
+        });
+    }
+}
+
+/// The file recording that the tour has been dismissed, which lives next to the config file
+pub fn marker_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(MARKER_FILE_NAME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marker_next_to_config() {
+        assert_eq!(
+            marker_path(Path::new(
+                r"C:\Users\a\AppData\Roaming\filo-clipboard\config.toml"
+            )),
+            Path::new(r"C:\Users\a\AppData\Roaming\filo-clipboard\tour-seen")
+        );
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::VecDeque,
+    convert::{TryFrom, TryInto},
+    fmt, fs, io,
+    path::Path,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clipboard_extras::ClipboardItem;
+use crate::entry::Entry;
+use crate::storage::Storage;
+use crate::winapi_functions::{crypt_protect_data, crypt_unprotect_data};
+
+const MAGIC: &[u8; 4] = b"FILO";
+/// Encrypted data starts with this, followed by data encrypted with DPAPI. Older versions encrypted
+/// the whole file, newer ones the contents of each item
+const ENCRYPTED_MAGIC: &[u8; 4] = b"FILP";
+pub(crate) const VERSION: u32 = 3;
+/// Version 2 files have no checksum for each entry
+const VERSION_WITHOUT_CHECKSUMS: u32 = 2;
+/// Version 1 files have no flags either
+const VERSION_WITHOUT_FLAGS: u32 = 1;
+const FLAG_PINNED: u32 = 1;
+const FLAG_REMOTE: u32 = 2;
+const FLAG_BURN: u32 = 4;
+
+pub type History = VecDeque<Entry>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_round_trip() {
+        let mut history = History::new();
+        history.push_back(Entry::new(vec![
+            ClipboardItem {
+                format: 1,
+                content: b"text\0".to_vec(),
+            },
+            ClipboardItem {
+                format: 13,
+                content: vec![b't', 0, 0, 0],
+            },
+        ]));
+        history.push_back(Entry::new(vec![ClipboardItem {
+            format: 8,
+            content: vec![0xFF; 100],
+        }]));
+
+        let data = encode(&history, &[]);
+        for entry in history.iter_mut() {
+            entry.checksum = Some(entry.fingerprint());
+        }
+        assert_eq!(decode(&data).unwrap(), history);
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut history = History::new();
+        history.push_back(Entry::new(vec![ClipboardItem {
+            format: 1,
+            content: b"text\0".to_vec(),
+        }]));
+        let mut data = encode(&history, &[]);
+        let last = data.len() - 2;
+        data[last] = b'X';
+        let decoded = decode(&data).unwrap();
+        assert!(!decoded[0].is_intact());
+    }
+
+    #[test]
+    fn pinned_entries_are_kept() {
+        let mut history = History::new();
+        history.push_back(Entry::new(vec![ClipboardItem {
+            format: 1,
+            content: b"recent\0".to_vec(),
+        }]));
+        history[0].burn = true;
+        let mut pinned = Entry::new(vec![ClipboardItem {
+            format: 1,
+            content: b"pinned\0".to_vec(),
+        }]);
+        pinned.pinned = true;
+        pinned.remote = true;
+
+        let decoded = decode(&encode(&history, &[pinned.clone()])).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].items, history[0].items);
+        assert!(!decoded[0].pinned);
+        assert!(!decoded[0].remote);
+        assert!(decoded[0].burn);
+        assert_eq!(decoded[1].items, pinned.items);
+        assert!(decoded[1].pinned);
+        assert!(decoded[1].remote);
+        assert!(!decoded[1].burn);
+    }
+
+    #[test]
+    fn version_1_files_are_read() {
+        let mut data = MAGIC.to_vec();
+        for field in &[VERSION_WITHOUT_FLAGS, 1, 1, 1] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&5u64.to_le_bytes());
+        data.extend_from_slice(b"text\0");
+        let history = decode(&data).unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(!history[0].pinned);
+        assert_eq!(history[0].items[0].content, b"text\0");
+    }
+
+    #[test]
+    fn unencrypted_data_is_left_alone() {
+        let data = encode(&History::new(), &[]);
+        assert_eq!(decrypt(data.clone()).unwrap(), data);
+    }
+
+    #[test]
+    fn truncated_data_is_invalid() {
+        let mut history = History::new();
+        history.push_back(Entry::new(vec![ClipboardItem {
+            format: 1,
+            content: b"text\0".to_vec(),
+        }]));
+        let data = encode(&history, &[]);
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        assert!(decode(b"JUNK").is_err());
+    }
+
+    #[test]
+    fn parse_flush_strategy() {
+        assert_eq!("change".parse(), Ok(FlushStrategy::OnChange));
+        assert_eq!("exit".parse(), Ok(FlushStrategy::OnExit));
+        assert_eq!(
+            "30s".parse(),
+            Ok(FlushStrategy::Interval(Duration::from_secs(30)))
+        );
+        assert!("0s".parse::<FlushStrategy>().is_err());
+        assert!("sometimes".parse::<FlushStrategy>().is_err());
+    }
+}
+
+/// When the history is written to disk
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum FlushStrategy {
+    /// After every change
+    #[default]
+    OnChange,
+    /// At most once per interval, if anything changed
+    Interval(Duration),
+    /// Only when the program exits
+    OnExit,
+}
+
+impl std::str::FromStr for FlushStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "change" => Ok(FlushStrategy::OnChange),
+            "exit" => Ok(FlushStrategy::OnExit),
+            _ => match value.strip_suffix('s').map(str::parse) {
+                Some(Ok(seconds)) if seconds > 0 => {
+                    Ok(FlushStrategy::Interval(Duration::from_secs(seconds)))
+                }
+                _ => Err(format!(
+                    "expected \"change\", \"exit\" or an interval such as \"30s\", found \"{}\"",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for FlushStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlushStrategy::OnChange => write!(f, "change"),
+            FlushStrategy::Interval(interval) => write!(f, "{}s", interval.as_secs()),
+            FlushStrategy::OnExit => write!(f, "exit"),
+        }
+    }
+}
+
+impl TryFrom<String> for FlushStrategy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<FlushStrategy> for String {
+    fn from(strategy: FlushStrategy) -> Self {
+        strategy.to_string()
+    }
+}
+
+/// Serialize the history followed by the pinned entries: a magic number and version, then each
+/// entry as written by `encode_entry`
+fn encode(history: &History, pinned: &[Entry]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&((history.len() + pinned.len()) as u32).to_le_bytes());
+    for entry in history.iter().chain(pinned) {
+        encode_entry(&mut data, entry);
+    }
+    data
+}
+
+pub(crate) fn flags(entry: &Entry) -> u32 {
+    let mut flags = 0;
+    if entry.pinned {
+        flags |= FLAG_PINNED;
+    }
+    if entry.remote {
+        flags |= FLAG_REMOTE;
+    }
+    if entry.burn {
+        flags |= FLAG_BURN;
+    }
+    flags
+}
+
+pub(crate) fn set_flags(entry: &mut Entry, flags: u32) {
+    entry.pinned = flags & FLAG_PINNED != 0;
+    entry.remote = flags & FLAG_REMOTE != 0;
+    entry.burn = flags & FLAG_BURN != 0;
+}
+
+/// Append an entry as its flags, its fingerprint as a checksum and a count of items followed by
+/// each item's format, length and content
+pub(crate) fn encode_entry(data: &mut Vec<u8>, entry: &Entry) {
+    data.extend_from_slice(&flags(entry).to_le_bytes());
+    data.extend_from_slice(&entry.fingerprint().to_le_bytes());
+    data.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+    for item in entry.iter() {
+        data.extend_from_slice(&item.format.to_le_bytes());
+        data.extend_from_slice(&(item.content.len() as u64).to_le_bytes());
+        data.extend_from_slice(&item.content);
+    }
+}
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Reads the fields of encoded data in turn
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub(crate) fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        let truncated = || invalid("the history file is truncated");
+        let end = self.position.checked_add(length).ok_or_else(truncated)?;
+        let bytes = self.data.get(self.position..end).ok_or_else(truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// How much has been read so far
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+}
+
+fn decode(data: &[u8]) -> io::Result<History> {
+    let mut reader = Reader::new(data);
+    if reader.take(4)? != MAGIC {
+        return Err(invalid("not a history file"));
+    }
+    let version = reader.u32()?;
+    if ![VERSION, VERSION_WITHOUT_CHECKSUMS, VERSION_WITHOUT_FLAGS].contains(&version) {
+        return Err(invalid("unsupported history file version"));
+    }
+
+    let entry_count = reader.u32()?;
+    let mut history = History::new();
+    for _ in 0..entry_count {
+        history.push_back(decode_entry(&mut reader, version)?);
+    }
+    Ok(history)
+}
+
+/// Read an entry written by `encode_entry`, or by an older version of it
+pub(crate) fn decode_entry(reader: &mut Reader<'_>, version: u32) -> io::Result<Entry> {
+    let flags = if version == VERSION_WITHOUT_FLAGS {
+        0
+    } else {
+        reader.u32()?
+    };
+    let checksum = if version == VERSION {
+        Some(reader.u64()?)
+    } else {
+        None
+    };
+    let item_count = reader.u32()?;
+    let mut items = Vec::new();
+    for _ in 0..item_count {
+        let format = reader.u32()?;
+        let length = usize::try_from(reader.u64()?).map_err(|_| invalid("item is too large"))?;
+        items.push(ClipboardItem {
+            format,
+            content: reader.take(length)?.to_vec(),
+        });
+    }
+    // Anything worked out from the items is recalculated rather than stored
+    let mut entry = Entry::new(items);
+    set_flags(&mut entry, flags);
+    // Checked before the entry is put on the clipboard, so a damaged file isn't pasted
+    entry.checksum = checksum;
+    Ok(entry)
+}
+
+/// Encrypt data for the current user, marking it so `decrypt` recognises it
+pub(crate) fn encrypt(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encrypted = ENCRYPTED_MAGIC.to_vec();
+    encrypted.extend(
+        crypt_protect_data(data).map_err(|error| {
+            io::Error::other(format!("could not encrypt the history: {}", error))
+        })?,
+    );
+    Ok(encrypted)
+}
+
+/// Decrypt data written by `encrypt`. Anything else is returned as it is, so data written with
+/// encryption switched off can still be read
+pub(crate) fn decrypt(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match data.strip_prefix(ENCRYPTED_MAGIC) {
+        Some(encrypted) => crypt_unprotect_data(encrypted).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "could not decrypt the history, which only the user who wrote it can do: {}",
+                    error
+                ),
+            )
+        }),
+        None => Ok(data),
+    }
+}
+
+/// Read the history and pinned entries from `path`, with the pinned entries marked and last. A
+/// missing file is treated as an empty history. Files encrypted as a whole, as older versions
+/// did, are decrypted
+pub fn load(path: &Path) -> io::Result<History> {
+    match fs::read(path) {
+        Ok(data) => decode(&decrypt(data)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(History::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// The format version of the history file at `path`, or `None` if there isn't one
+pub fn file_version(path: &Path) -> io::Result<Option<u32>> {
+    let data = match fs::read(path) {
+        Ok(data) => decrypt(data)?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let mut reader = Reader::new(&data);
+    if reader.take(4)? != MAGIC {
+        return Err(invalid("not a history file"));
+    }
+    reader.u32().map(Some)
+}
+
+/// Write the history and pinned entries to `path`. The old file is only replaced once the new one
+/// is complete
+pub fn save(path: &Path, history: &History, pinned: &[Entry]) -> io::Result<()> {
+    replace(path, &encode(history, pinned))
+}
+
+/// Write `data` to `path` through a temporary file, so a crash part way leaves the old file whole
+pub(crate) fn replace(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, data)?;
+    fs::rename(&temp_path, path)
+}
+
+/// Keeps track of whether the history needs writing to its storage, following the flush strategy
+pub struct Store {
+    storage: Box<dyn Storage>,
+    strategy: FlushStrategy,
+    dirty: bool,
+}
+
+impl Store {
+    pub fn new(storage: Box<dyn Storage>, strategy: FlushStrategy) -> Self {
+        Self {
+            storage,
+            strategy,
+            dirty: false,
+        }
+    }
+
+    pub fn strategy(&self) -> FlushStrategy {
+        self.strategy
+    }
+
+    pub fn load(&mut self) -> io::Result<History> {
+        self.storage.load()
+    }
+
+    /// Record that the history has changed, writing it straight away if the strategy requires
+    pub fn changed(&mut self, history: &History, pinned: &[Entry]) -> io::Result<()> {
+        self.dirty = true;
+        match self.strategy {
+            FlushStrategy::OnChange => self.flush(history, pinned),
+            FlushStrategy::Interval(_) | FlushStrategy::OnExit => Ok(()),
+        }
+    }
+
+    /// Write the history if it has changed since it was last written
+    pub fn flush(&mut self, history: &History, pinned: &[Entry]) -> io::Result<()> {
+        if self.dirty {
+            self.storage.save(history, pinned)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+use std::{
+    mem, ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use winapi::shared::{
+    minwindef::{LPARAM, LRESULT, UINT, WPARAM},
+    windef::{HBITMAP__, HWND, HWND__},
+    winerror,
+};
+use winapi::um::{wingdi, winuser};
+
+use crate::clipboard_extras::Dib;
+use crate::entry::Entry;
+use crate::search;
+use crate::winapi_functions::{
+    create_dib_bitmap, create_window_ex_w, delete_bitmap, destroy_window, get_cursor_pos,
+    get_foreground_window, get_stock_object, get_system_metrics, get_window_text_w,
+    is_window_or_child, move_window, post_message_a, register_class_ex_w, send_message_w,
+    set_focus, set_foreground_window, show_window, to_wide, SystemError,
+};
+
+/// Posted to the picker when it should close without a choice being made
+const WM_PICKER_CANCEL: u32 = winuser::WM_APP + 3;
+/// Posted to the picker to move the selection by `wParam` items, as an isize
+const WM_PICKER_MOVE: u32 = winuser::WM_APP + 6;
+/// Posted to the picker to paste the selected item
+const WM_PICKER_CONFIRM: u32 = winuser::WM_APP + 7;
+
+const CLASS_NAME: &str = "filo-clipboard_picker";
+const WIDTH: i32 = 420;
+const MAX_VISIBLE_ITEMS: usize = 15;
+const LABEL_LENGTH: usize = 80;
+/// The width and height images are shrunk to fit beside the list
+const THUMBNAIL_SIZE: usize = 96;
+
+/// The picker's window while it's open, or 0, so other threads can steer it
+static OPEN: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard_extras::ClipboardItem;
+
+    #[test]
+    fn label_uses_the_given_number() {
+        let entry = Entry::new(vec![ClipboardItem {
+            format: winuser::CF_TEXT,
+            content: b"text\0".to_vec(),
+        }]);
+        assert_eq!(label(9, &entry), "9\ttext");
+    }
+}
+
+/// What to do with the item chosen in the picker
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Choice {
+    Paste(usize),
+    /// Wipe the item once the default expiry has passed
+    Expire(usize),
+    /// Move the item into the pinned items
+    Pin(usize),
+    /// Wipe the item once it's been pasted
+    Burn(usize),
+    /// Paste the item, then remove it from the history as though it had been popped
+    PasteAndRemove(usize),
+    /// Save a copy of the item as a snippet
+    Snippet(usize),
+}
+
+impl Choice {
+    /// The same choice for the item `map` gives, such as from its row in the picker to its
+    /// position in the history
+    pub fn map(self, map: impl FnOnce(usize) -> usize) -> Self {
+        match self {
+            Choice::Paste(index) => Choice::Paste(map(index)),
+            Choice::Expire(index) => Choice::Expire(map(index)),
+            Choice::Pin(index) => Choice::Pin(map(index)),
+            Choice::Burn(index) => Choice::Burn(map(index)),
+            Choice::PasteAndRemove(index) => Choice::PasteAndRemove(map(index)),
+            Choice::Snippet(index) => Choice::Snippet(map(index)),
+        }
+    }
+}
+
+/// The line shown for a history item: its number, as `list` shows it, and a summary of its contents
+pub fn label(number: usize, entry: &Entry) -> String {
+    format!("{}\t{}", number, entry.summary(LABEL_LENGTH))
+}
+
+/// A small copy of the item's image to show beside the list when it's selected, if it has one
+pub fn thumbnail(entry: &Entry) -> Option<Vec<u8>> {
+    entry.thumbnail(THUMBNAIL_SIZE)
+}
+
+/// The picture beside the list, showing the selected item's thumbnail
+struct Preview {
+    view: &'static mut HWND__,
+    /// A bitmap for each item which has an image
+    bitmaps: Vec<Option<&'static mut HBITMAP__>>,
+    shown: Option<usize>,
+}
+
+impl Preview {
+    /// Add the picture to `window` at `x`, if any of the items have a thumbnail
+    fn new(
+        window: &mut HWND__,
+        thumbnails: &[Option<Vec<u8>>],
+        x: i32,
+    ) -> Result<Option<Self>, SystemError> {
+        if thumbnails.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+        let view = create_window_ex_w(
+            0,
+            "STATIC",
+            "",
+            winuser::WS_CHILD | winuser::WS_VISIBLE | winuser::SS_BITMAP | winuser::SS_CENTERIMAGE,
+            x,
+            0,
+            THUMBNAIL_SIZE as i32,
+            THUMBNAIL_SIZE as i32,
+            Some(window),
+        )?;
+        let bitmaps = thumbnails
+            .iter()
+            .map(|thumbnail| {
+                let thumbnail = thumbnail.as_deref()?;
+                let pixel_offset = Dib::parse(thumbnail)?.pixel_offset();
+                create_dib_bitmap(thumbnail, pixel_offset).ok()
+            })
+            .collect();
+        Ok(Some(Self {
+            view,
+            bitmaps,
+            shown: None,
+        }))
+    }
+
+    /// Show the thumbnail of the item at `index`, or nothing if it has none
+    fn show(&mut self, index: Option<usize>) {
+        if index == self.shown {
+            return;
+        }
+        self.shown = index;
+        let bitmap = index
+            .and_then(|index| self.bitmaps.get_mut(index))
+            .and_then(Option::as_deref_mut)
+            .map_or(ptr::null_mut(), |bitmap| bitmap as *mut HBITMAP__);
+        send_message_w(
+            self.view,
+            winuser::STM_SETIMAGE,
+            winuser::IMAGE_BITMAP as usize,
+            bitmap as isize,
+        );
+    }
+}
+
+impl Drop for Preview {
+    fn drop(&mut self) {
+        for bitmap in self.bitmaps.iter_mut().flatten() {
+            delete_bitmap(bitmap);
+        }
+    }
+}
+
+unsafe extern "system" fn window_proc(
+    h_wnd: HWND,
+    msg: UINT,
+    w_param: WPARAM,
+    l_param: LPARAM,
+) -> LRESULT {
+    match msg {
+        // Closing the picker or clicking away from it cancels it. The window is only destroyed once
+        // the message loop in `pick` has seen the cancellation
+        winuser::WM_CLOSE => {
+            let _ = post_message_a(&mut *h_wnd, WM_PICKER_CANCEL, 0, 0);
+            0
+        }
+        winuser::WM_ACTIVATE if w_param & 0xFFFF == winuser::WA_INACTIVE as usize => {
+            let _ = post_message_a(&mut *h_wnd, WM_PICKER_CANCEL, 0, 0);
+            winuser::DefWindowProcW(h_wnd, msg, w_param, l_param)
+        }
+        _ => winuser::DefWindowProcW(h_wnd, msg, w_param, l_param),
+    }
+}
+
+fn register_class() -> Result<(), SystemError> {
+    let class_name = to_wide(CLASS_NAME);
+    let class = winuser::WNDCLASSEXW {
+        cbSize: mem::size_of::<winuser::WNDCLASSEXW>() as u32,
+        lpfnWndProc: Some(window_proc),
+        lpszClassName: class_name.as_ptr(),
+        ..Default::default()
+    };
+    match register_class_ex_w(&class) {
+        Err(error) if error.raw_code() as u32 == winerror::ERROR_CLASS_ALREADY_EXISTS => Ok(()),
+        result => result.map(|_| ()),
+    }
+}
+
+/// Show the labels in a list at the cursor and wait for the user to choose one, returning its index.
+/// The selected item's thumbnail, if it has one, is shown beside the list. Enter, a double click or
+/// a digit followed by Enter chooses an item, E marks it to expire, P pins it, S saves it as a
+/// snippet, and Escape or clicking away cancels. Messages for other windows which arrive in the
+/// meantime are posted again afterwards
+pub fn pick(
+    labels: &[String],
+    thumbnails: &[Option<Vec<u8>>],
+) -> Result<Option<Choice>, SystemError> {
+    open_modal(|window| {
+        fill_window(window, labels, thumbnails)
+            .map(|(list, mut preview)| run_modal_loop(window, list, preview.as_mut()))
+    })
+}
+
+/// Show the labels under a text box at the cursor, narrowing them to the items whose `texts` match
+/// what's typed, as in `search::filter`. Up and Down move the selection, Enter chooses the selected
+/// item, Shift+Enter chooses it to be removed after pasting, and Escape or clicking away cancels
+pub fn search(labels: &[String], texts: &[String]) -> Result<Option<Choice>, SystemError> {
+    open_modal(|window| {
+        fill_search_window(window, labels.len())
+            .map(|(edit, list)| run_search_loop(window, edit, list, labels, texts))
+    })
+}
+
+/// Create the popup, let `run` fill it and wait for a choice, then close it again
+fn open_modal(
+    run: impl FnOnce(&mut HWND__) -> Result<Option<Choice>, SystemError>,
+) -> Result<Option<Choice>, SystemError> {
+    register_class()?;
+    let previous_window = get_foreground_window();
+
+    let window = create_window_ex_w(
+        winuser::WS_EX_TOPMOST | winuser::WS_EX_TOOLWINDOW,
+        CLASS_NAME,
+        "FILO Clipboard",
+        winuser::WS_POPUP | winuser::WS_BORDER,
+        0,
+        0,
+        WIDTH,
+        0,
+        None,
+    )?;
+    OPEN.store(window as *mut HWND__ as usize, Ordering::SeqCst);
+    let result = run(window);
+    OPEN.store(0, Ordering::SeqCst);
+    let _ = destroy_window(window);
+
+    // Give the focus back so the chosen item is pasted where the user was typing
+    if let Some(previous_window) = previous_window {
+        set_foreground_window(previous_window);
+    }
+    result
+}
+
+/// Add the list of labels and the thumbnail preview to the window, then size it and show it at the
+/// cursor
+fn fill_window(
+    window: &mut HWND__,
+    labels: &[String],
+    thumbnails: &[Option<Vec<u8>>],
+) -> Result<(&'static mut HWND__, Option<Preview>), SystemError> {
+    let list = create_list(window)?;
+    for label in labels {
+        let label = to_wide(label);
+        send_message_w(list, winuser::LB_ADDSTRING, 0, label.as_ptr() as isize);
+    }
+    send_message_w(list, winuser::LB_SETCURSEL, 0, 0);
+
+    let item_height = send_message_w(list, winuser::LB_GETITEMHEIGHT, 0, 0) as i32;
+    let border = get_system_metrics(winuser::SM_CYBORDER);
+    let list_width = WIDTH - 2 * border;
+    let mut preview = Preview::new(window, thumbnails, list_width)?;
+    let (width, min_height) = match preview {
+        Some(_) => (WIDTH + THUMBNAIL_SIZE as i32, THUMBNAIL_SIZE as i32),
+        None => (WIDTH, 0),
+    };
+    let list_height =
+        (item_height * labels.len().clamp(1, MAX_VISIBLE_ITEMS) as i32 + 4).max(min_height);
+
+    move_window(list, 0, 0, list_width, list_height)?;
+    if let Some(preview) = preview.as_mut() {
+        preview.show(Some(0));
+    }
+    show_at_cursor(window, width, list_height + 2 * border)?;
+    set_focus(list);
+    Ok((list, preview))
+}
+
+/// Add a text box with the list below it, sized for `count` items, then size the window and show it
+/// at the cursor
+fn fill_search_window(
+    window: &mut HWND__,
+    count: usize,
+) -> Result<(&'static mut HWND__, &'static mut HWND__), SystemError> {
+    let edit = create_window_ex_w(
+        winuser::WS_EX_CLIENTEDGE,
+        "EDIT",
+        "",
+        winuser::WS_CHILD | winuser::WS_VISIBLE | winuser::ES_AUTOHSCROLL,
+        0,
+        0,
+        0,
+        0,
+        Some(window),
+    )?;
+    let list = create_list(window)?;
+    let font = get_stock_object(wingdi::DEFAULT_GUI_FONT as i32);
+    send_message_w(edit, winuser::WM_SETFONT, font as usize, 0);
+
+    let item_height = send_message_w(list, winuser::LB_GETITEMHEIGHT, 0, 0) as i32;
+    let border = get_system_metrics(winuser::SM_CYBORDER);
+    let list_width = WIDTH - 2 * border;
+    let edit_height = item_height + 8;
+    let list_height = item_height * count.clamp(1, MAX_VISIBLE_ITEMS) as i32 + 4;
+
+    move_window(edit, 0, 0, list_width, edit_height)?;
+    move_window(list, 0, edit_height, list_width, list_height)?;
+    show_at_cursor(window, WIDTH, edit_height + list_height + 2 * border)?;
+    set_focus(edit);
+    Ok((edit, list))
+}
+
+fn create_list(window: &mut HWND__) -> Result<&'static mut HWND__, SystemError> {
+    let list = create_window_ex_w(
+        0,
+        "LISTBOX",
+        "",
+        winuser::WS_CHILD
+            | winuser::WS_VISIBLE
+            | winuser::WS_VSCROLL
+            | winuser::LBS_NOTIFY
+            | winuser::LBS_NOINTEGRALHEIGHT
+            | winuser::LBS_USETABSTOPS,
+        0,
+        0,
+        0,
+        0,
+        Some(window),
+    )?;
+
+    let font = get_stock_object(wingdi::DEFAULT_GUI_FONT as i32);
+    send_message_w(list, winuser::WM_SETFONT, font as usize, 0);
+    Ok(list)
+}
+
+/// Move the window to the cursor, keeping it on screen, and bring it to the front
+fn show_at_cursor(window: &mut HWND__, width: i32, height: i32) -> Result<(), SystemError> {
+    // Open at the cursor, but keep the whole list on screen
+    let cursor = get_cursor_pos().unwrap_or_default();
+    let screen_right = get_system_metrics(winuser::SM_XVIRTUALSCREEN)
+        + get_system_metrics(winuser::SM_CXVIRTUALSCREEN);
+    let screen_bottom = get_system_metrics(winuser::SM_YVIRTUALSCREEN)
+        + get_system_metrics(winuser::SM_CYVIRTUALSCREEN);
+    let x = cursor.x.min(screen_right - width);
+    let y = cursor.y.min(screen_bottom - height);
+
+    move_window(window, x, y, width, height)?;
+    show_window(window, winuser::SW_SHOW);
+    set_foreground_window(window);
+    Ok(())
+}
+
+/// The row selected in the list, if any
+fn selection(list: &mut HWND__) -> Option<usize> {
+    match send_message_w(list, winuser::LB_GETCURSEL, 0, 0) {
+        winuser::LB_ERR => None,
+        index => Some(index as usize),
+    }
+}
+
+/// Move the list's selection by `by` rows, stopping at the ends
+fn move_list_selection(list: &mut HWND__, by: isize) {
+    let count = send_message_w(list, winuser::LB_GETCOUNT, 0, 0);
+    let current = selection(list).unwrap_or(0) as isize;
+    let next = (current + by).clamp(0, (count - 1).max(0));
+    send_message_w(list, winuser::LB_SETCURSEL, next as usize, 0);
+}
+
+/// Post messages for other windows which arrived while the popup was open
+fn post_deferred(deferred: Vec<winuser::MSG>) {
+    for lp_msg in deferred {
+        if let Some(h_wnd) = unsafe { lp_msg.hwnd.as_mut() } {
+            let _ = post_message_a(h_wnd, lp_msg.message, lp_msg.wParam, lp_msg.lParam);
+        }
+    }
+}
+
+fn run_modal_loop(
+    window: &mut HWND__,
+    list: &mut HWND__,
+    mut preview: Option<&mut Preview>,
+) -> Option<Choice> {
+    let mut deferred = Vec::new();
+    let mut lp_msg = winuser::MSG::default();
+    let choice = loop {
+        match unsafe { winuser::GetMessageW(&mut lp_msg, std::ptr::null_mut(), 0, 0) } {
+            0 => {
+                // Leave WM_QUIT for the main loop
+                unsafe { winuser::PostQuitMessage(lp_msg.wParam as i32) };
+                break None;
+            }
+            -1 => break None,
+            _ => {}
+        }
+        let ours = unsafe { lp_msg.hwnd.as_mut() }
+            .map(|h_wnd| is_window_or_child(window, h_wnd))
+            .unwrap_or(false);
+        if !ours {
+            deferred.push(lp_msg);
+            continue;
+        }
+        match lp_msg.message {
+            winuser::WM_KEYDOWN if lp_msg.wParam == winuser::VK_RETURN as usize => {
+                break selection(list).map(Choice::Paste)
+            }
+            winuser::WM_KEYDOWN if lp_msg.wParam == 'E' as usize => {
+                break selection(list).map(Choice::Expire)
+            }
+            winuser::WM_KEYDOWN if lp_msg.wParam == 'P' as usize => {
+                break selection(list).map(Choice::Pin)
+            }
+            winuser::WM_KEYDOWN if lp_msg.wParam == 'B' as usize => {
+                break selection(list).map(Choice::Burn)
+            }
+            winuser::WM_KEYDOWN if lp_msg.wParam == 'S' as usize => {
+                break selection(list).map(Choice::Snippet)
+            }
+            winuser::WM_KEYDOWN if lp_msg.wParam == winuser::VK_ESCAPE as usize => break None,
+            winuser::WM_LBUTTONDBLCLK => break selection(list).map(Choice::Paste),
+            WM_PICKER_CANCEL => break None,
+            WM_PICKER_CONFIRM => break selection(list).map(Choice::Paste),
+            WM_PICKER_MOVE => move_list_selection(list, lp_msg.wParam as isize),
+            _ => unsafe {
+                winuser::TranslateMessage(&lp_msg);
+                winuser::DispatchMessageW(&lp_msg);
+            },
+        }
+        if let Some(preview) = preview.as_mut() {
+            preview.show(selection(list));
+        }
+    };
+
+    post_deferred(deferred);
+    choice
+}
+
+/// Replace the list's rows with the labels of the items matching `query`, returning their indices
+fn show_matches(list: &mut HWND__, labels: &[String], texts: &[String], query: &str) -> Vec<usize> {
+    let shown = search::filter(texts, query);
+    send_message_w(list, winuser::LB_RESETCONTENT, 0, 0);
+    for &index in shown.iter() {
+        let label = to_wide(&labels[index]);
+        send_message_w(list, winuser::LB_ADDSTRING, 0, label.as_ptr() as isize);
+    }
+    send_message_w(list, winuser::LB_SETCURSEL, 0, 0);
+    shown
+}
+
+fn run_search_loop(
+    window: &mut HWND__,
+    edit: &mut HWND__,
+    list: &mut HWND__,
+    labels: &[String],
+    texts: &[String],
+) -> Option<Choice> {
+    let mut query = String::new();
+    let mut shown = show_matches(list, labels, texts, &query);
+    // The list's rows only hold the matches, so a row has to be mapped back to the item's index
+    let chosen = |list: &mut HWND__, shown: &[usize]| {
+        selection(list).and_then(|row| shown.get(row).copied())
+    };
+
+    let mut deferred = Vec::new();
+    let mut lp_msg = winuser::MSG::default();
+    let choice = loop {
+        match unsafe { winuser::GetMessageW(&mut lp_msg, std::ptr::null_mut(), 0, 0) } {
+            0 => {
+                // Leave WM_QUIT for the main loop
+                unsafe { winuser::PostQuitMessage(lp_msg.wParam as i32) };
+                break None;
+            }
+            -1 => break None,
+            _ => {}
+        }
+        let ours = unsafe { lp_msg.hwnd.as_mut() }
+            .map(|h_wnd| is_window_or_child(window, h_wnd))
+            .unwrap_or(false);
+        if !ours {
+            deferred.push(lp_msg);
+            continue;
+        }
+        match lp_msg.message {
+            winuser::WM_KEYDOWN if lp_msg.wParam == winuser::VK_RETURN as usize => {
+                let shift = unsafe { winuser::GetKeyState(winuser::VK_SHIFT) } < 0;
+                break chosen(list, &shown).map(if shift {
+                    Choice::PasteAndRemove
+                } else {
+                    Choice::Paste
+                });
+            }
+            winuser::WM_KEYDOWN if lp_msg.wParam == winuser::VK_ESCAPE as usize => break None,
+            winuser::WM_KEYDOWN if lp_msg.wParam == winuser::VK_DOWN as usize => {
+                move_list_selection(list, 1)
+            }
+            winuser::WM_KEYDOWN if lp_msg.wParam == winuser::VK_UP as usize => {
+                move_list_selection(list, -1)
+            }
+            winuser::WM_LBUTTONDBLCLK => break chosen(list, &shown).map(Choice::Paste),
+            WM_PICKER_CANCEL => break None,
+            WM_PICKER_CONFIRM => break chosen(list, &shown).map(Choice::Paste),
+            WM_PICKER_MOVE => move_list_selection(list, lp_msg.wParam as isize),
+            _ => unsafe {
+                winuser::TranslateMessage(&lp_msg);
+                winuser::DispatchMessageW(&lp_msg);
+            },
+        }
+        let text = get_window_text_w(edit);
+        if text != query {
+            query = text;
+            shown = show_matches(list, labels, texts, &query);
+        }
+    };
+
+    post_deferred(deferred);
+    choice
+}
+
+/// Post `msg` to the picker, returning false if it isn't open
+fn post_to_open(msg: u32, w_param: usize) -> bool {
+    match unsafe { (OPEN.load(Ordering::SeqCst) as *mut HWND__).as_mut() } {
+        Some(window) => post_message_a(window, msg, w_param, 0).is_ok(),
+        None => false,
+    }
+}
+
+/// Move the open picker's selection down by `by` items, or up if it's negative, stopping at the
+/// ends of the list. Returns false if the picker isn't open
+pub fn move_selection(by: isize) -> bool {
+    post_to_open(WM_PICKER_MOVE, by as usize)
+}
+
+/// Paste the item selected in the open picker, returning false if it isn't open
+pub fn confirm() -> bool {
+    post_to_open(WM_PICKER_CONFIRM, 0)
+}
+
+/// Close the open picker without choosing anything, returning false if it isn't open
+pub fn cancel() -> bool {
+    post_to_open(WM_PICKER_CANCEL, 0)
+}
@@ -0,0 +1,88 @@
+use crate::config::Profile;
+use crate::winapi_functions::reg_get_local_machine_dword;
+
+/// Where administrators set policies, for example through Group Policy
+pub const POLICY_KEY: &str = r"SOFTWARE\Policies\filo-clipboard";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_policy_values() {
+        let policy = Policy::from_values(|name| match name {
+            "DisablePersistence" => Some(1),
+            "MaxHistory" => Some(20),
+            _ => None,
+        });
+        assert!(policy.disable_persistence);
+        assert!(!policy.force_plain_paste);
+        assert_eq!(policy.max_history, Some(20));
+        assert!(policy.is_active());
+    }
+
+    #[test]
+    fn zero_max_history_is_ignored() {
+        let policy = Policy::from_values(|name| match name {
+            "MaxHistory" => Some(0),
+            _ => None,
+        });
+        assert_eq!(policy, Policy::default());
+        assert!(!policy.is_active());
+    }
+
+    #[test]
+    fn caps_profile() {
+        let policy = Policy {
+            max_history: Some(10),
+            ..Default::default()
+        };
+        let mut profile = Profile::default();
+        policy.apply(&mut profile);
+        assert_eq!(profile.max_history, 10);
+        profile.max_history = 5;
+        policy.apply(&mut profile);
+        assert_eq!(profile.max_history, 5);
+    }
+}
+
+/// Limits set by an administrator in HKEY_LOCAL_MACHINE, which take precedence over every other
+/// layer of settings
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    /// Never write the history to disk
+    pub disable_persistence: bool,
+    /// Only ever put the plain text of items back on the clipboard
+    pub force_plain_paste: bool,
+    /// The most items any profile may keep
+    pub max_history: Option<usize>,
+}
+
+impl Policy {
+    /// Read the policy from the registry. A missing key or value leaves that setting unrestricted
+    pub fn load() -> Self {
+        Self::from_values(|name| reg_get_local_machine_dword(POLICY_KEY, name).ok())
+    }
+
+    fn from_values(lookup: impl Fn(&str) -> Option<u32>) -> Self {
+        Self {
+            disable_persistence: lookup("DisablePersistence").unwrap_or(0) != 0,
+            force_plain_paste: lookup("ForcePlainPaste").unwrap_or(0) != 0,
+            max_history: lookup("MaxHistory")
+                .filter(|max_history| *max_history > 0)
+                .map(|max_history| max_history as usize),
+        }
+    }
+
+    /// Whether any setting is restricted
+    pub fn is_active(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Cap the profile's values to the policy's limits
+    pub fn apply(&self, profile: &mut Profile) {
+        if let Some(max_history) = self.max_history {
+            profile.max_history = profile.max_history.min(max_history);
+        }
+    }
+}
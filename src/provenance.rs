@@ -0,0 +1,113 @@
+use std::{
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{clipboard_extras::ClipboardItem, entry::Entry};
+
+/// The private format the marker is written in. Other programs ignore formats they don't know
+pub const FORMAT_NAME: &str = "FILO-Clipboard Provenance";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marker_round_trip() {
+        let provenance = Provenance {
+            tool: TOOL.to_owned(),
+            source_app: Some("notepad.exe".to_owned()),
+            copied_at: Some(1_700_000_000),
+            restored_at: 1_700_000_060,
+            machine: Some("DESKTOP-1".to_owned()),
+        };
+        let item = provenance.to_item(49_300);
+        assert_eq!(item.format, 49_300);
+        assert_eq!(item.content.last(), Some(&0));
+        assert_eq!(Provenance::from_item(&item), Some(provenance));
+        assert_eq!(
+            Provenance::from_item(&ClipboardItem {
+                format: 49_300,
+                content: b"not json\0".to_vec(),
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn strip_marker() {
+        let mut items = vec![
+            ClipboardItem {
+                format: 13,
+                content: vec![b'a', 0, 0, 0],
+            },
+            ClipboardItem {
+                format: 49_300,
+                content: vec![b'{', b'}', 0],
+            },
+        ];
+        strip(&mut items, 49_300);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].format, 13);
+    }
+}
+
+const TOOL: &str = "filo-clipboard";
+
+/// Where an item put back on the clipboard came from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Provenance {
+    pub tool: String,
+    /// The executable of the program the item was first copied from, if it could be told
+    pub source_app: Option<String>,
+    /// Seconds since the Unix epoch when the item was copied, if it was copied in this session
+    pub copied_at: Option<u64>,
+    /// Seconds since the Unix epoch when the item was put back on the clipboard
+    pub restored_at: u64,
+    pub machine: Option<String>,
+}
+
+impl Provenance {
+    /// The provenance of `entry` as it's restored now
+    pub fn now(entry: &Entry) -> Self {
+        Self {
+            tool: TOOL.to_owned(),
+            source_app: entry.source_app.clone(),
+            copied_at: entry.copied_at,
+            restored_at: unix_time(),
+            machine: env::var("COMPUTERNAME").ok(),
+        }
+    }
+
+    /// The marker as a null terminated JSON string in `format`
+    pub fn to_item(&self, format: u32) -> ClipboardItem {
+        let mut content = serde_json::to_vec(self).expect("The provenance to serialize");
+        content.push(0);
+        ClipboardItem { format, content }
+    }
+
+    pub fn from_item(item: &ClipboardItem) -> Option<Self> {
+        let end = item
+            .content
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(item.content.len());
+        serde_json::from_slice(&item.content[..end]).ok()
+    }
+}
+
+/// Remove any marker from `items`, so a restored item which is copied again isn't recorded with
+/// the old marker
+pub fn strip(items: &mut Vec<ClipboardItem>, format: u32) {
+    items.retain(|item| item.format != format);
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,317 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, BufRead, IsTerminal, Write},
+};
+
+use crate::export;
+use crate::ipc::{self, Request};
+use crate::search;
+use crate::winapi_functions::{alloc_console, free_console, has_std_input, read_console_w};
+
+const PROMPT: &str = "filo> ";
+/// The commands and what they do, as listed by `help` and completed with Tab
+const COMMANDS: &[(&str, &str)] = &[
+    ("ls", "list the history and pinned items"),
+    ("pop", "remove the next item, printing its text"),
+    (
+        "push",
+        "push \"text\": add text to the front of the history",
+    ),
+    (
+        "inspect",
+        "inspect <n>: show where item n came from and its formats",
+    ),
+    ("grep", "grep <words>: list the items containing every word"),
+    ("status", "show the profile, history and hotkeys"),
+    ("help", "list the commands"),
+    ("exit", "leave the prompt"),
+];
+/// The ReadConsoleW wakeup mask bit for Tab
+const TAB_WAKEUP: u32 = 1 << 9;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse(" ls "), Ok(Some(Line::List)));
+        assert_eq!(parse(""), Ok(None));
+        assert_eq!(
+            parse(r#"push "two\nlines \"quoted\"""#),
+            Ok(Some(Line::Push("two\nlines \"quoted\"".to_owned())))
+        );
+        assert_eq!(
+            parse("push plain words"),
+            Ok(Some(Line::Push("plain words".to_owned())))
+        );
+        assert_eq!(parse("inspect 3"), Ok(Some(Line::Inspect(3))));
+        assert!(parse("inspect three").is_err());
+        assert_eq!(
+            parse("grep Foo bar"),
+            Ok(Some(Line::Grep("Foo bar".to_owned())))
+        );
+        assert_eq!(parse("quit"), Ok(Some(Line::Exit)));
+        assert!(parse("push").is_err());
+        assert!(parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn complete_commands() {
+        assert_eq!(complete("in"), ("inspect ".to_owned(), Vec::new()));
+        assert_eq!(complete("  s"), ("  status ".to_owned(), Vec::new()));
+        assert_eq!(complete("p"), ("p".to_owned(), vec!["pop", "push"]));
+        assert_eq!(complete("pu"), ("push ".to_owned(), Vec::new()));
+        // Only the command is completed
+        assert_eq!(complete("grep in"), ("grep in".to_owned(), Vec::new()));
+        assert_eq!(complete("x"), ("x".to_owned(), Vec::new()));
+    }
+
+    #[test]
+    fn grep_lines() {
+        let listing = "0\tHello World\n1\tgoodbye\n*0\tpinned hello";
+        assert_eq!(grep(listing, "HELLO"), "0\tHello World\n*0\tpinned hello");
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Line {
+    List,
+    Pop,
+    Push(String),
+    Inspect(usize),
+    Grep(String),
+    Status,
+    Help,
+    Exit,
+}
+
+/// Parse a line typed at the prompt, or `None` if it's blank
+fn parse(line: &str) -> Result<Option<Line>, String> {
+    let line = line.trim();
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    let needs = |usage: &str| format!("Usage: {}", usage);
+    Ok(Some(match command {
+        "" => return Ok(None),
+        "ls" => Line::List,
+        "pop" => Line::Pop,
+        "push" if rest.is_empty() => return Err(needs("push \"text\"")),
+        "push" => Line::Push(unquote(rest)),
+        "inspect" => Line::Inspect(rest.parse().map_err(|_| needs("inspect <n>"))?),
+        "grep" if rest.is_empty() => return Err(needs("grep <words>")),
+        "grep" => Line::Grep(rest.to_owned()),
+        "status" => Line::Status,
+        "help" | "?" => Line::Help,
+        "exit" | "quit" => Line::Exit,
+        _ => {
+            return Err(format!(
+                "Unknown command \"{}\". Type help for a list",
+                command
+            ))
+        }
+    }))
+}
+
+/// The text between double quotes, with \n, \t, \" and \\ escapes, or the text as it is if it isn't
+/// quoted
+fn unquote(text: &str) -> String {
+    let inner = match text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+    {
+        Some(inner) => inner,
+        None => return text.to_owned(),
+    };
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Complete the command at the start of `line`, returning the line as far as it can be completed
+/// and, if more than one command would fit, their names
+fn complete(line: &str) -> (String, Vec<&'static str>) {
+    let start = line.len() - line.trim_start().len();
+    let word = &line[start..];
+    if word.contains(' ') {
+        return (line.to_owned(), Vec::new());
+    }
+    let candidates: Vec<_> = COMMANDS
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| name.starts_with(word))
+        .collect();
+    match candidates.as_slice() {
+        [] => (line.to_owned(), Vec::new()),
+        [name] => (format!("{}{} ", &line[..start], name), Vec::new()),
+        _ => (line.to_owned(), candidates),
+    }
+}
+
+/// The lines of a `list` reply which contain every word of `query`
+fn grep(listing: &str, query: &str) -> String {
+    listing
+        .lines()
+        .filter(|line| search::matches(&line.to_lowercase(), query))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Send `request` to the running instance, returning its reply or why it couldn't be had
+fn send(request: &Request) -> Result<String, String> {
+    match ipc::send(request) {
+        Ok(response) if response.ok => Ok(response.message),
+        Ok(response) => Err(response.message),
+        Err(error) => Err(format!("Could not reach the running instance: {}", error)),
+    }
+}
+
+/// Carry out a line, returning what to print
+fn execute(line: Line) -> Result<String, String> {
+    match line {
+        Line::List => send(&Request::List),
+        Line::Pop => send(&Request::Pop),
+        Line::Push(text) => send(&Request::Push { text }),
+        Line::Inspect(index) => {
+            let from = send(&Request::ConfigGet {
+                key: "index_from".to_owned(),
+            })?
+            .parse()?;
+            export::describe(&send(&Request::Export)?, index, from)
+        }
+        Line::Grep(query) => send(&Request::List).map(|listing| grep(&listing, &query)),
+        Line::Status => send(&Request::Status { json: false }),
+        Line::Help => Ok(COMMANDS
+            .iter()
+            .map(|(name, help)| format!("{}\t{}", name, help))
+            .collect::<Vec<_>>()
+            .join("\n")),
+        Line::Exit => Ok(String::new()),
+    }
+}
+
+/// Parse and carry out a line, printing the result. Returns whether it worked, or `None` to exit
+fn run_line(line: &str) -> Option<bool> {
+    let result = match parse(line) {
+        Ok(None) => return Some(true),
+        Ok(Some(Line::Exit)) => return None,
+        Ok(Some(line)) => execute(line),
+        Err(error) => Err(error),
+    };
+    Some(match result {
+        Ok(output) => {
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+            true
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            false
+        }
+    })
+}
+
+/// Read a line at the prompt, completing commands when Tab is pressed. Returns `None` at the end of
+/// input
+fn read_line(console: &std::fs::File) -> io::Result<Option<String>> {
+    print!("{}", PROMPT);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    loop {
+        let read = read_console_w(console, &line, TAB_WAKEUP)
+            .map_err(|error| io::Error::other(error.to_string()))?;
+        let typed = match read.find('\t') {
+            Some(tab) => &read[..tab],
+            None => {
+                let line = read.trim_end_matches(&['\r', '\n'][..]);
+                // Ctrl+Z then Enter, the console's end of input
+                return Ok(Some(line.to_owned()).filter(|line| !line.starts_with('\u{1a}')));
+            }
+        };
+        let (completed, candidates) = complete(typed);
+        if !candidates.is_empty() {
+            print!("\n{}\n{}{}", candidates.join("  "), PROMPT, completed);
+        } else {
+            // The tab was echoed as up to eight spaces, so clear them before writing the line again
+            print!(
+                "\r{}{}{}\r{}{}",
+                PROMPT,
+                completed,
+                " ".repeat(8),
+                PROMPT,
+                completed
+            );
+        }
+        io::stdout().flush()?;
+        line = completed;
+    }
+}
+
+/// Run commands against the running instance, from a prompt or one per line of redirected input.
+/// Returns the exit code
+pub fn run() -> i32 {
+    if has_std_input() && !io::stdin().is_terminal() {
+        // Scripted, so carry on after a failure but say so in the exit code
+        let mut code = 0;
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    eprintln!("Could not read stdin: {}", error);
+                    return 1;
+                }
+            };
+            match run_line(&line) {
+                Some(true) => {}
+                Some(false) => code = 1,
+                None => break,
+            }
+        }
+        return code;
+    }
+
+    // Release builds have no console of their own, and the one they borrow is still read by the
+    // shell they were started from
+    if !cfg!(debug_assertions) {
+        let _ = free_console();
+        if let Err(error) = alloc_console() {
+            eprintln!("Could not open a console: {}", error);
+            return 1;
+        }
+    }
+    let console = match OpenOptions::new().read(true).write(true).open("CONIN$") {
+        Ok(console) => console,
+        Err(error) => {
+            eprintln!("Could not open the console: {}", error);
+            return 1;
+        }
+    };
+    println!("Type help for a list of commands, and Tab to complete one");
+    loop {
+        match read_line(&console) {
+            Ok(Some(line)) => {
+                if run_line(&line).is_none() {
+                    return 0;
+                }
+            }
+            Ok(None) => return 0,
+            Err(error) => {
+                eprintln!("Could not read the console: {}", error);
+                return 1;
+            }
+        }
+    }
+}
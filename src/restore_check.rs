@@ -0,0 +1,384 @@
+use winapi::um::{wingdi, winuser};
+
+use crate::clipboard_extras::{
+    normalize_hdrop, only_formats, plain_text, read_hdrop, ClipboardItem, Dib,
+};
+use crate::entry::Entry;
+use crate::html;
+
+/// The size of a DROPFILES header, which starts every CF_HDROP item
+const DROPFILES_SIZE: usize = 20;
+/// BITMAPINFOHEADER and the V2 to V5 headers which extend it
+const DIB_HEADER_SIZES: [u32; 5] = [40, 52, 56, 108, 124];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clipboard_extras::{write_hdrop, write_unicode_text};
+
+    const HTML_FORMAT: u32 = 49_400;
+
+    fn name(format: u32) -> String {
+        match format {
+            winuser::CF_UNICODETEXT => "CF_UNICODETEXT".to_owned(),
+            HTML_FORMAT => "HTML Format".to_owned(),
+            format => format.to_string(),
+        }
+    }
+
+    fn dib(width: i32, height: i32, pixels: usize) -> ClipboardItem {
+        let mut content = vec![0; 40];
+        content[0..4].copy_from_slice(&40u32.to_le_bytes());
+        content[4..8].copy_from_slice(&width.to_le_bytes());
+        content[8..12].copy_from_slice(&height.to_le_bytes());
+        content[14..16].copy_from_slice(&24u16.to_le_bytes());
+        content.resize(40 + pixels, 0);
+        ClipboardItem {
+            format: winuser::CF_DIB,
+            content,
+        }
+    }
+
+    fn check(items: Vec<ClipboardItem>, options: &RestoreOptions) -> RestoreCheck {
+        RestoreCheck::new(&Entry::new(items), options, name)
+    }
+
+    #[test]
+    fn sound_formats_are_restored() {
+        let report = check(
+            vec![
+                write_unicode_text("text"),
+                write_hdrop(&["C:\\a.txt"]),
+                dib(2, 2, 16),
+            ],
+            &RestoreOptions::default(),
+        );
+        assert!(report
+            .formats
+            .iter()
+            .all(|format| format.outcome == Outcome::Restored));
+        assert_eq!(report.lines()[0], "Would restore 3 of 3 formats");
+    }
+
+    #[test]
+    fn malformed_headers_are_reported() {
+        let unterminated = ClipboardItem {
+            format: winuser::CF_UNICODETEXT,
+            content: vec![b'a', 0],
+        };
+        let no_files = ClipboardItem {
+            format: winuser::CF_HDROP,
+            content: vec![0; 8],
+        };
+        let report = check(
+            vec![unterminated, no_files, dib(2, 2, 4), dib(0, 2, 16)],
+            &RestoreOptions::default(),
+        );
+        let outcomes: Vec<_> = report
+            .formats
+            .iter()
+            .map(|format| &format.outcome)
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                &Outcome::Invalid("no null terminator"),
+                &Outcome::Invalid("shorter than its DROPFILES header"),
+                &Outcome::Invalid("pixels cut short"),
+                &Outcome::Invalid("no width or height"),
+            ]
+        );
+    }
+
+    #[test]
+    fn conversions_and_skips() {
+        let html = ClipboardItem {
+            format: HTML_FORMAT,
+            content: b"Version:0.9\r\nStartHTML:0000000055\r\nEndHTML:0000000068\r\n<b>bold</b>"
+                .to_vec(),
+        };
+        let items = vec![write_unicode_text("bold"), html, dib(2, 2, 16)];
+        let options = RestoreOptions {
+            html_format: Some(HTML_FORMAT),
+            ..RestoreOptions::default()
+        };
+        let report = check(items.clone(), &options);
+        assert!(matches!(report.formats[1].outcome, Outcome::Converted(_)));
+
+        let plain = RestoreOptions {
+            plain: true,
+            ..options
+        };
+        let report = check(items.clone(), &plain);
+        assert_eq!(report.formats[0].outcome, Outcome::Restored);
+        assert!(matches!(report.formats[1].outcome, Outcome::Skipped(_)));
+        assert!(matches!(report.formats[2].outcome, Outcome::Skipped(_)));
+        assert_eq!(report.lines()[0], "Would restore 1 of 3 formats");
+
+        let safe = [winuser::CF_UNICODETEXT];
+        let report = check(
+            items,
+            &RestoreOptions {
+                safe_formats: Some(&safe),
+                ..RestoreOptions::default()
+            },
+        );
+        assert!(report.lines()[3].ends_with("skipped in safe_restore_apps"));
+    }
+
+    #[test]
+    fn damaged_items_are_not_restored() {
+        let mut entry = Entry::new(vec![write_unicode_text("text")]);
+        entry.checksum = Some(entry.fingerprint().wrapping_add(1));
+        let report = RestoreCheck::new(&entry, &RestoreOptions::default(), name);
+        assert!(report.damaged);
+        assert!(report.lines()[0].contains("damaged"));
+    }
+}
+
+/// What restoring would do with one of an item's formats
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Restored,
+    /// Written as it's stored, though it looks malformed, so the program pasted into may reject it
+    Invalid(&'static str),
+    /// Rewritten before it's written
+    Converted(&'static str),
+    /// Left off the clipboard
+    Skipped(&'static str),
+}
+
+/// What restoring would do with a format, and whether it's left out when pasting into one of
+/// `safe_restore_apps`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatCheck {
+    pub format: u32,
+    pub name: String,
+    pub bytes: usize,
+    pub outcome: Outcome,
+    pub unsafe_format: bool,
+}
+
+/// The settings which change what's put on the clipboard when an item is restored
+#[derive(Debug, Default, Clone)]
+pub struct RestoreOptions<'a> {
+    /// Only the text is restored, as with plain paste
+    pub plain: bool,
+    pub html_format: Option<u32>,
+    pub html_to_text: bool,
+    /// The formats kept when pasting into `safe_restore_apps`, if any are set
+    pub safe_formats: Option<&'a [u32]>,
+    /// The names of formats added to every restore, such as the restore tag
+    pub added: Vec<String>,
+}
+
+/// What restoring an item would put on the clipboard, worked out without touching it
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestoreCheck {
+    /// The item no longer matches its checksum, so it wouldn't be restored at all
+    pub damaged: bool,
+    pub formats: Vec<FormatCheck>,
+    pub added: Vec<String>,
+}
+
+impl RestoreCheck {
+    pub fn new(entry: &Entry, options: &RestoreOptions, name: impl Fn(u32) -> String) -> Self {
+        let has_text = entry.items.iter().any(|item| is_text(item.format));
+        // Formats left out for safe_restore_apps are only marked, as it depends on the window
+        let restored = restore_items(
+            &entry.items,
+            &RestoreOptions {
+                safe_formats: None,
+                added: Vec::new(),
+                ..options.clone()
+            },
+        );
+        let mut added: Vec<_> = restored
+            .iter()
+            .filter(|item| !entry.items.iter().any(|old| old.format == item.format))
+            .map(|item| format!("{}, from the HTML", name(item.format)))
+            .collect();
+        added.extend(options.added.iter().cloned());
+
+        let formats = entry
+            .items
+            .iter()
+            .map(|item| {
+                let written = restored.iter().find(|new| new.format == item.format);
+                let rewritten = written.is_some_and(|new| new.content != item.content);
+                let outcome = if written.is_none() && options.plain && has_text {
+                    Outcome::Skipped("plain paste only restores the text")
+                } else if written.is_none() {
+                    Outcome::Skipped("html_to_text only restores the HTML's text")
+                } else if rewritten && item.format == winuser::CF_UNICODETEXT {
+                    Outcome::Converted("replaced by the HTML's text, with html_to_text")
+                } else if let Some(problem) = problem(item, options.html_format) {
+                    Outcome::Invalid(problem)
+                } else if rewritten {
+                    Outcome::Converted("CF_HTML offsets rewritten to match the document")
+                } else {
+                    conversion(item).map_or(Outcome::Restored, Outcome::Converted)
+                };
+                FormatCheck {
+                    format: item.format,
+                    name: name(item.format),
+                    bytes: item.content.len(),
+                    outcome,
+                    unsafe_format: options
+                        .safe_formats
+                        .is_some_and(|safe| !safe.contains(&item.format)),
+                }
+            })
+            .collect();
+        Self {
+            damaged: !entry.is_intact(),
+            formats,
+            added,
+        }
+    }
+
+    /// A summary, then a line for each format and any formats added
+    pub fn lines(&self) -> Vec<String> {
+        let restored = self
+            .formats
+            .iter()
+            .filter(|format| !matches!(format.outcome, Outcome::Skipped(_)))
+            .count();
+        let mut lines = vec![if self.damaged {
+            "The item is damaged in the history file, so it wouldn't be restored".to_owned()
+        } else {
+            format!(
+                "Would restore {} of {} format{}",
+                restored,
+                self.formats.len(),
+                if self.formats.len() == 1 { "" } else { "s" }
+            )
+        }];
+        lines.extend(self.formats.iter().map(|format| {
+            let outcome = match &format.outcome {
+                Outcome::Restored => "restored".to_owned(),
+                Outcome::Invalid(problem) => format!("restored, but malformed: {}", problem),
+                Outcome::Converted(how) => format!("converted: {}", how),
+                Outcome::Skipped(why) => format!("skipped: {}", why),
+            };
+            let safe = match format.outcome {
+                Outcome::Skipped(_) => "",
+                _ if format.unsafe_format => ", skipped in safe_restore_apps",
+                _ => "",
+            };
+            format!(
+                "{}\t{} bytes\t{}{}",
+                format.name, format.bytes, outcome, safe
+            )
+        }));
+        if !self.added.is_empty() {
+            lines.push(format!("Added: {}", self.added.join("; ")));
+        }
+        lines
+    }
+}
+
+fn is_text(format: u32) -> bool {
+    format == winuser::CF_UNICODETEXT || format == winuser::CF_TEXT
+}
+
+/// The formats put on the clipboard when `items` are restored with `options`, before any formats
+/// in `added`
+pub fn restore_items(items: &[ClipboardItem], options: &RestoreOptions) -> Vec<ClipboardItem> {
+    let mut items = if options.plain {
+        plain_text(items)
+    } else {
+        items.to_vec()
+    };
+    if let Some(format) = options.html_format {
+        // Items from older history files were stored as they were copied
+        html::normalize_items(&mut items, format);
+        if options.html_to_text {
+            if let Some(text) = html::as_text(&items, format) {
+                items = text;
+            }
+        }
+    }
+    if let Some(safe) = options.safe_formats {
+        items = only_formats(&items, safe);
+    }
+    items
+}
+
+/// What's wrong with a format's content, if it looks malformed
+fn problem(item: &ClipboardItem, html_format: Option<u32>) -> Option<&'static str> {
+    let content = &item.content;
+    if content.is_empty() {
+        return Some("empty");
+    }
+    match item.format {
+        winuser::CF_UNICODETEXT if !content.len().is_multiple_of(2) => {
+            Some("an odd number of bytes")
+        }
+        winuser::CF_UNICODETEXT if !content.chunks_exact(2).any(|pair| pair == [0, 0]) => {
+            Some("no null terminator")
+        }
+        winuser::CF_TEXT | winuser::CF_OEMTEXT if !content.contains(&0) => {
+            Some("no null terminator")
+        }
+        winuser::CF_HDROP => hdrop_problem(item),
+        winuser::CF_DIB | winuser::CF_DIBV5 => dib_problem(content),
+        format if Some(format) == html_format && !content.starts_with(b"Version:") => {
+            Some("no CF_HTML header")
+        }
+        _ => None,
+    }
+}
+
+fn hdrop_problem(item: &ClipboardItem) -> Option<&'static str> {
+    let content = &item.content;
+    if content.len() < DROPFILES_SIZE {
+        return Some("shorter than its DROPFILES header");
+    }
+    let offset = u32::from_le_bytes([content[0], content[1], content[2], content[3]]) as usize;
+    if offset < DROPFILES_SIZE || offset >= content.len() {
+        Some("its file list is outside the item")
+    } else if read_hdrop(item).is_empty() {
+        Some("it lists no files")
+    } else {
+        None
+    }
+}
+
+fn dib_problem(content: &[u8]) -> Option<&'static str> {
+    let dib = match Dib::parse(content) {
+        Some(dib) => dib,
+        None => return Some("shorter than its BITMAPINFOHEADER"),
+    };
+    if !DIB_HEADER_SIZES.contains(&dib.header_size) {
+        return Some("an unknown header size");
+    }
+    if dib.width <= 0 || dib.height == 0 {
+        return Some("no width or height");
+    }
+    // 0 is for JPEG and PNG compression, where the size of the pixels can't be worked out
+    if ![0, 1, 4, 8, 16, 24, 32].contains(&dib.bit_count) {
+        return Some("an unknown bit count");
+    }
+    let pixels = match dib.pixels() {
+        Some(pixels) => pixels,
+        None => return Some("its colour table runs past the end"),
+    };
+    let uncompressed = dib.compression == wingdi::BI_RGB || dib.compression == wingdi::BI_BITFIELDS;
+    let stride = (dib.width as usize * dib.bit_count as usize).div_ceil(32) * 4;
+    if uncompressed && pixels.len() < stride * dib.height.unsigned_abs() as usize {
+        return Some("pixels cut short");
+    }
+    None
+}
+
+/// How a sound format is rewritten as it's written, if it is
+fn conversion(item: &ClipboardItem) -> Option<&'static str> {
+    match item.format {
+        winuser::CF_HDROP if normalize_hdrop(item).as_ref() != Some(item) => {
+            Some("DROPFILES header rewritten with wide file names")
+        }
+        _ => None,
+    }
+}
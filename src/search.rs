@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use crate::entry::Entry;
+
+/// Only the start of very long items is searched, so the index stays small
+const MAX_INDEXED_LENGTH: usize = 16 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard_extras::write_unicode_text;
+
+    fn entry(text: &str) -> Entry {
+        Entry::new(vec![write_unicode_text(text)])
+    }
+
+    #[test]
+    fn text_includes_label_and_source() {
+        let mut entry = entry("Hello World");
+        entry.label = Some("Greeting".to_owned());
+        entry.source_document = Some("Notes.TXT".to_owned());
+        let text = search_text(&entry, &content_text(&entry));
+        assert!(matches(&text, "greeting"));
+        assert!(matches(&text, "WORLD hello"));
+        assert!(matches(&text, "notes.txt"));
+        assert!(!matches(&text, "goodbye"));
+        assert!(matches(&named_text("Sig", &entry), "sig hello"));
+    }
+
+    #[test]
+    fn every_word_must_match() {
+        let texts = ["alpha beta", "beta gamma", "gamma alpha"].map(str::to_owned);
+        assert_eq!(filter(&texts, "alpha"), vec![0, 2]);
+        assert_eq!(filter(&texts, " gamma  beta "), vec![1]);
+        assert_eq!(filter(&texts, ""), vec![0, 1, 2]);
+        assert_eq!(filter(&texts, "delta"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn index_forgets_removed_items() {
+        let (first, second) = (entry("first"), entry("second"));
+        let mut index = SearchIndex::default();
+        assert_eq!(index.texts([&first, &second]), vec!["first", "second"]);
+        assert_eq!(index.texts([&second]), vec!["second"]);
+        assert_eq!(index.texts.len(), 1);
+    }
+}
+
+/// The lowercased text an item's contents are found by: its text, or the names of its files. Only
+/// this part is kept in the index, as labels can change without the item changing
+fn content_text(entry: &Entry) -> String {
+    let mut text = entry.preview().trim_end_matches('\0').to_lowercase();
+    if let Some((end, _)) = text.char_indices().nth(MAX_INDEXED_LENGTH) {
+        text.truncate(end);
+    }
+    text
+}
+
+/// The lowercased text an item is found by: its label and where it was copied from, followed by
+/// `content`. Items without text, like images, can still be found by label or source
+fn search_text(entry: &Entry, content: &str) -> String {
+    let mut text = String::new();
+    let details = [&entry.label, &entry.source_document, &entry.source_app];
+    for detail in details.iter().copied().flatten() {
+        text.push_str(&detail.to_lowercase());
+        text.push('\n');
+    }
+    text.push_str(content);
+    text
+}
+
+/// The search text of an item saved under `name`, which can be found by its name too
+pub fn named_text(name: &str, entry: &Entry) -> String {
+    format!(
+        "{}\n{}",
+        name.to_lowercase(),
+        search_text(entry, &content_text(entry))
+    )
+}
+
+/// Whether every word of `query` appears somewhere in `text`, ignoring case. `text` must already be
+/// lowercase, as from `search_text`
+pub fn matches(text: &str, query: &str) -> bool {
+    query
+        .to_lowercase()
+        .split_whitespace()
+        .all(|word| text.contains(word))
+}
+
+/// The positions of the texts which match `query`, in order. An empty query matches everything
+pub fn filter(texts: &[String], query: &str) -> Vec<usize> {
+    texts
+        .iter()
+        .enumerate()
+        .filter(|(_, text)| matches(text, query))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// The text of each item's contents, kept by fingerprint so items aren't decoded again every time
+/// the search is opened
+#[derive(Default)]
+pub struct SearchIndex {
+    texts: HashMap<u64, String>,
+}
+
+impl SearchIndex {
+    /// The search text of each of `entries` in order, indexing any new ones and forgetting those
+    /// which are no longer there
+    pub fn texts<'a>(&mut self, entries: impl IntoIterator<Item = &'a Entry>) -> Vec<String> {
+        let mut texts = HashMap::new();
+        let result = entries
+            .into_iter()
+            .map(|entry| {
+                let fingerprint = entry.fingerprint();
+                let content = self
+                    .texts
+                    .remove(&fingerprint)
+                    .or_else(|| texts.get(&fingerprint).cloned())
+                    .unwrap_or_else(|| content_text(entry));
+                let text = search_text(entry, &content);
+                texts.insert(fingerprint, content);
+                text
+            })
+            .collect();
+        self.texts = texts;
+        result
+    }
+}
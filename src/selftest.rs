@@ -0,0 +1,168 @@
+use clipboard_win::{formats, Clipboard, Getter};
+use winapi::um::winuser;
+
+use crate::clipboard_extras::{get_all, set_all, write_hdrop, write_unicode_text, ClipboardItem};
+use crate::winapi_functions::register_clipboard_format;
+
+/// A 2x2 32 bit DIB with a different colour in each pixel
+fn dib_payload() -> Vec<u8> {
+    let mut dib = vec![0u8; 40];
+    dib[..4].copy_from_slice(&40u32.to_le_bytes());
+    dib[4..8].copy_from_slice(&2i32.to_le_bytes());
+    dib[8..12].copy_from_slice(&2i32.to_le_bytes());
+    dib[12..14].copy_from_slice(&1u16.to_le_bytes());
+    dib[14..16].copy_from_slice(&32u16.to_le_bytes());
+    dib.extend_from_slice(&[0, 0, 255, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 255, 255, 0]);
+    dib
+}
+
+/// A small CF_HTML fragment with its description header
+fn html_payload() -> Vec<u8> {
+    let html =
+        "<html><body><!--StartFragment--><b>FILO selftest</b><!--EndFragment--></body></html>";
+    let header_length = "Version:0.9\r\nStartHTML:0000000000\r\nEndHTML:0000000000\r\n".len();
+    format!(
+        "Version:0.9\r\nStartHTML:{:010}\r\nEndHTML:{:010}\r\n{}",
+        header_length,
+        header_length + html.len(),
+        html
+    )
+    .into_bytes()
+}
+
+/// The name and contents of each format to check
+fn payloads() -> Vec<(&'static str, ClipboardItem)> {
+    let mut payloads = vec![
+        (
+            "CF_TEXT",
+            ClipboardItem {
+                format: winuser::CF_TEXT,
+                content: b"FILO selftest\0".to_vec(),
+            },
+        ),
+        (
+            "CF_UNICODETEXT",
+            write_unicode_text("FILO selftest ✓ ü 漢字"),
+        ),
+        (
+            "CF_DIB",
+            ClipboardItem {
+                format: winuser::CF_DIB,
+                content: dib_payload(),
+            },
+        ),
+        (
+            "CF_HDROP",
+            write_hdrop(&["C:\\filo-clipboard-selftest.txt"]),
+        ),
+    ];
+    if let Ok(format) = register_clipboard_format("HTML Format") {
+        payloads.push((
+            "HTML Format",
+            ClipboardItem {
+                format,
+                content: html_payload(),
+            },
+        ));
+    }
+    payloads
+}
+
+/// Whether the data read back matches what was written. The clipboard may round allocations up,
+/// so trailing zeros are allowed
+fn round_trips(written: &[u8], read: &[u8]) -> bool {
+    read.len() >= written.len()
+        && read[..written.len()] == *written
+        && read[written.len()..].iter().all(|byte| *byte == 0)
+}
+
+/// Write and read back one payload, describing the result
+fn check(item: &ClipboardItem, ignore: &[ClipboardItem]) -> Result<(), String> {
+    let mut items = vec![item.clone()];
+    items.extend_from_slice(ignore);
+    if let Some(Err(error)) = set_all(&items).into_iter().next() {
+        return Err(format!("could not write: {}", error));
+    }
+
+    let mut read = Vec::new();
+    formats::RawData(item.format)
+        .read_clipboard(&mut read)
+        .map_err(|error| format!("could not read back: {}", error))?;
+    if round_trips(&item.content, &read) {
+        Ok(())
+    } else {
+        Err(format!(
+            "read back different data ({} bytes written, {} read)",
+            item.content.len(),
+            read.len()
+        ))
+    }
+}
+
+/// Check that each supported format comes back from the clipboard unchanged, printing a line for
+/// each and returning the exit code. The clipboard's contents are put back afterwards
+pub fn run() -> i32 {
+    let _clip = match Clipboard::new_attempts(10) {
+        Ok(clip) => clip,
+        Err(error) => {
+            eprintln!("Could not open the clipboard: {}", error);
+            return 1;
+        }
+    };
+    let saved = get_all();
+    // Mark our writes so a running instance doesn't add them to its history
+    let ignore: Vec<_> = register_clipboard_format("Clipboard Viewer Ignore")
+        .map(|format| ClipboardItem {
+            format,
+            content: vec![0],
+        })
+        .into_iter()
+        .collect();
+
+    let mut failures = 0;
+    for (name, item) in payloads() {
+        match check(&item, &ignore) {
+            Ok(()) => println!("{}\tok", name),
+            Err(reason) => {
+                failures += 1;
+                println!("{}\t{}", name, reason);
+            }
+        }
+    }
+
+    let mut restore = saved;
+    restore.extend(ignore);
+    if set_all(&restore).iter().any(|result| result.is_err()) {
+        eprintln!("Could not put the original clipboard contents back");
+        failures += 1;
+    }
+
+    if failures == 0 {
+        println!("Every format round-tripped cleanly");
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding_is_ignored() {
+        assert!(round_trips(b"abc", b"abc"));
+        assert!(round_trips(b"abc", b"abc\0\0\0\0\0"));
+        assert!(!round_trips(b"abc", b"abd"));
+        assert!(!round_trips(b"abc", b"ab"));
+        assert!(!round_trips(b"abc", b"abc\0x"));
+    }
+
+    #[test]
+    fn html_offsets() {
+        let html = html_payload();
+        let text = String::from_utf8(html).unwrap();
+        let start: usize = text[23..33].parse().unwrap();
+        assert!(text[start..].starts_with("<html>"));
+    }
+}
@@ -0,0 +1,1004 @@
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use log::LevelFilter;
+
+use crate::cli::Opts;
+use crate::config::{
+    self, Age, ByteSize, Config, ConfigError, Dedup, HistoryEnd, LogLevel, Order, PasteMode,
+    Profile, QuietHours, RemoteClipboard, DEFAULT_PROFILE, DEFAULT_RESTORE_TIMEOUT_MS,
+    DEFAULT_SAFE_RESTORE_APPS,
+};
+use crate::formats::FormatList;
+use crate::hotkey::{Hotkey, Keystroke};
+use crate::logging::DEFAULT_LOG_LEVEL;
+use crate::persistence::FlushStrategy;
+use crate::policy::Policy;
+use crate::storage::StorageKind;
+
+const ENV_PREFIX: &str = "FILO_CLIPBOARD_";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn parse_env_vars() {
+        let overrides = Overrides::from_vars(lookup(&[
+            ("FILO_CLIPBOARD_MAX_HISTORY", "10"),
+            ("FILO_CLIPBOARD_CAPTURE", "false"),
+            ("FILO_CLIPBOARD_DEBOUNCE_MS", "150"),
+        ]))
+        .unwrap();
+        assert_eq!(overrides.max_history, Some(10));
+        assert_eq!(overrides.capture, Some(false));
+        assert_eq!(overrides.debounce_ms, Some(150));
+        assert_eq!(overrides.profile, None);
+    }
+
+    #[test]
+    fn exclude_apps_env_var() {
+        let overrides = Overrides::from_vars(lookup(&[(
+            "FILO_CLIPBOARD_EXCLUDE_APPS",
+            "keepass.exe,bitwarden.exe",
+        )]))
+        .unwrap();
+        assert_eq!(
+            overrides.exclude_apps,
+            Some(vec!["keepass.exe".to_owned(), "bitwarden.exe".to_owned()])
+        );
+    }
+
+    #[test]
+    fn queue_keys_env_var() {
+        let overrides =
+            Overrides::from_vars(lookup(&[("FILO_CLIPBOARD_QUEUE_KEYS", "tab, shift+enter")]))
+                .unwrap();
+        assert_eq!(
+            overrides.queue_keys,
+            Some(vec![Keystroke::tab(), "shift+enter".parse().unwrap()])
+        );
+        let overrides = Overrides::from_vars(lookup(&[("FILO_CLIPBOARD_QUEUE_KEYS", "")])).unwrap();
+        assert_eq!(overrides.queue_keys, Some(Vec::new()));
+        assert!(
+            Overrides::from_vars(lookup(&[("FILO_CLIPBOARD_QUEUE_KEYS", "tab,nope")])).is_err()
+        );
+    }
+
+    #[test]
+    fn invalid_env_var() {
+        assert!(
+            Overrides::from_vars(lookup(&[("FILO_CLIPBOARD_SIMILARITY_THRESHOLD", "300")]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn profile_replaces_hotkeys_and_filters() {
+        let config: Config = toml::from_str(
+            r#"
+            exclude_apps = ["keepass.exe"]
+            burn_apps = ["authenticator.exe"]
+
+            [profiles.work]
+            hotkey = "ctrl+alt+v"
+            exclude_apps = ["outlook.exe"]
+            max_age = "1h"
+            "#,
+        )
+        .unwrap();
+        let overrides = Overrides {
+            max_age: Some("5m".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut settings = Settings::from_config(
+            None,
+            config,
+            overrides,
+            Policy::default(),
+            Some("work".to_owned()),
+        );
+        assert_eq!(settings.hotkey, "ctrl+alt+v".parse().unwrap());
+        assert_eq!(settings.exclude_apps, vec!["outlook.exe".to_owned()]);
+        assert_eq!(settings.burn_apps, vec!["authenticator.exe".to_owned()]);
+        assert_eq!(settings.max_age, Some(Duration::from_secs(300)));
+
+        settings.switch_profile(DEFAULT_PROFILE).unwrap();
+        assert_eq!(settings.hotkey, Hotkey::default());
+        assert_eq!(settings.exclude_apps, vec!["keepass.exe".to_owned()]);
+        assert!(settings.switch_profile("missing").is_err());
+        assert_eq!(settings.profile_name, DEFAULT_PROFILE);
+    }
+
+    #[test]
+    fn higher_layer_wins() {
+        let cli = Overrides {
+            max_history: Some(5),
+            ..Default::default()
+        };
+        let env = Overrides {
+            max_history: Some(10),
+            capture: Some(false),
+            ..Default::default()
+        };
+        let mut profile = Profile::default();
+        cli.or(env).apply(&mut profile);
+        assert_eq!(profile.max_history, 5);
+        assert!(!profile.capture);
+    }
+}
+
+/// One layer of settings. Any value which is set replaces the one from the layers beneath it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overrides {
+    pub config: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub max_history: Option<usize>,
+    pub similarity_threshold: Option<u8>,
+    pub capture: Option<bool>,
+    pub index_from: Option<HistoryEnd>,
+    pub picker_order: Option<HistoryEnd>,
+    pub persist: Option<PathBuf>,
+    pub flush: Option<FlushStrategy>,
+    pub storage: Option<StorageKind>,
+    pub encrypt: Option<bool>,
+    pub hotkey: Option<Hotkey>,
+    pub peek_hotkey: Option<Hotkey>,
+    pub picker_hotkey: Option<Hotkey>,
+    pub undo_hotkey: Option<Hotkey>,
+    pub next_hotkey: Option<Hotkey>,
+    pub previous_hotkey: Option<Hotkey>,
+    pub pinned_hotkey: Option<Hotkey>,
+    pub search_hotkey: Option<Hotkey>,
+    pub snippet_hotkey: Option<Hotkey>,
+    pub stack_hotkey: Option<Hotkey>,
+    pub queue_hotkey: Option<Hotkey>,
+    pub queue_keys: Option<Vec<Keystroke>>,
+    pub tap_key: Option<Keystroke>,
+    pub recapture_hotkey: Option<Hotkey>,
+    pub restore_timeout_ms: Option<u64>,
+    pub debounce_ms: Option<u64>,
+    pub plain_paste: Option<bool>,
+    pub html_to_text: Option<bool>,
+    pub terminal_paste: Option<bool>,
+    pub paste_mode: Option<PasteMode>,
+    pub mode: Option<Order>,
+    pub start_paused: Option<bool>,
+    pub lazy_restore: Option<bool>,
+    pub tag_restores: Option<bool>,
+    pub provenance: Option<bool>,
+    pub dedup: Option<Dedup>,
+    pub plain_paste_hotkey: Option<Hotkey>,
+    pub auto_grow: Option<bool>,
+    pub monitor_stacks: Option<bool>,
+    pub max_item_size: Option<ByteSize>,
+    pub max_total_memory: Option<ByteSize>,
+    pub max_age: Option<Age>,
+    pub quiet_hours: Option<QuietHours>,
+    pub exclude_apps: Option<Vec<String>>,
+    pub burn_apps: Option<Vec<String>>,
+    pub passthrough_apps: Option<Vec<String>>,
+    pub passthrough_classes: Option<Vec<String>>,
+    pub remote_clipboard: Option<RemoteClipboard>,
+    pub safe_restore_apps: Option<Vec<String>>,
+    pub capture_formats: Option<FormatList>,
+    pub ignore_formats: Option<FormatList>,
+    pub adaptive_formats: Option<bool>,
+    pub audit_log: Option<PathBuf>,
+    pub log_level: Option<LogLevel>,
+    pub log_file: Option<PathBuf>,
+    pub stream_deck_port: Option<u16>,
+    pub shared_view: Option<bool>,
+    pub jump_list: Option<bool>,
+    pub telemetry: Option<bool>,
+}
+
+impl Overrides {
+    /// The layer given on the command line
+    pub fn from_opts(opts: &Opts) -> Self {
+        let run = &opts.run;
+        Self {
+            config: opts.config.clone(),
+            profile: opts.profile.clone(),
+            max_history: run.max_history,
+            persist: run.persist.clone(),
+            flush: run.flush,
+            storage: run.storage,
+            encrypt: run.encrypt.then_some(true),
+            hotkey: run.hotkey,
+            peek_hotkey: run.peek_hotkey,
+            picker_hotkey: run.picker_hotkey,
+            undo_hotkey: run.undo_hotkey,
+            next_hotkey: run.next_hotkey,
+            previous_hotkey: run.previous_hotkey,
+            pinned_hotkey: run.pinned_hotkey,
+            search_hotkey: run.search_hotkey,
+            snippet_hotkey: run.snippet_hotkey,
+            stack_hotkey: run.stack_hotkey,
+            queue_hotkey: run.queue_hotkey,
+            queue_keys: Some(run.queue_key.clone()).filter(|keys| !keys.is_empty()),
+            tap_key: run.tap_key,
+            recapture_hotkey: run.recapture_hotkey,
+            restore_timeout_ms: run.restore_timeout_ms,
+            debounce_ms: run.debounce_ms,
+            plain_paste: run.plain_paste.then_some(true),
+            html_to_text: run.html_to_text.then_some(true),
+            terminal_paste: run.terminal_paste.then_some(true),
+            paste_mode: run.paste_mode,
+            mode: run.mode,
+            start_paused: run.start_paused.then_some(true),
+            lazy_restore: run.lazy_restore.then_some(true),
+            tag_restores: run.tag_restores.then_some(true),
+            provenance: run.provenance.then_some(true),
+            dedup: run.dedup,
+            plain_paste_hotkey: run.plain_paste_hotkey,
+            auto_grow: run.auto_grow.then_some(true),
+            monitor_stacks: run.monitor_stacks.then_some(true),
+            max_item_size: run.max_item_size,
+            max_total_memory: run.max_total_memory,
+            max_age: run.max_age,
+            quiet_hours: run.quiet_hours.clone(),
+            exclude_apps: Some(run.exclude_app.clone()).filter(|apps| !apps.is_empty()),
+            burn_apps: Some(run.burn_app.clone()).filter(|apps| !apps.is_empty()),
+            passthrough_apps: Some(run.passthrough_app.clone()).filter(|apps| !apps.is_empty()),
+            passthrough_classes: Some(run.passthrough_class.clone())
+                .filter(|classes| !classes.is_empty()),
+            remote_clipboard: run.remote_clipboard,
+            safe_restore_apps: Some(run.safe_restore_app.clone()).filter(|apps| !apps.is_empty()),
+            capture_formats: run.capture_formats.clone(),
+            ignore_formats: run.ignore_formats.clone(),
+            adaptive_formats: run.adaptive_formats.then_some(true),
+            audit_log: run.audit_log.clone(),
+            log_level: run.log_level,
+            log_file: run.log_file.clone(),
+            stream_deck_port: run.stream_deck_port,
+            shared_view: run.shared_view.then_some(true),
+            jump_list: run.jump_list.then_some(true),
+            telemetry: run.telemetry.then_some(true),
+            ..Default::default()
+        }
+    }
+
+    /// The layer given by `FILO_CLIPBOARD_*` environment variables
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        Ok(Self {
+            config: lookup(&format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from),
+            profile: lookup(&format!("{}PROFILE", ENV_PREFIX)),
+            max_history: parse_var(&lookup, "MAX_HISTORY")?,
+            similarity_threshold: parse_var(&lookup, "SIMILARITY_THRESHOLD")?,
+            capture: parse_var(&lookup, "CAPTURE")?,
+            index_from: parse_var(&lookup, "INDEX_FROM")?,
+            picker_order: parse_var(&lookup, "PICKER_ORDER")?,
+            persist: lookup(&format!("{}PERSIST", ENV_PREFIX)).map(PathBuf::from),
+            flush: parse_var(&lookup, "FLUSH")?,
+            storage: parse_var(&lookup, "STORAGE")?,
+            encrypt: parse_var(&lookup, "ENCRYPT")?,
+            hotkey: parse_var(&lookup, "HOTKEY")?,
+            peek_hotkey: parse_var(&lookup, "PEEK_HOTKEY")?,
+            picker_hotkey: parse_var(&lookup, "PICKER_HOTKEY")?,
+            undo_hotkey: parse_var(&lookup, "UNDO_HOTKEY")?,
+            next_hotkey: parse_var(&lookup, "NEXT_HOTKEY")?,
+            previous_hotkey: parse_var(&lookup, "PREVIOUS_HOTKEY")?,
+            pinned_hotkey: parse_var(&lookup, "PINNED_HOTKEY")?,
+            search_hotkey: parse_var(&lookup, "SEARCH_HOTKEY")?,
+            snippet_hotkey: parse_var(&lookup, "SNIPPET_HOTKEY")?,
+            stack_hotkey: parse_var(&lookup, "STACK_HOTKEY")?,
+            queue_hotkey: parse_var(&lookup, "QUEUE_HOTKEY")?,
+            queue_keys: parse_list_var(&lookup, "QUEUE_KEYS")?,
+            tap_key: parse_var(&lookup, "TAP_KEY")?,
+            recapture_hotkey: parse_var(&lookup, "RECAPTURE_HOTKEY")?,
+            restore_timeout_ms: parse_var(&lookup, "RESTORE_TIMEOUT_MS")?,
+            debounce_ms: parse_var(&lookup, "DEBOUNCE_MS")?,
+            plain_paste: parse_var(&lookup, "PLAIN_PASTE")?,
+            html_to_text: parse_var(&lookup, "HTML_TO_TEXT")?,
+            terminal_paste: parse_var(&lookup, "TERMINAL_PASTE")?,
+            paste_mode: parse_var(&lookup, "PASTE_MODE")?,
+            mode: parse_var(&lookup, "MODE")?,
+            start_paused: parse_var(&lookup, "START_PAUSED")?,
+            lazy_restore: parse_var(&lookup, "LAZY_RESTORE")?,
+            tag_restores: parse_var(&lookup, "TAG_RESTORES")?,
+            provenance: parse_var(&lookup, "PROVENANCE")?,
+            dedup: parse_var(&lookup, "DEDUP")?,
+            plain_paste_hotkey: parse_var(&lookup, "PLAIN_PASTE_HOTKEY")?,
+            auto_grow: parse_var(&lookup, "AUTO_GROW")?,
+            monitor_stacks: parse_var(&lookup, "MONITOR_STACKS")?,
+            max_item_size: parse_var(&lookup, "MAX_ITEM_SIZE")?,
+            max_total_memory: parse_var(&lookup, "MAX_TOTAL_MEMORY")?,
+            max_age: parse_var(&lookup, "MAX_AGE")?,
+            quiet_hours: parse_var(&lookup, "QUIET_HOURS")?,
+            exclude_apps: lookup(&format!("{}EXCLUDE_APPS", ENV_PREFIX))
+                .map(|apps| apps.split(',').map(str::to_owned).collect()),
+            burn_apps: lookup(&format!("{}BURN_APPS", ENV_PREFIX))
+                .map(|apps| apps.split(',').map(str::to_owned).collect()),
+            passthrough_apps: lookup(&format!("{}PASSTHROUGH_APPS", ENV_PREFIX))
+                .map(|apps| apps.split(',').map(str::to_owned).collect()),
+            passthrough_classes: lookup(&format!("{}PASSTHROUGH_CLASSES", ENV_PREFIX))
+                .map(|classes| classes.split(',').map(str::to_owned).collect()),
+            remote_clipboard: parse_var(&lookup, "REMOTE_CLIPBOARD")?,
+            safe_restore_apps: lookup(&format!("{}SAFE_RESTORE_APPS", ENV_PREFIX))
+                .map(|apps| apps.split(',').map(str::to_owned).collect()),
+            capture_formats: parse_var(&lookup, "CAPTURE_FORMATS")?,
+            ignore_formats: parse_var(&lookup, "IGNORE_FORMATS")?,
+            adaptive_formats: parse_var(&lookup, "ADAPTIVE_FORMATS")?,
+            audit_log: lookup(&format!("{}AUDIT_LOG", ENV_PREFIX)).map(PathBuf::from),
+            log_level: parse_var(&lookup, "LOG_LEVEL")?,
+            log_file: lookup(&format!("{}LOG_FILE", ENV_PREFIX)).map(PathBuf::from),
+            stream_deck_port: parse_var(&lookup, "STREAM_DECK_PORT")?,
+            shared_view: parse_var(&lookup, "SHARED_VIEW")?,
+            jump_list: parse_var(&lookup, "JUMP_LIST")?,
+            telemetry: parse_var(&lookup, "TELEMETRY")?,
+        })
+    }
+
+    /// Combine two layers, with `self` taking precedence over `lower`
+    pub fn or(self, lower: Self) -> Self {
+        Self {
+            config: self.config.or(lower.config),
+            profile: self.profile.or(lower.profile),
+            max_history: self.max_history.or(lower.max_history),
+            similarity_threshold: self.similarity_threshold.or(lower.similarity_threshold),
+            capture: self.capture.or(lower.capture),
+            index_from: self.index_from.or(lower.index_from),
+            picker_order: self.picker_order.or(lower.picker_order),
+            persist: self.persist.or(lower.persist),
+            flush: self.flush.or(lower.flush),
+            storage: self.storage.or(lower.storage),
+            encrypt: self.encrypt.or(lower.encrypt),
+            hotkey: self.hotkey.or(lower.hotkey),
+            peek_hotkey: self.peek_hotkey.or(lower.peek_hotkey),
+            picker_hotkey: self.picker_hotkey.or(lower.picker_hotkey),
+            undo_hotkey: self.undo_hotkey.or(lower.undo_hotkey),
+            next_hotkey: self.next_hotkey.or(lower.next_hotkey),
+            previous_hotkey: self.previous_hotkey.or(lower.previous_hotkey),
+            pinned_hotkey: self.pinned_hotkey.or(lower.pinned_hotkey),
+            search_hotkey: self.search_hotkey.or(lower.search_hotkey),
+            snippet_hotkey: self.snippet_hotkey.or(lower.snippet_hotkey),
+            stack_hotkey: self.stack_hotkey.or(lower.stack_hotkey),
+            queue_hotkey: self.queue_hotkey.or(lower.queue_hotkey),
+            queue_keys: self.queue_keys.or(lower.queue_keys),
+            tap_key: self.tap_key.or(lower.tap_key),
+            recapture_hotkey: self.recapture_hotkey.or(lower.recapture_hotkey),
+            restore_timeout_ms: self.restore_timeout_ms.or(lower.restore_timeout_ms),
+            debounce_ms: self.debounce_ms.or(lower.debounce_ms),
+            plain_paste: self.plain_paste.or(lower.plain_paste),
+            html_to_text: self.html_to_text.or(lower.html_to_text),
+            terminal_paste: self.terminal_paste.or(lower.terminal_paste),
+            paste_mode: self.paste_mode.or(lower.paste_mode),
+            mode: self.mode.or(lower.mode),
+            start_paused: self.start_paused.or(lower.start_paused),
+            lazy_restore: self.lazy_restore.or(lower.lazy_restore),
+            tag_restores: self.tag_restores.or(lower.tag_restores),
+            provenance: self.provenance.or(lower.provenance),
+            dedup: self.dedup.or(lower.dedup),
+            plain_paste_hotkey: self.plain_paste_hotkey.or(lower.plain_paste_hotkey),
+            auto_grow: self.auto_grow.or(lower.auto_grow),
+            monitor_stacks: self.monitor_stacks.or(lower.monitor_stacks),
+            max_item_size: self.max_item_size.or(lower.max_item_size),
+            max_total_memory: self.max_total_memory.or(lower.max_total_memory),
+            max_age: self.max_age.or(lower.max_age),
+            quiet_hours: self.quiet_hours.or(lower.quiet_hours),
+            exclude_apps: self.exclude_apps.or(lower.exclude_apps),
+            burn_apps: self.burn_apps.or(lower.burn_apps),
+            passthrough_apps: self.passthrough_apps.or(lower.passthrough_apps),
+            passthrough_classes: self.passthrough_classes.or(lower.passthrough_classes),
+            remote_clipboard: self.remote_clipboard.or(lower.remote_clipboard),
+            safe_restore_apps: self.safe_restore_apps.or(lower.safe_restore_apps),
+            capture_formats: self.capture_formats.or(lower.capture_formats),
+            ignore_formats: self.ignore_formats.or(lower.ignore_formats),
+            adaptive_formats: self.adaptive_formats.or(lower.adaptive_formats),
+            audit_log: self.audit_log.or(lower.audit_log),
+            log_level: self.log_level.or(lower.log_level),
+            log_file: self.log_file.or(lower.log_file),
+            stream_deck_port: self.stream_deck_port.or(lower.stream_deck_port),
+            shared_view: self.shared_view.or(lower.shared_view),
+            jump_list: self.jump_list.or(lower.jump_list),
+            telemetry: self.telemetry.or(lower.telemetry),
+        }
+    }
+
+    /// The config file selected by this layer, or the default location
+    pub fn config_path(&self) -> Option<PathBuf> {
+        self.config.clone().or_else(config::default_path)
+    }
+
+    /// Overwrite the profile values which are set in this layer
+    pub fn apply(&self, profile: &mut Profile) {
+        if let Some(max_history) = self.max_history {
+            profile.max_history = max_history;
+        }
+        if let Some(similarity_threshold) = self.similarity_threshold {
+            profile.similarity_threshold = similarity_threshold;
+        }
+        if let Some(capture) = self.capture {
+            profile.capture = capture;
+        }
+        if let Some(index_from) = self.index_from {
+            profile.index_from = index_from;
+        }
+        if let Some(picker_order) = self.picker_order {
+            profile.picker_order = picker_order;
+        }
+    }
+}
+
+/// Parse a comma separated list from an environment variable, where an empty value is an empty list
+fn parse_list_var<T: FromStr>(
+    lookup: &impl Fn(&str) -> Option<String>,
+    key: &str,
+) -> Result<Option<Vec<T>>, ConfigError> {
+    let name = format!("{}{}", ENV_PREFIX, key);
+    match lookup(&name) {
+        Some(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Some)
+            .map_err(|_| ConfigError::InvalidEnvVar { name, value }),
+        None => Ok(None),
+    }
+}
+
+fn parse_var<T: FromStr>(
+    lookup: &impl Fn(&str) -> Option<String>,
+    key: &str,
+) -> Result<Option<T>, ConfigError> {
+    let name = format!("{}{}", ENV_PREFIX, key);
+    match lookup(&name) {
+        Some(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(ConfigError::InvalidEnvVar { name, value }),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Load and validate a config file, printing any problems and falling back to defaults where needed
+fn load_or_default(path: &Path) -> Config {
+    match Config::load(path) {
+        Ok(mut config) => {
+            for issue in config.validate() {
+                eprintln!("{}: {}", path.display(), issue);
+            }
+            config
+        }
+        Err(error) => {
+            eprintln!("{}: {}, using the default settings", path.display(), error);
+            Config::default()
+        }
+    }
+}
+
+/// The fully resolved settings: defaults, then the config file, then the environment, then the command line
+pub struct Settings {
+    pub config_path: Option<PathBuf>,
+    pub config: Config,
+    pub profile_name: String,
+    pub profile: Profile,
+    pub persist: Option<PathBuf>,
+    pub flush: FlushStrategy,
+    pub storage: StorageKind,
+    /// Encrypt the history file for the current user
+    pub encrypt: bool,
+    pub hotkey: Hotkey,
+    pub peek_hotkey: Hotkey,
+    pub picker_hotkey: Hotkey,
+    pub undo_hotkey: Hotkey,
+    pub next_hotkey: Hotkey,
+    pub previous_hotkey: Hotkey,
+    pub pinned_hotkey: Hotkey,
+    pub search_hotkey: Hotkey,
+    pub snippet_hotkey: Hotkey,
+    pub stack_hotkey: Hotkey,
+    pub queue_hotkey: Hotkey,
+    /// The keys pressed after each paste from the queue hotkey
+    pub queue_keys: Vec<Keystroke>,
+    /// The key which pastes when tapped and opens the picker when held, if there is one
+    pub tap_key: Option<Keystroke>,
+    /// The hotkey which reads the clipboard again into the front item, if there is one
+    pub recapture_hotkey: Option<Hotkey>,
+    pub restore_timeout: Duration,
+    /// How long to wait after the clipboard changes before reading it, if at all
+    pub debounce: Option<Duration>,
+    pub plain_paste: bool,
+    /// Paste HTML items as their plain text
+    pub html_to_text: bool,
+    /// Paste into terminals with the keys they use
+    pub terminal_paste: bool,
+    pub paste_mode: PasteMode,
+    /// Take the newest or the oldest item first
+    pub mode: Order,
+    /// Start with monitoring paused
+    pub start_paused: bool,
+    /// Only put the next item on the clipboard when it's pasted
+    pub lazy_restore: bool,
+    pub tag_restores: bool,
+    /// Add a provenance marker to restored items
+    pub provenance: bool,
+    pub dedup: Dedup,
+    pub plain_paste_hotkey: Hotkey,
+    /// Raise the history limit for a while rather than drop items
+    pub auto_grow: bool,
+    /// Follow the monitor showing the foreground window with the stack of the same number
+    pub monitor_stacks: bool,
+    /// The largest format recorded, in bytes
+    pub max_item_size: Option<usize>,
+    /// The most memory the history's formats may take up, in bytes
+    pub max_total_memory: Option<usize>,
+    /// How long items are kept before they're wiped, if there's a limit
+    pub max_age: Option<Duration>,
+    /// When capture is paused each day
+    pub quiet_hours: QuietHours,
+    /// Programs whose copies aren't recorded
+    pub exclude_apps: Vec<String>,
+    /// Programs whose copies are wiped once they've been pasted
+    pub burn_apps: Vec<String>,
+    /// Programs and window classes the hotkeys are released for while they're in the foreground
+    pub passthrough_apps: Vec<String>,
+    pub passthrough_classes: Vec<String>,
+    pub remote_clipboard: RemoteClipboard,
+    /// Programs which are only given the standard formats when pasting
+    pub safe_restore_apps: Vec<String>,
+    /// The only formats recorded, if set
+    pub capture_formats: Option<FormatList>,
+    /// Formats which are never recorded
+    pub ignore_formats: Option<FormatList>,
+    /// Learn which large formats are read, and drop the unread ones under memory pressure
+    pub adaptive_formats: bool,
+    /// Where pastes and pops are logged, if anywhere
+    pub audit_log: Option<PathBuf>,
+    pub log_level: LevelFilter,
+    /// The file the log is appended to, if any
+    pub log_file: Option<PathBuf>,
+    /// The port Stream Deck plugins connect to, if they're accepted
+    pub stream_deck_port: Option<u16>,
+    /// Whether the history previews are published in shared memory
+    pub shared_view: bool,
+    /// Whether the most recent items are offered in the jump list
+    pub jump_list: bool,
+    /// Whether feature use and errors are counted locally
+    pub telemetry: bool,
+    /// The environment and command line layers, reapplied whenever the profile changes
+    pub overrides: Overrides,
+    /// The administrator's limits, which override everything else
+    pub policy: Policy,
+}
+
+impl Settings {
+    /// Resolve every layer, then apply the administrator's policy. Problems with the config file or
+    /// profile are reported and replaced with defaults, so only an invalid environment variable is
+    /// an error
+    pub fn resolve(opts: &Opts) -> Result<Self, ConfigError> {
+        let overrides = Overrides::from_opts(opts).or(Overrides::from_env()?);
+        let profile_name = overrides.profile.clone();
+        Ok(Self::build(overrides, Policy::load(), profile_name))
+    }
+
+    /// Load the config file and resolve the settings from it, `overrides` and `policy`, starting
+    /// with `profile_name` if it's given
+    fn build(overrides: Overrides, policy: Policy, profile_name: Option<String>) -> Self {
+        let config_path = overrides.config_path();
+        let config = match &config_path {
+            Some(path) => load_or_default(path),
+            None => Config::default(),
+        };
+        Self::from_config(config_path, config, overrides, policy, profile_name)
+    }
+
+    /// Resolve the settings from a loaded `config`, as for `build`. The profile's hotkeys and filters
+    /// replace the config's, beneath the environment and command line
+    fn from_config(
+        config_path: Option<PathBuf>,
+        config: Config,
+        overrides: Overrides,
+        policy: Policy,
+        profile_name: Option<String>,
+    ) -> Self {
+        let mut profile_name = profile_name
+            .or_else(|| config.active_profile.clone())
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_owned());
+        let profile = config.profile(&profile_name).unwrap_or_else(|error| {
+            eprintln!("{}, using \"{}\"", error, DEFAULT_PROFILE);
+            profile_name = DEFAULT_PROFILE.to_owned();
+            Profile::default()
+        });
+
+        let persist = overrides
+            .persist
+            .clone()
+            .or_else(|| config.persist.clone())
+            .filter(|_| !policy.disable_persistence);
+        let flush = overrides.flush.unwrap_or(config.flush);
+        let storage = overrides.storage.unwrap_or(config.storage);
+        let encrypt = overrides.encrypt.unwrap_or(config.encrypt);
+        let hotkey = overrides.hotkey.or(profile.hotkey).unwrap_or(config.hotkey);
+        let peek_hotkey = overrides
+            .peek_hotkey
+            .or(profile.peek_hotkey)
+            .or(config.peek_hotkey)
+            .unwrap_or_else(Hotkey::default_peek);
+        let picker_hotkey = overrides
+            .picker_hotkey
+            .or(profile.picker_hotkey)
+            .or(config.picker_hotkey)
+            .unwrap_or_else(Hotkey::default_picker);
+        let undo_hotkey = overrides
+            .undo_hotkey
+            .or(profile.undo_hotkey)
+            .or(config.undo_hotkey)
+            .unwrap_or_else(Hotkey::default_undo);
+        let next_hotkey = overrides
+            .next_hotkey
+            .or(profile.next_hotkey)
+            .or(config.next_hotkey)
+            .unwrap_or_else(Hotkey::default_next);
+        let previous_hotkey = overrides
+            .previous_hotkey
+            .or(profile.previous_hotkey)
+            .or(config.previous_hotkey)
+            .unwrap_or_else(Hotkey::default_previous);
+        let pinned_hotkey = overrides
+            .pinned_hotkey
+            .or(profile.pinned_hotkey)
+            .or(config.pinned_hotkey)
+            .unwrap_or_else(Hotkey::default_pinned);
+        let search_hotkey = overrides
+            .search_hotkey
+            .or(profile.search_hotkey)
+            .or(config.search_hotkey)
+            .unwrap_or_else(Hotkey::default_search);
+        let snippet_hotkey = overrides
+            .snippet_hotkey
+            .or(profile.snippet_hotkey)
+            .or(config.snippet_hotkey)
+            .unwrap_or_else(Hotkey::default_snippet);
+        let stack_hotkey = overrides
+            .stack_hotkey
+            .or(profile.stack_hotkey)
+            .or(config.stack_hotkey)
+            .unwrap_or_else(Hotkey::default_stack);
+        let queue_hotkey = overrides
+            .queue_hotkey
+            .or(profile.queue_hotkey)
+            .or(config.queue_hotkey)
+            .unwrap_or_else(Hotkey::default_queue);
+        let queue_keys = overrides
+            .queue_keys
+            .clone()
+            .or_else(|| config.queue_keys.clone())
+            .unwrap_or_else(|| vec![Keystroke::tab()]);
+        let tap_key = overrides.tap_key.or(profile.tap_key).or(config.tap_key);
+        let recapture_hotkey = overrides
+            .recapture_hotkey
+            .or(profile.recapture_hotkey)
+            .or(config.recapture_hotkey);
+        let plain_paste =
+            overrides.plain_paste.unwrap_or(config.plain_paste) || policy.force_plain_paste;
+        let html_to_text = overrides.html_to_text.unwrap_or(config.html_to_text);
+        let terminal_paste = overrides.terminal_paste.unwrap_or(config.terminal_paste);
+        let paste_mode = overrides.paste_mode.unwrap_or(config.paste_mode);
+        let mode = overrides.mode.unwrap_or(config.mode);
+        let start_paused = overrides.start_paused.unwrap_or(config.start_paused);
+        let lazy_restore = overrides.lazy_restore.unwrap_or(config.lazy_restore);
+        let tag_restores = overrides.tag_restores.unwrap_or(config.tag_restores);
+        let provenance = overrides.provenance.unwrap_or(config.provenance);
+        let dedup = overrides.dedup.unwrap_or(config.dedup);
+        let plain_paste_hotkey = overrides
+            .plain_paste_hotkey
+            .or(profile.plain_paste_hotkey)
+            .or(config.plain_paste_hotkey)
+            .unwrap_or_else(Hotkey::default_plain_paste);
+        // Waiting no time at all would mean never restoring anything
+        let restore_timeout = Duration::from_millis(
+            overrides
+                .restore_timeout_ms
+                .filter(|timeout| *timeout > 0)
+                .or(config.restore_timeout_ms)
+                .unwrap_or(DEFAULT_RESTORE_TIMEOUT_MS),
+        );
+        let debounce = overrides
+            .debounce_ms
+            .or(config.debounce_ms)
+            .filter(|debounce| *debounce > 0)
+            .map(Duration::from_millis);
+
+        let auto_grow = overrides.auto_grow.unwrap_or(config.auto_grow);
+        let monitor_stacks = overrides.monitor_stacks.unwrap_or(config.monitor_stacks);
+        let max_item_size = overrides
+            .max_item_size
+            .or(config.max_item_size)
+            .map(|size| size.0 as usize);
+        let max_total_memory = overrides
+            .max_total_memory
+            .or(config.max_total_memory)
+            .map(|size| size.0 as usize);
+        let max_age = overrides
+            .max_age
+            .or(profile.max_age)
+            .or(config.max_age)
+            .map(|age| age.0);
+        let quiet_hours = overrides
+            .quiet_hours
+            .clone()
+            .or_else(|| profile.quiet_hours.clone())
+            .or_else(|| config.quiet_hours.clone())
+            .unwrap_or_default();
+        let exclude_apps = overrides
+            .exclude_apps
+            .clone()
+            .or_else(|| profile.exclude_apps.clone())
+            .unwrap_or_else(|| config.exclude_apps.clone());
+        let burn_apps = overrides
+            .burn_apps
+            .clone()
+            .or_else(|| profile.burn_apps.clone())
+            .unwrap_or_else(|| config.burn_apps.clone());
+        let passthrough_apps = overrides
+            .passthrough_apps
+            .clone()
+            .or_else(|| profile.passthrough_apps.clone())
+            .unwrap_or_else(|| config.passthrough_apps.clone());
+        let passthrough_classes = overrides
+            .passthrough_classes
+            .clone()
+            .or_else(|| profile.passthrough_classes.clone())
+            .unwrap_or_else(|| config.passthrough_classes.clone());
+        let remote_clipboard = overrides
+            .remote_clipboard
+            .unwrap_or(config.remote_clipboard);
+        let safe_restore_apps = overrides
+            .safe_restore_apps
+            .clone()
+            .or_else(|| config.safe_restore_apps.clone())
+            .unwrap_or_else(|| {
+                DEFAULT_SAFE_RESTORE_APPS
+                    .iter()
+                    .map(|&app| app.to_owned())
+                    .collect()
+            });
+        let capture_formats = overrides
+            .capture_formats
+            .clone()
+            .or_else(|| profile.capture_formats.clone())
+            .or_else(|| config.capture_formats.clone());
+        let ignore_formats = overrides
+            .ignore_formats
+            .clone()
+            .or_else(|| profile.ignore_formats.clone())
+            .or_else(|| config.ignore_formats.clone());
+        let adaptive_formats = overrides
+            .adaptive_formats
+            .unwrap_or(config.adaptive_formats);
+        let audit_log = overrides
+            .audit_log
+            .clone()
+            .or_else(|| config.audit_log.clone());
+        let log_level = overrides
+            .log_level
+            .or(config.log_level)
+            .map(|level| level.0)
+            .unwrap_or(DEFAULT_LOG_LEVEL);
+        let log_file = overrides
+            .log_file
+            .clone()
+            .or_else(|| config.log_file.clone());
+        let stream_deck_port = overrides.stream_deck_port.or(config.stream_deck_port);
+        let shared_view = overrides.shared_view.unwrap_or(config.shared_view);
+        let jump_list = overrides.jump_list.unwrap_or(config.jump_list);
+        let telemetry = overrides.telemetry.unwrap_or(config.telemetry);
+
+        let mut settings = Self {
+            config_path,
+            config,
+            profile_name,
+            profile,
+            persist,
+            flush,
+            storage,
+            encrypt,
+            hotkey,
+            peek_hotkey,
+            picker_hotkey,
+            undo_hotkey,
+            next_hotkey,
+            previous_hotkey,
+            pinned_hotkey,
+            search_hotkey,
+            snippet_hotkey,
+            stack_hotkey,
+            queue_hotkey,
+            queue_keys,
+            tap_key,
+            recapture_hotkey,
+            restore_timeout,
+            debounce,
+            plain_paste,
+            html_to_text,
+            terminal_paste,
+            paste_mode,
+            mode,
+            start_paused,
+            lazy_restore,
+            tag_restores,
+            provenance,
+            dedup,
+            plain_paste_hotkey,
+            auto_grow,
+            monitor_stacks,
+            max_item_size,
+            max_total_memory,
+            max_age,
+            quiet_hours,
+            exclude_apps,
+            burn_apps,
+            passthrough_apps,
+            passthrough_classes,
+            remote_clipboard,
+            safe_restore_apps,
+            capture_formats,
+            ignore_formats,
+            adaptive_formats,
+            audit_log,
+            log_level,
+            log_file,
+            stream_deck_port,
+            shared_view,
+            jump_list,
+            telemetry,
+            overrides,
+            policy,
+        };
+        settings.overrides.apply(&mut settings.profile);
+        settings.policy.apply(&mut settings.profile);
+        settings
+    }
+
+    /// Read the config file again and apply it, staying on the current profile. Hotkeys, the
+    /// history file, the log file and the Stream Deck port are set up at startup, so changes to them are ignored and
+    /// described in the returned notes instead
+    pub fn reload(&mut self) -> Vec<String> {
+        let mut reloaded = Self::build(
+            self.overrides.clone(),
+            self.policy.clone(),
+            Some(self.profile_name.clone()),
+        );
+        let mut notes = Vec::new();
+        let hotkeys = |settings: &Self| {
+            [
+                settings.hotkey,
+                settings.peek_hotkey,
+                settings.picker_hotkey,
+                settings.plain_paste_hotkey,
+                settings.undo_hotkey,
+                settings.next_hotkey,
+                settings.previous_hotkey,
+                settings.pinned_hotkey,
+                settings.search_hotkey,
+                settings.snippet_hotkey,
+                settings.stack_hotkey,
+                settings.queue_hotkey,
+            ]
+        };
+        if hotkeys(&reloaded) != hotkeys(self)
+            || reloaded.tap_key != self.tap_key
+            || reloaded.recapture_hotkey != self.recapture_hotkey
+        {
+            notes.push("Hotkey changes take effect after a restart".to_owned());
+        }
+        if reloaded.persist != self.persist
+            || reloaded.flush != self.flush
+            || reloaded.storage != self.storage
+            || reloaded.encrypt != self.encrypt
+        {
+            notes.push("History file changes take effect after a restart".to_owned());
+        }
+        if reloaded.log_file != self.log_file {
+            notes.push("Log file changes take effect after a restart".to_owned());
+        }
+        if reloaded.stream_deck_port != self.stream_deck_port {
+            notes.push("Stream Deck port changes take effect after a restart".to_owned());
+        }
+        if reloaded.shared_view != self.shared_view {
+            notes.push("Shared view changes take effect after a restart".to_owned());
+        }
+        if reloaded.jump_list != self.jump_list {
+            notes.push("Jump list changes take effect after a restart".to_owned());
+        }
+        if reloaded.telemetry != self.telemetry {
+            notes.push("Telemetry changes take effect after a restart".to_owned());
+        }
+        reloaded.hotkey = self.hotkey;
+        reloaded.peek_hotkey = self.peek_hotkey;
+        reloaded.picker_hotkey = self.picker_hotkey;
+        reloaded.plain_paste_hotkey = self.plain_paste_hotkey;
+        reloaded.undo_hotkey = self.undo_hotkey;
+        reloaded.next_hotkey = self.next_hotkey;
+        reloaded.previous_hotkey = self.previous_hotkey;
+        reloaded.pinned_hotkey = self.pinned_hotkey;
+        reloaded.search_hotkey = self.search_hotkey;
+        reloaded.snippet_hotkey = self.snippet_hotkey;
+        reloaded.stack_hotkey = self.stack_hotkey;
+        reloaded.queue_hotkey = self.queue_hotkey;
+        reloaded.tap_key = self.tap_key;
+        reloaded.recapture_hotkey = self.recapture_hotkey;
+        reloaded.keep_startup_settings(self);
+        *self = reloaded;
+        notes
+    }
+
+    /// Keep the settings other than the hotkeys which are only read at startup from `current`
+    fn keep_startup_settings(&mut self, current: &mut Self) {
+        self.persist = current.persist.take();
+        self.flush = current.flush;
+        self.storage = current.storage;
+        self.encrypt = current.encrypt;
+        self.log_file = current.log_file.take();
+        self.stream_deck_port = current.stream_deck_port;
+        self.shared_view = current.shared_view;
+        self.jump_list = current.jump_list;
+        self.telemetry = current.telemetry;
+    }
+
+    /// Change a setting of the current profile. If `save` is set, the change is also written to the
+    /// config file, otherwise it only lasts until the profile is switched
+    pub fn set(&mut self, key: &str, value: &str, save: bool) -> Result<(), ConfigError> {
+        if let Some(limit) = self.policy.max_history {
+            if key == "max_history" && value.parse().map(|n: usize| n > limit).unwrap_or(false) {
+                return Err(ConfigError::Policy(format!(
+                    "max_history is limited to {} by policy",
+                    limit
+                )));
+            }
+        }
+        self.profile.set(key, value)?;
+        if save {
+            let path = self.config_path.clone().ok_or_else(|| {
+                ConfigError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no config file given and %APPDATA% isn't set",
+                ))
+            })?;
+            let mut profile = self.config.profile(&self.profile_name)?;
+            profile.set(key, value)?;
+            self.config
+                .profiles
+                .insert(self.profile_name.clone(), profile);
+            self.config.save(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Switch to another profile from the config, keeping the environment and command line overrides.
+    /// The profile's hotkeys replace the current ones, so they need registering again
+    pub fn switch_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        // Resolving falls back to the default profile, so a missing one is caught first
+        self.config.profile(name)?;
+        let mut switched = Self::from_config(
+            self.config_path.clone(),
+            self.config.clone(),
+            self.overrides.clone(),
+            self.policy.clone(),
+            Some(name.to_owned()),
+        );
+        switched.keep_startup_settings(self);
+        *self = switched;
+        Ok(())
+    }
+}
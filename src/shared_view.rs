@@ -0,0 +1,217 @@
+use std::{
+    fs::File,
+    os::windows::io::FromRawHandle,
+    ptr,
+    sync::atomic::{fence, AtomicU32, Ordering},
+    thread,
+};
+
+use winapi::um::memoryapi;
+
+use crate::winapi_functions::{
+    create_file_mapping_w, map_view_of_file, open_file_mapping_w, unmap_view_of_file,
+};
+
+/// The name companion tools open the section with. Local to the session, like the instance mutex
+pub const SECTION_NAME: &str = "Local\\filo-clipboard-history";
+const SIZE: usize = 64 * 1024;
+const MAGIC: u32 = u32::from_le_bytes(*b"FILV");
+const VERSION: u32 = 1;
+/// The magic number, version, sequence and text length as 32 bit integers, then the history's
+/// revision as a 64 bit one, all little endian, followed by the text
+const HEADER_SIZE: usize = 24;
+const SEQUENCE_OFFSET: usize = 8;
+const LENGTH_OFFSET: usize = 12;
+const REVISION_OFFSET: usize = 16;
+/// How many times a reader tries again when it catches the view part way through an update
+const READ_ATTEMPTS: usize = 100;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer the size of the section, aligned for the sequence number
+    fn section() -> Vec<u64> {
+        vec![0; SIZE / 8]
+    }
+
+    #[test]
+    fn published_text_is_read() {
+        let mut buffer = section();
+        let view = buffer.as_mut_ptr() as *mut u8;
+        unsafe {
+            assert_eq!(read_view(view), Err(ReadError::Invalid));
+            write_view(view, 7, "0\tfirst\n1\tsecond");
+            assert_eq!(read_view(view), Ok((7, "0\tfirst\n1\tsecond".to_owned())));
+            write_view(view, 8, "");
+            assert_eq!(read_view(view), Ok((8, String::new())));
+            assert_eq!(sequence(view).load(Ordering::Relaxed), 4);
+        }
+    }
+
+    #[test]
+    fn reader_waits_for_writer() {
+        let mut buffer = section();
+        let view = buffer.as_mut_ptr() as *mut u8;
+        unsafe {
+            write_view(view, 1, "0\tfirst");
+            // An odd sequence number means an update is under way
+            sequence(view).store(3, Ordering::Relaxed);
+            assert_eq!(read_view(view), Err(ReadError::Busy));
+        }
+    }
+
+    #[test]
+    fn long_text_is_cut_at_a_line() {
+        let line = "x".repeat(1000);
+        let text = vec![line.as_str(); 100].join("\n");
+        let fitted = fit(&text);
+        assert!(fitted.len() <= SIZE - HEADER_SIZE);
+        assert!(fitted.ends_with(&line));
+        assert_eq!(fit("short"), "short");
+    }
+}
+
+/// A shared memory section holding the history's previews, one line per item as in the reply to
+/// `list`, and the revision they were taken at. Updates are guarded by a sequence number which is
+/// odd while the text is being rewritten, so readers can copy it without taking a lock and try
+/// again if it changed under them
+pub struct SharedView {
+    _mapping: File,
+    view: *mut u8,
+}
+
+impl SharedView {
+    pub fn create() -> Result<Self, String> {
+        let handle = create_file_mapping_w(SECTION_NAME, SIZE as u32)
+            .map_err(|error| format!("Could not create the shared view: {}", error))?;
+        // The file takes ownership of the handle, closing it on drop
+        let mapping = unsafe { File::from_raw_handle(handle) };
+        let view = map_view_of_file(&mapping, memoryapi::FILE_MAP_WRITE, SIZE)
+            .map_err(|error| format!("Could not map the shared view: {}", error))?;
+        Ok(Self {
+            _mapping: mapping,
+            view,
+        })
+    }
+
+    /// Replace the previews, dropping whole lines from the end if they don't fit
+    pub fn publish(&mut self, revision: u64, text: &str) {
+        unsafe { write_view(self.view, revision, text) }
+    }
+}
+
+impl Drop for SharedView {
+    fn drop(&mut self) {
+        unsafe { unmap_view_of_file(self.view) };
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ReadError {
+    /// The section doesn't hold a view this version understands
+    Invalid,
+    /// The view kept changing while it was read
+    Busy,
+}
+
+/// Read the revision and previews published by the running instance
+pub fn read() -> Result<(u64, String), String> {
+    let handle = open_file_mapping_w(SECTION_NAME, memoryapi::FILE_MAP_READ).map_err(|error| {
+        format!(
+            "Could not open the shared view, which is only published with --shared-view: {}",
+            error
+        )
+    })?;
+    let mapping = unsafe { File::from_raw_handle(handle) };
+    let view = map_view_of_file(&mapping, memoryapi::FILE_MAP_READ, SIZE)
+        .map_err(|error| format!("Could not map the shared view: {}", error))?;
+    let result = (0..READ_ATTEMPTS)
+        .find_map(|_| match unsafe { read_view(view) } {
+            Err(ReadError::Busy) => {
+                thread::yield_now();
+                None
+            }
+            result => Some(result),
+        })
+        .unwrap_or(Err(ReadError::Busy));
+    unsafe { unmap_view_of_file(view) };
+    result.map_err(|error| match error {
+        ReadError::Invalid => "The shared view isn't in a format this version reads".to_owned(),
+        ReadError::Busy => "The shared view kept changing while it was read".to_owned(),
+    })
+}
+
+/// The longest run of whole lines from the start of `text` which fits in the section
+fn fit(text: &str) -> &str {
+    let space = SIZE - HEADER_SIZE;
+    if text.len() <= space {
+        return text;
+    }
+    // A newline is never part of a longer character, so cutting there leaves valid UTF-8
+    text.as_bytes()[..space]
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or("", |end| &text[..end])
+}
+
+/// # Safety
+/// `view` must point to `SIZE` bytes aligned to 4
+unsafe fn sequence<'a>(view: *mut u8) -> &'a AtomicU32 {
+    &*(view.add(SEQUENCE_OFFSET) as *const AtomicU32)
+}
+
+/// # Safety
+/// `view` must point to `SIZE` writable bytes aligned to 4, which only this process writes
+unsafe fn write_view(view: *mut u8, revision: u64, text: &str) {
+    let text = fit(text);
+    let sequence = sequence(view);
+    let start = sequence.load(Ordering::Relaxed) & !1;
+    sequence.store(start.wrapping_add(1), Ordering::Relaxed);
+    fence(Ordering::Release);
+    ptr::copy_nonoverlapping(MAGIC.to_le_bytes().as_ptr(), view, 4);
+    ptr::copy_nonoverlapping(VERSION.to_le_bytes().as_ptr(), view.add(4), 4);
+    ptr::copy_nonoverlapping(
+        (text.len() as u32).to_le_bytes().as_ptr(),
+        view.add(LENGTH_OFFSET),
+        4,
+    );
+    ptr::copy_nonoverlapping(
+        revision.to_le_bytes().as_ptr(),
+        view.add(REVISION_OFFSET),
+        8,
+    );
+    ptr::copy_nonoverlapping(text.as_ptr(), view.add(HEADER_SIZE), text.len());
+    sequence.store(start.wrapping_add(2), Ordering::Release);
+}
+
+/// # Safety
+/// `view` must point to `SIZE` readable bytes aligned to 4
+unsafe fn read_view(view: *mut u8) -> Result<(u64, String), ReadError> {
+    let sequence = sequence(view);
+    let start = sequence.load(Ordering::Acquire);
+    if start % 2 == 1 {
+        return Err(ReadError::Busy);
+    }
+    let mut header = [0; HEADER_SIZE];
+    ptr::copy_nonoverlapping(view, header.as_mut_ptr(), HEADER_SIZE);
+    let field = |offset: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&header[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    let length = (field(LENGTH_OFFSET) as usize).min(SIZE - HEADER_SIZE);
+    let mut text = vec![0; length];
+    ptr::copy_nonoverlapping(view.add(HEADER_SIZE), text.as_mut_ptr(), length);
+    fence(Ordering::Acquire);
+    if sequence.load(Ordering::Relaxed) != start {
+        return Err(ReadError::Busy);
+    }
+    if field(0) != MAGIC || field(4) != VERSION {
+        return Err(ReadError::Invalid);
+    }
+    let mut revision = [0; 8];
+    revision.copy_from_slice(&header[REVISION_OFFSET..]);
+    let text = String::from_utf8(text).map_err(|_| ReadError::Invalid)?;
+    Ok((u64::from_le_bytes(revision), text))
+}
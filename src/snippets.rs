@@ -0,0 +1,185 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::entry::Entry;
+use crate::persistence::{self, Reader};
+
+/// Kept next to the config file, apart from the history, so snippets outlive clearing it
+const FILE_NAME: &str = "snippets.dat";
+const MAGIC: &[u8; 4] = b"FILS";
+const VERSION: u32 = 1;
+/// The longest a name given to a snippet can be, in characters
+const MAX_NAME_LENGTH: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard_extras::ClipboardItem;
+
+    fn entry(text: &str) -> Entry {
+        Entry::new(vec![ClipboardItem {
+            format: 1,
+            content: format!("{}\0", text).into_bytes(),
+        }])
+    }
+
+    #[test]
+    fn snippets_round_trip() {
+        let mut snippets = Snippets::default();
+        snippets.insert("signature".to_owned(), entry("Best wishes"));
+        snippets.insert("address".to_owned(), entry("1 High Street"));
+        let decoded = Snippets::decode(&snippets.encode()).unwrap();
+        let names: Vec<_> = decoded.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["address", "signature"]);
+        assert_eq!(
+            decoded.get("signature").map(|entry| entry.items.clone()),
+            Some(entry("Best wishes").items)
+        );
+        assert!(Snippets::decode(b"FILO").is_err());
+    }
+
+    #[test]
+    fn names_are_found_ignoring_case() {
+        let mut snippets = Snippets::default();
+        snippets.insert("Signature".to_owned(), entry("Best wishes"));
+        assert!(snippets.get("signature").is_some());
+        assert!(snippets.remove("SIGNATURE").is_some());
+        assert_eq!(snippets.len(), 0);
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert_eq!(check_name(" invoice "), Ok("invoice".to_owned()));
+        assert!(check_name("").is_err());
+        assert!(check_name("two\tparts").is_err());
+        assert!(check_name(&"x".repeat(MAX_NAME_LENGTH + 1)).is_err());
+    }
+}
+
+/// Where the snippets are kept, next to the config file
+pub fn snippets_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(FILE_NAME)
+}
+
+/// A name for a snippet with the spaces around it removed, or why it can't be used
+pub fn check_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        Err("A snippet needs a name".to_owned())
+    } else if name.chars().any(char::is_control) {
+        Err("A snippet's name can't contain tabs or line breaks".to_owned())
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        Err(format!(
+            "A snippet's name can be at most {} characters",
+            MAX_NAME_LENGTH
+        ))
+    } else {
+        Ok(name.to_owned())
+    }
+}
+
+/// Items saved under a name to be pasted again later, such as a signature or an address. Names
+/// are matched ignoring case, and listed in order
+#[derive(Default)]
+pub struct Snippets {
+    snippets: BTreeMap<String, Entry>,
+}
+
+impl Snippets {
+    /// Read the snippets from `path`, or none if it doesn't exist yet
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => Self::decode(&persistence::decrypt(data)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Write the snippets to `path`, encrypted for the current user if `encrypt` is set
+    pub fn save(&self, path: &Path, encrypt: bool) -> io::Result<()> {
+        let data = self.encode();
+        let data = if encrypt {
+            persistence::encrypt(&data)?
+        } else {
+            data
+        };
+        persistence::replace(path, &data)
+    }
+
+    /// A magic number and version, then each snippet's name as a length and UTF-8, followed by its
+    /// entry as in the history file
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&(self.snippets.len() as u32).to_le_bytes());
+        for (name, entry) in self.snippets.iter() {
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            persistence::encode_entry(&mut data, entry);
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(data);
+        if reader.take(4)? != MAGIC {
+            return Err(persistence::invalid("not a snippets file"));
+        }
+        if reader.u32()? != VERSION {
+            return Err(persistence::invalid("unsupported snippets file version"));
+        }
+        let mut snippets = Self::default();
+        for _ in 0..reader.u32()? {
+            let length = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(length)?.to_vec())
+                .map_err(|_| persistence::invalid("a snippet's name isn't valid UTF-8"))?;
+            let entry = persistence::decode_entry(&mut reader, persistence::VERSION)?;
+            snippets.insert(name, entry);
+        }
+        Ok(snippets)
+    }
+
+    /// The name a snippet is stored under, matching `name` ignoring case
+    fn key(&self, name: &str) -> Option<String> {
+        let name = name.to_lowercase();
+        self.snippets
+            .keys()
+            .find(|key| key.to_lowercase() == name)
+            .cloned()
+    }
+
+    /// Save `entry` under `name`, replacing any snippet with that name
+    pub fn insert(&mut self, name: String, mut entry: Entry) {
+        // A snippet is kept until it's deleted, whatever happened to the item in the history
+        entry.pinned = false;
+        entry.expires_at = None;
+        if let Some(key) = self.key(&name) {
+            self.snippets.remove(&key);
+        }
+        self.snippets.insert(name, entry);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Entry> {
+        self.key(name).and_then(|key| self.snippets.get(&key))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Entry> {
+        self.key(name).and_then(|key| self.snippets.remove(&key))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.snippets.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snippets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snippets.is_empty()
+    }
+}
@@ -0,0 +1,79 @@
+use winapi::um::winuser;
+
+use crate::clipboard_extras::{read_hdrop, ClipboardItem};
+
+/// Registered formats in which Office and other OLE programs name the document a selection was
+/// copied from, as the program, document and item separated by nulls
+pub const LINK_FORMAT_NAMES: &[&str] = &["ObjectLink", "Link"];
+
+/// The document named in a link format, if it's been saved. Excel names the sheet as well, as in
+/// `C:\Reports\[Sales.xlsx]Q1`
+fn link_document(content: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(content);
+    let topic = text.split('\0').nth(1)?;
+    let path = match (topic.find('['), topic.find(']')) {
+        (Some(open), Some(close)) if open < close => {
+            format!("{}{}", &topic[..open], &topic[open + 1..close])
+        }
+        _ => topic.to_owned(),
+    };
+    // Unsaved documents are only named, such as "Book1"
+    if path.contains('\\') || path.contains('/') {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Where the copied data came from: the first file copied in Explorer, or the document a selection
+/// was copied from in Office. `link_formats` are the ids of the LINK_FORMAT_NAMES formats
+pub fn document_path(items: &[ClipboardItem], link_formats: &[u32]) -> Option<String> {
+    items
+        .iter()
+        .find(|item| item.format == winuser::CF_HDROP)
+        .and_then(|item| read_hdrop(item).into_iter().next())
+        .or_else(|| {
+            items
+                .iter()
+                .filter(|item| link_formats.contains(&item.format))
+                .find_map(|item| link_document(&item.content))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard_extras::write_hdrop;
+
+    #[test]
+    fn office_link_document() {
+        assert_eq!(
+            link_document(b"Excel\0C:\\Reports\\[Sales.xlsx]Q1\0R1C1:R2C2\0\0").as_deref(),
+            Some("C:\\Reports\\Sales.xlsx")
+        );
+        assert_eq!(
+            link_document(b"Word.Document.12\0C:\\Notes\\plan.docx\0OLE_LINK1\0\0").as_deref(),
+            Some("C:\\Notes\\plan.docx")
+        );
+        // Unsaved documents have no folder
+        assert_eq!(link_document(b"Excel\0[Book1]Sheet1\0R1C1\0\0"), None);
+    }
+
+    #[test]
+    fn files_come_first() {
+        let link = ClipboardItem {
+            format: 49_400,
+            content: b"Word.Document.12\0C:\\Notes\\plan.docx\0OLE_LINK1\0\0".to_vec(),
+        };
+        let files = write_hdrop(&["C:\\Photos\\cat.png", "C:\\Photos\\dog.png"]);
+        assert_eq!(
+            document_path(&[link.clone(), files], &[49_400]).as_deref(),
+            Some("C:\\Photos\\cat.png")
+        );
+        assert_eq!(
+            document_path(std::slice::from_ref(&link), &[49_400]).as_deref(),
+            Some("C:\\Notes\\plan.docx")
+        );
+        assert_eq!(document_path(&[link], &[]), None);
+    }
+}
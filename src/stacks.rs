@@ -0,0 +1,90 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use crate::entry::Entry;
+use crate::persistence::Store;
+
+/// The stack the clipboard starts with, saved in the history file itself
+pub const DEFAULT_STACK: &str = "default";
+const MAX_NAME_LENGTH: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stacks_are_saved_beside_the_history() {
+        let path = Path::new(r"C:\Users\me\history.dat");
+        assert_eq!(stack_path(path, DEFAULT_STACK), path);
+        assert_eq!(
+            stack_path(path, "work"),
+            Path::new(r"C:\Users\me\history.work.dat")
+        );
+        assert_eq!(
+            stack_path(Path::new("history"), "code"),
+            Path::new("history.code")
+        );
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert_eq!(check_name(" Work "), Ok("work".to_owned()));
+        assert_eq!(
+            check_name("side-project_2"),
+            Ok("side-project_2".to_owned())
+        );
+        assert!(check_name("").is_err());
+        assert!(check_name("../up").is_err());
+        assert!(check_name("two words").is_err());
+    }
+}
+
+/// A stack which isn't the active one: its items, and where they're saved if the history is
+#[derive(Default)]
+pub struct Stack {
+    pub history: VecDeque<Entry>,
+    pub store: Option<Store>,
+}
+
+/// A stack's name in lowercase, or why it can't be used. Names become part of a file name, so only
+/// letters, digits, hyphens and underscores are allowed
+pub fn check_name(name: &str) -> Result<String, String> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        Err("A stack needs a name".to_owned())
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        Err(format!(
+            "A stack's name can be at most {} characters",
+            MAX_NAME_LENGTH
+        ))
+    } else if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        Err(format!(
+            "\"{}\" isn't a valid stack name: use only letters, digits, '-' and '_'",
+            name
+        ))
+    } else {
+        Ok(name)
+    }
+}
+
+/// Where a stack is saved: the history file itself for the default stack, or a file beside it with
+/// the stack's name before the extension for the others
+pub fn stack_path(persist: &Path, name: &str) -> PathBuf {
+    if name == DEFAULT_STACK {
+        return persist.to_owned();
+    }
+    let stem = persist
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file_name = match persist.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, name, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, name),
+    };
+    persist.with_file_name(file_name)
+}
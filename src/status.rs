@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::audit::format_timestamp;
+use crate::entry::Entry;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clipboard_extras::write_unicode_text;
+
+    fn report() -> Health {
+        let mut copied = Entry::new(vec![write_unicode_text("one")]);
+        copied.copied_at = Some(86_400);
+        copied.source_app = Some("notepad.exe".to_owned());
+        let loaded = Entry::new(vec![write_unicode_text("two")]);
+        Health::new(
+            [&copied, &loaded].iter().copied(),
+            Some(100),
+            Duration::from_secs(3725),
+            2,
+        )
+    }
+
+    #[test]
+    fn health_table() {
+        assert_eq!(
+            report().lines(),
+            [
+                "Memory: 16 bytes of 100",
+                "Uptime: 1h 02m 05s",
+                "Capture errors: 2",
+                "#\tBytes\tCopied\tFrom",
+                "0\t8\t1970-01-02 00:00:00Z\tnotepad.exe",
+                "1\t8\t-\t-",
+            ]
+        );
+    }
+
+    #[test]
+    fn health_json() {
+        let json: serde_json::Value = serde_json::from_str(&report().to_json()).unwrap();
+        assert_eq!(json["item_count"], 2);
+        assert_eq!(json["uptime_seconds"], 3725);
+        assert_eq!(json["items"][0]["copied_at"], 86_400);
+        assert!(json["items"][1]["copied_at"].is_null());
+    }
+
+    #[test]
+    fn uptimes() {
+        assert_eq!(format_uptime(Duration::from_secs(59)), "59s");
+        assert_eq!(format_uptime(Duration::from_secs(61)), "1m 01s");
+        assert_eq!(format_uptime(Duration::from_secs(90_061)), "1d 01h 01m");
+    }
+}
+
+/// The size and origin of an item, for `status`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ItemHealth {
+    pub bytes: usize,
+    /// Seconds since the Unix epoch, if it was copied since the clipboard started
+    pub copied_at: Option<u64>,
+    pub source_app: Option<String>,
+}
+
+/// How the running instance is doing: how much the history takes up, how long it's been running
+/// and how often reading the clipboard has failed
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Health {
+    pub item_count: usize,
+    pub memory_bytes: usize,
+    pub memory_limit: Option<usize>,
+    pub uptime_seconds: u64,
+    pub capture_errors: u64,
+    /// In the order `list` numbers them
+    pub items: Vec<ItemHealth>,
+}
+
+impl Health {
+    pub fn new<'a>(
+        entries: impl IntoIterator<Item = &'a Entry>,
+        memory_limit: Option<usize>,
+        uptime: Duration,
+        capture_errors: u64,
+    ) -> Self {
+        let items: Vec<_> = entries
+            .into_iter()
+            .map(|entry| ItemHealth {
+                bytes: entry.size(),
+                copied_at: entry.copied_at,
+                source_app: entry.source_app.clone(),
+            })
+            .collect();
+        Self {
+            item_count: items.len(),
+            memory_bytes: items.iter().map(|item| item.bytes).sum(),
+            memory_limit,
+            uptime_seconds: uptime.as_secs(),
+            capture_errors,
+            items,
+        }
+    }
+
+    /// The figures, then a table of the items
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            match self.memory_limit {
+                Some(limit) => format!("Memory: {} bytes of {}", self.memory_bytes, limit),
+                None => format!("Memory: {} bytes", self.memory_bytes),
+            },
+            format!(
+                "Uptime: {}",
+                format_uptime(Duration::from_secs(self.uptime_seconds))
+            ),
+            format!("Capture errors: {}", self.capture_errors),
+        ];
+        if !self.items.is_empty() {
+            lines.push("#\tBytes\tCopied\tFrom".to_owned());
+        }
+        lines.extend(self.items.iter().enumerate().map(|(index, item)| {
+            format!(
+                "{}\t{}\t{}\t{}",
+                index,
+                item.bytes,
+                item.copied_at.map_or("-".to_owned(), format_timestamp),
+                item.source_app.as_deref().unwrap_or("-")
+            )
+        }));
+        lines
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("The status to serialize")
+    }
+}
+
+/// A duration to the second, or to the minute once it's more than a day
+fn format_uptime(uptime: Duration) -> String {
+    let seconds = uptime.as_secs();
+    let (days, hours, minutes) = (seconds / 86_400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d {:02}h {:02}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, seconds % 60)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
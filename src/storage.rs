@@ -0,0 +1,692 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::clipboard_extras::ClipboardItem;
+use crate::entry::Entry;
+use crate::persistence::{
+    self, decode_entry, decrypt, encode_entry, encrypt, flags, invalid, replace, set_flags,
+    History, Reader, VERSION,
+};
+
+const JOURNAL_MAGIC: &[u8; 4] = b"FILJ";
+const JOURNAL_VERSION: u32 = 1;
+/// A record holding an entry's contents, written the first time the entry is saved
+const RECORD_ENTRY: u32 = 1;
+/// A record listing the fingerprints and flags of the history in order, written on every save
+const RECORD_ORDER: u32 = 2;
+/// The journal is rewritten once it's this many times bigger than the history it holds
+const COMPACT_RATIO: u64 = 2;
+/// ...and at least this big, so a small history isn't rewritten every few saves
+const COMPACT_MIN: u64 = 1 << 20;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(text: &str) -> Entry {
+        Entry::new(vec![ClipboardItem {
+            format: 1,
+            content: text.as_bytes().to_vec(),
+        }])
+    }
+
+    fn texts(history: &History) -> Vec<&[u8]> {
+        history
+            .iter()
+            .map(|entry| &*entry.items[0].content)
+            .collect()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "filo-clipboard-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn journal_round_trip() {
+        let path = temp_path("round-trip");
+        let mut journal = JournalStorage::new(&path);
+        assert!(journal.load().unwrap().is_empty());
+        let mut history = History::from(vec![entry("one"), entry("two")]);
+        let mut pinned = entry("pinned");
+        pinned.pinned = true;
+        journal.save(&history, &[pinned.clone()]).unwrap();
+        let written = fs::metadata(&path).unwrap().len();
+
+        // Saving again only appends the new order, not the entries
+        history.swap(0, 1);
+        journal.save(&history, &[pinned]).unwrap();
+        let grown = fs::metadata(&path).unwrap().len() - written;
+        assert_eq!(grown, 4 + 8 + (4 + 3 * 12) + 8);
+
+        let loaded = JournalStorage::new(&path).load().unwrap();
+        assert_eq!(
+            texts(&loaded),
+            [&b"two"[..], &b"one"[..], &b"pinned"[..]].to_vec()
+        );
+        assert!(loaded.iter().all(Entry::is_intact));
+        assert!(!loaded[0].pinned);
+        assert!(loaded[2].pinned);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journal_drops_torn_tail() {
+        let path = temp_path("torn-tail");
+        let mut journal = JournalStorage::new(&path);
+        journal.load().unwrap();
+        journal
+            .save(&History::from(vec![entry("kept")]), &[])
+            .unwrap();
+        let length = fs::metadata(&path).unwrap().len();
+        journal
+            .save(&History::from(vec![entry("lost"), entry("kept")]), &[])
+            .unwrap();
+        // Cut the last save short, as a crash part way through writing would
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 3]).unwrap();
+
+        let mut reopened = JournalStorage::new(&path);
+        assert_eq!(texts(&reopened.load().unwrap()), [&b"kept"[..]].to_vec());
+        // The torn record is cut off, so later saves can be read
+        assert!(fs::metadata(&path).unwrap().len() < data.len() as u64);
+        assert!(fs::metadata(&path).unwrap().len() >= length);
+        reopened
+            .save(&History::from(vec![entry("new"), entry("kept")]), &[])
+            .unwrap();
+        assert_eq!(
+            texts(&JournalStorage::new(&path).load().unwrap()),
+            [&b"new"[..], &b"kept"[..]].to_vec()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migrate_between_backends() {
+        let path = temp_path("migrate");
+        let mut memory = MemoryStorage::default();
+        let mut pinned = entry("pinned");
+        pinned.pinned = true;
+        memory
+            .save(&History::from(vec![entry("one")]), &[pinned])
+            .unwrap();
+
+        let mut journal = JournalStorage::new(&path);
+        assert_eq!(migrate(&mut memory, &mut journal).unwrap(), 2);
+        let loaded = JournalStorage::new(&path).load().unwrap();
+        assert_eq!(texts(&loaded), [&b"one"[..], &b"pinned"[..]].to_vec());
+        assert!(loaded[1].pinned);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parse_storage_kind() {
+        assert_eq!("journal".parse(), Ok(StorageKind::Journal));
+        assert_eq!(StorageKind::Memory.to_string(), "memory");
+        assert!("floppy".parse::<StorageKind>().is_err());
+    }
+}
+
+/// Somewhere the history is kept between runs
+pub trait Storage {
+    /// Read the history followed by the pinned entries, which are marked. Nothing stored yet is an
+    /// empty history
+    fn load(&mut self) -> io::Result<History>;
+
+    /// Replace what's stored with the history followed by the pinned entries
+    fn save(&mut self, history: &History, pinned: &[Entry]) -> io::Result<()>;
+}
+
+/// Which storage backend the history is kept in
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum StorageKind {
+    /// The whole history in one file, rewritten on every save
+    #[default]
+    File,
+    /// An append-only file, which only has new entries added on each save
+    Journal,
+    /// An SQLite database, when built with the sqlite feature
+    Sqlite,
+    /// Nowhere, so nothing is left on disk even if persist is set
+    Memory,
+}
+
+impl StorageKind {
+    /// The extension given to migrated files when no path is given
+    pub fn extension(self) -> &'static str {
+        match self {
+            StorageKind::File => "dat",
+            StorageKind::Journal => "journal",
+            StorageKind::Sqlite => "sqlite3",
+            StorageKind::Memory => "",
+        }
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "file" => Ok(StorageKind::File),
+            "journal" => Ok(StorageKind::Journal),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "memory" => Ok(StorageKind::Memory),
+            _ => Err(format!(
+                "expected \"file\", \"journal\", \"sqlite\" or \"memory\", found \"{}\"",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageKind::File => write!(f, "file"),
+            StorageKind::Journal => write!(f, "journal"),
+            StorageKind::Sqlite => write!(f, "sqlite"),
+            StorageKind::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl TryFrom<String> for StorageKind {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<StorageKind> for String {
+    fn from(kind: StorageKind) -> Self {
+        kind.to_string()
+    }
+}
+
+/// Open the `kind` of storage at `path`, encrypting the contents of each item for the current user
+/// if `encrypted` is set
+pub fn open(kind: StorageKind, path: &Path, encrypted: bool) -> io::Result<Box<dyn Storage>> {
+    fn wrap<S: Storage + 'static>(storage: S, encrypted: bool) -> Box<dyn Storage> {
+        if encrypted {
+            Box::new(Encrypted::new(storage))
+        } else {
+            Box::new(storage)
+        }
+    }
+
+    Ok(match kind {
+        StorageKind::File => wrap(FileStorage::new(path), encrypted),
+        StorageKind::Journal => wrap(JournalStorage::new(path), encrypted),
+        #[cfg(feature = "sqlite")]
+        StorageKind::Sqlite => wrap(SqliteStorage::open(path)?, encrypted),
+        #[cfg(not(feature = "sqlite"))]
+        StorageKind::Sqlite => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this build doesn't include SQLite storage, which needs the sqlite feature",
+            ))
+        }
+        // Nothing leaves the process, so there's nothing to encrypt
+        StorageKind::Memory => Box::new(MemoryStorage::default()),
+    })
+}
+
+/// Copy the intact entries in `from` into `to`, replacing what was there, and return how many were
+/// copied. Damaged entries are left behind, as copying them would store a new checksum for them
+pub fn migrate(from: &mut dyn Storage, to: &mut dyn Storage) -> io::Result<usize> {
+    let (intact, damaged): (Vec<_>, Vec<_>) = from.load()?.into_iter().partition(Entry::is_intact);
+    if !damaged.is_empty() {
+        warn!("Left behind {} damaged items", damaged.len());
+    }
+    let copied = intact.len();
+    let (pinned, history): (Vec<_>, Vec<_>) = intact.into_iter().partition(|entry| entry.pinned);
+    to.save(&History::from(history), &pinned)?;
+    Ok(copied)
+}
+
+/// Keeps the history only as long as the program runs
+#[derive(Default)]
+pub struct MemoryStorage {
+    history: History,
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> io::Result<History> {
+        Ok(self.history.clone())
+    }
+
+    fn save(&mut self, history: &History, pinned: &[Entry]) -> io::Result<()> {
+        self.history = history.iter().chain(pinned).cloned().collect();
+        Ok(())
+    }
+}
+
+/// The whole history in one file, as `persistence::save` writes it
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> io::Result<History> {
+        persistence::load(&self.path)
+    }
+
+    fn save(&mut self, history: &History, pinned: &[Entry]) -> io::Result<()> {
+        persistence::save(&self.path, history, pinned)
+    }
+}
+
+/// An append-only file of records, each a kind, a length, the contents and a checksum of the
+/// contents. An entry's contents are only written the first time it's saved, followed on every save
+/// by the order of the whole history, so copying one item to a large history writes little. A
+/// record cut short by a crash is dropped when the journal is next loaded, and once the journal
+/// has grown well beyond the history it's rewritten with only what's still needed
+pub struct JournalStorage {
+    path: PathBuf,
+    /// The fingerprints of the entries already in the journal
+    known: HashSet<u64>,
+    /// How much of the file holds complete records. Zero until it's been read or written, so the
+    /// first save starts a new file
+    length: u64,
+    /// Set when an append failed, possibly leaving a torn record, so the next save rewrites it
+    needs_rewrite: bool,
+}
+
+impl JournalStorage {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            known: HashSet::new(),
+            length: 0,
+            needs_rewrite: false,
+        }
+    }
+
+    /// Replace the journal with one holding just the current history
+    fn rewrite(&mut self, entries: &[&Entry]) -> io::Result<()> {
+        let mut data = JOURNAL_MAGIC.to_vec();
+        data.extend_from_slice(&JOURNAL_VERSION.to_le_bytes());
+        let mut known = HashSet::new();
+        for entry in entries {
+            if known.insert(entry.fingerprint()) {
+                let mut payload = Vec::new();
+                encode_entry(&mut payload, entry);
+                push_record(&mut data, RECORD_ENTRY, &payload);
+            }
+        }
+        push_record(&mut data, RECORD_ORDER, &encode_order(entries));
+        replace(&self.path, &data)?;
+        self.known = known;
+        self.length = data.len() as u64;
+        self.needs_rewrite = false;
+        Ok(())
+    }
+}
+
+impl Storage for JournalStorage {
+    fn load(&mut self) -> io::Result<History> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(History::new()),
+            Err(error) => return Err(error),
+        };
+        let mut reader = Reader::new(&data);
+        if reader.take(4)? != JOURNAL_MAGIC {
+            return Err(invalid("not a history journal"));
+        }
+        if reader.u32()? != JOURNAL_VERSION {
+            return Err(invalid("unsupported history journal version"));
+        }
+
+        let mut entries = HashMap::new();
+        let mut order = Vec::new();
+        let mut end = reader.position();
+        while let Some((kind, payload)) = read_record(&mut reader) {
+            match kind {
+                RECORD_ENTRY => {
+                    let entry = decode_entry(&mut Reader::new(payload), VERSION)?;
+                    entries.insert(entry.fingerprint(), entry);
+                }
+                RECORD_ORDER => order = decode_order(payload)?,
+                _ => return Err(invalid("unknown history journal record")),
+            }
+            end = reader.position();
+        }
+        if end < data.len() {
+            warn!(
+                "Dropped {} bytes from the end of the history journal, written when it was last \
+                 closed part way through a save",
+                data.len() - end
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&self.path)?
+                .set_len(end as u64)?;
+        }
+        self.known = entries.keys().copied().collect();
+        self.length = end as u64;
+
+        let mut history = History::new();
+        for (fingerprint, flags) in order {
+            match entries.get(&fingerprint) {
+                Some(entry) => {
+                    let mut entry = entry.clone();
+                    set_flags(&mut entry, flags);
+                    history.push_back(entry);
+                }
+                None => warn!("The history journal is missing an item, so it was skipped"),
+            }
+        }
+        Ok(history)
+    }
+
+    fn save(&mut self, history: &History, pinned: &[Entry]) -> io::Result<()> {
+        let entries = history.iter().chain(pinned).collect::<Vec<_>>();
+        let mut live = HashSet::new();
+        let live_size = entries
+            .iter()
+            .filter(|entry| live.insert(entry.fingerprint()))
+            .map(|entry| entry.size() as u64)
+            .sum::<u64>();
+        if self.length == 0
+            || self.needs_rewrite
+            || self.length > COMPACT_MIN.max(live_size * COMPACT_RATIO)
+        {
+            return self.rewrite(&entries);
+        }
+
+        let mut data = Vec::new();
+        let mut known = self.known.clone();
+        for entry in entries.iter() {
+            if known.insert(entry.fingerprint()) {
+                let mut payload = Vec::new();
+                encode_entry(&mut payload, entry);
+                push_record(&mut data, RECORD_ENTRY, &payload);
+            }
+        }
+        push_record(&mut data, RECORD_ORDER, &encode_order(&entries));
+        let written = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.write_all(&data)?;
+                file.sync_data()
+            });
+        if let Err(error) = written {
+            self.needs_rewrite = true;
+            return Err(error);
+        }
+        self.known = known;
+        self.length += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// A 64 bit FNV-1a hash, as for `Entry::fingerprint`
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn push_record(data: &mut Vec<u8>, kind: u32, payload: &[u8]) {
+    data.extend_from_slice(&kind.to_le_bytes());
+    data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    data.extend_from_slice(payload);
+    data.extend_from_slice(&checksum(payload).to_le_bytes());
+}
+
+/// The next complete record's kind and contents, or None at the end of the journal or at a record
+/// which was cut short
+fn read_record<'a>(reader: &mut Reader<'a>) -> Option<(u32, &'a [u8])> {
+    let kind = reader.u32().ok()?;
+    let length = usize::try_from(reader.u64().ok()?).ok()?;
+    let payload = reader.take(length).ok()?;
+    if reader.u64().ok()? == checksum(payload) {
+        Some((kind, payload))
+    } else {
+        None
+    }
+}
+
+fn encode_order(entries: &[&Entry]) -> Vec<u8> {
+    let mut data = (entries.len() as u32).to_le_bytes().to_vec();
+    for entry in entries {
+        data.extend_from_slice(&entry.fingerprint().to_le_bytes());
+        data.extend_from_slice(&flags(entry).to_le_bytes());
+    }
+    data
+}
+
+fn decode_order(data: &[u8]) -> io::Result<Vec<(u64, u32)>> {
+    let mut reader = Reader::new(data);
+    (0..reader.u32()?)
+        .map(|_| Ok((reader.u64()?, reader.u32()?)))
+        .collect()
+}
+
+/// The history in an SQLite database: each entry's contents once, keyed by its fingerprint, and a
+/// table of the history's order which is replaced on every save
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    connection: rusqlite::Connection,
+    /// The fingerprints of the entries already in the database
+    known: HashSet<u64>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    const SCHEMA: &'static str = "
+        PRAGMA journal_mode = WAL;
+        CREATE TABLE IF NOT EXISTS entries (fingerprint INTEGER PRIMARY KEY, data BLOB NOT NULL);
+        CREATE TABLE IF NOT EXISTS history (
+            position INTEGER PRIMARY KEY,
+            fingerprint INTEGER NOT NULL,
+            flags INTEGER NOT NULL
+        );";
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let connection = rusqlite::Connection::open(path).map_err(io::Error::other)?;
+        connection
+            .execute_batch(Self::SCHEMA)
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            connection,
+            known: HashSet::new(),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> io::Result<History> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT history.flags, entries.data FROM history
+                 JOIN entries ON entries.fingerprint = history.fingerprint
+                 ORDER BY history.position",
+            )
+            .map_err(io::Error::other)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .map_err(io::Error::other)?;
+        let mut history = History::new();
+        for row in rows {
+            let (flags, data) = row.map_err(io::Error::other)?;
+            let mut entry = decode_entry(&mut Reader::new(&data), VERSION)?;
+            set_flags(&mut entry, flags);
+            self.known.insert(entry.fingerprint());
+            history.push_back(entry);
+        }
+        Ok(history)
+    }
+
+    fn save(&mut self, history: &History, pinned: &[Entry]) -> io::Result<()> {
+        let transaction = self.connection.transaction().map_err(io::Error::other)?;
+        transaction
+            .execute("DELETE FROM history", [])
+            .map_err(io::Error::other)?;
+        let mut live = HashSet::new();
+        for (position, entry) in history.iter().chain(pinned).enumerate() {
+            let fingerprint = entry.fingerprint();
+            if !self.known.contains(&fingerprint) && live.insert(fingerprint) {
+                let mut data = Vec::new();
+                encode_entry(&mut data, entry);
+                transaction
+                    .execute(
+                        "INSERT OR REPLACE INTO entries VALUES (?1, ?2)",
+                        rusqlite::params![fingerprint as i64, data],
+                    )
+                    .map_err(io::Error::other)?;
+            }
+            live.insert(fingerprint);
+            transaction
+                .execute(
+                    "INSERT INTO history VALUES (?1, ?2, ?3)",
+                    rusqlite::params![position as i64, fingerprint as i64, flags(entry)],
+                )
+                .map_err(io::Error::other)?;
+        }
+        transaction
+            .execute(
+                "DELETE FROM entries WHERE fingerprint NOT IN (SELECT fingerprint FROM history)",
+                [],
+            )
+            .map_err(io::Error::other)?;
+        transaction.commit().map_err(io::Error::other)?;
+        self.known = live;
+        Ok(())
+    }
+}
+
+/// Encrypts the contents of each item for the current user before it's stored. DPAPI gives
+/// different output each time, so the encrypted copy of each entry is remembered, letting the
+/// journal and SQLite backends see that an entry is already stored
+pub struct Encrypted<S> {
+    inner: S,
+    /// The encrypted items of each stored entry, by the fingerprint of its plain items
+    sealed: HashMap<u64, Vec<ClipboardItem>>,
+}
+
+impl<S: Storage> Encrypted<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            sealed: HashMap::new(),
+        }
+    }
+
+    fn seal(
+        &self,
+        entry: &Entry,
+        sealed: &mut HashMap<u64, Vec<ClipboardItem>>,
+    ) -> io::Result<Entry> {
+        let fingerprint = entry.fingerprint();
+        let items = match self
+            .sealed
+            .get(&fingerprint)
+            .or_else(|| sealed.get(&fingerprint))
+        {
+            Some(items) => items.clone(),
+            None => entry
+                .items
+                .iter()
+                .map(|item| {
+                    Ok(ClipboardItem {
+                        format: item.format,
+                        content: encrypt(&item.content)?,
+                    })
+                })
+                .collect::<io::Result<Vec<_>>>()?,
+        };
+        sealed.insert(fingerprint, items.clone());
+        Ok(Entry {
+            items,
+            pinned: entry.pinned,
+            remote: entry.remote,
+            burn: entry.burn,
+            ..Entry::default()
+        })
+    }
+}
+
+impl<S: Storage> Storage for Encrypted<S> {
+    fn load(&mut self) -> io::Result<History> {
+        let mut history = History::new();
+        self.sealed.clear();
+        for entry in self.inner.load()? {
+            // Left as it is, so it's reported as damaged rather than failing to decrypt
+            if !entry.is_intact() {
+                history.push_back(entry);
+                continue;
+            }
+            let items = entry
+                .items
+                .iter()
+                .map(|item| {
+                    Ok(ClipboardItem {
+                        format: item.format,
+                        content: decrypt(item.content.clone())?,
+                    })
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            let mut plain = Entry::new(items);
+            plain.pinned = entry.pinned;
+            plain.remote = entry.remote;
+            plain.burn = entry.burn;
+            // Items stored before encryption was switched on are encrypted when next saved
+            if plain.items != entry.items {
+                self.sealed.insert(plain.fingerprint(), entry.items);
+            }
+            history.push_back(plain);
+        }
+        Ok(history)
+    }
+
+    fn save(&mut self, history: &History, pinned: &[Entry]) -> io::Result<()> {
+        let mut sealed = HashMap::new();
+        let history = history
+            .iter()
+            .map(|entry| self.seal(entry, &mut sealed))
+            .collect::<io::Result<History>>()?;
+        let pinned = pinned
+            .iter()
+            .map(|entry| self.seal(entry, &mut sealed))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.inner.save(&history, &pinned)?;
+        self.sealed = sealed;
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    fs,
+    io::{self, BufReader},
+    net::{Ipv4Addr, Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::events::Event;
+use crate::ipc::{self, Request, Response};
+use crate::websocket::{self, Message};
+use crate::winapi_functions::bcrypt_gen_random;
+
+/// Previews are sent for this many items from the front, enough for a key each on the largest deck
+const PREVIEW_COUNT: usize = 32;
+
+/// Written next to the config file, so only plugins run by this user can read the token
+const TOKEN_FILE_NAME: &str = "stream-deck-token";
+
+/// Held while talking to the message window, as its pipe serves one client at a time
+static PIPE: Mutex<()> = Mutex::new(());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"action": "paste"}"#).unwrap(),
+            Command::Paste
+        );
+        assert!(serde_json::from_str::<Command>(r#"{"action": "format"}"#).is_err());
+    }
+
+    #[test]
+    fn previews_from_list() {
+        let update = previews("0\tfirst\n1\tsecond\tC:\\plan.docx\npinned 0\taddress");
+        assert_eq!(
+            serde_json::to_string(&update).unwrap(),
+            r#"{"event":"previews","depth":2,"items":["first","second"]}"#
+        );
+        assert_eq!(
+            previews(""),
+            Update::Previews {
+                depth: 0,
+                items: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn origins() {
+        assert!(allowed_origin(None));
+        assert!(allowed_origin(Some("null")));
+        assert!(!allowed_origin(Some("file://")));
+        assert!(!allowed_origin(Some("https://example.com")));
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(same_token(Some("0a1b"), "0a1b"));
+        assert!(!same_token(Some("0a1c"), "0a1b"));
+        assert!(!same_token(Some("0a1"), "0a1b"));
+        assert!(!same_token(None, "0a1b"));
+    }
+}
+
+/// A message from a plugin, such as `{"action": "paste"}`
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+enum Command {
+    /// Paste the front item into the focused program and remove it
+    Paste,
+    /// Paste the front item without removing it
+    Peek,
+    Clear,
+    /// Ask for the previews straight away, rather than waiting for the history to change
+    Previews,
+}
+
+/// A message to a plugin
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Update {
+    /// A summary of each item from the front, sent whenever the history changes
+    Previews { depth: usize, items: Vec<String> },
+    /// The outcome of a command
+    Result { ok: bool, message: String },
+}
+
+/// Previews from the reply to a `List` request, leaving out pinned items
+fn previews(list: &str) -> Update {
+    let items: Vec<_> = list
+        .lines()
+        .filter(|line| !line.starts_with("pinned "))
+        // Lines may end with the source document after another tab
+        .filter_map(|line| line.split('\t').nth(1))
+        .map(str::to_owned)
+        .collect();
+    Update::Previews {
+        depth: items.len(),
+        items: items.into_iter().take(PREVIEW_COUNT).collect(),
+    }
+}
+
+/// Browsers say which page opened a connection, and web pages mustn't be able to read the history.
+/// Plugins send no origin, or "null" when they run as a local page
+fn allowed_origin(origin: Option<&str>) -> bool {
+    matches!(origin, None | Some("null"))
+}
+
+/// Whether a client gave the session's token. Every byte is compared, so how long it takes doesn't
+/// say how much of a guess was right
+fn same_token(given: Option<&str>, token: &str) -> bool {
+    given.is_some_and(|given| {
+        given.len() == token.len()
+            && given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    })
+}
+
+/// A new token for this session, as hex
+fn new_token() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    bcrypt_gen_random(&mut bytes).map_err(io::Error::other)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Where plugins read the token from
+pub fn token_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(TOKEN_FILE_NAME)
+}
+
+/// Forward a request to the message window through the IPC pipe
+fn request(request: &Request) -> Response {
+    let _pipe = PIPE.lock().unwrap_or_else(|error| error.into_inner());
+    ipc::send(request).unwrap_or_else(|error| Response::error(error.to_string()))
+}
+
+fn send(writer: &Mutex<TcpStream>, update: &Update) -> io::Result<()> {
+    let text = serde_json::to_string(update).expect("The update to serialize");
+    websocket::write_text(&mut *lock(writer), &text)
+}
+
+fn send_previews(writer: &Mutex<TcpStream>) -> io::Result<()> {
+    let response = request(&Request::List);
+    let update = if response.ok {
+        previews(&response.message)
+    } else {
+        Update::Result {
+            ok: false,
+            message: response.message,
+        }
+    };
+    send(writer, &update)
+}
+
+fn lock(writer: &Mutex<TcpStream>) -> MutexGuard<'_, TcpStream> {
+    // Frames are written whole or not at all, so a panic part way through leaves nothing broken
+    writer.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Accept a plugin's connection, then answer its commands and push previews whenever `changes`
+/// says the history has changed
+fn serve(stream: TcpStream, token: &str, changes: Receiver<()>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let handshake = websocket::read_handshake(&mut reader)?;
+    let writer = Arc::new(Mutex::new(stream));
+    if !allowed_origin(handshake.origin.as_deref()) {
+        warn!(
+            "Refused a Stream Deck connection from {}",
+            handshake.origin.as_deref().unwrap_or_default()
+        );
+        return websocket::write_forbidden(&mut *lock(&writer));
+    }
+    if !same_token(handshake.token.as_deref(), token) {
+        warn!("Refused a Stream Deck connection without this session's token");
+        return websocket::write_forbidden(&mut *lock(&writer));
+    }
+    websocket::write_handshake(&mut *lock(&writer), &handshake)?;
+    send_previews(&writer)?;
+
+    let pusher = Arc::clone(&writer);
+    thread::spawn(move || {
+        for () in changes.iter() {
+            if send_previews(&pusher).is_err() {
+                break;
+            }
+        }
+    });
+
+    let result = loop {
+        let command = match websocket::read_message(&mut reader) {
+            Ok(Message::Text(text)) => serde_json::from_str(&text),
+            Ok(Message::Ping(payload)) => {
+                websocket::write_pong(&mut *lock(&writer), &payload)?;
+                continue;
+            }
+            Ok(Message::Close) => break websocket::write_close(&mut *lock(&writer)),
+            Err(error) => break Err(error),
+        };
+        let response = match command {
+            Ok(Command::Paste) => request(&Request::Paste),
+            Ok(Command::Peek) => request(&Request::Peek),
+            Ok(Command::Clear) => request(&Request::Clear),
+            Ok(Command::Previews) => {
+                send_previews(&writer)?;
+                continue;
+            }
+            Err(error) => Response::error(error.to_string()),
+        };
+        send(
+            &writer,
+            &Update::Result {
+                ok: response.ok,
+                message: response.message,
+            },
+        )?;
+    };
+    // Stops the pusher the next time it writes
+    let _ = lock(&writer).shutdown(Shutdown::Both);
+    result
+}
+
+/// A WebSocket endpoint for Stream Deck plugins and other macro pads, which can paste, peek and
+/// clear, and are sent previews of the front items to show on their keys
+pub struct StreamDeck {
+    clients: Arc<Mutex<Vec<Sender<()>>>>,
+}
+
+impl StreamDeck {
+    /// Listen on `port` on the loopback interface, so only programs on this machine can connect.
+    /// The port is shared by every session, so a new token is written to `token_path` and only
+    /// clients which give it are accepted
+    pub fn spawn(port: u16, token_path: &Path) -> io::Result<Self> {
+        let token: Arc<str> = new_token()?.into();
+        fs::write(token_path, token.as_bytes())?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let clients: Arc<Mutex<Vec<Sender<()>>>> = Arc::default();
+        let accepted = Arc::clone(&clients);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (sender, receiver) = channel::unbounded();
+                accepted
+                    .lock()
+                    .unwrap_or_else(|error| error.into_inner())
+                    .push(sender);
+                let token = Arc::clone(&token);
+                thread::spawn(move || {
+                    if let Err(error) = serve(stream, &token, receiver) {
+                        info!("Stream Deck connection closed: {}", error);
+                    }
+                });
+            }
+        });
+        Ok(Self { clients })
+    }
+
+    /// Tell every connected plugin to fetch new previews if the history changed
+    pub fn handle_event(&mut self, event: &Event) {
+        // Previews are hidden during quiet hours, so they're sent again when those start and end
+        if event.changes_history() || matches!(event, Event::QuietHoursChanged { .. }) {
+            self.clients
+                .lock()
+                .unwrap_or_else(|error| error.into_inner())
+                .retain(|client| client.send(()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::Event;
+
+/// Written next to the config file when the clipboard exits, if telemetry is on
+const FILE_NAME: &str = "telemetry.toml";
+/// Words which put an error in a category, checked in order. Only the category is kept, never the
+/// message, as messages can name files and snippets
+const ERROR_CATEGORIES: [(&str, &[&str]); 7] = [
+    ("history file", &["history", "damaged"]),
+    ("hotkey", &["hotkey", "release"]),
+    ("clipboard", &["clipboard", "restore", "format"]),
+    ("paste", &["paste", "type"]),
+    ("config", &["config", "profile", "setting"]),
+    ("audit log", &["audit"]),
+    ("picker", &["picker", "search", "snippet"]),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_is_left_out() {
+        let mut telemetry = Telemetry::default();
+        telemetry.record_event(&Event::EntryPushed {
+            depth: 1,
+            preview: "secret".to_owned(),
+        });
+        telemetry.record_use("request", "Push { text: \"secret\" }");
+        telemetry.record_event(&Event::Error {
+            message: "Could not save history: C:\\secret.toml".to_owned(),
+        });
+        telemetry.record_event(&Event::Error {
+            message: "Something odd".to_owned(),
+        });
+        assert_eq!(telemetry.features["EntryPushed"], 1);
+        assert_eq!(telemetry.features["request Push"], 1);
+        assert_eq!(telemetry.errors["history file"], 1);
+        assert_eq!(telemetry.errors["other"], 1);
+        assert!(!telemetry.to_json().contains("secret"));
+    }
+
+    #[test]
+    fn capture_timings() {
+        let mut telemetry = Telemetry::default();
+        for millis in &[2, 4] {
+            telemetry.record_event(&Event::CaptureDecided {
+                decision: crate::events::Decision::Different,
+                depth: 1,
+                read: Duration::from_millis(*millis),
+                compare: Duration::from_micros(10),
+            });
+        }
+        assert_eq!(telemetry.capture_read.count, 2);
+        assert_eq!(
+            telemetry.capture_read.average(),
+            Some(Duration::from_millis(3))
+        );
+        assert_eq!(telemetry.capture_read.max_micros, 4000);
+        let text = toml::to_string(&telemetry).unwrap();
+        assert_eq!(toml::from_str::<Telemetry>(&text).unwrap(), telemetry);
+    }
+}
+
+/// How long something took each time it was timed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Timing {
+    pub count: u64,
+    pub total_micros: u64,
+    pub max_micros: u64,
+}
+
+impl Timing {
+    fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        self.count += 1;
+        self.total_micros += micros;
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            count => Some(Duration::from_micros(self.total_micros / count)),
+        }
+    }
+}
+
+/// Counts of which features are used, which errors happen and how long captures take, kept on
+/// this computer and only if telemetry is switched on. Nothing copied is ever recorded
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Telemetry {
+    pub sessions: u64,
+    pub capture_read: Timing,
+    pub capture_compare: Timing,
+    /// Events, hotkeys and requests by name
+    pub features: BTreeMap<String, u64>,
+    /// Why copies weren't recorded
+    pub skipped: BTreeMap<String, u64>,
+    /// Errors by `ERROR_CATEGORIES`
+    pub errors: BTreeMap<String, u64>,
+}
+
+impl Telemetry {
+    /// Load the counts from `path`. Missing or unreadable counts start again from nothing
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = toml::to_string(self).expect("The telemetry to serialize");
+        fs::write(path, contents)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("The telemetry to serialize")
+    }
+
+    pub fn record_event(&mut self, event: &Event) {
+        match event {
+            Event::CaptureDecided { read, compare, .. } => {
+                self.capture_read.record(*read);
+                self.capture_compare.record(*compare);
+            }
+            Event::CaptureSkipped { reason } => {
+                *self.skipped.entry(format!("{:?}", reason)).or_default() += 1
+            }
+            Event::Error { message } => {
+                *self
+                    .errors
+                    .entry(error_category(message).to_owned())
+                    .or_default() += 1
+            }
+            Event::Notice { .. } => {}
+            event => self.record_use("", &format!("{:?}", event)),
+        }
+    }
+
+    /// Count a use of `kind`, such as a hotkey or request, given by its `Debug` output
+    pub fn record_use(&mut self, kind: &str, debug: &str) {
+        let name = variant_name(debug);
+        let key = if kind.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{} {}", kind, name)
+        };
+        *self.features.entry(key).or_default() += 1;
+    }
+
+    /// A summary for `stats --detailed`
+    pub fn report(&self) -> String {
+        let millis = |duration: Option<Duration>| {
+            duration.map_or("-".to_owned(), |duration| {
+                format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+            })
+        };
+        let mut lines = vec![
+            format!("Sessions: {}", self.sessions),
+            format!(
+                "Reading the clipboard: {} on average, {} at most, {} times",
+                millis(self.capture_read.average()),
+                millis(Some(Duration::from_micros(self.capture_read.max_micros))),
+                self.capture_read.count
+            ),
+            format!(
+                "Comparing with the history: {} on average, {} at most",
+                millis(self.capture_compare.average()),
+                millis(Some(Duration::from_micros(self.capture_compare.max_micros)))
+            ),
+        ];
+        for (title, counts) in &[
+            ("Features", &self.features),
+            ("Skipped copies", &self.skipped),
+            ("Errors", &self.errors),
+        ] {
+            lines.push(format!("{}:", title));
+            if counts.is_empty() {
+                lines.push("\tnone".to_owned());
+            }
+            lines.extend(
+                counts
+                    .iter()
+                    .map(|(name, count)| format!("\t{}\t{}", name, count)),
+            );
+        }
+        lines.join("\n")
+    }
+}
+
+/// The name of an enum variant from its `Debug` output, leaving out its fields, which can hold
+/// content
+fn variant_name(debug: &str) -> &str {
+    let end = debug
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(debug.len());
+    &debug[..end]
+}
+
+fn error_category(message: &str) -> &'static str {
+    let message = message.to_lowercase();
+    ERROR_CATEGORIES
+        .iter()
+        .find(|(_, words)| words.iter().any(|word| message.contains(word)))
+        .map_or("other", |(category, _)| category)
+}
+
+/// Where the counts are kept, next to the config file
+pub fn telemetry_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(FILE_NAME)
+}
@@ -0,0 +1,421 @@
+use std::{convert::TryInto, mem};
+
+use winapi::um::{shellapi, winuser};
+
+use crate::events::Event;
+use crate::winapi_functions::{
+    append_menu_w, create_icon_from_resource_ex, create_popup_menu, destroy_icon, destroy_menu,
+    get_cursor_pos, get_system_metrics, post_message_a, set_foreground_window, shell_notify_icon_w,
+    track_popup_menu, SystemError,
+};
+
+/// Sent to the message window when the tray icon is clicked
+pub const WM_TRAY_CALLBACK: u32 = winuser::WM_APP + 2;
+
+const TRAY_ICON_ID: u32 = 1;
+const PREVIEW_LENGTH: usize = 60;
+
+const MENU_PAUSE: usize = 1;
+const MENU_RESUME: usize = 2;
+const MENU_CLEAR: usize = 3;
+const MENU_EXIT: usize = 4;
+const MENU_SAVE: usize = 5;
+const MENU_COPY_FILE: usize = 6;
+const MENU_ABOUT: usize = 7;
+const MENU_FIRST_PROFILE: usize = 100;
+
+const ACTIVE_ICON: &[u8] = include_bytes!("../assets/tray-active.ico");
+const EMPTY_ICON: &[u8] = include_bytes!("../assets/tray-empty.ico");
+const PAUSED_ICON: &[u8] = include_bytes!("../assets/tray-paused.ico");
+const ERROR_ICON: &[u8] = include_bytes!("../assets/tray-error.ico");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tooltip_empty() {
+        assert_eq!(tooltip_text(0, ""), "FILO Clipboard: history empty");
+    }
+
+    #[test]
+    fn tooltip_preview_single_line() {
+        let preview = "first line\r\n  second line ".to_owned() + &"x".repeat(100);
+        let tooltip = tooltip_text(2, &preview);
+        assert!(tooltip.starts_with("FILO Clipboard: 2 items\nNext: first line second line x"));
+        assert!(tooltip.ends_with('…'));
+        assert!(tooltip.encode_utf16().count() < 128);
+    }
+
+    #[test]
+    fn embedded_icons_have_small_images() {
+        for ico in &[ACTIVE_ICON, EMPTY_ICON, PAUSED_ICON, ERROR_ICON] {
+            let image = icon_image(ico, 16).unwrap();
+            // A BITMAPINFOHEADER with the icon's width
+            assert_eq!(&image[..8], &[40, 0, 0, 0, 16, 0, 0, 0]);
+            assert!(icon_image(ico, 20).is_some());
+        }
+    }
+}
+
+/// The text shown when hovering over the tray icon
+fn tooltip_text(depth: usize, preview: &str) -> String {
+    if depth == 0 {
+        return "FILO Clipboard: history empty".to_owned();
+    }
+
+    let mut preview = preview.split_whitespace().collect::<Vec<_>>().join(" ");
+    if let Some((index, _)) = preview.char_indices().nth(PREVIEW_LENGTH) {
+        preview.truncate(index);
+        preview.push('…');
+    }
+    format!(
+        "FILO Clipboard: {} item{}\nNext: {}",
+        depth,
+        if depth == 1 { "" } else { "s" },
+        preview
+    )
+}
+
+/// Copy `text` into a fixed size, null terminated UTF-16 buffer, truncating it if needed
+fn copy_wide(buffer: &mut [u16], text: &str) {
+    let max_length = buffer.len() - 1;
+    let mut length = 0;
+    for (dest, src) in buffer.iter_mut().zip(text.encode_utf16().take(max_length)) {
+        *dest = src;
+        length += 1;
+    }
+    buffer[length] = 0;
+}
+
+/// Find the image in a .ico file closest to `size`, preferring an exact match and then the largest
+fn icon_image(ico: &[u8], size: u32) -> Option<&[u8]> {
+    let read_u16 = |offset: usize| {
+        Some(u16::from_le_bytes(
+            ico.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let read_u32 = |offset: usize| {
+        Some(u32::from_le_bytes(
+            ico.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+
+    let count = read_u16(4)? as usize;
+    let mut best: Option<(u32, usize, usize)> = None;
+    for index in 0..count {
+        let entry = 6 + index * 16;
+        // A width of 0 means 256 pixels
+        let width = match *ico.get(entry)? {
+            0 => 256,
+            width => width as u32,
+        };
+        let length = read_u32(entry + 8)? as usize;
+        let offset = read_u32(entry + 12)? as usize;
+        let better = match best {
+            None => true,
+            Some((best_width, _, _)) => best_width != size && (width == size || width > best_width),
+        };
+        if better {
+            best = Some((width, offset, length));
+        }
+    }
+
+    best.and_then(|(_, offset, length)| ico.get(offset..offset + length))
+}
+
+/// An action chosen from the tray icon's context menu
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrayCommand {
+    Pause,
+    Resume,
+    ClearHistory,
+    SaveNext,
+    CopyNextAsFile,
+    SwitchProfile(String),
+    About,
+    Exit,
+}
+
+/// Show the context menu at the cursor and wait for the user to choose an item
+pub fn show_menu(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    paused: bool,
+    profiles: &[String],
+    active_profile: &str,
+) -> Result<Option<TrayCommand>, SystemError> {
+    let menu = create_popup_menu()?;
+    let result = fill_menu(menu, paused, profiles, active_profile).map(|_| {
+        let cursor = get_cursor_pos().unwrap_or_default();
+        // Without this the menu doesn't close when clicking elsewhere
+        set_foreground_window(h_wnd);
+        let id = track_popup_menu(
+            menu,
+            winuser::TPM_RETURNCMD | winuser::TPM_NONOTIFY | winuser::TPM_RIGHTBUTTON,
+            cursor.x,
+            cursor.y,
+            h_wnd,
+        );
+        let _ = post_message_a(h_wnd, winuser::WM_NULL, 0, 0);
+
+        match id as usize {
+            MENU_PAUSE => Some(TrayCommand::Pause),
+            MENU_RESUME => Some(TrayCommand::Resume),
+            MENU_CLEAR => Some(TrayCommand::ClearHistory),
+            MENU_SAVE => Some(TrayCommand::SaveNext),
+            MENU_COPY_FILE => Some(TrayCommand::CopyNextAsFile),
+            MENU_ABOUT => Some(TrayCommand::About),
+            MENU_EXIT => Some(TrayCommand::Exit),
+            id if id >= MENU_FIRST_PROFILE => profiles
+                .get(id - MENU_FIRST_PROFILE)
+                .map(|name| TrayCommand::SwitchProfile(name.clone())),
+            _ => None,
+        }
+    });
+    // This also destroys the profile submenu
+    let _ = destroy_menu(menu);
+    result
+}
+
+fn fill_menu(
+    menu: &mut winapi::shared::windef::HMENU__,
+    paused: bool,
+    profiles: &[String],
+    active_profile: &str,
+) -> Result<(), SystemError> {
+    let enabled_if = |enabled| {
+        if enabled {
+            winuser::MF_STRING
+        } else {
+            winuser::MF_STRING | winuser::MF_GRAYED
+        }
+    };
+    append_menu_w(menu, enabled_if(!paused), MENU_PAUSE, "&Pause monitoring")?;
+    append_menu_w(menu, enabled_if(paused), MENU_RESUME, "&Resume")?;
+    append_menu_w(menu, winuser::MF_STRING, MENU_CLEAR, "&Clear history")?;
+    append_menu_w(
+        menu,
+        winuser::MF_STRING,
+        MENU_SAVE,
+        "&Save next item to file",
+    )?;
+    append_menu_w(
+        menu,
+        winuser::MF_STRING,
+        MENU_COPY_FILE,
+        "Copy next item as &file",
+    )?;
+
+    let profile_menu = create_popup_menu()?;
+    for (index, name) in profiles.iter().enumerate() {
+        let flags = if name == active_profile {
+            winuser::MF_STRING | winuser::MF_CHECKED
+        } else {
+            winuser::MF_STRING
+        };
+        append_menu_w(profile_menu, flags, MENU_FIRST_PROFILE + index, name)?;
+    }
+    append_menu_w(
+        menu,
+        winuser::MF_POPUP,
+        profile_menu as *mut _ as usize,
+        "P&rofile",
+    )?;
+
+    append_menu_w(menu, winuser::MF_SEPARATOR, 0, "")?;
+    append_menu_w(menu, winuser::MF_STRING, MENU_ABOUT, "&About")?;
+    append_menu_w(menu, winuser::MF_STRING, MENU_EXIT, "E&xit")
+}
+
+/// What the tray icon shows, in order of priority
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IconState {
+    Error,
+    Paused,
+    Empty,
+    Active,
+}
+
+struct Icons {
+    active: winapi::shared::windef::HICON,
+    empty: winapi::shared::windef::HICON,
+    paused: winapi::shared::windef::HICON,
+    error: winapi::shared::windef::HICON,
+}
+
+impl Icons {
+    fn load() -> Result<Self, SystemError> {
+        let size = get_system_metrics(winuser::SM_CXSMICON);
+        let load = |ico| {
+            let image = icon_image(ico, size as u32).expect("The embedded icons to be valid");
+            create_icon_from_resource_ex(image, size, size)
+        };
+        Ok(Self {
+            active: load(ACTIVE_ICON)?,
+            empty: load(EMPTY_ICON)?,
+            paused: load(PAUSED_ICON)?,
+            error: load(ERROR_ICON)?,
+        })
+    }
+
+    fn get(&self, state: IconState) -> winapi::shared::windef::HICON {
+        match state {
+            IconState::Active => self.active,
+            IconState::Empty => self.empty,
+            IconState::Paused => self.paused,
+            IconState::Error => self.error,
+        }
+    }
+}
+
+impl Drop for Icons {
+    fn drop(&mut self) {
+        for icon in &[self.active, self.empty, self.paused, self.error] {
+            if let Some(icon) = unsafe { icon.as_mut() } {
+                let _ = destroy_icon(icon);
+            }
+        }
+    }
+}
+
+/// The notification area icon, which shows how many items are left in its tooltip and whether
+/// capture is running in its image
+pub struct TrayIcon {
+    data: shellapi::NOTIFYICONDATAW,
+    icons: Icons,
+    depth: usize,
+    paused: bool,
+    error: bool,
+}
+
+impl TrayIcon {
+    pub fn new(
+        h_wnd: &mut winapi::shared::windef::HWND__,
+        paused: bool,
+    ) -> Result<Self, SystemError> {
+        let icons = Icons::load()?;
+        let mut data = shellapi::NOTIFYICONDATAW {
+            cbSize: mem::size_of::<shellapi::NOTIFYICONDATAW>() as u32,
+            hWnd: h_wnd,
+            uID: TRAY_ICON_ID,
+            uFlags: shellapi::NIF_MESSAGE | shellapi::NIF_ICON | shellapi::NIF_TIP,
+            uCallbackMessage: WM_TRAY_CALLBACK,
+            hIcon: icons.get(if paused {
+                IconState::Paused
+            } else {
+                IconState::Empty
+            }),
+            ..Default::default()
+        };
+        copy_wide(&mut data.szTip, &tooltip_text(0, ""));
+        shell_notify_icon_w(shellapi::NIM_ADD, &mut data)?;
+
+        Ok(Self {
+            data,
+            icons,
+            depth: 0,
+            paused,
+            error: false,
+        })
+    }
+
+    pub fn state(&self) -> IconState {
+        if self.error {
+            IconState::Error
+        } else if self.paused {
+            IconState::Paused
+        } else if self.depth == 0 {
+            IconState::Empty
+        } else {
+            IconState::Active
+        }
+    }
+
+    fn update(&mut self, tooltip: Option<&str>) -> Result<(), SystemError> {
+        self.data.uFlags = shellapi::NIF_ICON;
+        self.data.hIcon = self.icons.get(self.state());
+        if let Some(tooltip) = tooltip {
+            copy_wide(&mut self.data.szTip, tooltip);
+            self.data.uFlags |= shellapi::NIF_TIP;
+        }
+        shell_notify_icon_w(shellapi::NIM_MODIFY, &mut self.data)
+    }
+
+    /// Pop up a short balloon by the icon, dropped if it can't be shown straight away so quickly
+    /// repeated notices don't queue up
+    fn notify(&mut self, text: &str) -> Result<(), SystemError> {
+        self.data.uFlags = shellapi::NIF_INFO | shellapi::NIF_REALTIME;
+        self.data.dwInfoFlags = shellapi::NIIF_INFO | shellapi::NIIF_NOSOUND;
+        copy_wide(&mut self.data.szInfoTitle, "FILO Clipboard");
+        copy_wide(&mut self.data.szInfo, text);
+        shell_notify_icon_w(shellapi::NIM_MODIFY, &mut self.data)
+    }
+
+    /// Keep the tooltip and icon in sync with the history
+    pub fn handle_event(&mut self, event: &Event) {
+        let tooltip = match event {
+            Event::EntryPushed { depth, preview }
+            | Event::HistoryLoaded { depth, preview }
+            | Event::EntryUpdated { depth, preview }
+            | Event::EntryPopped { depth, preview }
+            | Event::PopUndone { depth, preview }
+            | Event::HistoryTrimmed { depth, preview }
+            | Event::EntriesExpired { depth, preview }
+            | Event::HistoryEdited { depth, preview }
+            | Event::QuietHoursChanged { depth, preview, .. }
+            | Event::PinsChanged { depth, preview, .. } => {
+                self.depth = *depth;
+                self.error = false;
+                Some(tooltip_text(*depth, preview))
+            }
+            Event::HistoryRotated { depth, preview } => {
+                self.depth = *depth;
+                self.error = false;
+                // Show what's now at the front, as there's no other sign the history has turned
+                let _ = self.notify(&tooltip_text(*depth, preview));
+                Some(tooltip_text(*depth, preview))
+            }
+            Event::StackSwitched {
+                name,
+                depth,
+                preview,
+            } => {
+                self.depth = *depth;
+                self.error = false;
+                let _ = self.notify(&format!(
+                    "{} stack\n{}",
+                    name,
+                    tooltip_text(*depth, preview)
+                ));
+                Some(tooltip_text(*depth, preview))
+            }
+            Event::HistoryCleared => {
+                self.depth = 0;
+                Some(tooltip_text(0, ""))
+            }
+            Event::CaptureToggled { enabled } => {
+                self.paused = !enabled;
+                None
+            }
+            Event::Notice { message } => {
+                let _ = self.notify(message);
+                return;
+            }
+            Event::Error { message } => {
+                self.error = true;
+                Some(format!("FILO Clipboard: {}", message))
+            }
+            Event::CaptureSkipped { .. }
+            | Event::CaptureDecided { .. }
+            | Event::ProfileSwitched { .. } => return,
+        };
+        let _ = self.update(tooltip.as_deref());
+    }
+}
+
+impl Drop for TrayIcon {
+    fn drop(&mut self) {
+        self.data.uFlags = 0;
+        let _ = shell_notify_icon_w(shellapi::NIM_DELETE, &mut self.data);
+    }
+}
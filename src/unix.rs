@@ -0,0 +1,331 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    process, thread,
+    time::Duration,
+};
+
+use clap::{AppSettings, Clap};
+
+use crate::backend::{ClipboardBackend, CommandClipboard};
+use crate::engine::{ClipboardEngine, Dedup, DEFAULT_SIMILARITY_THRESHOLD};
+use crate::history::{HistoryHandle, DEFAULT_MAX_HISTORY};
+
+/// How often the clipboard is read, as the command line tools can't say when it changes
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// The socket commands are sent over, in the user's own runtime or temporary directory
+const SOCKET_NAME: &str = "filo-clipboard.sock";
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::backend::MemoryClipboard;
+    use crate::clipboard_extras::{read_text, write_unicode_text};
+
+    fn server() -> Server<MemoryClipboard> {
+        Server::new(MemoryClipboard::default(), 10, |_| Ok(()))
+    }
+
+    fn copy(server: &mut Server<MemoryClipboard>, text: &str) {
+        server.clipboard.set(&[write_unicode_text(text)]).unwrap();
+        server.capture();
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        for command in [
+            UnixCommand::Run,
+            UnixCommand::Paste,
+            UnixCommand::Pop,
+            UnixCommand::List,
+            UnixCommand::Clear,
+            UnixCommand::Shutdown,
+        ]
+        .iter()
+        {
+            assert_eq!(UnixCommand::from_name(command.name()), Some(*command));
+        }
+        assert_eq!(UnixCommand::from_name("peek"), None);
+    }
+
+    #[test]
+    fn paste_in_reverse() {
+        let mut server = server();
+        copy(&mut server, "one");
+        copy(&mut server, "two");
+        assert_eq!(server.handle(UnixCommand::List).unwrap(), "0\ttwo\n1\tone");
+        for text in &["two", "one"] {
+            assert!(server.handle(UnixCommand::Paste).is_ok());
+            assert_eq!(
+                read_text(&server.clipboard.read_all(|_| true)),
+                Some(text.to_string())
+            );
+            // Putting the item on the clipboard doesn't record it again
+            server.capture();
+        }
+        assert!(server.handle(UnixCommand::Paste).is_err());
+    }
+
+    #[test]
+    fn pop_and_clear() {
+        let mut server = server();
+        copy(&mut server, "one");
+        copy(&mut server, "two");
+        assert_eq!(server.handle(UnixCommand::Pop).unwrap(), "two");
+        assert_eq!(server.handle(UnixCommand::List).unwrap(), "0\tone");
+        assert!(server.handle(UnixCommand::Clear).is_ok());
+        assert!(server.handle(UnixCommand::Pop).is_err());
+    }
+}
+
+/// A text-only FILO clipboard for Linux and macOS. Start it once to record copies, then bind
+/// `filo-clipboard paste` to a shortcut in the desktop's keyboard settings
+#[derive(Clap)]
+#[clap(version = "1.0", author = "David A. <github.com/davystrong>")]
+#[clap(setting = AppSettings::ColoredHelp)]
+pub struct UnixOpts {
+    /// The number of items to keep. Defaults to 50
+    #[clap(long)]
+    pub max_history: Option<usize>,
+    #[clap(subcommand)]
+    pub command: Option<UnixCommand>,
+}
+
+#[derive(Clap, Debug, Clone, Copy, PartialEq)]
+pub enum UnixCommand {
+    /// Record copies in the background. This is the default
+    Run,
+    /// Put the next item of the running instance's history on the clipboard and paste it
+    Paste,
+    /// Put the next item of the running instance's history on the clipboard without pasting it,
+    /// and print its text
+    Pop,
+    /// Print a summary of each item in the running instance's history
+    List,
+    /// Remove every item from the running instance's history
+    Clear,
+    /// Stop the running instance
+    Shutdown,
+}
+
+impl UnixCommand {
+    /// The word sent over the socket
+    fn name(self) -> &'static str {
+        match self {
+            UnixCommand::Run => "run",
+            UnixCommand::Paste => "paste",
+            UnixCommand::Pop => "pop",
+            UnixCommand::List => "list",
+            UnixCommand::Clear => "clear",
+            UnixCommand::Shutdown => "shutdown",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            UnixCommand::Run,
+            UnixCommand::Paste,
+            UnixCommand::Pop,
+            UnixCommand::List,
+            UnixCommand::Clear,
+            UnixCommand::Shutdown,
+        ]
+        .iter()
+        .copied()
+        .find(|command| command.name() == name)
+    }
+}
+
+/// The history, and the clipboard it's recorded from and pasted onto
+struct Server<C> {
+    history: HistoryHandle,
+    clipboard: C,
+    engine: ClipboardEngine,
+    /// Presses the paste shortcut once an item is on the clipboard
+    send_paste: fn(&C) -> Result<(), String>,
+}
+
+impl<C: ClipboardBackend> Server<C> {
+    fn new(clipboard: C, max_history: usize, send_paste: fn(&C) -> Result<(), String>) -> Self {
+        Self {
+            history: HistoryHandle::new(max_history),
+            clipboard,
+            engine: ClipboardEngine::new(DEFAULT_SIMILARITY_THRESHOLD, Dedup::Off),
+            send_paste,
+        }
+    }
+
+    fn capture(&mut self) {
+        self.history.capture(&self.clipboard, &self.engine);
+    }
+
+    fn handle(&mut self, command: UnixCommand) -> Result<String, String> {
+        // Something may have been copied since the clipboard was last read
+        self.capture();
+        match command {
+            UnixCommand::Paste => match self.history.pop_to(&mut self.clipboard)? {
+                Some(_) => (self.send_paste)(&self.clipboard).map(|_| "Pasted".to_owned()),
+                None => Err("The history is empty".to_owned()),
+            },
+            UnixCommand::Pop => match self.history.pop_to(&mut self.clipboard)? {
+                Some(entry) => Ok(entry.text()),
+                None => Err("The history is empty".to_owned()),
+            },
+            UnixCommand::List => Ok(self
+                .history
+                .iter()
+                .enumerate()
+                .map(|(index, entry)| {
+                    let preview = entry.preview();
+                    format!("{}\t{}", index, preview.lines().next().unwrap_or_default())
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            UnixCommand::Clear => {
+                self.history.clear();
+                Ok("Cleared the history".to_owned())
+            }
+            UnixCommand::Run | UnixCommand::Shutdown => Err(format!(
+                "{} isn't answered by the running instance",
+                command.name()
+            )),
+        }
+    }
+}
+
+/// Where the socket goes: the runtime directory on Linux, or the temporary directory on macOS,
+/// both of which only the user can read
+fn socket_path() -> io::Result<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .or_else(|| env::var_os("TMPDIR"))
+        .map(|dir| PathBuf::from(dir).join(SOCKET_NAME))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "neither XDG_RUNTIME_DIR nor TMPDIR is set, so there's nowhere private for the socket",
+            )
+        })
+}
+
+/// Listen on the socket, replacing one left behind by an instance which didn't stop cleanly
+fn listen() -> io::Result<UnixListener> {
+    let path = socket_path()?;
+    if UnixStream::connect(&path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another instance is already running",
+        ));
+    }
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Answer one command, with a status line followed by the message as the Windows pipe does.
+/// Returns whether the instance should stop
+fn serve_client(stream: UnixStream, server: &mut Server<CommandClipboard>) -> io::Result<bool> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    // Another instance checking whether this one is running connects without a command
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(false);
+    }
+    let (result, stop) = match UnixCommand::from_name(line.trim_end()) {
+        Some(UnixCommand::Shutdown) => (Ok("Shutting down".to_owned()), true),
+        Some(command) => (server.handle(command), false),
+        None => (Err(format!("Unknown command: {}", line.trim_end())), false),
+    };
+    let reply = match result {
+        Ok(message) => format!("ok\n{}", message),
+        Err(message) => format!("error\n{}", message),
+    };
+    reader.into_inner().write_all(reply.as_bytes())?;
+    Ok(stop)
+}
+
+/// Record copies until told to stop, answering commands in between reads
+fn serve(listener: UnixListener, clipboard: CommandClipboard, max_history: usize) {
+    let mut server = Server::new(clipboard, max_history, CommandClipboard::send_paste);
+    loop {
+        server.capture();
+        while let Ok((stream, _)) = listener.accept() {
+            match serve_client(stream, &mut server) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(error) => eprintln!("Command failed: {}", error),
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Send `command` to the running instance and print its reply, returning the exit code
+fn send(command: UnixCommand) -> i32 {
+    let reply = socket_path()
+        .and_then(UnixStream::connect)
+        .and_then(|mut stream| {
+            stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+            writeln!(stream, "{}", command.name())?;
+            stream.shutdown(Shutdown::Write)?;
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply)?;
+            Ok(reply)
+        });
+    match reply {
+        Ok(reply) => match reply.split_once('\n') {
+            Some(("ok", message)) => {
+                if !message.is_empty() {
+                    println!("{}", message);
+                }
+                0
+            }
+            Some((_, message)) => {
+                eprintln!("{}", message);
+                1
+            }
+            None => {
+                eprintln!("Malformed response: {}", reply);
+                1
+            }
+        },
+        Err(error) => {
+            eprintln!("Could not reach FILO Clipboard. Is it running? {}", error);
+            1
+        }
+    }
+}
+
+pub fn run(opts: UnixOpts) {
+    let command = opts.command.unwrap_or(UnixCommand::Run);
+    if command != UnixCommand::Run {
+        process::exit(send(command));
+    }
+    let clipboard = CommandClipboard::detect().unwrap_or_else(|| {
+        eprintln!("No clipboard tools are known for this desktop. Neither WAYLAND_DISPLAY nor DISPLAY is set");
+        process::exit(1);
+    });
+    let listener = listen().unwrap_or_else(|error| {
+        eprintln!("Could not listen for commands: {}", error);
+        process::exit(1);
+    });
+    serve(
+        listener,
+        clipboard,
+        opts.max_history.unwrap_or(DEFAULT_MAX_HISTORY).max(1),
+    );
+    if let Ok(path) = socket_path() {
+        let _ = fs::remove_file(path);
+    }
+}
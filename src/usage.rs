@@ -0,0 +1,122 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Written next to the config file when the clipboard exits
+const FILE_NAME: &str = "usage.toml";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity_from_stats() {
+        let mut stats = UsageStats::default();
+        assert_eq!(stats.capacity(20, None), 0);
+        stats.record_copy(1000);
+        stats.record_copy(3000);
+        assert_eq!(stats.average_entry_size(), Some(2000));
+        stats.record_depth(12);
+        stats.record_depth(8);
+        stats.end_session();
+        assert_eq!(stats.typical_depth, 12);
+        // Never more than the profile allows, or than the memory limit could hold
+        assert_eq!(stats.capacity(10, None), 10);
+        assert_eq!(stats.capacity(20, None), 12);
+        assert_eq!(stats.capacity(20, Some(10_000)), 5);
+    }
+
+    #[test]
+    fn typical_depth_follows_recent_sessions() {
+        let mut stats = UsageStats {
+            typical_depth: 40,
+            ..Default::default()
+        };
+        stats.record_depth(8);
+        stats.end_session();
+        assert_eq!(stats.typical_depth, 32);
+        // A session where nothing was copied says nothing about depth
+        stats.end_session();
+        assert_eq!(stats.typical_depth, 32);
+    }
+}
+
+/// A rough picture of how the clipboard is used, kept between runs so the history can be sized
+/// up front rather than growing during the first burst of copies
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct UsageStats {
+    /// Items recorded over every session
+    pub copies: u64,
+    /// Bytes recorded over every session
+    pub total_bytes: u64,
+    /// A moving average of the deepest the history got in each session
+    pub typical_depth: usize,
+    #[serde(skip)]
+    session_depth: usize,
+}
+
+impl UsageStats {
+    /// Load the stats from `path`. Missing or unreadable stats start again from nothing
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = toml::to_string(self).expect("The usage stats to serialize");
+        fs::write(path, contents)
+    }
+
+    pub fn record_copy(&mut self, size: usize) {
+        self.copies += 1;
+        self.total_bytes += size as u64;
+    }
+
+    pub fn record_depth(&mut self, depth: usize) {
+        self.session_depth = self.session_depth.max(depth);
+    }
+
+    /// Fold this session's deepest history into the typical depth, weighting earlier sessions more
+    pub fn end_session(&mut self) {
+        if self.session_depth == 0 {
+            return;
+        }
+        self.typical_depth = if self.typical_depth == 0 {
+            self.session_depth
+        } else {
+            (self.typical_depth * 3 + self.session_depth) / 4
+        };
+        self.session_depth = 0;
+    }
+
+    pub fn average_entry_size(&self) -> Option<usize> {
+        match self.copies {
+            0 => None,
+            copies => Some((self.total_bytes / copies) as usize),
+        }
+    }
+
+    /// How many items to make room for at startup: the typical depth, but no more than
+    /// `max_history` or than `max_total_memory` would hold at the average size
+    pub fn capacity(&self, max_history: usize, max_total_memory: Option<usize>) -> usize {
+        let by_memory = match (max_total_memory, self.average_entry_size()) {
+            (Some(limit), Some(size)) if size > 0 => limit / size,
+            _ => usize::MAX,
+        };
+        self.typical_depth.min(max_history).min(by_memory)
+    }
+}
+
+/// Where the stats are kept, next to the config file
+pub fn stats_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(FILE_NAME)
+}
@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+use winapi::um::winuser;
+
+/// The modifiers checked, each side separately as that's how they're left down
+pub const MODIFIERS: [u16; 8] = [
+    winuser::VK_LCONTROL as u16,
+    winuser::VK_RCONTROL as u16,
+    winuser::VK_LSHIFT as u16,
+    winuser::VK_RSHIFT as u16,
+    winuser::VK_LMENU as u16,
+    winuser::VK_RMENU as u16,
+    winuser::VK_LWIN as u16,
+    winuser::VK_RWIN as u16,
+];
+/// How long after a failed injection modifiers are watched
+const WATCH_FOR: Duration = Duration::from_secs(10);
+/// How long a modifier has to stay down before it's taken to be stuck rather than held by the user
+const STUCK_AFTER: Duration = Duration::from_millis(1500);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CTRL: u16 = winuser::VK_LCONTROL as u16;
+
+    #[test]
+    fn release_modifiers_held_too_long() {
+        let start = Instant::now();
+        let mut watchdog = ModifierWatchdog::default();
+        assert!(watchdog.check(start, |_| true).is_empty());
+        watchdog.arm(start);
+        let ctrl = |key| key == CTRL;
+        assert!(watchdog.check(start, ctrl).is_empty());
+        assert!(watchdog
+            .check(start + Duration::from_millis(1000), ctrl)
+            .is_empty());
+        assert_eq!(
+            watchdog.check(start + Duration::from_millis(1600), ctrl),
+            [CTRL]
+        );
+        assert!(watchdog.is_armed());
+        assert!(watchdog.check(start + WATCH_FOR, ctrl).is_empty());
+        assert!(!watchdog.is_armed());
+    }
+
+    #[test]
+    fn let_go_modifiers_start_again() {
+        let start = Instant::now();
+        let mut watchdog = ModifierWatchdog::default();
+        watchdog.arm(start);
+        watchdog.check(start, |_| true);
+        watchdog.check(start + Duration::from_millis(1000), |_| false);
+        assert!(watchdog
+            .check(start + Duration::from_millis(2000), |_| true)
+            .is_empty());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            modifier_names(&[CTRL, winuser::VK_RWIN as u16]),
+            "Ctrl and Win"
+        );
+    }
+}
+
+/// Watches for modifiers left down after sending keys failed partway, such as the hotkey's
+/// modifiers being pressed again but never released, which would leave every program on the
+/// desktop seeing Ctrl or Shift held
+#[derive(Debug, Default)]
+pub struct ModifierWatchdog {
+    /// When each modifier which is down was first seen down
+    held_since: Vec<(u16, Instant)>,
+    until: Option<Instant>,
+}
+
+impl ModifierWatchdog {
+    /// Start watching, or watch for longer if it already is
+    pub fn arm(&mut self, now: Instant) {
+        self.until = Some(now + WATCH_FOR);
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.until.is_some()
+    }
+
+    /// Check which modifiers are down, returning those which have been down for long enough to be
+    /// stuck, which should be released. One still down is only returned again once it's been down
+    /// for as long again
+    pub fn check(&mut self, now: Instant, is_pressed: impl Fn(u16) -> bool) -> Vec<u16> {
+        let until = match self.until {
+            Some(until) => until,
+            None => return Vec::new(),
+        };
+        if now >= until {
+            self.until = None;
+            self.held_since.clear();
+            return Vec::new();
+        }
+        let mut stuck = Vec::new();
+        for key in MODIFIERS.iter().copied() {
+            let position = self.held_since.iter().position(|(held, _)| *held == key);
+            match (is_pressed(key), position) {
+                (true, None) => self.held_since.push((key, now)),
+                (true, Some(position)) if now - self.held_since[position].1 >= STUCK_AFTER => {
+                    self.held_since.remove(position);
+                    stuck.push(key);
+                }
+                (false, Some(position)) => {
+                    self.held_since.remove(position);
+                }
+                _ => {}
+            }
+        }
+        stuck
+    }
+}
+
+/// The modifiers' names, such as "Ctrl and Shift", with each named once
+pub fn modifier_names(keys: &[u16]) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    for key in keys {
+        let name = match *key as i32 {
+            winuser::VK_LCONTROL | winuser::VK_RCONTROL => "Ctrl",
+            winuser::VK_LSHIFT | winuser::VK_RSHIFT => "Shift",
+            winuser::VK_LMENU | winuser::VK_RMENU => "Alt",
+            _ => "Win",
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    match names.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,339 @@
+use std::io::{self, BufRead, Read, Write};
+
+/// Appended to the client's key to prove the server understood the handshake, from RFC 6455
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Larger messages are refused, as nothing sent to us needs more than a few bytes
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha1_digest() {
+        let hex: String = sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        // The example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn handshake_request() {
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let handshake = read_handshake(&mut request.as_bytes()).unwrap();
+        assert_eq!(handshake.key, "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(handshake.token, None);
+        assert_eq!(handshake.origin, None);
+    }
+
+    #[test]
+    fn handshake_token() {
+        assert_eq!(
+            query_token("GET /?v=1&token=abc HTTP/1.1\r\n"),
+            Some("abc".to_owned())
+        );
+        assert_eq!(query_token("GET /?v=1 HTTP/1.1"), None);
+        assert_eq!(query_token("GET /token=abc HTTP/1.1"), None);
+    }
+
+    #[test]
+    fn masked_text_frame() {
+        // A masked "Hello" from a client, from RFC 6455
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert_eq!(
+            read_message(&mut &frame[..]).unwrap(),
+            Message::Text("Hello".to_owned())
+        );
+    }
+
+    #[test]
+    fn written_frames_are_read_back() {
+        let mut data = Vec::new();
+        write_text(&mut data, &"x".repeat(300)).unwrap();
+        assert_eq!(&data[..4], &[0x81, 126, 0x01, 0x2c]);
+        assert_eq!(
+            read_message(&mut &data[..]).unwrap(),
+            Message::Text("x".repeat(300))
+        );
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let mut frame = vec![0x81, 127];
+        frame.extend_from_slice(&u64::MAX.to_be_bytes());
+        let error = read_message(&mut &frame[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // The first fragment counts towards the limit too
+        let mut frame = vec![0x01, 1, b'x', 0x80, 127];
+        frame.extend_from_slice(&MAX_MESSAGE_SIZE.to_be_bytes());
+        let error = read_message(&mut &frame[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_control_frames_are_refused() {
+        let mut ping = vec![0x89, 126, 0, 126];
+        ping.extend_from_slice(&[0; 126]);
+        assert!(read_message(&mut &ping[..]).is_err());
+        let unfinished_close = [0x08, 0];
+        assert!(read_message(&mut &unfinished_close[..]).is_err());
+        let ping = [0x89, 1, 7];
+        assert_eq!(
+            read_message(&mut &ping[..]).unwrap(),
+            Message::Ping(vec![7])
+        );
+    }
+}
+
+/// The parts of a client's opening handshake which matter to us
+#[derive(Debug, PartialEq)]
+pub struct Handshake {
+    pub key: String,
+    /// The `token` given in the query string of the requested URL, if there is one
+    pub token: Option<String>,
+    /// Set by browsers, so pages can be told apart from local tools
+    pub origin: Option<String>,
+}
+
+/// A message from the client
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Read the client's HTTP upgrade request, up to the blank line which ends it
+pub fn read_handshake(reader: &mut impl BufRead) -> io::Result<Handshake> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let token = query_token(&request_line);
+    let mut key = None;
+    let mut origin = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+                key = Some(value.trim().to_owned());
+            } else if name.eq_ignore_ascii_case("Origin") {
+                origin = Some(value.trim().to_owned());
+            }
+        }
+    }
+    match key {
+        Some(key) => Ok(Handshake { key, token, origin }),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a WebSocket handshake",
+        )),
+    }
+}
+
+/// The `token` parameter of the URL in a request line such as `GET /?token=abc HTTP/1.1`
+fn query_token(request_line: &str) -> Option<String> {
+    let target = request_line.split_whitespace().nth(1)?;
+    let (_, query) = target.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(str::to_owned)
+}
+
+/// Accept the connection described by `handshake`
+pub fn write_handshake(writer: &mut impl Write, handshake: &Handshake) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&handshake.key)
+    )?;
+    writer.flush()
+}
+
+/// Turn down a connection before it's upgraded
+pub fn write_forbidden(writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")?;
+    writer.flush()
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode(sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+/// Read frames until a whole message has arrived, answering nothing itself
+pub fn read_message(reader: &mut impl Read) -> io::Result<Message> {
+    let mut message = Vec::new();
+    loop {
+        let mut header = [0; 2];
+        reader.read_exact(&mut header)?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        let masked = header[1] & 0x80 != 0;
+        let length = match header[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                reader.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        // Control frames can't be split up and carry at most 125 bytes, as RFC 6455 says
+        let control = opcode & 0x08 != 0;
+        if control && (length > 125 || !fin) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed control frame",
+            ));
+        }
+        if length > MAX_MESSAGE_SIZE - message.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message too large",
+            ));
+        }
+        let mut mask = [0; 4];
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+
+        match opcode {
+            OPCODE_TEXT | OPCODE_CONTINUATION => {
+                message.extend(payload);
+                if fin {
+                    return String::from_utf8(message)
+                        .map(Message::Text)
+                        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+                }
+            }
+            OPCODE_CLOSE => return Ok(Message::Close),
+            OPCODE_PING => return Ok(Message::Ping(payload)),
+            // Pongs and binary messages aren't used
+            _ => {}
+        }
+    }
+}
+
+pub fn write_text(writer: &mut impl Write, text: &str) -> io::Result<()> {
+    write_frame(writer, OPCODE_TEXT, text.as_bytes())
+}
+
+pub fn write_pong(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    write_frame(writer, OPCODE_PONG, payload)
+}
+
+pub fn write_close(writer: &mut impl Write) -> io::Result<()> {
+    write_frame(writer, OPCODE_CLOSE, &[])
+}
+
+/// Write a single unmasked frame, as servers send
+fn write_frame(writer: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// SHA-1, which the handshake needs and nothing else does
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut words = [0u32; 80];
+        for (index, word) in block.chunks(4).enumerate() {
+            words[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for index in 16..80 {
+            words[index] =
+                (words[index - 3] ^ words[index - 8] ^ words[index - 14] ^ words[index - 16])
+                    .rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (index, word) in words.iter().enumerate() {
+            let (f, k) = match index {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *value = value.wrapping_add(*added);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, value) in digest.chunks_mut(4).zip(state.iter()) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
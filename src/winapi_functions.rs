@@ -1,5 +1,15 @@
-use std::{ffi::CString, ptr};
-use winapi::um::winuser;
+use std::{
+    ffi::{CString, OsString},
+    os::windows::{ffi::OsStringExt, io::AsRawHandle},
+    path::PathBuf,
+    ptr,
+};
+use winapi::shared::{bcrypt, winerror};
+use winapi::um::{
+    consoleapi, dpapi, fileapi, handleapi, memoryapi, minwinbase, namedpipeapi, processenv,
+    processthreadsapi, shellapi, synchapi, sysinfoapi, winbase, wincon, wincrypt, wingdi, winnt,
+    winreg, winuser,
+};
 
 pub type SystemError = error_code::ErrorCode<error_code::SystemCategory>;
 
@@ -21,7 +31,7 @@ pub fn create_window_ex_a<'a>(
     y: i32,
     n_width: i32,
     n_height: i32,
-    h_wnd_parent: &'a mut winapi::shared::windef::HWND__,
+    h_wnd_parent: Option<&'a mut winapi::shared::windef::HWND__>,
     h_menu: Option<&'a mut winapi::shared::windef::HMENU__>,
     h_instance: Option<&'a mut winapi::shared::minwindef::HINSTANCE__>,
     lp_param: Option<&'a mut std::ffi::c_void>,
@@ -40,7 +50,7 @@ pub fn create_window_ex_a<'a>(
             y,
             n_width,
             n_height,
-            h_wnd_parent,
+            h_wnd_parent.map(|x| x as *mut _).unwrap_or(ptr::null_mut()),
             h_menu.map(|x| x as *mut _).unwrap_or(ptr::null_mut()),
             h_instance.map(|x| x as *mut _).unwrap_or(ptr::null_mut()),
             lp_param.map(|x| x as *mut _).unwrap_or(ptr::null_mut()),
@@ -62,6 +72,20 @@ pub fn send_input(
     }
 }
 
+/// Stop keyboard and mouse input reaching programs, or let it through again. Windows refuses when
+/// the program in the foreground runs at a higher integrity level
+pub fn block_input(block: bool) -> Result<(), SystemError> {
+    match unsafe { winuser::BlockInput(block as i32) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// Synthesize a key event with the older keybd_event, which can't report whether it worked
+pub fn keybd_event(key: u16, flags: u32) {
+    unsafe { winuser::keybd_event(key as u8, 0, flags, 0) }
+}
+
 pub fn add_clipboard_format_listener(
     h_wnd: &mut winapi::shared::windef::HWND__,
 ) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
@@ -161,3 +185,930 @@ pub fn get_clipboard_data(
         handle => Ok(handle),
     }
 }
+
+pub fn attach_console(
+    dw_process_id: u32,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { wincon::AttachConsole(dw_process_id) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+pub fn post_message_a(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    msg: u32,
+    w_param: usize,
+    l_param: isize,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::PostMessageA(h_wnd, msg, w_param, l_param) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// The Remote Desktop Services session the current process runs in
+pub fn current_session_id() -> Result<u32, error_code::ErrorCode<error_code::SystemCategory>> {
+    let mut session_id = 0;
+    match unsafe {
+        processthreadsapi::ProcessIdToSessionId(
+            processthreadsapi::GetCurrentProcessId(),
+            &mut session_id,
+        )
+    } {
+        0 => Err(SystemError::last()),
+        _ => Ok(session_id),
+    }
+}
+
+pub fn create_named_pipe_a(
+    lp_name: &str,
+    dw_open_mode: u32,
+    dw_pipe_mode: u32,
+    n_max_instances: u32,
+    n_out_buffer_size: u32,
+    n_in_buffer_size: u32,
+    n_default_time_out: u32,
+) -> Result<winapi::um::winnt::HANDLE, error_code::ErrorCode<error_code::SystemCategory>> {
+    let name = CString::new(lp_name).unwrap();
+    match unsafe {
+        winbase::CreateNamedPipeA(
+            name.as_ptr(),
+            dw_open_mode,
+            dw_pipe_mode,
+            n_max_instances,
+            n_out_buffer_size,
+            n_in_buffer_size,
+            n_default_time_out,
+            ptr::null_mut(),
+        )
+    } {
+        handle if handle == handleapi::INVALID_HANDLE_VALUE => Err(SystemError::last()),
+        handle => Ok(handle),
+    }
+}
+
+pub fn connect_named_pipe(
+    h_named_pipe: &impl AsRawHandle,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe {
+        namedpipeapi::ConnectNamedPipe(h_named_pipe.as_raw_handle() as _, ptr::null_mut())
+    } {
+        0 => match SystemError::last() {
+            // The client connected between creating the pipe and waiting for it
+            error if error.raw_code() == winerror::ERROR_PIPE_CONNECTED as i32 => Ok(()),
+            error => Err(error),
+        },
+        _ => Ok(()),
+    }
+}
+
+pub fn shell_notify_icon_w(
+    dw_message: u32,
+    lp_data: &mut shellapi::NOTIFYICONDATAW,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { shellapi::Shell_NotifyIconW(dw_message, lp_data) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// Load one of the predefined system icons, such as `IDI_APPLICATION`
+pub fn load_system_icon(
+    icon_id: u16,
+) -> Result<winapi::shared::windef::HICON, error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::LoadIconW(ptr::null_mut(), winuser::MAKEINTRESOURCEW(icon_id)) } {
+        icon if icon.is_null() => Err(SystemError::last()),
+        icon => Ok(icon),
+    }
+}
+
+/// Create an icon from the image data of a single entry in a .ico file
+pub fn create_icon_from_resource_ex(
+    presbits: &[u8],
+    cx_desired: i32,
+    cy_desired: i32,
+) -> Result<winapi::shared::windef::HICON, error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe {
+        winuser::CreateIconFromResourceEx(
+            presbits.as_ptr() as *mut _,
+            presbits.len() as u32,
+            1,
+            0x00030000,
+            cx_desired,
+            cy_desired,
+            winuser::LR_DEFAULTCOLOR,
+        )
+    } {
+        icon if icon.is_null() => Err(SystemError::last()),
+        icon => Ok(icon),
+    }
+}
+
+pub fn destroy_icon(
+    h_icon: &mut winapi::shared::windef::HICON__,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::DestroyIcon(h_icon) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+pub fn get_system_metrics(n_index: i32) -> i32 {
+    unsafe { winuser::GetSystemMetrics(n_index) }
+}
+
+/// Encode a string as null terminated UTF-16
+pub fn to_wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+pub fn create_popup_menu() -> Result<
+    &'static mut winapi::shared::windef::HMENU__,
+    error_code::ErrorCode<error_code::SystemCategory>,
+> {
+    match unsafe { winuser::CreatePopupMenu() } {
+        h_menu if h_menu.is_null() => Err(SystemError::last()),
+        h_menu => Ok(unsafe { &mut *h_menu }),
+    }
+}
+
+pub fn destroy_menu(
+    h_menu: &mut winapi::shared::windef::HMENU__,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::DestroyMenu(h_menu) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+pub fn append_menu_w(
+    h_menu: &mut winapi::shared::windef::HMENU__,
+    u_flags: u32,
+    u_id_new_item: usize,
+    lp_new_item: &str,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    let new_item = to_wide(lp_new_item);
+    match unsafe { winuser::AppendMenuW(h_menu, u_flags, u_id_new_item, new_item.as_ptr()) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// Show a popup menu. With `TPM_RETURNCMD` this returns the chosen item id, or 0 if it was dismissed
+pub fn track_popup_menu(
+    h_menu: &mut winapi::shared::windef::HMENU__,
+    u_flags: u32,
+    x: i32,
+    y: i32,
+    h_wnd: &mut winapi::shared::windef::HWND__,
+) -> i32 {
+    unsafe { winuser::TrackPopupMenu(h_menu, u_flags, x, y, 0, h_wnd, ptr::null()) }
+}
+
+pub fn set_foreground_window(h_wnd: &mut winapi::shared::windef::HWND__) -> bool {
+    unsafe { winuser::SetForegroundWindow(h_wnd) != 0 }
+}
+
+pub fn get_cursor_pos(
+) -> Result<winapi::shared::windef::POINT, error_code::ErrorCode<error_code::SystemCategory>> {
+    let mut point = winapi::shared::windef::POINT::default();
+    match unsafe { winuser::GetCursorPos(&mut point) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(point),
+    }
+}
+
+/// Open a file with its default program
+pub fn shell_execute_open(
+    lp_file: &std::path::Path,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    use std::os::windows::ffi::OsStrExt;
+
+    let operation = to_wide("open");
+    let file: Vec<u16> = lp_file
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    // Values above 32 indicate success
+    match unsafe {
+        shellapi::ShellExecuteW(
+            ptr::null_mut(),
+            operation.as_ptr(),
+            file.as_ptr(),
+            ptr::null(),
+            ptr::null(),
+            winuser::SW_SHOWNORMAL,
+        )
+    } as usize
+    {
+        0..=32 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// The number of minutes since midnight, local time
+pub fn local_minute_of_day() -> u32 {
+    let mut time = minwinbase::SYSTEMTIME::default();
+    unsafe { sysinfoapi::GetLocalTime(&mut time) };
+    u32::from(time.wHour) * 60 + u32::from(time.wMinute)
+}
+
+pub fn set_timer(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    n_id_event: usize,
+    u_elapse: u32,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::SetTimer(h_wnd, n_id_event, u_elapse, None) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+pub fn kill_timer(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    u_id_event: usize,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::KillTimer(h_wnd, u_id_event) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+pub fn register_class_ex_w(
+    lp_wnd_class: &winuser::WNDCLASSEXW,
+) -> Result<u16, error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::RegisterClassExW(lp_wnd_class) } {
+        0 => Err(SystemError::last()),
+        atom => Ok(atom),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_window_ex_w(
+    dw_ex_style: u32,
+    lp_class_name: &str,
+    lp_window_name: &str,
+    dw_style: u32,
+    x: i32,
+    y: i32,
+    n_width: i32,
+    n_height: i32,
+    h_wnd_parent: Option<&mut winapi::shared::windef::HWND__>,
+) -> Result<
+    &'static mut winapi::shared::windef::HWND__,
+    error_code::ErrorCode<error_code::SystemCategory>,
+> {
+    let class_name = to_wide(lp_class_name);
+    let window_name = to_wide(lp_window_name);
+    match unsafe {
+        winuser::CreateWindowExW(
+            dw_ex_style,
+            class_name.as_ptr(),
+            window_name.as_ptr(),
+            dw_style,
+            x,
+            y,
+            n_width,
+            n_height,
+            h_wnd_parent.map(|x| x as *mut _).unwrap_or(ptr::null_mut()),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    } {
+        h_wnd if h_wnd.is_null() => Err(SystemError::last()),
+        h_wnd => Ok(unsafe { &mut *h_wnd }),
+    }
+}
+
+pub fn destroy_window(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::DestroyWindow(h_wnd) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+pub fn send_message_w(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    msg: u32,
+    w_param: usize,
+    l_param: isize,
+) -> isize {
+    unsafe { winuser::SendMessageW(h_wnd, msg, w_param, l_param) }
+}
+
+pub fn get_foreground_window() -> Option<&'static mut winapi::shared::windef::HWND__> {
+    unsafe { winuser::GetForegroundWindow().as_mut() }
+}
+
+/// Call `callback` whenever another window comes to the foreground. It's called on this thread
+/// while it waits for messages
+pub fn set_foreground_event_hook(
+    callback: winuser::WINEVENTPROC,
+) -> Option<&'static mut winapi::shared::windef::HWINEVENTHOOK__> {
+    unsafe {
+        winuser::SetWinEventHook(
+            winuser::EVENT_SYSTEM_FOREGROUND,
+            winuser::EVENT_SYSTEM_FOREGROUND,
+            ptr::null_mut(),
+            callback,
+            0,
+            0,
+            winuser::WINEVENT_OUTOFCONTEXT | winuser::WINEVENT_SKIPOWNPROCESS,
+        )
+        .as_mut()
+    }
+}
+
+pub fn unhook_win_event(hook: &mut winapi::shared::windef::HWINEVENTHOOK__) {
+    unsafe { winuser::UnhookWinEvent(hook) };
+}
+
+/// The name of the class `h_wnd` was created with, via GetClassNameW
+pub fn get_class_name(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+) -> Result<String, error_code::ErrorCode<error_code::SystemCategory>> {
+    let mut buffer = [0u16; 256];
+    match unsafe { winuser::GetClassNameW(h_wnd, buffer.as_mut_ptr(), buffer.len() as i32) } {
+        0 => Err(SystemError::last()),
+        length => Ok(String::from_utf16_lossy(&buffer[..length as usize])),
+    }
+}
+
+pub fn show_window(h_wnd: &mut winapi::shared::windef::HWND__, n_cmd_show: i32) {
+    unsafe { winuser::ShowWindow(h_wnd, n_cmd_show) };
+}
+
+pub fn set_focus(h_wnd: &mut winapi::shared::windef::HWND__) {
+    unsafe { winuser::SetFocus(h_wnd) };
+}
+
+pub fn move_window(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    x: i32,
+    y: i32,
+    n_width: i32,
+    n_height: i32,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { winuser::MoveWindow(h_wnd, x, y, n_width, n_height, 1) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `h_wnd` is `h_wnd_parent` or one of its descendants
+pub fn is_window_or_child(
+    h_wnd_parent: &mut winapi::shared::windef::HWND__,
+    h_wnd: &mut winapi::shared::windef::HWND__,
+) -> bool {
+    ptr::eq(h_wnd, h_wnd_parent) || unsafe { winuser::IsChild(h_wnd_parent, h_wnd) != 0 }
+}
+
+pub fn get_stock_object(i: i32) -> winapi::shared::windef::HGDIOBJ {
+    unsafe { winapi::um::wingdi::GetStockObject(i) }
+}
+
+pub fn get_clipboard_owner() -> Option<&'static mut winapi::shared::windef::HWND__> {
+    unsafe { winuser::GetClipboardOwner().as_mut() }
+}
+
+/// The window which has the clipboard open, if it was opened with one
+pub fn get_open_clipboard_window() -> Option<&'static mut winapi::shared::windef::HWND__> {
+    unsafe { winuser::GetOpenClipboardWindow().as_mut() }
+}
+
+pub fn get_window_thread_process_id(h_wnd: &mut winapi::shared::windef::HWND__) -> u32 {
+    let mut process_id = 0;
+    unsafe { winuser::GetWindowThreadProcessId(h_wnd, &mut process_id) };
+    process_id
+}
+
+/// The full path of a process's executable, via OpenProcess and QueryFullProcessImageNameW
+pub fn query_full_process_image_name(
+    process_id: u32,
+) -> Result<PathBuf, error_code::ErrorCode<error_code::SystemCategory>> {
+    let process = unsafe {
+        processthreadsapi::OpenProcess(winnt::PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id)
+    };
+    if process.is_null() {
+        return Err(SystemError::last());
+    }
+    let mut buffer = vec![0u16; 32_768];
+    let mut size = buffer.len() as u32;
+    let result =
+        unsafe { winbase::QueryFullProcessImageNameW(process, 0, buffer.as_mut_ptr(), &mut size) };
+    let error = SystemError::last();
+    unsafe { handleapi::CloseHandle(process) };
+    match result {
+        0 => Err(error),
+        _ => Ok(PathBuf::from(OsString::from_wide(&buffer[..size as usize]))),
+    }
+}
+
+/// Read a DWORD value from HKEY_LOCAL_MACHINE, via RegGetValueW
+pub fn reg_get_local_machine_dword(
+    sub_key: &str,
+    value: &str,
+) -> Result<u32, error_code::ErrorCode<error_code::SystemCategory>> {
+    let sub_key = to_wide(sub_key);
+    let value = to_wide(value);
+    let mut data = 0u32;
+    let mut size = std::mem::size_of::<u32>() as u32;
+    match unsafe {
+        winreg::RegGetValueW(
+            winreg::HKEY_LOCAL_MACHINE,
+            sub_key.as_ptr(),
+            value.as_ptr(),
+            winreg::RRF_RT_REG_DWORD,
+            ptr::null_mut(),
+            &mut data as *mut u32 as *mut _,
+            &mut size,
+        )
+    } {
+        0 => Ok(data),
+        code => Err(SystemError::new(code)),
+    }
+}
+
+/// Set a string value under HKEY_CURRENT_USER, creating the key if it's missing, via RegSetValueExW
+pub fn reg_set_current_user_string(
+    sub_key: &str,
+    value: &str,
+    data: &str,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    let sub_key = to_wide(sub_key);
+    let value = to_wide(value);
+    let data = to_wide(data);
+    let mut key = ptr::null_mut();
+    match unsafe {
+        winreg::RegCreateKeyExW(
+            winreg::HKEY_CURRENT_USER,
+            sub_key.as_ptr(),
+            0,
+            ptr::null_mut(),
+            winnt::REG_OPTION_NON_VOLATILE,
+            winnt::KEY_SET_VALUE,
+            ptr::null_mut(),
+            &mut key,
+            ptr::null_mut(),
+        )
+    } {
+        0 => {}
+        code => return Err(SystemError::new(code)),
+    }
+    let result = unsafe {
+        winreg::RegSetValueExW(
+            key,
+            value.as_ptr(),
+            0,
+            winnt::REG_SZ,
+            data.as_ptr() as *const u8,
+            (data.len() * 2) as u32,
+        )
+    };
+    unsafe { winreg::RegCloseKey(key) };
+    match result {
+        0 => Ok(()),
+        code => Err(SystemError::new(code)),
+    }
+}
+
+/// Delete a value under HKEY_CURRENT_USER, returning whether it was there
+pub fn reg_delete_current_user_value(
+    sub_key: &str,
+    value: &str,
+) -> Result<bool, error_code::ErrorCode<error_code::SystemCategory>> {
+    let sub_key = to_wide(sub_key);
+    let value = to_wide(value);
+    match unsafe {
+        winreg::RegDeleteKeyValueW(winreg::HKEY_CURRENT_USER, sub_key.as_ptr(), value.as_ptr())
+    } as u32
+    {
+        0 => Ok(true),
+        winerror::ERROR_FILE_NOT_FOUND => Ok(false),
+        code => Err(SystemError::new(code as i32)),
+    }
+}
+
+/// The monitor showing most of `h_wnd`, or the nearest one if it's off screen
+pub fn monitor_from_window(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+) -> Option<&'static mut winapi::shared::windef::HMONITOR__> {
+    unsafe { winuser::MonitorFromWindow(h_wnd, winuser::MONITOR_DEFAULTTONEAREST).as_mut() }
+}
+
+/// The device name of a monitor, such as `\\.\DISPLAY2`
+pub fn get_monitor_device_name(
+    monitor: &mut winapi::shared::windef::HMONITOR__,
+) -> Result<String, SystemError> {
+    let mut info = winuser::MONITORINFOEXW {
+        cbSize: std::mem::size_of::<winuser::MONITORINFOEXW>() as u32,
+        ..Default::default()
+    };
+    match unsafe { winuser::GetMonitorInfoW(monitor, &mut info as *mut _ as *mut _) } {
+        0 => Err(SystemError::last()),
+        _ => {
+            let length = info
+                .szDevice
+                .iter()
+                .position(|c| *c == 0)
+                .unwrap_or(info.szDevice.len());
+            Ok(String::from_utf16_lossy(&info.szDevice[..length]))
+        }
+    }
+}
+
+/// Show a modal message box without an owner, returning the button which was pressed, such as IDOK
+pub fn message_box_w(
+    text: &str,
+    caption: &str,
+    u_type: u32,
+) -> Result<i32, error_code::ErrorCode<error_code::SystemCategory>> {
+    let text = to_wide(text);
+    let caption = to_wide(caption);
+    match unsafe { winuser::MessageBoxW(ptr::null_mut(), text.as_ptr(), caption.as_ptr(), u_type) }
+    {
+        0 => Err(SystemError::last()),
+        button => Ok(button),
+    }
+}
+
+/// A number which changes whenever the clipboard's contents do, or 0 if it can't be read
+pub fn get_clipboard_sequence_number() -> u32 {
+    unsafe { winuser::GetClipboardSequenceNumber() }
+}
+
+/// Open a directory so changes to it can be watched with read_directory_changes_w
+pub fn open_directory(
+    path: &std::path::Path,
+) -> Result<winnt::HANDLE, error_code::ErrorCode<error_code::SystemCategory>> {
+    use std::os::windows::ffi::OsStrExt;
+
+    let path: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    match unsafe {
+        fileapi::CreateFileW(
+            path.as_ptr(),
+            winnt::FILE_LIST_DIRECTORY,
+            winnt::FILE_SHARE_READ | winnt::FILE_SHARE_WRITE | winnt::FILE_SHARE_DELETE,
+            ptr::null_mut(),
+            fileapi::OPEN_EXISTING,
+            winbase::FILE_FLAG_BACKUP_SEMANTICS,
+            ptr::null_mut(),
+        )
+    } {
+        handle if handle == handleapi::INVALID_HANDLE_VALUE => Err(SystemError::last()),
+        handle => Ok(handle),
+    }
+}
+
+/// Wait for files in a directory to change, returning the names of the files which did
+pub fn read_directory_changes_w(
+    directory: &impl AsRawHandle,
+    dw_notify_filter: u32,
+) -> Result<Vec<OsString>, error_code::ErrorCode<error_code::SystemCategory>> {
+    // FILE_NOTIFY_INFORMATION records have to be DWORD aligned
+    let mut buffer = vec![0u32; 1024];
+    let mut bytes_returned = 0;
+    if unsafe {
+        winbase::ReadDirectoryChangesW(
+            directory.as_raw_handle() as _,
+            buffer.as_mut_ptr() as _,
+            (buffer.len() * 4) as u32,
+            0,
+            dw_notify_filter,
+            &mut bytes_returned,
+            ptr::null_mut(),
+            None,
+        )
+    } == 0
+    {
+        return Err(SystemError::last());
+    }
+
+    let mut names = Vec::new();
+    // Nothing is returned if the buffer overflowed, in which case the caller can't tell what changed
+    if bytes_returned == 0 {
+        return Ok(names);
+    }
+    let mut offset = 0;
+    loop {
+        let info = unsafe {
+            &*((buffer.as_ptr() as *const u8).add(offset) as *const winnt::FILE_NOTIFY_INFORMATION)
+        };
+        let name = unsafe {
+            std::slice::from_raw_parts(info.FileName.as_ptr(), info.FileNameLength as usize / 2)
+        };
+        names.push(OsString::from_wide(name));
+        match info.NextEntryOffset {
+            0 => break,
+            next => offset += next as usize,
+        }
+    }
+    Ok(names)
+}
+
+/// Create a named mutex, or open it if it already exists. Returns the handle and whether it
+/// already existed
+pub fn create_mutex_w(
+    name: &str,
+) -> Result<(winnt::HANDLE, bool), error_code::ErrorCode<error_code::SystemCategory>> {
+    let name = to_wide(name);
+    match unsafe { synchapi::CreateMutexW(ptr::null_mut(), 0, name.as_ptr()) } {
+        handle if handle.is_null() => Err(SystemError::last()),
+        handle => {
+            let already_exists =
+                SystemError::last().raw_code() == winerror::ERROR_ALREADY_EXISTS as i32;
+            Ok((handle, already_exists))
+        }
+    }
+}
+
+pub fn set_window_long_ptr_a(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    n_index: i32,
+    dw_new_long: isize,
+) -> isize {
+    unsafe { winuser::SetWindowLongPtrA(h_wnd, n_index, dw_new_long) }
+}
+
+pub fn set_console_ctrl_handler(
+    handler_routine: wincon::PHANDLER_ROUTINE,
+    add: bool,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    match unsafe { consoleapi::SetConsoleCtrlHandler(handler_routine, add as i32) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// Give the process a console window of its own
+pub fn alloc_console() -> Result<(), SystemError> {
+    match unsafe { consoleapi::AllocConsole() } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+pub fn free_console() -> Result<(), SystemError> {
+    match unsafe { wincon::FreeConsole() } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the process has a standard input handle. Programs without a console of their own only
+/// get one when their input is redirected
+pub fn has_std_input() -> bool {
+    let handle = unsafe { processenv::GetStdHandle(winbase::STD_INPUT_HANDLE) };
+    !handle.is_null() && handle != handleapi::INVALID_HANDLE_VALUE
+}
+
+/// Read a line typed into `console`, opened from "CONIN$", via ReadConsoleW. The line starts with
+/// `initial`, which must already be on screen, and reading stops early when one of the control
+/// characters in `wakeup_mask` is typed, such as `1 << 9` for Tab. The text is returned with the
+/// Enter or control character which ended it
+pub fn read_console_w(
+    console: &std::fs::File,
+    initial: &str,
+    wakeup_mask: u32,
+) -> Result<String, SystemError> {
+    let mut buffer = vec![0u16; 4096];
+    let initial: Vec<_> = initial.encode_utf16().take(buffer.len() - 1).collect();
+    buffer[..initial.len()].copy_from_slice(&initial);
+    let mut control = wincon::CONSOLE_READCONSOLE_CONTROL {
+        nLength: std::mem::size_of::<wincon::CONSOLE_READCONSOLE_CONTROL>() as u32,
+        nInitialChars: initial.len() as u32,
+        dwCtrlWakeupMask: wakeup_mask,
+        dwControlKeyState: 0,
+    };
+    let mut read = 0;
+    match unsafe {
+        consoleapi::ReadConsoleW(
+            console.as_raw_handle() as _,
+            buffer.as_mut_ptr() as *mut _,
+            buffer.len() as u32,
+            &mut read,
+            &mut control,
+        )
+    } {
+        0 => Err(SystemError::last()),
+        _ => Ok(String::from_utf16_lossy(&buffer[..read as usize])),
+    }
+}
+
+pub fn set_window_text_w(
+    h_wnd: &mut winapi::shared::windef::HWND__,
+    text: &str,
+) -> Result<(), error_code::ErrorCode<error_code::SystemCategory>> {
+    let text = to_wide(text);
+    match unsafe { winuser::SetWindowTextW(h_wnd, text.as_ptr()) } {
+        0 => Err(SystemError::last()),
+        _ => Ok(()),
+    }
+}
+
+/// The text of a window or control, or an empty string if it has none, via GetWindowTextW
+pub fn get_window_text_w(h_wnd: &mut winapi::shared::windef::HWND__) -> String {
+    let length = unsafe { winuser::GetWindowTextLengthW(h_wnd) };
+    let mut buffer = vec![0u16; length as usize + 1];
+    let copied =
+        unsafe { winuser::GetWindowTextW(h_wnd, buffer.as_mut_ptr(), buffer.len() as i32) };
+    String::from_utf16_lossy(&buffer[..copied.max(0) as usize])
+}
+
+/// The name a format was registered with, or None for predefined formats
+pub fn get_clipboard_format_name_w(format: u32) -> Option<String> {
+    let mut buffer = [0u16; 256];
+    match unsafe {
+        winuser::GetClipboardFormatNameW(format, buffer.as_mut_ptr(), buffer.len() as i32)
+    } {
+        0 => None,
+        length => Some(String::from_utf16_lossy(&buffer[..length as usize])),
+    }
+}
+
+/// Encrypt `data` so only the current user can decrypt it, via CryptProtectData
+pub fn crypt_protect_data(
+    data: &[u8],
+) -> Result<Vec<u8>, error_code::ErrorCode<error_code::SystemCategory>> {
+    let mut input = wincrypt::DATA_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut u8,
+    };
+    let mut output = wincrypt::DATA_BLOB {
+        cbData: 0,
+        pbData: ptr::null_mut(),
+    };
+    match unsafe {
+        dpapi::CryptProtectData(
+            &mut input,
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            dpapi::CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+    } {
+        0 => Err(SystemError::last()),
+        _ => Ok(take_blob(output)),
+    }
+}
+
+/// Decrypt data encrypted by `crypt_protect_data`, via CryptUnprotectData
+pub fn crypt_unprotect_data(
+    data: &[u8],
+) -> Result<Vec<u8>, error_code::ErrorCode<error_code::SystemCategory>> {
+    let mut input = wincrypt::DATA_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut u8,
+    };
+    let mut output = wincrypt::DATA_BLOB {
+        cbData: 0,
+        pbData: ptr::null_mut(),
+    };
+    match unsafe {
+        dpapi::CryptUnprotectData(
+            &mut input,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            dpapi::CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+    } {
+        0 => Err(SystemError::last()),
+        _ => Ok(take_blob(output)),
+    }
+}
+
+/// Fill `buffer` with random bytes fit for secrets, via BCryptGenRandom
+pub fn bcrypt_gen_random(buffer: &mut [u8]) -> Result<(), String> {
+    match unsafe {
+        bcrypt::BCryptGenRandom(
+            ptr::null_mut(),
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+            bcrypt::BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+        )
+    } {
+        0 => Ok(()),
+        status => Err(format!("BCryptGenRandom failed with status {:#x}", status)),
+    }
+}
+
+/// Copy out a blob allocated by the crypt functions, then free it with LocalFree
+fn take_blob(blob: wincrypt::DATA_BLOB) -> Vec<u8> {
+    let data = unsafe { std::slice::from_raw_parts(blob.pbData, blob.cbData as usize) }.to_vec();
+    unsafe { winbase::LocalFree(blob.pbData as *mut _) };
+    data
+}
+
+/// A bitmap for drawing on the screen, made from a packed DIB whose pixels start at
+/// `pixel_offset`, via CreateDIBitmap
+pub fn create_dib_bitmap(
+    dib: &[u8],
+    pixel_offset: usize,
+) -> Result<
+    &'static mut winapi::shared::windef::HBITMAP__,
+    error_code::ErrorCode<error_code::SystemCategory>,
+> {
+    let dc = unsafe { winuser::GetDC(ptr::null_mut()) };
+    if dc.is_null() {
+        return Err(SystemError::last());
+    }
+    let bitmap = unsafe {
+        wingdi::CreateDIBitmap(
+            dc,
+            dib.as_ptr() as *const wingdi::BITMAPINFOHEADER,
+            wingdi::CBM_INIT,
+            dib[pixel_offset..].as_ptr() as *const _,
+            dib.as_ptr() as *const wingdi::BITMAPINFO,
+            wingdi::DIB_RGB_COLORS,
+        )
+    };
+    let error = SystemError::last();
+    unsafe { winuser::ReleaseDC(ptr::null_mut(), dc) };
+    unsafe { bitmap.as_mut() }.ok_or(error)
+}
+
+pub fn delete_bitmap(bitmap: &mut winapi::shared::windef::HBITMAP__) -> bool {
+    unsafe { wingdi::DeleteObject(bitmap as *mut _ as *mut _) != 0 }
+}
+
+/// Create a named section of `size` bytes backed by the page file, or open it if it already exists
+pub fn create_file_mapping_w(name: &str, size: u32) -> Result<winnt::HANDLE, SystemError> {
+    let name = to_wide(name);
+    match unsafe {
+        memoryapi::CreateFileMappingW(
+            handleapi::INVALID_HANDLE_VALUE,
+            ptr::null_mut(),
+            winnt::PAGE_READWRITE,
+            0,
+            size,
+            name.as_ptr(),
+        )
+    } {
+        handle if handle.is_null() => Err(SystemError::last()),
+        handle => Ok(handle),
+    }
+}
+
+pub fn open_file_mapping_w(name: &str, desired_access: u32) -> Result<winnt::HANDLE, SystemError> {
+    let name = to_wide(name);
+    match unsafe { memoryapi::OpenFileMappingW(desired_access, 0, name.as_ptr()) } {
+        handle if handle.is_null() => Err(SystemError::last()),
+        handle => Ok(handle),
+    }
+}
+
+pub fn map_view_of_file(
+    mapping: &impl AsRawHandle,
+    desired_access: u32,
+    size: usize,
+) -> Result<*mut u8, SystemError> {
+    match unsafe {
+        memoryapi::MapViewOfFile(mapping.as_raw_handle() as _, desired_access, 0, 0, size)
+    } {
+        view if view.is_null() => Err(SystemError::last()),
+        view => Ok(view as *mut u8),
+    }
+}
+
+/// # Safety
+/// `view` must have been returned by `map_view_of_file` and not be used afterwards
+pub unsafe fn unmap_view_of_file(view: *mut u8) -> bool {
+    memoryapi::UnmapViewOfFile(view as *const _) != 0
+}
+
+/// Whether this process's window station is interactive, so it has a clipboard and keyboard the
+/// user sees. Services and programs started over SSH run in one which isn't
+pub fn is_interactive_window_station() -> Result<bool, SystemError> {
+    let station = unsafe { winuser::GetProcessWindowStation() };
+    if station.is_null() {
+        return Err(SystemError::last());
+    }
+    let mut flags = winuser::USEROBJECTFLAGS::default();
+    let mut needed = 0;
+    match unsafe {
+        winuser::GetUserObjectInformationW(
+            station as _,
+            winuser::UOI_FLAGS as i32,
+            &mut flags as *mut _ as *mut _,
+            std::mem::size_of::<winuser::USEROBJECTFLAGS>() as u32,
+            &mut needed,
+        )
+    } {
+        0 => Err(SystemError::last()),
+        _ => Ok(flags.dwFlags & winuser::WSF_VISIBLE != 0),
+    }
+}
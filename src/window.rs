@@ -1,93 +1,379 @@
-use std::{collections::VecDeque, ffi::CString, mem, ptr, thread, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    ffi::CString,
+    mem,
+    path::Path,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
 
+use winapi::shared::{
+    minwindef::{BOOL, DWORD, FALSE, LRESULT, TRUE},
+    ntdef::LONG,
+    windef::{HWINEVENTHOOK, HWND, HWND__},
+};
 use winapi::um::winuser;
 
 use crate::winapi_functions::{
-    add_clipboard_format_listener, create_window_ex_a, is_clipboard_format_available,
-    register_class_ex_a, register_clipboard_format, register_hotkey,
-    remove_clipboard_format_listener, unregister_hotkey,
+    add_clipboard_format_listener, create_window_ex_a, get_class_name,
+    get_clipboard_sequence_number, get_foreground_window, get_monitor_device_name,
+    is_clipboard_format_available, is_interactive_window_station, kill_timer, local_minute_of_day,
+    monitor_from_window, post_message_a, register_class_ex_a, register_clipboard_format,
+    remove_clipboard_format_listener, send_message_w, set_console_ctrl_handler,
+    set_foreground_event_hook, set_timer, set_window_long_ptr_a, shell_execute_open,
+    unhook_win_event, SystemError,
 };
 
-use clipboard_win::{formats, Clipboard, EnumFormats, Getter};
+use crossbeam::channel::Receiver;
+use log::{debug, error, info, warn};
 
-use crate::clipboard_extras::{set_all, ClipboardItem};
-use crate::key_utils::trigger_keys;
+use crate::about;
+use crate::apps::{clipboard_owner_app, is_remote_clipboard, matches_app, window_app};
+use crate::audit::{self, Action};
+use crate::backend::{ClipboardBackend, WindowsClipboard};
+use crate::clipboard_extras::{
+    limit_size, plain_text, read_text, write_hdrop, write_unicode_text, ClipboardItem,
+};
+use crate::config::{ConfigError, Order, PasteMode, RemoteClipboard};
+use crate::config_watch::{self, WM_CONFIG_CHANGED};
+use crate::delayed_rendering;
+use crate::engine::{compare_data, Capture, ClipboardEngine, ComparisonResult};
+use crate::entry::{Entry, DEFAULT_EXPIRY};
+use crate::events::{self, Event, EventBus, SkipReason};
+use crate::export;
+use crate::file_export::save_entry;
+use crate::format_usage::{self, FormatUsage};
+use crate::formats::{self, FormatFilter};
+use crate::history;
+use crate::hotkey::{Hotkey, HotkeyAction, HotkeyRegistry};
+use crate::html;
+use crate::ipc::{
+    self, BatchOperation, PendingRequest, Request, Response, WM_IPC_ERROR, WM_IPC_REQUEST,
+};
+use crate::jump_list::{self, JumpList};
+use crate::key_utils::{
+    is_key_pressed, key_held, send_paste, trigger_keys, trigger_keys_fallback, type_text, PasteKeys,
+};
+use crate::monitors::MonitorStacks;
+use crate::persistence::{FlushStrategy, Store};
+use crate::picker::{self, Choice};
+use crate::provenance::{self, Provenance};
+use crate::restore_check::{restore_items, RestoreCheck, RestoreOptions};
+use crate::search::{self, SearchIndex};
+use crate::settings::Settings;
+use crate::shared_view::SharedView;
+use crate::snippets::{self, Snippets};
+use crate::source_document;
+use crate::stacks::{self, Stack, DEFAULT_STACK};
+use crate::status::Health;
+use crate::storage;
+use crate::stream_deck::{self, StreamDeck};
+use crate::telemetry::{self, Telemetry};
+use crate::tray::{self, TrayCommand, TrayIcon, WM_TRAY_CALLBACK};
+use crate::usage::{self, UsageStats};
+use crate::watchdog::{self, ModifierWatchdog};
 
 pub type MessageType = u32;
 pub type WParam = usize;
 pub type LParam = isize;
 
 const MAX_RETRIES: u8 = 10;
-const SIMILARITY_THRESHOLD: u8 = 230;
+const PERSIST_TIMER_ID: usize = 1;
+const RESTORE_RETRY_TIMER_ID: usize = 2;
+const EXPIRY_TIMER_ID: usize = 3;
+const AUTO_GROW_TIMER_ID: usize = 4;
+const CONFIG_RELOAD_TIMER_ID: usize = 5;
+const MAX_AGE_TIMER_ID: usize = 6;
+const QUIET_HOURS_TIMER_ID: usize = 7;
+const TAP_TIMER_ID: usize = 8;
+const WATCHDOG_TIMER_ID: usize = 9;
+const DEBOUNCE_TIMER_ID: usize = 10;
+/// How long to wait after the config file changes before reading it, so a save is finished
+const CONFIG_RELOAD_DELAY_MS: u32 = 250;
+/// How long the history limit stays raised after it last grew
+const AUTO_GROW_HOLD_MS: u32 = 10 * 60 * 1000;
+/// How often to look for items older than max_age, at most
+const MAX_AGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Quiet hours are given to the minute, so this is often enough to start and end them on time
+const QUIET_HOURS_CHECK_INTERVAL_MS: u32 = 15 * 1000;
+/// How often to look at the tap key while it's held
+const TAP_CHECK_INTERVAL_MS: u32 = 30;
+/// How often the modifiers are checked after sending keys failed
+const WATCHDOG_INTERVAL_MS: u32 = 250;
+/// Holding the tap key this long opens the picker rather than pasting
+const LONG_PRESS: Duration = Duration::from_millis(500);
+/// Shown in place of the next item during quiet hours
+const QUIET_PREVIEW: &str = "(hidden during quiet hours)";
+/// The most the history limit is raised to, as a multiple of max_history
+const AUTO_GROW_FACTOR: usize = 4;
+/// How many times the history has to run out after dropping items before suggesting a larger limit
+const EXHAUSTION_WARNING_COUNT: u32 = 3;
+const RESTORE_RETRY_INTERVAL_MS: u32 = 1000;
+const MAX_RESTORE_RETRIES: u8 = 5;
+const LIST_SUMMARY_LENGTH: usize = 100;
+/// Jump list titles are cut short by the shell well before this
+const JUMP_LIST_TITLE_LENGTH: usize = 60;
+/// How much of an item's text names a snippet saved from the picker
+const SNIPPET_NAME_LENGTH: usize = 32;
+/// How many popped items can be put back
+const MAX_UNDO: usize = 20;
+/// The most characters typed for one paste with the sendinput-text paste mode, as typing a large
+/// item would take minutes and couldn't be stopped
+const MAX_TYPED_LENGTH: usize = 10_000;
+/// Sent by the console control handler to shut down from the event loop's thread
+const WM_SHUTDOWN: u32 = winuser::WM_APP + 5;
+/// Posted to open the picker once the IPC request asking for it has been answered
+const WM_OPEN_PICKER: u32 = winuser::WM_APP + 8;
+/// Posted when another window comes to the foreground
+const WM_FOREGROUND_CHANGED: u32 = winuser::WM_APP + 9;
+
+/// The window running the event loop, for the console control handler, or 0 when there isn't one
+static CONSOLE_TARGET: AtomicUsize = AtomicUsize::new(0);
+/// The window told about foreground changes, or 0 when there isn't one
+static FOREGROUND_TARGET: AtomicUsize = AtomicUsize::new(0);
 
-#[derive(Debug, PartialEq)]
-enum ComparisonResult {
-    Same,
-    Similar,
-    Different,
+/// The filter for the formats `settings` asks to record
+fn format_filter(settings: &Settings) -> Result<FormatFilter, String> {
+    FormatFilter::new(
+        settings.capture_formats.as_ref(),
+        settings.ignore_formats.as_ref(),
+    )
 }
 
-fn compare_data(
-    cb_data: &[ClipboardItem],
-    prev_cb_data: &[ClipboardItem],
-    threshold: u8,
-) -> ComparisonResult {
-    match (cb_data.len(), prev_cb_data.len()) {
-        (0, 0) => ComparisonResult::Same,
-        (0, _) | (_, 0) => ComparisonResult::Different,
-        _ => {
-            let count_eq = cb_data
-                .iter()
-                .filter(
-                    |x| match prev_cb_data.iter().find(|y| x.format == y.format) {
-                        Some(y) => **x == *y,
-                        None => false,
-                    },
-                )
-                .count();
-
-            let max_eq = *[cb_data.len(), prev_cb_data.len()].iter().max().unwrap();
-
-            if count_eq == max_eq {
-                ComparisonResult::Same
-            } else if count_eq * 255 >= max_eq * threshold as usize {
-                ComparisonResult::Similar
-            } else {
-                ComparisonResult::Different
+/// Register the hotkeys `settings` gives. The others are optional, so any that another program has are
+/// logged and skipped, and only an error registering the paste hotkey is returned
+fn register_hotkeys(
+    h_wnd: &mut HWND__,
+    hotkeys: &mut HotkeyRegistry,
+    settings: &Settings,
+) -> Result<(), SystemError> {
+    let result = hotkeys.register(h_wnd, HotkeyAction::Paste, settings.hotkey);
+    for (action, hotkey) in &[
+        (HotkeyAction::Peek, settings.peek_hotkey),
+        (HotkeyAction::Picker, settings.picker_hotkey),
+        (HotkeyAction::PlainPaste, settings.plain_paste_hotkey),
+        (HotkeyAction::UndoPop, settings.undo_hotkey),
+        (HotkeyAction::Next, settings.next_hotkey),
+        (HotkeyAction::Previous, settings.previous_hotkey),
+        (HotkeyAction::Pinned, settings.pinned_hotkey),
+        (HotkeyAction::Search, settings.search_hotkey),
+        (HotkeyAction::Snippet, settings.snippet_hotkey),
+        (HotkeyAction::NextStack, settings.stack_hotkey),
+        (HotkeyAction::Queue, settings.queue_hotkey),
+    ] {
+        if let Err(error) = hotkeys.register(h_wnd, *action, *hotkey) {
+            warn!("Could not register {}: {}", hotkey, error);
+        }
+    }
+    if let Some(tap_key) = settings.tap_key {
+        if let Err(error) = hotkeys.register(h_wnd, HotkeyAction::Tap, tap_key.as_hotkey()) {
+            warn!("Could not register the tap key {}: {}", tap_key, error);
+        }
+    }
+    if let Some(hotkey) = settings.recapture_hotkey {
+        if let Err(error) = hotkeys.register(h_wnd, HotkeyAction::Recapture, hotkey) {
+            warn!("Could not register {}: {}", hotkey, error);
+        }
+    }
+    result
+}
+
+thread_local! {
+    /// Whether a message is being handled. Modal loops inside a handler, such as the tray menu's,
+    /// dispatch further messages which have to wait until it's finished
+    static DISPATCHING: Cell<bool> = const { Cell::new(false) };
+    static DEFERRED: RefCell<VecDeque<(MessageType, WParam, LParam)>> = const { RefCell::new(VecDeque::new()) };
+}
+
+/// Pass messages for the message window to the `Window` running the event loop, queueing any which
+/// arrive while another is being handled
+unsafe extern "system" fn window_proc(
+    h_wnd: HWND,
+    msg: MessageType,
+    w_param: WParam,
+    l_param: LParam,
+) -> LRESULT {
+    let window = winuser::GetWindowLongPtrA(h_wnd, winuser::GWLP_USERDATA) as *mut Window;
+    if window.is_null() {
+        return winuser::DefWindowProcA(h_wnd, msg, w_param, l_param);
+    }
+    if DISPATCHING.with(Cell::get) {
+        return match msg {
+            winuser::WM_QUERYENDSESSION => 1,
+            // The process can end as soon as these return, so they can't wait for the handler
+            // stuck in a modal loop
+            winuser::WM_ENDSESSION | WM_SHUTDOWN => {
+                (*window).handle_message(msg, w_param, l_param);
+                0
+            }
+            winuser::WM_RENDERFORMAT
+            | winuser::WM_RENDERALLFORMATS
+            | winuser::WM_DESTROYCLIPBOARD => {
+                delayed_rendering::handle_message(&mut *h_wnd, msg, w_param).unwrap_or(0)
             }
+            winuser::WM_CLIPBOARDUPDATE
+            | winuser::WM_HOTKEY
+            | winuser::WM_TIMER
+            | WM_CONFIG_CHANGED
+            | WM_TRAY_CALLBACK
+            | WM_OPEN_PICKER
+            | WM_FOREGROUND_CHANGED
+            | WM_IPC_REQUEST
+            | WM_IPC_ERROR => {
+                DEFERRED.with(|deferred| {
+                    let mut deferred = deferred.borrow_mut();
+                    // Timers keep firing while a menu is open, but only need handling once
+                    if msg != winuser::WM_TIMER || !deferred.contains(&(msg, w_param, l_param)) {
+                        deferred.push_back((msg, w_param, l_param));
+                    }
+                });
+                0
+            }
+            _ => winuser::DefWindowProcA(h_wnd, msg, w_param, l_param),
+        };
+    }
+
+    DISPATCHING.with(|dispatching| dispatching.set(true));
+    let window = &mut *window;
+    let result = window.handle_message(msg, w_param, l_param);
+    while let Some((msg, w_param, l_param)) =
+        DEFERRED.with(|deferred| deferred.borrow_mut().pop_front())
+    {
+        window.handle_message(msg, w_param, l_param);
+    }
+    DISPATCHING.with(|dispatching| dispatching.set(false));
+    result.unwrap_or_else(|| winuser::DefWindowProcA(h_wnd, msg, w_param, l_param))
+}
+
+/// Shut down cleanly when Ctrl+C is pressed, the console is closed or the session ends. This runs
+/// on a thread of its own, and the process may be ended as soon as it returns
+unsafe extern "system" fn console_ctrl_handler(_ctrl_type: DWORD) -> BOOL {
+    match (CONSOLE_TARGET.load(Ordering::SeqCst) as *mut HWND__).as_mut() {
+        Some(h_wnd) => {
+            // Sending waits until the event loop's thread has shut down
+            send_message_w(h_wnd, WM_SHUTDOWN, 0, 0);
+            TRUE
         }
+        None => FALSE,
     }
 }
 
-#[cfg(debug_assertions)]
-fn get_cb_text(cb_data: &[ClipboardItem]) -> String {
-    cb_data
-        .iter()
-        .find(|item| item.format == winuser::CF_TEXT)
-        .map(|res| String::from_utf8(res.content.clone()).unwrap_or_default())
-        .unwrap_or_default()
+/// Pass on a change of foreground window to the window running the event loop. This runs on the
+/// event loop's thread while it waits for messages, so it only posts a message
+unsafe extern "system" fn foreground_changed(
+    _hook: HWINEVENTHOOK,
+    _event: DWORD,
+    _h_wnd: HWND,
+    _object: LONG,
+    _child: LONG,
+    _thread: DWORD,
+    _time: DWORD,
+) {
+    if let Some(h_wnd) = (FOREGROUND_TARGET.load(Ordering::SeqCst) as *mut HWND__).as_mut() {
+        let _ = post_message_a(h_wnd, WM_FOREGROUND_CHANGED, 0, 0);
+    }
 }
 
 pub struct Window<'a> {
     h_wnd: &'a mut winapi::shared::windef::HWND__,
-    cb_history: VecDeque<Vec<ClipboardItem>>,
-    last_internal_update: Option<Vec<ClipboardItem>>,
-    skip_clipboard: bool,
-    max_history: usize,
+    cb_history: VecDeque<Entry>,
+    last_internal_update: Option<Entry>,
+    /// Popped items which can be put back, most recent last
+    popped: Vec<Entry>,
+    /// Items kept apart from the history, so they're never trimmed or popped
+    pinned: Vec<Entry>,
+    /// The pinned item the pinned hotkey pastes next
+    pinned_cursor: usize,
+    /// Items saved under a name, kept in their own file
+    snippets: Snippets,
+    /// The stack `cb_history` holds, and the other stacks which have been used since startup
+    stack_name: String,
+    stacks: BTreeMap<String, Stack>,
+    /// The clipboard sequence number after our last write, so updates we caused can be told apart
+    /// from other programs' even when they arrive in between
+    own_sequence: Option<u32>,
+    /// The sequence number of the last clipboard contents we looked at or wrote
+    seen_sequence: u32,
+    clipboard: WindowsClipboard,
     ignore_format_id: Option<u32>,
+    provenance_format_id: Option<u32>,
+    html_format_id: Option<u32>,
+    /// The formats Office names the source document in
+    link_format_ids: Vec<u32>,
+    /// The formats given to `safe_restore_apps`
+    safe_format_ids: Vec<u32>,
+    format_filter: FormatFilter,
+    settings: Settings,
+    hotkeys: HotkeyRegistry,
+    ipc_requests: Receiver<PendingRequest>,
+    events: EventBus,
+    paused: bool,
+    /// Whether it's currently one of the quiet hours
+    quiet: bool,
+    /// Why the clipboard can't be used, if it can't, in which case only IPC requests which don't
+    /// paste are handled
+    degraded: Option<String>,
+    /// Counts changes to the history, so a batch can check nothing changed since it was planned
+    revision: u64,
+    running: bool,
+    /// Whether the history has been saved and the listeners removed, ahead of exiting
+    shut_down: bool,
+    store: Option<Store>,
+    /// The previews published for companion tools, if shared_view is set
+    shared_view: Option<SharedView>,
+    /// The most recent items offered from the taskbar, if jump_list is set
+    jump_list: Option<JumpList>,
+    /// The text of each item, kept between searches
+    search_index: SearchIndex,
+    restore_retries: u8,
+    /// How far above max_history the limit has been raised by auto_grow
+    extra_history: usize,
+    /// Whether items have been dropped for being beyond the limit since the history last ran out
+    dropped_since_empty: bool,
+    /// How many times the history has been popped empty after dropping items
+    exhaustions: u32,
+    /// When the tap key was pressed, while it's still being timed
+    tap_started: Option<Instant>,
+    started: Instant,
+    /// Releases modifiers left down after sending keys failed
+    watchdog: ModifierWatchdog,
+    /// Which stack each monitor uses, with `monitor_stacks`
+    monitor_stacks: MonitorStacks,
+    /// How many times the clipboard changed but couldn't be read
+    capture_errors: u64,
+    usage: UsageStats,
+    /// Which large formats programs have read when pasting
+    format_usage: FormatUsage,
+    /// Counts of feature use and errors, if telemetry is set
+    telemetry: Option<Telemetry>,
 }
 
 impl Window<'_> {
-    pub fn new(max_history: usize) -> Self {
+    /// Create the message window and start listening. Fails if the paste hotkey can't be registered
+    pub fn new(settings: Settings) -> Result<Self, String> {
         //http://www.clipboardextender.com/developing-clipboard-aware-programs-for-windows/ignoring-clipboard-updates-with-the-cf_clipboard_viewer_ignore-clipboard-format
         let ignore_format_id = match register_clipboard_format("Clipboard Viewer Ignore") {
             Ok(format_id) => Some(format_id),
             Err(_) => {
-                println!("Failed to register ignore format. This shouldn't cause a problem as it's only used in very specific clipboard programs");
+                warn!("Failed to register ignore format. This shouldn't cause a problem as it's only used in very specific clipboard programs");
                 None
             }
         };
 
+        let provenance_format_id = register_clipboard_format(provenance::FORMAT_NAME).ok();
+        let html_format_id = register_clipboard_format(html::FORMAT_NAME).ok();
+        let link_format_ids: Vec<_> = source_document::LINK_FORMAT_NAMES
+            .iter()
+            .filter_map(|name| register_clipboard_format(name).ok())
+            .collect();
+        let format_filter = format_filter(&settings).unwrap_or_else(|error| {
+            warn!("Recording every format, as {}", error);
+            FormatFilter::default()
+        });
+
         // Create and register a class
         let class_name = "filo-clipboard_class";
         let window_name = "filo-clipboard";
@@ -95,7 +381,7 @@ impl Window<'_> {
         let class_name_c_string = CString::new(class_name).unwrap();
         let lp_wnd_class = winuser::WNDCLASSEXA {
             cbSize: mem::size_of::<winuser::WNDCLASSEXA>() as u32,
-            lpfnWndProc: Some(winuser::DefWindowProcA),
+            lpfnWndProc: Some(window_proc),
             hInstance: ptr::null_mut(),
             lpszClassName: class_name_c_string.as_ptr(),
             style: 0,
@@ -110,213 +396,2985 @@ impl Window<'_> {
 
         register_class_ex_a(&lp_wnd_class).unwrap();
 
-        // Create the message window
+        // Create the message window. It's a hidden top-level window rather than a message-only one,
+        // as only top-level windows are told when the session ends
         let h_wnd = create_window_ex_a(
-            winuser::WS_EX_LEFT,
+            winuser::WS_EX_TOOLWINDOW,
             class_name,
             window_name,
+            winuser::WS_POPUP,
             0,
             0,
             0,
             0,
-            0,
-            unsafe { &mut *winuser::HWND_MESSAGE },
+            None,
             None,
             None,
             None,
         )
         .unwrap();
 
-        // Register the clipboard listener to the message window
-        add_clipboard_format_listener(h_wnd).unwrap();
+        // Services and programs started over SSH have no clipboard or keyboard to listen to, so
+        // rather than exiting they only answer IPC requests which don't need them
+        let degraded = match is_interactive_window_station() {
+            Ok(false) => Some("this session has no interactive desktop".to_owned()),
+            _ => add_clipboard_format_listener(h_wnd)
+                .err()
+                .map(|error| format!("the clipboard can't be watched: {}", error)),
+        };
+        if let Some(reason) = &degraded {
+            warn!(
+                "Running without the clipboard, as {}. Only IPC requests which don't paste will work",
+                reason
+            );
+        }
 
         // Register the hotkey listener to the message window
-        register_hotkey(
-            h_wnd,
-            1,
-            (winuser::MOD_CONTROL | winuser::MOD_SHIFT) as u32,
-            'V' as u32,
-        )
-        .expect("Could not register hotkey. Is an instance already running?");
+        let mut hotkeys = HotkeyRegistry::default();
+        if degraded.is_none() {
+            if let Err(error) = register_hotkeys(h_wnd, &mut hotkeys, &settings) {
+                return Err(format!(
+                    "Could not register {}: {}. Is another program using it? Choose a different one with --hotkey",
+                    settings.hotkey, error
+                ));
+            }
+        }
+
+        // Large items are only copied onto the clipboard when they're pasted
+        if let Err(error) = delayed_rendering::start() {
+            warn!("Could not start delayed rendering: {}", error);
+        }
+
+        // Listen for commands from other instances
+        let ipc_requests = ipc::spawn_server(h_wnd);
+
+        // Apply changes to the config file as it's edited
+        if let Some(path) = settings.config_path.clone() {
+            config_watch::spawn(h_wnd, path);
+        }
+
+        let mut events = EventBus::default();
+        events.subscribe(events::log_event);
+
+        // Show the tray icon. It's kept up to date by the events, so the bus owns it
+        let paused = settings.start_paused;
+        match TrayIcon::new(h_wnd, paused || !settings.profile.capture) {
+            Ok(mut tray) => events.subscribe(move |event| tray.handle_event(event)),
+            Err(error) => warn!("Could not create tray icon: {}", error),
+        }
+
+        // Let Stream Deck plugins paste and clear, and show the front items on their keys
+        match (settings.stream_deck_port, settings.config_path.as_deref()) {
+            (Some(port), Some(config_path)) => {
+                match StreamDeck::spawn(port, &stream_deck::token_path(config_path)) {
+                    Ok(mut deck) => events.subscribe(move |event| deck.handle_event(event)),
+                    Err(error) => warn!("Could not listen for Stream Deck plugins: {}", error),
+                }
+            }
+            (Some(_), None) => warn!(
+                "Not listening for Stream Deck plugins, as there's no config file to put their token next to"
+            ),
+            (None, _) => {}
+        }
+
+        let shared_view = if settings.shared_view {
+            SharedView::create()
+                .map_err(|error| warn!("{}", error))
+                .ok()
+        } else {
+            None
+        };
+
+        let jump_list = if settings.jump_list {
+            JumpList::create()
+                .map_err(|error| warn!("Could not set up the jump list: {}", error))
+                .ok()
+        } else {
+            None
+        };
+
+        // Reload the history from the last run
+        let mut store = settings.persist.as_deref().and_then(|path| {
+            match storage::open(settings.storage, path, settings.encrypt) {
+                Ok(storage) => Some(Store::new(storage, settings.flush)),
+                Err(error) => {
+                    error!("Could not open the history {}: {}", settings.storage, error);
+                    None
+                }
+            }
+        });
+        let history = match &mut store {
+            Some(store) => store.load().unwrap_or_else(|error| {
+                error!("Could not load history: {}", error);
+                VecDeque::new()
+            }),
+            None => VecDeque::new(),
+        };
+        let (pinned, mut cb_history): (VecDeque<_>, VecDeque<_>) =
+            history.into_iter().partition(|entry| entry.pinned);
+        // Make room for as many items as usually get copied, so the first burst doesn't reallocate
+        let usage = settings
+            .config_path
+            .as_deref()
+            .map(|path| UsageStats::load(&usage::stats_path(path)))
+            .unwrap_or_default();
+        let format_usage = settings
+            .config_path
+            .as_deref()
+            .map(|path| FormatUsage::load(&format_usage::usage_path(path)))
+            .unwrap_or_default();
+        let snippets = settings
+            .config_path
+            .as_deref()
+            .map(|path| {
+                Snippets::load(&snippets::snippets_path(path)).unwrap_or_else(|error| {
+                    error!("Could not load snippets: {}", error);
+                    Snippets::default()
+                })
+            })
+            .unwrap_or_default();
+        let telemetry = settings
+            .config_path
+            .as_deref()
+            .filter(|_| settings.telemetry)
+            .map(|path| Telemetry::load(&telemetry::telemetry_path(path)));
+        let capacity = usage.capacity(settings.profile.max_history, settings.max_total_memory);
+        cb_history.reserve(capacity.saturating_sub(cb_history.len()));
+        if let Some(FlushStrategy::Interval(interval)) = store.as_ref().map(Store::strategy) {
+            set_timer(h_wnd, PERSIST_TIMER_ID, interval.as_millis() as u32).unwrap();
+        }
 
-        Self {
+        let mut window = Self {
             h_wnd,
-            cb_history: VecDeque::new(),
+            cb_history,
             last_internal_update: None,
-            skip_clipboard: false,
-            max_history,
+            popped: Vec::new(),
+            pinned: pinned.into(),
+            pinned_cursor: 0,
+            snippets,
+            stack_name: DEFAULT_STACK.to_owned(),
+            stacks: BTreeMap::new(),
+            own_sequence: None,
+            seen_sequence: get_clipboard_sequence_number(),
+            clipboard: WindowsClipboard,
             ignore_format_id,
+            provenance_format_id,
+            html_format_id,
+            link_format_ids,
+            safe_format_ids: formats::safe_formats(),
+            format_filter,
+            settings,
+            hotkeys,
+            ipc_requests,
+            events,
+            paused,
+            quiet: false,
+            degraded,
+            revision: 0,
+            running: true,
+            shut_down: false,
+            store,
+            shared_view,
+            jump_list,
+            search_index: SearchIndex::default(),
+            restore_retries: 0,
+            extra_history: 0,
+            dropped_since_empty: false,
+            exhaustions: 0,
+            tap_started: None,
+            started: Instant::now(),
+            watchdog: ModifierWatchdog::default(),
+            monitor_stacks: MonitorStacks::default(),
+            capture_errors: 0,
+            usage,
+            format_usage,
+            telemetry,
+        };
+        // The history file doesn't keep where entries came from, but it can be worked out again
+        for entry in window.cb_history.iter_mut() {
+            entry.source_document =
+                source_document::document_path(&entry.items, &window.link_format_ids);
+        }
+        window.trim_history();
+        window.schedule_max_age();
+        window.schedule_quiet_hours();
+        window.publish_shared_view();
+        window.publish_jump_list();
+        if !window.cb_history.is_empty() {
+            window.emit(Event::HistoryLoaded {
+                depth: window.cb_history.len(),
+                preview: window.next_preview(),
+            });
         }
+        Ok(window)
     }
 
-    pub fn run_event_loop(&mut self) {
-        let mut lp_msg = winuser::MSG::default();
-        #[cfg(debug_assertions)]
-        println!("Ready");
-        while unsafe { winuser::GetMessageA(&mut lp_msg, self.h_wnd, 0, 0) != 0 } {
-            match lp_msg.message {
-                winuser::WM_CLIPBOARDUPDATE => {
-                    if !self.skip_clipboard
-                        && !self
-                            .ignore_format_id
-                            .map(is_clipboard_format_available)
-                            .unwrap_or(false)
-                    {
-                        self.handle_clipboard();
-                    }
-                    self.skip_clipboard = false;
-                }
-                winuser::WM_HOTKEY => {
-                    if lp_msg.wParam == 1 {
-                        self.handle_ctrl_shift_v();
-                    }
+    /// Pass an event to the subscribers, saving the history first if it changed
+    fn emit(&mut self, event: Event) {
+        if let Some(telemetry) = self.telemetry.as_mut() {
+            telemetry.record_event(&event);
+        }
+        if event.changes_history() {
+            self.revision += 1;
+            // Pinned items are shared by every stack, so they're saved with the default one
+            let pinned: &[Entry] = if self.stack_name == DEFAULT_STACK {
+                &self.pinned
+            } else {
+                &[]
+            };
+            if let Some(store) = self.store.as_mut() {
+                if let Err(error) = store.changed(&self.cb_history, pinned) {
+                    self.events.emit(Event::Error {
+                        message: format!("Could not save history: {}", error),
+                    });
                 }
-                _ => {}
             }
         }
+        // Quiet hours hide the previews without changing the history
+        if event.changes_history() || matches!(event, Event::QuietHoursChanged { .. }) {
+            self.publish_shared_view();
+            self.publish_jump_list();
+        }
+        self.events.emit(event);
     }
 
-    fn handle_clipboard(&mut self) {
-        if let Ok(_clip) = Clipboard::new_attempts(10) {
-            let cb_data: Vec<_> = EnumFormats::new()
-                .filter_map(|format| {
-                    let mut clipboard_data = Vec::new();
-                    if let Ok(bytes) = formats::RawData(format).read_clipboard(&mut clipboard_data)
-                    {
-                        if bytes != 0 {
-                            return Some(ClipboardItem {
-                                format,
-                                content: clipboard_data,
-                            });
-                        }
-                    }
-                    None
+    /// Update the previews in shared memory, if they're published
+    fn publish_shared_view(&mut self) {
+        if self.shared_view.is_some() {
+            let list = self.list();
+            if let Some(shared_view) = self.shared_view.as_mut() {
+                shared_view.publish(self.revision, &list);
+            }
+        }
+    }
+
+    /// Offer the most recent items with text in the jump list, if it's shown. It's emptied during
+    /// quiet hours, and burn after reading items are left out as they're likely secrets
+    fn publish_jump_list(&mut self) {
+        let tasks = if self.quiet {
+            Vec::new()
+        } else {
+            jump_list::tasks(
+                self.cb_history
+                    .iter()
+                    .filter(|entry| !entry.burn && !entry.text().trim().is_empty())
+                    .map(|entry| (entry.fingerprint(), entry.summary(JUMP_LIST_TITLE_LENGTH))),
+            )
+        };
+        if let Some(jump_list) = self.jump_list.as_mut() {
+            if let Err(error) = jump_list.update(tasks) {
+                warn!("{}", error);
+            }
+        }
+    }
+
+    /// Write the history to disk if it has changed since it was last written
+    fn flush_history(&mut self) {
+        let pinned: &[Entry] = if self.stack_name == DEFAULT_STACK {
+            &self.pinned
+        } else {
+            &[]
+        };
+        if let Some(store) = self.store.as_mut() {
+            if let Err(error) = store.flush(&self.cb_history, pinned) {
+                self.events.emit(Event::Error {
+                    message: format!("Could not save history: {}", error),
+                });
+            }
+        }
+    }
+
+    /// Call `subscriber` whenever the history changes or an update is skipped
+    pub fn subscribe(&mut self, subscriber: impl FnMut(&Event) + 'static) {
+        self.events.subscribe(subscriber);
+    }
+
+    /// The text preview of the item which will be pasted next, hidden during quiet hours
+    fn next_preview(&self) -> String {
+        match self.cb_history.get(self.next_index()) {
+            Some(_) if self.quiet => QUIET_PREVIEW.to_owned(),
+            Some(entry) => entry.preview(),
+            None => String::new(),
+        }
+    }
+
+    /// A short summary of `entry` for listing, hidden during quiet hours
+    fn list_summary(&self, entry: &Entry) -> String {
+        if self.quiet {
+            QUIET_PREVIEW.to_owned()
+        } else {
+            entry.summary(LIST_SUMMARY_LENGTH)
+        }
+    }
+
+    /// A line of the `list` reply: the index, summary and source document, separated by tabs
+    fn list_line(&self, index: String, entry: &Entry) -> String {
+        match &entry.source_document {
+            Some(document) if !self.quiet => {
+                format!("{}\t{}\t{}", index, self.list_summary(entry), document)
+            }
+            _ => format!("{}\t{}", index, self.list_summary(entry)),
+        }
+    }
+
+    /// The position in the history of the item numbered `index` by `list` and the commands which
+    /// take an item number, or the number of the item at position `index`
+    fn position(&self, index: usize) -> usize {
+        self.settings
+            .profile
+            .index_from
+            .position(index, self.cb_history.len())
+    }
+
+    /// The history in the order `list` numbers it
+    fn numbered(&self) -> impl Iterator<Item = &Entry> {
+        (0..self.cb_history.len()).map(move |index| &self.cb_history[self.position(index)])
+    }
+
+    /// The reply to `list`: a line for each item in the history, followed by the pinned items
+    fn list(&self) -> String {
+        self.numbered()
+            .enumerate()
+            .map(|(index, entry)| self.list_line(index.to_string(), entry))
+            .chain(
+                self.pinned
+                    .iter()
+                    .enumerate()
+                    .map(|(index, entry)| self.list_line(format!("pinned {}", index), entry)),
+            )
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Add `text` to the front of the history as though it had been copied, and put it on the
+    /// clipboard
+    pub fn push_text(&mut self, text: &str) {
+        let mut entry = Entry::new(vec![write_unicode_text(text)]);
+        entry.copied_at = Some(provenance::unix_time());
+        self.usage.record_copy(entry.size());
+        self.cb_history.push_front(entry);
+        self.trim_history();
+        self.usage.record_depth(self.cb_history.len());
+        if !self.settings.lazy_restore && !self.restore_front() {
+            self.emit(Event::Error {
+                message: "Could not put the pushed item on the clipboard".to_owned(),
+            });
+        }
+        self.emit(Event::EntryPushed {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+    }
+
+    /// Add the entries in JSON written by `export::to_json` to the front of the history, keeping
+    /// their order, and pinned entries to the pinned items. Returns how many there were
+    fn import_json(&mut self, json: &str) -> Result<usize, String> {
+        let resolve = |name: &str| {
+            formats::resolve(name).and_then(|ids| {
+                ids.first()
+                    .copied()
+                    .ok_or_else(|| format!("unknown format \"{}\"", name))
+            })
+        };
+        let entries = export::from_json(json, resolve)?;
+        let count = entries.len();
+        let (pinned, history): (Vec<_>, Vec<_>) =
+            entries.into_iter().partition(|entry| entry.pinned);
+        for entry in history.into_iter().rev() {
+            self.usage.record_copy(entry.size());
+            self.cb_history.push_front(entry);
+        }
+        self.pinned.extend(pinned);
+        self.trim_history();
+        self.usage.record_depth(self.cb_history.len());
+        if !self.settings.lazy_restore && !self.restore_front() {
+            self.emit(Event::Error {
+                message: "Could not put the imported items on the clipboard".to_owned(),
+            });
+        }
+        self.emit(Event::EntryPushed {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+        Ok(count)
+    }
+
+    /// Remove every item from the history. Pinned items are kept
+    pub fn clear_history(&mut self) {
+        self.cb_history.clear();
+        self.last_internal_update = None;
+        self.popped.clear();
+        self.emit(Event::HistoryCleared);
+    }
+
+    /// Remove duplicate, blank and superseded items from the history, returning what was reclaimed.
+    /// Pinned items are left alone
+    fn compact_history(&mut self) -> String {
+        let before = self.next_fingerprint();
+        let engine = ClipboardEngine::new(
+            self.settings.profile.similarity_threshold,
+            self.settings.dedup,
+        );
+        let compaction = engine.compact(&mut self.cb_history);
+        if compaction.removed() == 0 {
+            return "Nothing to compact".to_owned();
+        }
+        if self.next_fingerprint() != before && !self.settings.lazy_restore && !self.restore_front()
+        {
+            self.emit(Event::Error {
+                message: "Could not put the next item on the clipboard".to_owned(),
+            });
+        }
+        self.emit(Event::HistoryEdited {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+        format!(
+            "Removed {} duplicate{}, {} blank item{} and {} older cop{}, reclaiming {} bytes",
+            compaction.duplicates,
+            if compaction.duplicates == 1 { "" } else { "s" },
+            compaction.blank,
+            if compaction.blank == 1 { "" } else { "s" },
+            compaction.revisions,
+            if compaction.revisions == 1 {
+                "y"
+            } else {
+                "ies"
+            },
+            compaction.bytes
+        )
+    }
+
+    /// Append a record of an item being used to the audit log, if there is one. The program is
+    /// the one in the foreground, which is where a paste goes
+    fn audit(&mut self, action: Action, fingerprint: u64, pasted: bool) {
+        let path = match &self.settings.audit_log {
+            Some(path) => path,
+            None => return,
+        };
+        let app = if pasted {
+            get_foreground_window()
+                .and_then(window_app)
+                .and_then(|path| {
+                    path.file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
                 })
-                .collect();
-
-            if !cb_data.is_empty() {
-                let (prev_item_similarity, current_item_similarity) = crossbeam::scope(|scope| {
-                    //If let chains would do this far more neatly
-                    let prev_item_similarity_handle = scope.spawn(|_| {
-                        self.last_internal_update
-                            .as_ref()
-                            .map(|last_update| {
-                                compare_data(&cb_data, last_update, SIMILARITY_THRESHOLD)
-                            })
-                            .unwrap_or(ComparisonResult::Different)
-                    });
-                    let current_item_similarity_handle = scope.spawn(|_| {
-                        self.cb_history
-                            .front()
-                            .map(|last_update| {
-                                compare_data(&cb_data, last_update, SIMILARITY_THRESHOLD)
-                            })
-                            .unwrap_or(ComparisonResult::Different)
-                    });
+        } else {
+            None
+        };
+        if let Err(error) = audit::append(path, &audit::Record::now(action, app, fingerprint)) {
+            self.emit(Event::Error {
+                message: format!("Could not write to the audit log: {}", error),
+            });
+        }
+    }
+
+    /// Drop the oldest items beyond the profile's maximum, then while the history takes up more
+    /// memory than allowed. The front item is always kept. With `auto_grow` the maximum is raised
+    /// for a while instead, up to `AUTO_GROW_FACTOR` times the profile's
+    fn trim_history(&mut self) {
+        let max_history = self.max_history();
+        if self.settings.auto_grow && self.cb_history.len() > max_history + self.extra_history {
+            let ceiling = (max_history * AUTO_GROW_FACTOR)
+                .min(self.settings.policy.max_history.unwrap_or(usize::MAX));
+            let grown = self.cb_history.len().min(ceiling).max(max_history);
+            if grown > max_history + self.extra_history {
+                self.extra_history = grown - max_history;
+                // Setting the timer again restarts it, so the limit stays up while copying continues
+                if let Err(error) = set_timer(self.h_wnd, AUTO_GROW_TIMER_ID, AUTO_GROW_HOLD_MS) {
+                    warn!("Could not schedule shrinking the history: {}", error);
+                }
+            }
+        }
+        if self.cb_history.len() > max_history + self.extra_history {
+            self.cb_history.truncate(max_history + self.extra_history);
+            self.dropped_since_empty = true;
+        }
+        if let Some(max_total_memory) = self.settings.max_total_memory {
+            let mut total: usize = self.cb_history.iter().map(Entry::size).sum();
+            while total > max_total_memory && self.cb_history.len() > 1 {
+                if let Some(oldest) = self.cb_history.pop_back() {
+                    total -= oldest.size();
+                }
+            }
+        }
+    }
+
+    /// Bring a raised history limit back down to the profile's maximum, dropping the oldest items
+    fn shrink_history(&mut self) {
+        let _ = kill_timer(self.h_wnd, AUTO_GROW_TIMER_ID);
+        if self.extra_history == 0 {
+            return;
+        }
+        self.extra_history = 0;
+        let length = self.cb_history.len();
+        self.trim_history();
+        if self.cb_history.len() != length {
+            self.emit(Event::HistoryTrimmed {
+                depth: self.cb_history.len(),
+                preview: self.next_preview(),
+            });
+        }
+    }
+
+    /// Count the history running out after items were dropped for lack of room, suggesting a larger
+    /// limit once it keeps happening
+    fn note_exhaustion(&mut self) {
+        if !self.cb_history.is_empty() || !self.dropped_since_empty {
+            return;
+        }
+        self.dropped_since_empty = false;
+        self.exhaustions += 1;
+        if self.exhaustions == EXHAUSTION_WARNING_COUNT && !self.settings.auto_grow {
+            self.emit(Event::Notice {
+                message: format!(
+                    "The history keeps running out after dropping old items. Consider raising \
+                     max_history above {} or starting with --auto-grow",
+                    self.max_history()
+                ),
+            });
+        }
+    }
+
+    /// The most items the active stack keeps: its own max_history if the config gives one, otherwise
+    /// the profile's
+    fn max_history(&self) -> usize {
+        self.settings
+            .config
+            .stacks
+            .get(&self.stack_name)
+            .and_then(|stack| stack.max_history)
+            .map_or(self.settings.profile.max_history, |max_history| {
+                max_history.min(self.settings.policy.max_history.unwrap_or(usize::MAX))
+            })
+    }
+
+    /// Every stack which can be switched to: the default one, those named in the config and any
+    /// others used since startup
+    fn stack_names(&self) -> Vec<String> {
+        let mut names: BTreeSet<String> = self.stacks.keys().cloned().collect();
+        names.extend(self.settings.config.stacks.keys().cloned());
+        names.insert(DEFAULT_STACK.to_owned());
+        names.insert(self.stack_name.clone());
+        names.into_iter().collect()
+    }
+
+    /// Make another stack the active one, creating it if it doesn't exist yet. The current stack is
+    /// saved and kept as it is, to be switched back to later
+    fn switch_stack(&mut self, name: &str) -> Result<(), String> {
+        self.enter_stack(name, true)
+    }
+
+    /// Switch to the stack called `name`, as for `switch_stack`, only putting its front item on the
+    /// clipboard if `restore` is set
+    fn enter_stack(&mut self, name: &str, restore: bool) -> Result<(), String> {
+        let name = stacks::check_name(name)?;
+        if name == self.stack_name {
+            return Ok(());
+        }
+        self.flush_history();
+        let next = match self.stacks.remove(&name) {
+            Some(stack) => stack,
+            None => self.open_stack(&name),
+        };
+        let previous = Stack {
+            history: mem::replace(&mut self.cb_history, next.history),
+            store: mem::replace(&mut self.store, next.store),
+        };
+        let previous_name = mem::replace(&mut self.stack_name, name.clone());
+        self.stacks.insert(previous_name, previous);
+        self.extra_history = 0;
+        self.trim_history();
+        if restore && !self.settings.lazy_restore && !self.restore_front() {
+            self.emit(Event::Error {
+                message: "Could not put the stack's next item on the clipboard".to_owned(),
+            });
+        }
+        self.emit(Event::StackSwitched {
+            name,
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+        Ok(())
+    }
+
+    /// With `monitor_stacks`, switch to the stack of the monitor showing the foreground window. Its
+    /// front item is only put on the clipboard if `restore` is set, as it mustn't be when something
+    /// has just been copied there
+    fn follow_monitor(&mut self, restore: bool) {
+        if !self.settings.monitor_stacks {
+            return;
+        }
+        let monitor = match get_foreground_window().and_then(monitor_from_window) {
+            Some(monitor) => monitor,
+            None => return,
+        };
+        let handle = monitor as *mut _ as isize;
+        let stack = match self
+            .monitor_stacks
+            .stack(handle, || get_monitor_device_name(monitor).ok())
+        {
+            Some(stack) => stack,
+            None => return,
+        };
+        if let Err(error) = self.enter_stack(&stack, restore) {
+            self.emit(Event::Error { message: error });
+        }
+    }
+
+    /// Switch to the stack after the active one in `stack_names`, going back to the first after the
+    /// last
+    fn switch_to_next_stack(&mut self) {
+        let names = self.stack_names();
+        let position = names
+            .iter()
+            .position(|name| *name == self.stack_name)
+            .unwrap_or(0);
+        let next = names[(position + 1) % names.len()].clone();
+        if next == self.stack_name {
+            self.emit(Event::Notice {
+                message: "There's only one stack. Add more with `filo-clipboard stack <name>` or \
+                          [stacks.<name>] in the config"
+                    .to_owned(),
+            });
+        } else if let Err(error) = self.switch_stack(&next) {
+            self.emit(Event::Error { message: error });
+        }
+    }
+
+    /// Open a stack which hasn't been used since startup, reading its items if the history is saved
+    fn open_stack(&mut self, name: &str) -> Stack {
+        let path = match self.settings.persist.as_deref() {
+            Some(path) => stacks::stack_path(path, name),
+            None => return Stack::default(),
+        };
+        let mut store = match storage::open(self.settings.storage, &path, self.settings.encrypt) {
+            Ok(storage) => Store::new(storage, self.settings.flush),
+            Err(error) => {
+                self.emit(Event::Error {
+                    message: format!("Could not open the {} stack's history: {}", name, error),
+                });
+                return Stack::default();
+            }
+        };
+        let history = store.load().unwrap_or_else(|error| {
+            self.emit(Event::Error {
+                message: format!("Could not load the {} stack: {}", name, error),
+            });
+            VecDeque::new()
+        });
+        Stack {
+            history,
+            store: Some(store),
+        }
+    }
 
+    /// The reply to `stacks`: each stack's name and size, with the active one marked
+    fn stack_list(&self) -> String {
+        self.stack_names()
+            .iter()
+            .map(|name| {
+                let (marker, depth) = if *name == self.stack_name {
+                    ("*", self.cb_history.len())
+                } else {
                     (
-                        prev_item_similarity_handle.join().unwrap(),
-                        current_item_similarity_handle.join().unwrap(),
+                        " ",
+                        self.stacks.get(name).map_or(0, |stack| stack.history.len()),
                     )
-                })
-                .unwrap();
+                };
+                format!("{} {}\t{} items", marker, name, depth)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The name of the profile currently in use
+    pub fn profile_name(&self) -> &str {
+        &self.settings.profile_name
+    }
 
-                #[cfg(debug_assertions)]
+    /// Switch to another profile from the config, trimming the history if the new profile keeps fewer items
+    pub fn switch_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        let was_capturing = self.capturing();
+        let previous = self.settings.profile_name.clone();
+        self.settings.switch_profile(name)?;
+        if self.degraded.is_none() {
+            self.hotkeys.unregister_all(self.h_wnd);
+            if let Err(error) = register_hotkeys(self.h_wnd, &mut self.hotkeys, &self.settings) {
+                // Stay on the previous profile rather than be left without a paste hotkey
+                let message = format!(
+                    "Could not register {}, so stayed on profile \"{}\": {}",
+                    self.settings.hotkey, previous, error
+                );
+                self.hotkeys.unregister_all(self.h_wnd);
+                self.settings.switch_profile(&previous)?;
+                if let Err(error) = register_hotkeys(self.h_wnd, &mut self.hotkeys, &self.settings)
                 {
-                    if let Some(cb_data) = self.last_internal_update.as_ref() {
-                        println!("prev_item: {}", get_cb_text(cb_data));
-                    }
+                    warn!(
+                        "Could not register {} again: {}",
+                        self.settings.hotkey, error
+                    );
+                }
+                return Err(ConfigError::Hotkey(message));
+            }
+        }
+        match format_filter(&self.settings) {
+            Ok(filter) => self.format_filter = filter,
+            Err(error) => self.emit(Event::Error {
+                message: format!("Kept the previous formats, as {}", error),
+            }),
+        }
+        self.trim_history();
+        self.schedule_max_age();
+        self.schedule_quiet_hours();
+        self.emit(Event::ProfileSwitched {
+            name: name.to_owned(),
+        });
+        self.emit_capture_change(was_capturing);
+        self.check_passthrough();
+        Ok(())
+    }
 
-                    if let Some(cb_data) = self.cb_history.front() {
-                        println!("current_item: {}", get_cb_text(cb_data));
-                    }
+    /// Read the config file again after it's changed, applying everything but the hotkeys and
+    /// history file
+    fn reload_config(&mut self) {
+        let was_capturing = self.capturing();
+        for note in self.settings.reload() {
+            self.emit(Event::Notice { message: note });
+        }
+        match format_filter(&self.settings) {
+            Ok(filter) => self.format_filter = filter,
+            Err(error) => self.emit(Event::Error {
+                message: format!("Kept the previous formats, as {}", error),
+            }),
+        }
+        log::set_max_level(self.settings.log_level);
+        self.trim_history();
+        self.schedule_max_age();
+        self.schedule_quiet_hours();
+        self.emit_capture_change(was_capturing);
+        self.check_passthrough();
+        info!("Reloaded the config");
+    }
 
-                    println!("New item: {}", get_cb_text(&cb_data));
-                }
+    /// Whether the window in the foreground belongs to one of `passthrough_apps` or has one of
+    /// `passthrough_classes`
+    fn foreground_is_passthrough(&self) -> bool {
+        let (apps, classes) = (
+            &self.settings.passthrough_apps,
+            &self.settings.passthrough_classes,
+        );
+        if apps.is_empty() && classes.is_empty() {
+            return false;
+        }
+        let window = match get_foreground_window() {
+            Some(window) => window,
+            None => return false,
+        };
+        let class_matches = !classes.is_empty()
+            && get_class_name(window).is_ok_and(|class| {
+                classes
+                    .iter()
+                    .any(|name| name.trim().eq_ignore_ascii_case(&class))
+            });
+        class_matches
+            || (!apps.is_empty() && window_app(window).is_some_and(|app| matches_app(&app, apps)))
+    }
 
-                match (prev_item_similarity, current_item_similarity) {
-                    (_, ComparisonResult::Same) | (ComparisonResult::Same, _) => {}
-                    (_, ComparisonResult::Similar) | (ComparisonResult::Similar, _) => {
-                        #[cfg(debug_assertions)]
-                        println!("Updating last element: {}", get_cb_text(&cb_data));
-                        if let Some(cb_history_front) = self.cb_history.front_mut() {
-                            *cb_history_front = cb_data;
-                            self.last_internal_update = None;
-                        }
-                    }
-                    (ComparisonResult::Different, ComparisonResult::Different) => {
-                        #[cfg(debug_assertions)]
-                        println!("Appending to history: {}", get_cb_text(&cb_data));
-                        self.cb_history.push_front(cb_data);
-                        self.cb_history.truncate(self.max_history);
-                        self.last_internal_update = None;
-                    }
-                }
+    /// Release the hotkeys while a passthrough program is in the foreground, so it gets their keys
+    /// itself, and take them back once it isn't
+    fn check_passthrough(&mut self) {
+        if self.degraded.is_some() {
+            return;
+        }
+        let passthrough = self.foreground_is_passthrough();
+        if passthrough == self.hotkeys.is_suspended() {
+            return;
+        }
+        if passthrough {
+            self.hotkeys.suspend(self.h_wnd);
+            debug!("Released the hotkeys for the program in the foreground");
+        } else {
+            for (hotkey, error) in self.hotkeys.resume(self.h_wnd) {
+                self.emit(Event::Error {
+                    message: format!("Could not register {} again: {}", hotkey, error),
+                });
             }
+            debug!("Took the hotkeys back");
         }
     }
 
-    fn handle_ctrl_shift_v(&mut self) {
-        #[cfg(debug_assertions)]
-        dbg!("Ctrl+Shift+V");
+    /// Whether new clipboard items are currently being recorded
+    pub fn capturing(&self) -> bool {
+        self.settings.profile.capture && !self.paused && !self.quiet && self.degraded.is_none()
+    }
+
+    /// Stop or restart recording new clipboard items, independently of the profile's capture setting
+    pub fn set_paused(&mut self, paused: bool) {
+        let was_capturing = self.capturing();
+        self.paused = paused;
+        self.emit_capture_change(was_capturing);
+    }
 
-        match trigger_keys(
-            &[
-                winuser::VK_SHIFT as u16,
-                winuser::VK_CONTROL as u16,
-                'V' as u16,
-                winuser::VK_CONTROL as u16,
-                'V' as u16,
-                winuser::VK_SHIFT as u16,
-            ],
-            &[
-                winuser::KEYEVENTF_KEYUP,
-                winuser::KEYEVENTF_KEYUP,
-                winuser::KEYEVENTF_KEYUP,
-                0,
-                0,
-                0,
-            ],
+    /// Let subscribers know if capture has been turned on or off since `was_capturing` was read
+    fn emit_capture_change(&mut self, was_capturing: bool) {
+        let enabled = self.capturing();
+        if enabled != was_capturing {
+            self.emit(Event::CaptureToggled { enabled });
+        }
+    }
+
+    pub fn run_event_loop(&mut self) {
+        // The window procedure finds us through the window's user data for as long as the loop runs
+        let this = self as *mut Self as isize;
+        set_window_long_ptr_a(self.h_wnd, winuser::GWLP_USERDATA, this);
+        CONSOLE_TARGET.store(self.h_wnd as *mut HWND__ as usize, Ordering::SeqCst);
+        // Without a console there's nothing to handle, so failing doesn't matter
+        let _ = set_console_ctrl_handler(Some(console_ctrl_handler), true);
+        // Without the clipboard there are no hotkeys to release
+        let foreground_hook = if self.degraded.is_none() {
+            FOREGROUND_TARGET.store(self.h_wnd as *mut HWND__ as usize, Ordering::SeqCst);
+            set_foreground_event_hook(Some(foreground_changed))
+        } else {
+            None
+        };
+        if self.degraded.is_none() && foreground_hook.is_none() {
+            warn!("Could not watch the foreground window, so the hotkeys are never passed through");
+        }
+        self.check_passthrough();
+        let mut lp_msg = winuser::MSG::default();
+        info!("Ready");
+        while self.running && unsafe { winuser::GetMessageA(&mut lp_msg, self.h_wnd, 0, 0) != 0 } {
+            unsafe { winuser::DispatchMessageA(&lp_msg) };
+        }
+        if let Some(hook) = foreground_hook {
+            unhook_win_event(hook);
+        }
+        FOREGROUND_TARGET.store(0, Ordering::SeqCst);
+        CONSOLE_TARGET.store(0, Ordering::SeqCst);
+        let _ = set_console_ctrl_handler(Some(console_ctrl_handler), false);
+        set_window_long_ptr_a(self.h_wnd, winuser::GWLP_USERDATA, 0);
+    }
+
+    /// The action of the hotkey which was pressed, counted if telemetry is on. With
+    /// `monitor_stacks`, the paste hotkeys switch to the stack of the monitor being pasted into first
+    fn hotkey_action(&mut self, id: i32, l_param: LParam) -> Option<HotkeyAction> {
+        let action = self.hotkeys.action(id, l_param)?;
+        if let Some(telemetry) = self.telemetry.as_mut() {
+            telemetry.record_use("hotkey", &format!("{:?}", action));
+        }
+        // These take items from the history, so use the stack of the monitor being pasted into
+        if matches!(
+            action,
+            HotkeyAction::Paste
+                | HotkeyAction::Peek
+                | HotkeyAction::PlainPaste
+                | HotkeyAction::Picker
+                | HotkeyAction::Search
+                | HotkeyAction::Queue
+                | HotkeyAction::Tap
         ) {
-            Ok(_) => {
-                // Sleep for less time than the lowest possible automatic keystroke repeat ((1000ms / 30) * 0.8)
-                thread::sleep(Duration::from_millis(25));
-                self.last_internal_update = self.cb_history.pop_front();
-                if let Some(prev_item) = self.cb_history.front() {
-                    if let Ok(_clip) = Clipboard::new_attempts(10) {
-                        self.skip_clipboard = true;
-                        let _ = set_all(prev_item);
+            self.follow_monitor(true);
+        }
+        Some(action)
+    }
+
+    /// Handle a message for the message window, returning None to leave it to DefWindowProc
+    fn handle_message(
+        &mut self,
+        msg: MessageType,
+        w_param: WParam,
+        l_param: LParam,
+    ) -> Option<isize> {
+        match msg {
+            winuser::WM_CLIPBOARDUPDATE => match self.settings.debounce {
+                // Setting the timer again restarts it, so a burst of updates is read once
+                Some(debounce) => {
+                    if let Err(error) =
+                        set_timer(self.h_wnd, DEBOUNCE_TIMER_ID, debounce.as_millis() as u32)
+                    {
+                        warn!("Could not delay reading the clipboard: {}", error);
+                        self.clipboard_updated();
+                    }
+                }
+                None => self.clipboard_updated(),
+            },
+            winuser::WM_HOTKEY => match self.hotkey_action(w_param as i32, l_param) {
+                Some(HotkeyAction::Paste) => self.handle_hotkey(),
+                Some(HotkeyAction::Peek) => self.peek_and_paste(Some(self.settings.peek_hotkey)),
+                Some(HotkeyAction::Picker) => self.handle_picker_hotkey(),
+                Some(HotkeyAction::PlainPaste) => self.handle_plain_paste_hotkey(),
+                Some(HotkeyAction::UndoPop) => self.undo_pop(),
+                Some(HotkeyAction::Next) => self.rotate(true),
+                Some(HotkeyAction::Previous) => self.rotate(false),
+                Some(HotkeyAction::Pinned) => self.paste_pinned(),
+                Some(HotkeyAction::Search) => self.handle_search_hotkey(),
+                Some(HotkeyAction::Snippet) => self.handle_snippet_hotkey(),
+                Some(HotkeyAction::NextStack) => self.switch_to_next_stack(),
+                Some(HotkeyAction::Queue) => self.handle_queue_hotkey(),
+                Some(HotkeyAction::Tap) => self.start_tap(),
+                Some(HotkeyAction::Recapture) => {
+                    if let Err(message) = self.recapture() {
+                        self.emit(Event::Error { message });
                     }
                 }
+                None => {}
+            },
+            winuser::WM_TIMER if w_param == PERSIST_TIMER_ID => self.flush_history(),
+            winuser::WM_TIMER if w_param == EXPIRY_TIMER_ID => self.remove_expired(),
+            winuser::WM_TIMER if w_param == MAX_AGE_TIMER_ID => self.remove_old(),
+            winuser::WM_TIMER if w_param == QUIET_HOURS_TIMER_ID => self.check_quiet_hours(),
+            winuser::WM_TIMER if w_param == AUTO_GROW_TIMER_ID => self.shrink_history(),
+            winuser::WM_TIMER if w_param == TAP_TIMER_ID => self.check_tap(),
+            winuser::WM_TIMER if w_param == WATCHDOG_TIMER_ID => self.check_modifiers(),
+            winuser::WM_TIMER if w_param == DEBOUNCE_TIMER_ID => {
+                let _ = kill_timer(self.h_wnd, DEBOUNCE_TIMER_ID);
+                // A paste may have recorded the copy in the meantime
+                if get_clipboard_sequence_number() != self.seen_sequence {
+                    self.clipboard_updated();
+                }
             }
-            Err(_) => {
-                let mut retries = 0u8;
-                while let Err(error) = trigger_keys(
-                    &[
-                        winuser::VK_SHIFT as u16,
-                        winuser::VK_CONTROL as u16,
-                        'V' as u16,
-                    ],
-                    &[
-                        winuser::KEYEVENTF_KEYUP,
-                        winuser::KEYEVENTF_KEYUP,
-                        winuser::KEYEVENTF_KEYUP,
-                    ],
-                ) {
-                    if retries >= MAX_RETRIES {
-                        panic!("Could not release keys after {} attemps. Something has gone badly wrong: {}", MAX_RETRIES, error)
+            winuser::WM_TIMER if w_param == CONFIG_RELOAD_TIMER_ID => {
+                let _ = kill_timer(self.h_wnd, CONFIG_RELOAD_TIMER_ID);
+                self.reload_config();
+            }
+            // Monitors get new handles when displays change
+            winuser::WM_DISPLAYCHANGE => {
+                self.monitor_stacks.clear();
+                return None;
+            }
+            WM_CONFIG_CHANGED => {
+                // Setting the timer again restarts it, so a burst of changes is read once
+                if let Err(error) =
+                    set_timer(self.h_wnd, CONFIG_RELOAD_TIMER_ID, CONFIG_RELOAD_DELAY_MS)
+                {
+                    warn!("Could not schedule reloading the config: {}", error);
+                }
+            }
+            winuser::WM_TIMER if w_param == RESTORE_RETRY_TIMER_ID => {
+                let _ = kill_timer(self.h_wnd, RESTORE_RETRY_TIMER_ID);
+                self.restore_front();
+            }
+            WM_TRAY_CALLBACK => {
+                let mouse_message = l_param as u32;
+                if mouse_message == winuser::WM_RBUTTONUP
+                    || mouse_message == winuser::WM_CONTEXTMENU
+                {
+                    self.show_tray_menu();
+                }
+            }
+            WM_IPC_REQUEST => {
+                while let Ok((request, reply)) = self.ipc_requests.try_recv() {
+                    let _ = reply.send(self.handle_ipc_request(request));
+                }
+            }
+            WM_IPC_ERROR if w_param == 0 => self.emit(Event::Notice {
+                message: "Listening for commands again".to_owned(),
+            }),
+            WM_IPC_ERROR => self.emit(Event::Error {
+                message: format!(
+                    "Commands can't reach this instance, as the IPC pipe couldn't be created: {}",
+                    SystemError::new(w_param as i32)
+                ),
+            }),
+            // Nothing needs saying before the session ends, so let it
+            winuser::WM_QUERYENDSESSION => return Some(1),
+            // The process is ended without warning once this returns, so tidy up now
+            winuser::WM_ENDSESSION if w_param != 0 => {
+                self.shut_down();
+                self.running = false;
+            }
+            WM_OPEN_PICKER => self.handle_picker_hotkey(),
+            WM_FOREGROUND_CHANGED => self.check_passthrough(),
+            WM_SHUTDOWN => {
+                self.shut_down();
+                self.running = false;
+                // Sent messages don't wake the event loop, so it needs a nudge to see it's done
+                let _ = post_message_a(self.h_wnd, winuser::WM_NULL, 0, 0);
+            }
+            winuser::WM_RENDERFORMAT
+            | winuser::WM_RENDERALLFORMATS
+            | winuser::WM_DESTROYCLIPBOARD => {
+                return delayed_rendering::handle_message(self.h_wnd, msg, w_param)
+            }
+            _ => return None,
+        }
+        Some(0)
+    }
+
+    fn show_tray_menu(&mut self) {
+        let command = tray::show_menu(
+            self.h_wnd,
+            self.paused,
+            &self.settings.config.profile_names(),
+            &self.settings.profile_name,
+        );
+        match command {
+            Ok(Some(TrayCommand::Pause)) => self.set_paused(true),
+            Ok(Some(TrayCommand::Resume)) => self.set_paused(false),
+            Ok(Some(TrayCommand::ClearHistory)) => self.clear_history(),
+            Ok(Some(TrayCommand::SaveNext)) => {
+                let response = self.save_item(self.next_index(), false);
+                if !response.ok {
+                    self.emit(Event::Error {
+                        message: response.message,
+                    });
+                }
+            }
+            Ok(Some(TrayCommand::CopyNextAsFile)) => {
+                let response = self.copy_as_file(self.next_index());
+                if !response.ok {
+                    self.emit(Event::Error {
+                        message: response.message,
+                    });
+                }
+            }
+            Ok(Some(TrayCommand::SwitchProfile(name))) => {
+                if let Err(error) = self.switch_profile(&name) {
+                    self.emit(Event::Error {
+                        message: error.to_string(),
+                    });
+                }
+            }
+            Ok(Some(TrayCommand::About)) => about::spawn(about::details(Ok(&self.settings))),
+            Ok(Some(TrayCommand::Exit)) => self.running = false,
+            Ok(None) => {}
+            Err(error) => warn!("Could not show tray menu: {}", error),
+        }
+    }
+
+    /// Save the primary content of a history item to a file, opening it if requested or configured
+    fn save_item(&mut self, index: usize, open: bool) -> Response {
+        let item = match self.cb_history.get(index) {
+            Some(item) => item,
+            None => return Response::error(format!("There is no item {}", index)),
+        };
+        match save_entry(item, &self.settings.config.save_directory()) {
+            Ok(path) => {
+                if open || self.settings.config.open_saved_files {
+                    if let Err(error) = shell_execute_open(&path) {
+                        return Response::error(format!(
+                            "Saved {} but could not open it: {}",
+                            path.display(),
+                            error
+                        ));
                     }
-                    retries += 1;
-                    thread::sleep(Duration::from_millis(25));
                 }
+                Response::ok(path.display().to_string())
             }
+            Err(error) => Response::error(format!("Could not save item {}: {}", index, error)),
         }
     }
-}
+
+    /// Where the paste hotkey takes the next item from: the front, or the back in FIFO mode
+    fn next_index(&self) -> usize {
+        self.next_index_in(self.settings.mode)
+    }
+
+    fn next_index_in(&self, order: Order) -> usize {
+        history::next_index(self.cb_history.len(), order)
+    }
+
+    /// The fingerprint of the item the paste hotkey would paste next, if there is one
+    fn next_fingerprint(&self) -> Option<u64> {
+        self.cb_history
+            .get(self.next_index())
+            .map(Entry::fingerprint)
+    }
+
+    /// Take the item the paste hotkey would paste next out of the history
+    fn take_next(&mut self) -> Option<Entry> {
+        self.take_next_in(self.settings.mode)
+    }
+
+    fn take_next_in(&mut self, order: Order) -> Option<Entry> {
+        history::take_next(&mut self.cb_history, order)
+    }
+
+    /// Remove the next item without pasting it, putting the front item on the clipboard
+    fn pop_item(&mut self) -> Response {
+        let popped = match self.take_next() {
+            Some(popped) => popped,
+            None => return Response::error("The history is empty"),
+        };
+        let text = popped.text();
+        self.audit(Action::Pop, popped.fingerprint(), false);
+        self.remember_popped(&popped);
+        self.last_internal_update = Some(popped);
+        self.note_exhaustion();
+        let restored = self.settings.lazy_restore || self.restore_front();
+        self.emit(Event::EntryPopped {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+        if restored {
+            Response::ok(text.trim_end_matches('\0'))
+        } else {
+            Response::error("Popped the item but could not restore the previous one")
+        }
+    }
+
+    /// Keep a copy of a popped item so it can be put back, forgetting the oldest beyond `MAX_UNDO`
+    fn remember_popped(&mut self, popped: &Entry) {
+        if self.popped.len() >= MAX_UNDO {
+            self.popped.remove(0).wipe();
+        }
+        self.popped.push(popped.clone());
+    }
+
+    /// Put the last popped item back where it was taken from and the front item on the clipboard,
+    /// so an accidental paste doesn't lose it
+    fn undo_pop(&mut self) {
+        let entry = match self.popped.pop() {
+            Some(entry) => entry,
+            None => return,
+        };
+        match self.settings.mode {
+            Order::Filo => self.cb_history.push_front(entry),
+            Order::Fifo => self.cb_history.push_back(entry),
+        }
+        self.trim_history();
+        if !self.restore_front() {
+            self.emit(Event::Error {
+                message: "Could not put the popped item back on the clipboard".to_owned(),
+            });
+        }
+        self.emit(Event::PopUndone {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+    }
+
+    /// Turn the history like a ring by one item, forwards so the next item is at the front or
+    /// backwards so the one at the back is, and put the new front item on the clipboard. Nothing
+    /// is removed
+    fn rotate(&mut self, forward: bool) {
+        if self.cb_history.len() < 2 {
+            return;
+        }
+        if forward {
+            self.cb_history.rotate_left(1);
+        } else {
+            self.cb_history.rotate_right(1);
+        }
+        self.capture_interleaved();
+        if !self.restore_item(self.next_index(), self.settings.plain_paste) {
+            self.emit(Event::Error {
+                message: "Could not put the next item on the clipboard".to_owned(),
+            });
+        }
+        self.emit(Event::HistoryRotated {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+    }
+
+    /// Move an item out of the history into the pinned items. If it was at the front, the next
+    /// item is put on the clipboard
+    fn pin_item(&mut self, index: usize) -> Result<(), String> {
+        let mut entry = self
+            .cb_history
+            .remove(index)
+            .ok_or_else(|| format!("There is no item {}", index))?;
+        entry.pinned = true;
+        // A pinned item is meant to stay, so it's no longer wiped
+        entry.expires_at = None;
+        entry.burn = false;
+        self.pinned.push(entry);
+        if index == 0 && !self.settings.lazy_restore && !self.restore_front() {
+            self.emit(Event::Error {
+                message: "Could not put the next item on the clipboard".to_owned(),
+            });
+        }
+        self.emit_pins_changed();
+        Ok(())
+    }
+
+    /// Move a pinned item back to the front of the history and put it on the clipboard
+    fn unpin_item(&mut self, index: usize) -> Result<(), String> {
+        if index >= self.pinned.len() {
+            return Err(format!("There is no pinned item {}", index));
+        }
+        let mut entry = self.pinned.remove(index);
+        entry.pinned = false;
+        self.cb_history.push_front(entry);
+        self.trim_history();
+        if !self.restore_front() {
+            self.emit(Event::Error {
+                message: "Could not put the unpinned item on the clipboard".to_owned(),
+            });
+        }
+        self.emit_pins_changed();
+        Ok(())
+    }
+
+    /// Apply every operation in a batch, or none of them if any is invalid or the history has
+    /// changed since `revision`. Messages are handled one at a time, so no copy is recorded halfway
+    /// through
+    fn apply_batch(
+        &mut self,
+        revision: Option<u64>,
+        operations: &[BatchOperation],
+    ) -> Result<String, String> {
+        if let Some(revision) = revision.filter(|revision| *revision != self.revision) {
+            return Err(format!(
+                "The history has changed since revision {} (it's now {}), so nothing was changed",
+                revision, self.revision
+            ));
+        }
+        let length = self.cb_history.len();
+        let mut deleted = vec![false; length];
+        let mut promoted = Vec::new();
+        for operation in operations {
+            let index = operation.index();
+            if index >= length {
+                return Err(format!(
+                    "There is no item {}, so nothing was changed",
+                    index
+                ));
+            }
+            let position = self.position(index);
+            match operation {
+                BatchOperation::Delete { .. } => deleted[position] = true,
+                BatchOperation::Promote { .. } => promoted.push(position),
+                BatchOperation::Label { .. } => {}
+            }
+        }
+        if let Some(position) = promoted.iter().find(|position| deleted[**position]) {
+            return Err(format!(
+                "Item {} can't be both deleted and promoted, so nothing was changed",
+                self.position(*position)
+            ));
+        }
+
+        for operation in operations {
+            if let BatchOperation::Label { index, label } = operation {
+                let position = self.position(*index);
+                self.cb_history[position].label =
+                    Some(label.clone()).filter(|label| !label.is_empty());
+            }
+        }
+        let front_changed =
+            deleted.first() == Some(&true) || promoted.last().is_some_and(|index| *index != 0);
+        let mut entries: Vec<_> = self.cb_history.drain(..).map(Some).collect();
+        for index in promoted {
+            if let Some(entry) = entries[index].take() {
+                self.cb_history.push_front(entry);
+            }
+        }
+        for (index, entry) in entries.into_iter().enumerate() {
+            match entry {
+                Some(mut entry) if deleted[index] => entry.wipe(),
+                Some(entry) => self.cb_history.push_back(entry),
+                None => {}
+            }
+        }
+        if front_changed && !self.settings.lazy_restore && !self.restore_front() {
+            self.emit(Event::Error {
+                message: "Could not put the next item on the clipboard".to_owned(),
+            });
+        }
+        self.emit(Event::HistoryEdited {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+        Ok(format!(
+            "Applied {} operation{}",
+            operations.len(),
+            if operations.len() == 1 { "" } else { "s" }
+        ))
+    }
+
+    fn emit_pins_changed(&mut self) {
+        self.pinned_cursor = 0;
+        self.emit(Event::PinsChanged {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+            pinned: self.pinned.len(),
+        });
+    }
+
+    /// Paste the pinned items in turn, starting again from the first after the last. The history
+    /// is left as it is, and its front item put back on the clipboard afterwards
+    fn paste_pinned(&mut self) {
+        let entry = match self.pinned.get(self.pinned_cursor) {
+            Some(entry) if !entry.is_intact() => {
+                self.emit_corrupted();
+                return;
+            }
+            Some(entry) => entry,
+            None => return,
+        };
+        let (items, provenance) = if self.settings.plain_paste {
+            (plain_text(entry), Provenance::now(entry))
+        } else {
+            (entry.items.clone(), Provenance::now(entry))
+        };
+        let fingerprint = entry.fingerprint();
+        self.capture_interleaved();
+        if !self.restore_items(items, provenance) {
+            self.emit(Event::Error {
+                message: "Could not put the pinned item on the clipboard".to_owned(),
+            });
+            return;
+        }
+        if self.send_paste(Some(self.settings.pinned_hotkey)) {
+            self.pinned_cursor = (self.pinned_cursor + 1) % self.pinned.len();
+            self.audit(Action::PastePinned, fingerprint, true);
+        }
+        if !self.settings.lazy_restore {
+            // Give the target program time to read the clipboard before it changes again
+            thread::sleep(Duration::from_millis(25));
+            if !self.restore_front() {
+                self.emit(Event::Error {
+                    message: "Could not restore the previous item".to_owned(),
+                });
+            }
+        }
+    }
+
+    /// Save a copy of a history item under `name`, returning the name as it was saved
+    fn save_snippet(&mut self, index: usize, name: &str) -> Result<String, String> {
+        let name = snippets::check_name(name)?;
+        let entry = self
+            .cb_history
+            .get(index)
+            .ok_or_else(|| format!("There is no item {}", index))?
+            .clone();
+        self.snippets.insert(name.clone(), entry);
+        self.write_snippets()?;
+        Ok(name)
+    }
+
+    fn delete_snippet(&mut self, name: &str) -> Result<(), String> {
+        let mut entry = self
+            .snippets
+            .remove(name)
+            .ok_or_else(|| format!("There is no snippet \"{}\"", name))?;
+        entry.wipe();
+        self.write_snippets()
+    }
+
+    /// Write the snippets to their file next to the config, if there is one
+    fn write_snippets(&self) -> Result<(), String> {
+        match self.settings.config_path.as_deref() {
+            Some(path) => self
+                .snippets
+                .save(&snippets::snippets_path(path), self.settings.encrypt)
+                .map_err(|error| format!("Could not save the snippets: {}", error)),
+            None => Ok(()),
+        }
+    }
+
+    /// Paste a snippet, then put the front of the history back on the clipboard
+    fn paste_snippet(&mut self, name: &str) -> Result<(), String> {
+        let entry = self
+            .snippets
+            .get(name)
+            .ok_or_else(|| format!("There is no snippet \"{}\"", name))?;
+        let (items, provenance) = if self.settings.plain_paste {
+            (plain_text(entry), Provenance::now(entry))
+        } else {
+            (entry.items.clone(), Provenance::now(entry))
+        };
+        let fingerprint = entry.fingerprint();
+        self.capture_interleaved();
+        if !self.restore_items(items, provenance) {
+            return Err("Could not put the snippet on the clipboard".to_owned());
+        }
+        let pasted = self.send_paste(None);
+        if pasted {
+            self.audit(Action::PasteSnippet, fingerprint, true);
+        }
+        if !self.settings.lazy_restore {
+            // Give the target program time to read the clipboard before it changes again
+            thread::sleep(Duration::from_millis(25));
+            if !self.restore_front() {
+                self.emit(Event::Error {
+                    message: "Could not restore the previous item".to_owned(),
+                });
+            }
+        }
+        if pasted {
+            Ok(())
+        } else {
+            Err("Could not paste the snippet".to_owned())
+        }
+    }
+
+    /// Mark an item to be wiped from the history, and from the clipboard if it's there, once `after`
+    /// has passed
+    fn expire_item(&mut self, index: usize, after: Duration) -> Result<(), String> {
+        let entry = self
+            .cb_history
+            .get_mut(index)
+            .ok_or_else(|| format!("There is no item {}", index))?;
+        entry.expires_at = Some(Instant::now() + after);
+        self.schedule_expiry();
+        Ok(())
+    }
+
+    /// Mark an item to be wiped from the history and the clipboard once it's been pasted
+    fn burn_after_reading(&mut self, index: usize) -> Result<(), String> {
+        let entry = self
+            .cb_history
+            .get_mut(index)
+            .ok_or_else(|| format!("There is no item {}", index))?;
+        entry.burn = true;
+        self.emit(Event::HistoryEdited {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+        Ok(())
+    }
+
+    /// Wipe an item marked to burn after reading once it's been pasted: from the history, from the
+    /// popped items undo can bring back and from the clipboard
+    fn burn(&mut self, fingerprint: u64) {
+        let (mut burned, kept): (VecDeque<_>, VecDeque<_>) = self
+            .cb_history
+            .drain(..)
+            .partition(|entry| entry.burn && entry.fingerprint() == fingerprint);
+        self.cb_history = kept;
+        for entry in self.popped.iter_mut() {
+            if entry.burn && entry.fingerprint() == fingerprint {
+                entry.wipe();
+            }
+        }
+        self.popped.retain(|entry| !entry.items.is_empty());
+        // A popped item is only left here, and still on the clipboard with lazy_restore
+        if self
+            .last_internal_update
+            .as_ref()
+            .is_some_and(|entry| entry.fingerprint() == fingerprint)
+        {
+            burned.extend(self.last_internal_update.take());
+        }
+        if self.clipboard_holds(&burned) {
+            // Give the target program time to read the clipboard before it's wiped
+            thread::sleep(Duration::from_millis(25));
+            self.wipe_clipboard();
+        }
+        for entry in burned.iter_mut() {
+            entry.wipe();
+        }
+        self.emit(Event::EntriesExpired {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+    }
+
+    /// Whether the clipboard holds the same data as one of `entries`
+    fn clipboard_holds(&self, entries: &VecDeque<Entry>) -> bool {
+        if entries.is_empty() {
+            return false;
+        }
+        let current = Entry::new(
+            self.clipboard
+                .read_all(|format| self.format_filter.allows(format)),
+        );
+        entries
+            .iter()
+            .any(|entry| compare_data(&current, entry, u8::MAX) == ComparisonResult::Same)
+    }
+
+    /// Set the expiry timer for the next item due to expire in any stack, if any are
+    fn schedule_expiry(&mut self) {
+        let now = Instant::now();
+        // Items in the other stacks expire on time too, so the timer is for the earliest anywhere
+        let next = self
+            .cb_history
+            .iter()
+            .chain(self.stacks.values().flat_map(|stack| stack.history.iter()))
+            .filter_map(|entry| entry.expires_at)
+            .min();
+        match next {
+            Some(at) => {
+                // Round up so the timer doesn't fire just before the item is due
+                let delay = at.saturating_duration_since(now).as_millis() as u32 + 1;
+                if let Err(error) = set_timer(self.h_wnd, EXPIRY_TIMER_ID, delay) {
+                    warn!("Could not schedule expiry: {}", error);
+                }
+            }
+            None => {
+                let _ = kill_timer(self.h_wnd, EXPIRY_TIMER_ID);
+            }
+        }
+    }
+
+    /// Look for items older than `max_age` every so often, or stop looking if there's no limit
+    fn schedule_max_age(&mut self) {
+        match self.settings.max_age {
+            Some(max_age) => {
+                let interval = max_age.min(MAX_AGE_CHECK_INTERVAL).as_millis() as u32;
+                if let Err(error) = set_timer(self.h_wnd, MAX_AGE_TIMER_ID, interval) {
+                    warn!("Could not schedule the age check: {}", error);
+                }
+            }
+            None => {
+                let _ = kill_timer(self.h_wnd, MAX_AGE_TIMER_ID);
+            }
+        }
+    }
+
+    /// Check for the start or end of quiet hours every so often, or stop checking if there are none
+    fn schedule_quiet_hours(&mut self) {
+        if self.settings.quiet_hours.0.is_empty() {
+            let _ = kill_timer(self.h_wnd, QUIET_HOURS_TIMER_ID);
+        } else if let Err(error) = set_timer(
+            self.h_wnd,
+            QUIET_HOURS_TIMER_ID,
+            QUIET_HOURS_CHECK_INTERVAL_MS,
+        ) {
+            warn!("Could not schedule the quiet hours check: {}", error);
+        }
+        self.check_quiet_hours();
+    }
+
+    /// Pause capture and hide the next item if quiet hours have started, or bring them back if
+    /// they've ended
+    fn check_quiet_hours(&mut self) {
+        let quiet = self.settings.quiet_hours.contains(local_minute_of_day());
+        if quiet == self.quiet {
+            return;
+        }
+        let was_capturing = self.capturing();
+        self.quiet = quiet;
+        self.emit(Event::QuietHoursChanged {
+            active: quiet,
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+        self.emit_capture_change(was_capturing);
+    }
+
+    /// Wipe the items older than `max_age` in every stack, as for expired items. The history file
+    /// doesn't record when items were copied, so items loaded from it are aged from when they were
+    /// loaded
+    fn remove_old(&mut self) {
+        let max_age = match self.settings.max_age {
+            Some(max_age) => max_age.as_secs(),
+            None => return,
+        };
+        let now = provenance::unix_time();
+        let mut any_old = false;
+        let others = self
+            .stacks
+            .values_mut()
+            .flat_map(|stack| stack.history.iter_mut());
+        for entry in self.cb_history.iter_mut().chain(others) {
+            let copied_at = *entry.copied_at.get_or_insert(now);
+            if now.saturating_sub(copied_at) >= max_age {
+                entry.expires_at = Some(Instant::now());
+                any_old = true;
+            }
+        }
+        if any_old {
+            self.remove_expired();
+        }
+    }
+
+    /// Wipe every item whose time is up in any stack, emptying the clipboard first if it holds one
+    /// of them
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        let (mut expired, kept): (VecDeque<_>, VecDeque<_>) = self
+            .cb_history
+            .drain(..)
+            .partition(|entry| entry.is_expired(now));
+        self.cb_history = kept;
+        for (name, stack) in self.stacks.iter_mut() {
+            if !stack.history.iter().any(|entry| entry.is_expired(now)) {
+                continue;
+            }
+            let (stack_expired, kept): (VecDeque<_>, VecDeque<_>) = stack
+                .history
+                .drain(..)
+                .partition(|entry| entry.is_expired(now));
+            stack.history = kept;
+            expired.extend(stack_expired);
+            // Other stacks are only saved when they're switched away from, so the items would
+            // stay in their files until then
+            let pinned: &[Entry] = if name == DEFAULT_STACK {
+                &self.pinned
+            } else {
+                &[]
+            };
+            if let Some(store) = stack.store.as_mut() {
+                if let Err(error) = store.flush(&stack.history, pinned) {
+                    self.events.emit(Event::Error {
+                        message: format!("Could not save the {} stack: {}", name, error),
+                    });
+                }
+            }
+        }
+
+        if !expired.is_empty() {
+            if self.clipboard_holds(&expired) {
+                self.wipe_clipboard();
+            }
+            if let Some(last_update) = self.last_internal_update.as_mut() {
+                if expired.iter().any(|entry| entry.items == last_update.items) {
+                    last_update.wipe();
+                    self.last_internal_update = None;
+                }
+            }
+            for entry in expired.iter_mut() {
+                entry.wipe();
+            }
+            // Popped copies of expired items mustn't be brought back either
+            for entry in self.popped.iter_mut() {
+                if entry.is_expired(now) {
+                    entry.wipe();
+                }
+            }
+            self.popped.retain(|entry| !entry.items.is_empty());
+            self.emit(Event::EntriesExpired {
+                depth: self.cb_history.len(),
+                preview: self.next_preview(),
+            });
+        }
+        self.schedule_expiry();
+    }
+
+    /// Replace the clipboard's contents with the front item, or empty it if the history is empty
+    fn wipe_clipboard(&mut self) {
+        if !self.cb_history.is_empty() && self.restore_front() {
+            return;
+        }
+        if self.clipboard.empty().is_ok() {
+            self.note_own_write();
+        }
+    }
+
+    /// Remember the clipboard's sequence number after we've written it, so the update isn't
+    /// recorded. Reading it once the clipboard is closed means only other programs' writes can
+    /// change it afterwards
+    fn note_own_write(&mut self) {
+        let sequence = get_clipboard_sequence_number();
+        self.own_sequence = Some(sequence).filter(|sequence| *sequence != 0);
+        self.seen_sequence = sequence;
+    }
+
+    /// Record the clipboard's contents, unless we wrote them or they should be skipped
+    fn clipboard_updated(&mut self) {
+        let sequence = get_clipboard_sequence_number();
+        match self.skip_reason(sequence) {
+            Some(reason) => self.emit(Event::CaptureSkipped { reason }),
+            None => {
+                self.follow_monitor(false);
+                self.handle_clipboard();
+            }
+        }
+        self.seen_sequence = sequence;
+    }
+
+    /// Record anything another program copied since we last looked, before we overwrite it. This
+    /// catches a copy made between popping an item and putting the next one back, whose update
+    /// would otherwise only arrive once the clipboard holds our item again
+    fn capture_interleaved(&mut self) {
+        let sequence = get_clipboard_sequence_number();
+        if sequence != 0 && sequence != self.seen_sequence && Some(sequence) != self.own_sequence {
+            self.clipboard_updated();
+        }
+    }
+
+    /// Save a history item to a file and put that file on the clipboard, so pasting into Explorer or
+    /// a chat app attaches the file. The history itself is left unchanged
+    fn copy_as_file(&mut self, index: usize) -> Response {
+        let item = match self.cb_history.get(index) {
+            Some(item) => item,
+            None => return Response::error(format!("There is no item {}", index)),
+        };
+        let provenance = Provenance::now(item);
+        let path = match save_entry(item, &self.settings.config.save_directory()) {
+            Ok(path) => path,
+            Err(error) => {
+                return Response::error(format!("Could not save item {}: {}", index, error))
+            }
+        };
+        match self.write_clipboard(vec![write_hdrop(&[&path])], provenance) {
+            Some(results) if results.iter().all(|result| result.is_ok()) => {
+                Response::ok(format!("Copied {}", path.display()))
+            }
+            Some(_) => Response::error(format!(
+                "Saved {} but could not put it on the clipboard",
+                path.display()
+            )),
+            None => Response::error(format!(
+                "Saved {} but timed out waiting for the clipboard",
+                path.display()
+            )),
+        }
+    }
+
+    /// Why the clipboard contents with sequence number `sequence` shouldn't be recorded, if they
+    /// shouldn't
+    fn skip_reason(&self, sequence: u32) -> Option<SkipReason> {
+        if self.own_sequence == Some(sequence) {
+            Some(SkipReason::OwnRestore)
+        } else if self.paused {
+            Some(SkipReason::Paused)
+        } else if self.quiet {
+            Some(SkipReason::QuietHours)
+        } else if !self.settings.profile.capture {
+            Some(SkipReason::CaptureDisabled)
+        } else if self
+            .ignore_format_id
+            .map(is_clipboard_format_available)
+            .unwrap_or(false)
+        {
+            Some(SkipReason::ViewerIgnore)
+        } else if !self.settings.exclude_apps.is_empty()
+            && clipboard_owner_app()
+                .map(|app| matches_app(&app, &self.settings.exclude_apps))
+                .unwrap_or(false)
+        {
+            Some(SkipReason::ExcludedApp)
+        } else if self.settings.remote_clipboard == RemoteClipboard::Skip
+            && clipboard_owner_app().is_some_and(|app| is_remote_clipboard(&app))
+        {
+            Some(SkipReason::RemoteSession)
+        } else {
+            None
+        }
+    }
+
+    /// A few lines describing the profile, history and hotkeys, for the status command
+    fn status(&self) -> String {
+        let capture = if self.degraded.is_some() {
+            "unavailable"
+        } else if self.paused {
+            "paused"
+        } else if self.quiet {
+            "paused for quiet hours"
+        } else if self.settings.profile.capture {
+            "on"
+        } else {
+            "off"
+        };
+        let next = self
+            .cb_history
+            .get(self.next_index())
+            .map(|entry| self.list_summary(entry))
+            .unwrap_or_else(|| "(empty)".to_owned());
+        let mut lines = vec![
+            format!("Profile: {}", self.settings.profile_name),
+            format!("Stack: {}", self.stack_name),
+            format!(
+                "History: {} of {} items{}",
+                self.cb_history.len(),
+                self.max_history() + self.extra_history,
+                if self.extra_history > 0 {
+                    " (raised by auto_grow)"
+                } else {
+                    ""
+                }
+            ),
+            format!("Next: {} ({} first)", next, match self.settings.mode {
+                Order::Filo => "newest",
+                Order::Fifo => "oldest",
+            }),
+            format!("Pinned: {} items", self.pinned.len()),
+            format!("Snippets: {}", self.snippets.len()),
+            format!("Capture: {}", capture),
+            format!("Revision: {}", self.revision),
+            format!(
+                "Hotkeys: paste {}, peek {}, picker {}, plain paste {}, undo {}, next {}, previous {}, pinned {}, search {}, snippets {}, next stack {}, queue {}",
+                self.settings.hotkey,
+                self.settings.peek_hotkey,
+                self.settings.picker_hotkey,
+                self.settings.plain_paste_hotkey,
+                self.settings.undo_hotkey,
+                self.settings.next_hotkey,
+                self.settings.previous_hotkey,
+                self.settings.pinned_hotkey,
+                self.settings.search_hotkey,
+                self.settings.snippet_hotkey,
+                self.settings.stack_hotkey,
+                self.settings.queue_hotkey
+            ),
+        ];
+        if let Some(tap_key) = self.settings.tap_key {
+            lines.push(format!(
+                "Tap key: {} pastes when tapped, opens the picker when held",
+                tap_key
+            ));
+        }
+        if let Some(hotkey) = self.settings.recapture_hotkey {
+            lines.push(format!("Recapture: {}", hotkey));
+        }
+        if self.hotkeys.is_suspended() {
+            lines.push(
+                "Hotkeys released while a passthrough program is in the foreground".to_owned(),
+            );
+        }
+        if let Some(reason) = &self.degraded {
+            lines.insert(
+                0,
+                format!(
+                    "Degraded: {}, so the clipboard isn't watched and nothing can be pasted",
+                    reason
+                ),
+            );
+        }
+        let policy = &self.settings.policy;
+        if policy.is_active() {
+            let mut limits = Vec::new();
+            if policy.disable_persistence {
+                limits.push("no persistence".to_owned());
+            }
+            if policy.force_plain_paste {
+                limits.push("plain text only".to_owned());
+            }
+            if let Some(max_history) = policy.max_history {
+                limits.push(format!("at most {} items", max_history));
+            }
+            lines.push(format!("Policy: {}", limits.join(", ")));
+        }
+        if let Some(path) = &self.settings.persist {
+            lines.push(format!(
+                "History file: {} ({} storage, flush {})",
+                path.display(),
+                self.settings.storage,
+                self.settings.flush
+            ));
+        }
+        lines.extend(self.health().lines());
+        lines.join("\n")
+    }
+
+    /// The memory the history takes up, the uptime, capture errors and each item's size
+    fn health(&self) -> Health {
+        Health::new(
+            self.numbered(),
+            self.settings.max_total_memory,
+            self.started.elapsed(),
+            self.capture_errors,
+        )
+    }
+
+    fn handle_ipc_request(&mut self, request: Request) -> Response {
+        if let Some(reason) = self.degraded.as_ref().filter(|_| request.needs_desktop()) {
+            return Response::error(format!("Can't paste or show the picker, as {}", reason));
+        }
+        if let Some(telemetry) = self.telemetry.as_mut() {
+            telemetry.record_use("request", &format!("{:?}", request));
+        }
+        let result = match request {
+            Request::ConfigGet { key } => self.settings.profile.get(&key),
+            Request::ConfigSet { key, value, save } => {
+                let was_capturing = self.capturing();
+                self.settings.set(&key, &value, save).map(|_| {
+                    self.trim_history();
+                    self.emit_capture_change(was_capturing);
+                    format!("{} = {}", key, value)
+                })
+            }
+            Request::SwitchProfile { name } => self
+                .switch_profile(&name)
+                .map(|_| format!("Switched to profile \"{}\"", name)),
+            Request::Save { index, open } => return self.save_item(self.position(index), open),
+            Request::CopyAsFile { index } => return self.copy_as_file(self.position(index)),
+            Request::Restore { index, .. } if index >= self.cb_history.len() => {
+                return Response::error(format!("There is no item {}", index))
+            }
+            Request::Restore {
+                index,
+                dry_run: true,
+            } => Ok(self.restore_check(self.position(index))),
+            Request::Restore {
+                index,
+                dry_run: false,
+            } => {
+                if !self.restore_item(self.position(index), self.settings.plain_paste) {
+                    return Response::error(format!(
+                        "Could not put item {} on the clipboard",
+                        index
+                    ));
+                }
+                Ok(format!("Put item {} on the clipboard", index))
+            }
+            Request::Expire { index, seconds } => {
+                let position = self.position(index);
+                return match self.expire_item(position, Duration::from_secs(seconds)) {
+                    Ok(()) => Response::ok(format!(
+                        "Item {} will be wiped in {} seconds",
+                        index, seconds
+                    )),
+                    Err(error) => Response::error(error),
+                };
+            }
+            Request::Burn { index } => {
+                return match self.burn_after_reading(self.position(index)) {
+                    Ok(()) => Response::ok(format!(
+                        "Item {} will be wiped once it's been pasted",
+                        index
+                    )),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::Pin { index } => {
+                return match self.pin_item(self.position(index)) {
+                    Ok(()) => Response::ok(format!("Pinned item {}", index)),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::Unpin { index } => {
+                return match self.unpin_item(index) {
+                    Ok(()) => Response::ok(format!("Unpinned item {}", index)),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::SnippetSave { index, name } => {
+                return match self.save_snippet(self.position(index), &name) {
+                    Ok(name) => Response::ok(format!("Saved item {} as \"{}\"", index, name)),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::SnippetPaste { name } => {
+                return match self.paste_snippet(&name) {
+                    Ok(()) => Response::ok(format!("Pasted \"{}\"", name)),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::SnippetDelete { name } => {
+                return match self.delete_snippet(&name) {
+                    Ok(()) => Response::ok(format!("Deleted \"{}\"", name)),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::SwitchStack { name } => {
+                return match self.switch_stack(&name) {
+                    Ok(()) => Response::ok(format!("Switched to the {} stack", self.stack_name)),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::StackList => Ok(self.stack_list()),
+            Request::SnippetList => Ok(self
+                .snippets
+                .iter()
+                .map(|(name, entry)| format!("{}\t{}", name, self.list_summary(entry)))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            Request::Batch {
+                revision,
+                operations,
+            } => {
+                return match self.apply_batch(revision, &operations) {
+                    Ok(message) => Response::ok(message),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::Push { text } => {
+                self.push_text(&text);
+                Ok("Pushed 1 item".to_owned())
+            }
+            Request::Export => Ok(export::to_json(
+                &self.cb_history,
+                &self.pinned,
+                formats::name,
+            )),
+            Request::Import { json } => {
+                return match self.import_json(&json) {
+                    Ok(count) => Response::ok(format!(
+                        "Imported {} item{}",
+                        count,
+                        if count == 1 { "" } else { "s" }
+                    )),
+                    Err(error) => Response::error(format!("Could not import: {}", error)),
+                }
+            }
+            Request::Pop => return self.pop_item(),
+            // Nothing is held down, so there are no hotkey keys to release first
+            Request::Paste if self.cb_history.is_empty() => {
+                return Response::error("The history is empty")
+            }
+            Request::Paste => {
+                self.pop_and_paste(None, self.settings.plain_paste, self.settings.mode);
+                Ok("Pasted".to_owned())
+            }
+            Request::PasteRecent { fingerprint } => {
+                let index = self
+                    .cb_history
+                    .iter()
+                    .position(|entry| entry.fingerprint() == fingerprint);
+                return match index {
+                    Some(index) if self.paste_chosen(index) => Response::ok("Pasted"),
+                    Some(_) => Response::error("Could not paste the item"),
+                    None => Response::error("The item is no longer in the history"),
+                };
+            }
+            Request::Peek if self.cb_history.is_empty() => {
+                return Response::error("The history is empty")
+            }
+            Request::Peek => {
+                self.peek_and_paste(None);
+                Ok("Pasted".to_owned())
+            }
+            Request::Clear => {
+                let count = self.cb_history.len();
+                self.clear_history();
+                Ok(format!(
+                    "Cleared {} item{}",
+                    count,
+                    if count == 1 { "" } else { "s" }
+                ))
+            }
+            Request::Compact => Ok(self.compact_history()),
+            Request::Recapture => {
+                return match self.recapture() {
+                    Ok(message) => Response::ok(message),
+                    Err(error) => Response::error(error),
+                }
+            }
+            // Pinned items are listed after the history, numbered for `unpin`
+            Request::List => Ok(self.list()),
+            Request::Status { json: false } => Ok(self.status()),
+            Request::Status { json: true } => Ok(self.health().to_json()),
+            Request::Shutdown => {
+                self.running = false;
+                Ok("Shutting down".to_owned())
+            }
+            // The picker waits for a choice, so it's opened after replying
+            Request::PickerOpen if self.cb_history.is_empty() => {
+                return Response::error("The history is empty")
+            }
+            Request::PickerOpen => {
+                return match post_message_a(self.h_wnd, WM_OPEN_PICKER, 0, 0) {
+                    Ok(()) => Response::ok("Opened the picker"),
+                    Err(error) => Response::error(format!("Could not open the picker: {}", error)),
+                }
+            }
+            // Answered by the IPC thread, as the picker holds back messages for this window
+            Request::PickerMove { .. } | Request::PickerConfirm | Request::PickerCancel => {
+                return Response::error("The picker isn't open")
+            }
+        };
+        match result {
+            Ok(message) => Response::ok(message),
+            Err(error) => Response::error(error.to_string()),
+        }
+    }
+
+    /// Read the clipboard into an entry as a copy is recorded, with whether
+    /// anything was read before the size limit. Returns `None` if the format filter left nothing
+    fn read_entry(&self) -> Option<(Entry, bool)> {
+        let filter = &self.format_filter;
+        let adaptive = self.settings.adaptive_formats;
+        // Nothing reads these formats, so they're the first to go when memory runs short
+        let drop_unused = adaptive && self.near_memory_limit();
+        let usage = &self.format_usage;
+        let mut items = self.clipboard.read_ordered(
+            |format| {
+                filter.allows(format) && !(drop_unused && usage.unused(&formats::name(format)))
+            },
+            |formats| {
+                if adaptive {
+                    usage.prioritize(formats, formats::name);
+                }
+            },
+        );
+        if items.is_empty() && !filter.is_empty() && !self.clipboard.formats().is_empty() {
+            return None;
+        }
+        let readable = !items.is_empty();
+        if let Some(format) = self.provenance_format_id {
+            provenance::strip(&mut items, format);
+        }
+        if let Some(format) = self.html_format_id {
+            html::normalize_items(&mut items, format);
+        }
+        if let Some(max_item_size) = self.settings.max_item_size {
+            items = limit_size(items, max_item_size);
+        }
+        let mut cb_data = Entry::new(items);
+        let owner = clipboard_owner_app();
+        cb_data.remote = self.settings.remote_clipboard == RemoteClipboard::Tag
+            && owner.as_deref().is_some_and(is_remote_clipboard);
+        cb_data.burn = !self.settings.burn_apps.is_empty()
+            && owner
+                .as_deref()
+                .is_some_and(|app| matches_app(app, &self.settings.burn_apps));
+        cb_data.source_app = owner.and_then(|app| {
+            app.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+        cb_data.copied_at = Some(provenance::unix_time());
+        cb_data.source_document =
+            source_document::document_path(&cb_data.items, &self.link_format_ids);
+        Some((cb_data, readable))
+    }
+
+    /// Read the clipboard again and replace the front item with it, for programs which offer more
+    /// formats once they've finished copying. The front item's label and expiry are kept
+    fn recapture(&mut self) -> Result<String, String> {
+        if self.cb_history.is_empty() {
+            return Err("The history is empty".to_owned());
+        }
+        let mut entry = match self.read_entry() {
+            Some((entry, _)) if !entry.is_empty() => entry,
+            _ => return Err("Nothing on the clipboard could be read".to_owned()),
+        };
+        let front = self
+            .cb_history
+            .front_mut()
+            .expect("The history not to be empty");
+        let before = front.len();
+        entry.label = front.label.take();
+        entry.expires_at = front.expires_at;
+        entry.burn |= front.burn;
+        front.wipe();
+        *front = entry;
+        let after = front.len();
+        self.emit(Event::EntryUpdated {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+        Ok(format!(
+            "Replaced the front item's {} format{} with {}",
+            before,
+            if before == 1 { "" } else { "s" },
+            after
+        ))
+    }
+
+    fn handle_clipboard(&mut self) {
+        let started = Instant::now();
+        let mut skipped = true;
+        let (cb_data, readable) = match self.read_entry() {
+            Some(read) => read,
+            None => {
+                self.emit(Event::CaptureSkipped {
+                    reason: SkipReason::FormatsFiltered,
+                });
+                return;
+            }
+        };
+
+        if readable && cb_data.is_empty() {
+            skipped = false;
+            self.emit(Event::CaptureSkipped {
+                reason: SkipReason::TooLarge,
+            });
+        } else if !cb_data.is_empty() {
+            skipped = false;
+            let read = started.elapsed();
+            let compare_started = Instant::now();
+            let engine = ClipboardEngine::new(
+                self.settings.profile.similarity_threshold,
+                self.settings.dedup,
+            );
+            let decision = engine.decide(
+                &cb_data,
+                self.last_internal_update.as_ref(),
+                &self.cb_history,
+            );
+            self.emit(Event::CaptureDecided {
+                decision,
+                depth: self.cb_history.len(),
+                read,
+                compare: compare_started.elapsed(),
+            });
+
+            let size = cb_data.size();
+            match engine.apply(decision, cb_data, &mut self.cb_history) {
+                Capture::Duplicate => {
+                    self.emit(Event::CaptureSkipped {
+                        reason: SkipReason::Duplicate,
+                    });
+                }
+                Capture::Replaced => {
+                    self.last_internal_update = None;
+                    self.emit(Event::EntryUpdated {
+                        depth: self.cb_history.len(),
+                        preview: self.next_preview(),
+                    });
+                }
+                Capture::Ignored => {}
+                Capture::Pushed => {
+                    self.usage.record_copy(size);
+                    self.trim_history();
+                    self.usage.record_depth(self.cb_history.len());
+                    self.last_internal_update = None;
+                    self.emit(Event::EntryPushed {
+                        depth: self.cb_history.len(),
+                        preview: self.next_preview(),
+                    });
+                }
+            }
+        }
+        if skipped {
+            self.capture_errors += 1;
+            self.emit(Event::CaptureSkipped {
+                reason: SkipReason::Unreadable,
+            });
+        }
+    }
+
+    /// The keys which paste into the foreground window: Ctrl+V, unless terminal_paste is on and it's
+    /// a terminal which uses other keys
+    fn paste_keys(&self) -> PasteKeys {
+        if !self.settings.terminal_paste {
+            return PasteKeys::CtrlV;
+        }
+        get_foreground_window()
+            .and_then(|window| get_class_name(window).ok())
+            .map_or(PasteKeys::CtrlV, |class| {
+                PasteKeys::for_window_class(&class)
+            })
+    }
+
+    /// Paste what's on the clipboard, returning whether the paste was sent, and note which large
+    /// formats it offered the program in the foreground
+    fn send_paste(&mut self, hotkey: Option<Hotkey>) -> bool {
+        if self.settings.paste_mode == PasteMode::SendinputText {
+            return self.type_paste(hotkey);
+        }
+        let pasted = self.press_paste(hotkey);
+        if pasted {
+            self.note_offered_formats();
+        }
+        pasted
+    }
+
+    /// Count the formats which are only promised to the clipboard as offered to the program in the
+    /// foreground, which is where a paste goes
+    fn note_offered_formats(&mut self) {
+        self.note_read_formats();
+        if !self.settings.adaptive_formats {
+            return;
+        }
+        let offered: Vec<_> = delayed_rendering::promised()
+            .into_iter()
+            .map(formats::name)
+            .collect();
+        if offered.is_empty() {
+            return;
+        }
+        let app = get_foreground_window()
+            .and_then(window_app)
+            .and_then(|path| {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            });
+        if let Some(app) = app {
+            self.format_usage.record_offered(&app, &offered);
+        }
+    }
+
+    /// Count the promised formats programs have read since this was last called
+    fn note_read_formats(&mut self) {
+        // Taken whether or not they're counted, so they don't pile up
+        let reads = delayed_rendering::take_reads();
+        if !self.settings.adaptive_formats {
+            return;
+        }
+        for (format, app) in reads {
+            let app = app
+                .as_deref()
+                .and_then(Path::file_name)
+                .map_or_else(|| "unknown".into(), |name| name.to_string_lossy());
+            self.format_usage.record_read(&app, &formats::name(format));
+        }
+    }
+
+    /// Whether the history takes up most of `max_total_memory`
+    fn near_memory_limit(&self) -> bool {
+        match self.settings.max_total_memory {
+            Some(limit) => {
+                let total: usize = self.cb_history.iter().map(Entry::size).sum();
+                total >= limit / 4 * 3
+            }
+            None => false,
+        }
+    }
+
+    /// Release `hotkey` and send the paste keys in its place, returning whether the paste was sent.
+    /// Without a hotkey, as for pastes asked for over IPC, they're simply pressed and released
+    fn press_paste(&mut self, hotkey: Option<Hotkey>) -> bool {
+        let hotkey = match hotkey {
+            Some(hotkey) => hotkey,
+            None => {
+                let sent = send_paste(self.paste_keys()).is_ok();
+                if !sent {
+                    self.arm_watchdog();
+                }
+                return sent;
+            }
+        };
+        let (paste_keys, paste_events) = hotkey.paste_sequence(self.paste_keys(), key_held);
+        if trigger_keys(&paste_keys, &paste_events).is_ok() {
+            return true;
+        }
+        self.arm_watchdog();
+
+        let (release_keys, release_events) = hotkey.release_sequence(key_held);
+        let mut retries = 0u8;
+        while let Err(error) = trigger_keys(&release_keys, &release_events) {
+            if retries >= MAX_RETRIES {
+                // Otherwise the modifiers could be left held down until they're pressed again
+                trigger_keys_fallback(&release_keys, &release_events);
+                self.emit(Event::Error {
+                    message: format!(
+                        "Could not release {} after {} attempts ({}), so it was released another \
+                         way. If keys seem stuck, press and release Ctrl, Alt, Shift and Win",
+                        hotkey, MAX_RETRIES, error
+                    ),
+                });
+                break;
+            }
+            retries += 1;
+            thread::sleep(Duration::from_millis(25));
+        }
+        false
+    }
+
+    /// Watch for modifiers left down, after sending keys failed partway
+    fn arm_watchdog(&mut self) {
+        self.watchdog.arm(Instant::now());
+        if let Err(error) = set_timer(self.h_wnd, WATCHDOG_TIMER_ID, WATCHDOG_INTERVAL_MS) {
+            warn!("Could not watch for stuck modifiers: {}", error);
+        }
+    }
+
+    /// Release any modifiers which have stayed down since sending keys failed, as nothing else
+    /// would until the user pressed them again
+    fn check_modifiers(&mut self) {
+        let stuck = self.watchdog.check(Instant::now(), |key| {
+            is_key_pressed(key as i32).unwrap_or(false)
+        });
+        if !self.watchdog.is_armed() {
+            let _ = kill_timer(self.h_wnd, WATCHDOG_TIMER_ID);
+        }
+        if stuck.is_empty() {
+            return;
+        }
+        let events = vec![winuser::KEYEVENTF_KEYUP; stuck.len()];
+        if trigger_keys(&stuck, &events).is_err() {
+            trigger_keys_fallback(&stuck, &events);
+        }
+        self.emit(Event::Notice {
+            message: format!(
+                "Released {}, which {} left held down after sending keys failed",
+                watchdog::modifier_names(&stuck),
+                if stuck.len() == 1 { "was" } else { "were" }
+            ),
+        });
+    }
+
+    /// Type the text on the clipboard, which is what Ctrl+V would paste, releasing `hotkey` first and
+    /// pressing its modifiers again afterwards. Returns whether it was typed
+    fn type_paste(&mut self, hotkey: Option<Hotkey>) -> bool {
+        let text =
+            read_text(&self.clipboard.read_all(|format| {
+                format == winuser::CF_UNICODETEXT || format == winuser::CF_TEXT
+            }));
+        let text = match text {
+            Some(text) if text.chars().count() <= MAX_TYPED_LENGTH => text,
+            Some(_) => {
+                warn!(
+                    "Not typing an item longer than {} characters",
+                    MAX_TYPED_LENGTH
+                );
+                return false;
+            }
+            None => {
+                warn!("Only text can be typed, and the item has none");
+                return false;
+            }
+        };
+        let (release_keys, release_events) = hotkey
+            .map(|hotkey| hotkey.release_sequence(key_held))
+            .unwrap_or_default();
+        let (hold_keys, hold_events) = hotkey
+            .map(|hotkey| hotkey.hold_sequence(key_held))
+            .unwrap_or_default();
+        match type_text(
+            (&release_keys, &release_events),
+            &text,
+            (&hold_keys, &hold_events),
+        ) {
+            Ok(_) => true,
+            Err(error) => {
+                warn!("Could not type the item: {}", error);
+                self.arm_watchdog();
+                false
+            }
+        }
+    }
+
+    /// Whether the program in the foreground, where a paste goes, is one of `safe_restore_apps`
+    fn pasting_into_safe_app(&self) -> bool {
+        let apps = &self.settings.safe_restore_apps;
+        !apps.is_empty()
+            && get_foreground_window()
+                .and_then(window_app)
+                .is_some_and(|app| matches_app(&app, apps))
+    }
+
+    /// Replace the clipboard's contents without recording them, giving up if the clipboard is held
+    /// for longer than the restore timeout. With `tag_restores` the "Clipboard Viewer Ignore"
+    /// format is added too, and with `provenance` a marker saying where the item came from. Only
+    /// the standard formats are written while one of `safe_restore_apps` is in the foreground
+    fn write_clipboard(
+        &mut self,
+        items: Vec<ClipboardItem>,
+        provenance: Provenance,
+    ) -> Option<Vec<Result<(), String>>> {
+        let options = RestoreOptions {
+            html_format: self.html_format_id,
+            html_to_text: self.settings.html_to_text,
+            safe_formats: Some(self.safe_format_ids.as_slice())
+                .filter(|_| self.pasting_into_safe_app()),
+            ..RestoreOptions::default()
+        };
+        let mut items = restore_items(&items, &options);
+        let count = items.len();
+        if let (true, Some(format)) = (self.settings.tag_restores, self.ignore_format_id) {
+            items.push(ClipboardItem {
+                format,
+                content: vec![0],
+            });
+        }
+        if let (true, Some(format)) = (self.settings.provenance, self.provenance_format_id) {
+            items.push(provenance.to_item(format));
+        }
+        // Only report on the item's own formats, not the tags
+        let results = self
+            .clipboard
+            .set_with_timeout(items, self.settings.restore_timeout)
+            .map(|mut results| {
+                results.truncate(count);
+                results
+            });
+        if results.is_some() {
+            self.note_own_write();
+        }
+        results
+    }
+
+    /// Put a history item on the clipboard without recording it again, with only its text if `plain`
+    /// is set. Returns false if there was an item and it was damaged or none of its formats could be
+    /// set. If the clipboard was busy, restoring the front item is retried later, leaving the
+    /// history as it is in the meantime
+    fn restore_item(&mut self, index: usize, plain: bool) -> bool {
+        let (items, provenance) = match self.cb_history.get(index) {
+            Some(item) if !item.is_intact() => {
+                self.emit_corrupted();
+                return false;
+            }
+            Some(item) if plain => (plain_text(item), Provenance::now(item)),
+            Some(item) => (item.items.clone(), Provenance::now(item)),
+            None => return true,
+        };
+        self.restore_items(items, provenance)
+    }
+
+    /// What restoring the item at `position` would put on the clipboard, worked out without
+    /// touching it
+    fn restore_check(&self, position: usize) -> String {
+        let added = [
+            (self.settings.tag_restores, self.ignore_format_id),
+            (self.settings.provenance, self.provenance_format_id),
+        ]
+        .iter()
+        .filter_map(|(enabled, format)| format.filter(|_| *enabled))
+        .map(formats::name)
+        .collect();
+        let options = RestoreOptions {
+            plain: self.settings.plain_paste,
+            html_format: self.html_format_id,
+            html_to_text: self.settings.html_to_text,
+            safe_formats: Some(self.safe_format_ids.as_slice())
+                .filter(|_| !self.settings.safe_restore_apps.is_empty()),
+            added,
+        };
+        let mut lines =
+            RestoreCheck::new(&self.cb_history[position], &options, formats::name).lines();
+        lines.push("Nothing was put on the clipboard".to_owned());
+        lines.join("\n")
+    }
+
+    /// Report an item whose bytes no longer match the checksum in the history file
+    fn emit_corrupted(&mut self) {
+        self.emit(Event::Error {
+            message: "The item is damaged in the history file, so it wasn't put on the clipboard. \
+                      Pop it or clear the history to get past it"
+                .to_owned(),
+        });
+    }
+
+    /// Put `items` on the clipboard as for `restore_item`, retrying the front item later if the
+    /// clipboard was busy
+    fn restore_items(&mut self, items: Vec<ClipboardItem>, provenance: Provenance) -> bool {
+        match self.write_clipboard(items, provenance) {
+            Some(results) => {
+                if self.restore_retries > 0 {
+                    self.restore_retries = 0;
+                    let _ = kill_timer(self.h_wnd, RESTORE_RETRY_TIMER_ID);
+                }
+                results.iter().any(|result| result.is_ok())
+            }
+            None => {
+                self.schedule_restore_retry();
+                false
+            }
+        }
+    }
+
+    fn schedule_restore_retry(&mut self) {
+        if self.restore_retries >= MAX_RESTORE_RETRIES {
+            self.restore_retries = 0;
+            self.emit(Event::Error {
+                message: format!(
+                    "Gave up restoring the clipboard after {} attempts",
+                    MAX_RESTORE_RETRIES
+                ),
+            });
+            return;
+        }
+        self.restore_retries += 1;
+        if let Err(error) = set_timer(
+            self.h_wnd,
+            RESTORE_RETRY_TIMER_ID,
+            RESTORE_RETRY_INTERVAL_MS,
+        ) {
+            warn!("Could not schedule a retry: {}", error);
+        }
+    }
+
+    fn restore_front(&mut self) -> bool {
+        self.capture_interleaved();
+        self.restore_item(0, self.settings.plain_paste)
+    }
+
+    /// Paste the next item without removing it from the history
+    fn peek_and_paste(&mut self, hotkey: Option<Hotkey>) {
+        let index = self.next_index();
+        // Something else may have been put on the clipboard since, such as a copied file
+        self.capture_interleaved();
+        if !self.restore_item(index, self.settings.plain_paste) {
+            self.emit(Event::Error {
+                message: "Could not put the next item on the clipboard".to_owned(),
+            });
+            return;
+        }
+        if self.send_paste(hotkey) {
+            let next = self
+                .cb_history
+                .get(index)
+                .map(|entry| (entry.fingerprint(), entry.burn));
+            if let Some((fingerprint, burn)) = next {
+                self.audit(Action::Peek, fingerprint, true);
+                if burn {
+                    return self.burn(fingerprint);
+                }
+            }
+        }
+        if index != 0 && !self.settings.lazy_restore {
+            thread::sleep(Duration::from_millis(25));
+            if !self.restore_front() {
+                self.emit(Event::Error {
+                    message: "Could not restore the previous item".to_owned(),
+                });
+            }
+        }
+    }
+
+    /// The positions of the history's items in the order the picker lists them
+    fn picker_positions(&self) -> Vec<usize> {
+        self.settings
+            .profile
+            .picker_order
+            .positions(self.cb_history.len())
+    }
+
+    /// The picker's line for the item at each of `positions`, numbered as `list` numbers them
+    fn picker_labels(&self, positions: &[usize]) -> Vec<String> {
+        positions
+            .iter()
+            .map(|position| picker::label(self.position(*position), &self.cb_history[*position]))
+            .collect()
+    }
+
+    /// Let the user choose any item from the history and paste it
+    fn handle_picker_hotkey(&mut self) {
+        if self.cb_history.is_empty() {
+            return;
+        }
+        let positions = self.picker_positions();
+        let labels = self.picker_labels(&positions);
+        let thumbnails: Vec<_> = positions
+            .iter()
+            .map(|position| picker::thumbnail(&self.cb_history[*position]))
+            .collect();
+        match picker::pick(&labels, &thumbnails) {
+            Ok(Some(choice)) => self.apply_choice(choice.map(|row| positions[row])),
+            Ok(None) => {}
+            Err(error) => self.emit(Event::Error {
+                message: format!("Could not open the picker: {}", error),
+            }),
+        }
+    }
+
+    /// Let the user find an item by typing part of it, then paste it
+    fn handle_search_hotkey(&mut self) {
+        if self.cb_history.is_empty() {
+            return;
+        }
+        let positions = self.picker_positions();
+        let labels = self.picker_labels(&positions);
+        let history = &self.cb_history;
+        let texts = self
+            .search_index
+            .texts(positions.iter().map(|position| &history[*position]));
+        match picker::search(&labels, &texts) {
+            Ok(Some(choice)) => self.apply_choice(choice.map(|row| positions[row])),
+            Ok(None) => {}
+            Err(error) => self.emit(Event::Error {
+                message: format!("Could not open the search: {}", error),
+            }),
+        }
+    }
+
+    /// Let the user find a snippet by typing part of its name or contents, then paste it
+    fn handle_snippet_hotkey(&mut self) {
+        if self.snippets.is_empty() {
+            self.emit(Event::Notice {
+                message: "There are no snippets yet. Press S in the picker to save one".to_owned(),
+            });
+            return;
+        }
+        let names: Vec<_> = self.snippets.iter().map(|(name, _)| name.clone()).collect();
+        let labels: Vec<_> = self
+            .snippets
+            .iter()
+            .map(|(name, entry)| format!("{}\t{}", name, entry.summary(LIST_SUMMARY_LENGTH)))
+            .collect();
+        let texts: Vec<_> = self
+            .snippets
+            .iter()
+            .map(|(name, entry)| search::named_text(name, entry))
+            .collect();
+        let index = match picker::search(&labels, &texts) {
+            Ok(Some(Choice::Paste(index))) | Ok(Some(Choice::PasteAndRemove(index))) => index,
+            Ok(_) => return,
+            Err(error) => {
+                return self.emit(Event::Error {
+                    message: format!("Could not open the snippets: {}", error),
+                })
+            }
+        };
+        if let Err(error) = self.paste_snippet(&names[index]) {
+            self.emit(Event::Error { message: error });
+        }
+    }
+
+    /// Do what was chosen in the picker or the search
+    fn apply_choice(&mut self, choice: Choice) {
+        let result = match choice {
+            Choice::Paste(index) => {
+                self.paste_chosen(index);
+                Ok(())
+            }
+            Choice::PasteAndRemove(index) => {
+                self.paste_and_remove(index);
+                Ok(())
+            }
+            Choice::Expire(index) => self.expire_item(index, DEFAULT_EXPIRY),
+            Choice::Pin(index) => self.pin_item(index),
+            Choice::Burn(index) => self.burn_after_reading(index),
+            Choice::Snippet(index) => self.snippet_from_picker(index),
+        };
+        if let Err(error) = result {
+            self.emit(Event::Error { message: error });
+        }
+    }
+
+    /// Save an item chosen in the picker as a snippet, named by its label or the start of its text
+    fn snippet_from_picker(&mut self, index: usize) -> Result<(), String> {
+        let entry = self
+            .cb_history
+            .get(index)
+            .ok_or_else(|| format!("There is no item {}", index))?;
+        let name = entry
+            .label
+            .clone()
+            .unwrap_or_else(|| entry.summary(SNIPPET_NAME_LENGTH))
+            .replace(char::is_control, " ");
+        let name = self.save_snippet(index, &name)?;
+        self.emit(Event::Notice {
+            message: format!("Saved as the snippet \"{}\"", name),
+        });
+        Ok(())
+    }
+
+    /// Paste an item chosen in the picker, returning whether it was pasted. With `rotate_on_pick`
+    /// the history is turned so that the item is at the front, otherwise the front item is put back
+    /// on the clipboard afterwards
+    fn paste_chosen(&mut self, index: usize) -> bool {
+        let rotate = self.settings.config.rotate_on_pick;
+        if rotate {
+            self.cb_history.rotate_left(index);
+        }
+        let restore_index = if rotate { 0 } else { index };
+        if !self.restore_item(restore_index, self.settings.plain_paste) {
+            self.emit(Event::Error {
+                message: "Could not put the chosen item on the clipboard".to_owned(),
+            });
+            return false;
+        }
+        let pasted = self.send_paste(None);
+        if pasted {
+            let chosen = self
+                .cb_history
+                .get(restore_index)
+                .map(|entry| (entry.fingerprint(), entry.burn));
+            if let Some((fingerprint, burn)) = chosen {
+                self.audit(Action::Pick, fingerprint, true);
+                if burn {
+                    self.burn(fingerprint);
+                    return true;
+                }
+            }
+        } else {
+            self.emit(Event::Error {
+                message: "Could not paste the chosen item".to_owned(),
+            });
+        }
+
+        if rotate {
+            self.emit(Event::HistoryRotated {
+                depth: self.cb_history.len(),
+                preview: self.next_preview(),
+            });
+        } else if index != 0 && !self.settings.lazy_restore {
+            // Give the target program time to read the clipboard before it changes again
+            thread::sleep(Duration::from_millis(25));
+            if !self.restore_front() {
+                self.emit(Event::Error {
+                    message: "Could not restore the previous item".to_owned(),
+                });
+            }
+        }
+        pasted
+    }
+
+    /// Paste an item chosen in the search, then take it out of the history as though it had been
+    /// popped, so it can be put back with undo
+    fn paste_and_remove(&mut self, index: usize) {
+        // Burning the item after the paste has already removed it
+        let burn = self.cb_history.get(index).is_some_and(|entry| entry.burn);
+        if !self.paste_chosen(index) || burn {
+            return;
+        }
+        // Turning the history on pick leaves the item at the front
+        let position = if self.settings.config.rotate_on_pick {
+            0
+        } else {
+            index
+        };
+        let removed = match self.cb_history.remove(position) {
+            Some(removed) => removed,
+            None => return,
+        };
+        self.remember_popped(&removed);
+        self.last_internal_update = Some(removed);
+        self.note_exhaustion();
+        if position == 0 && !self.settings.lazy_restore {
+            thread::sleep(Duration::from_millis(25));
+            if !self.restore_front() {
+                self.emit(Event::Error {
+                    message: "Could not restore the previous item".to_owned(),
+                });
+            }
+        }
+        self.emit(Event::EntryPopped {
+            depth: self.cb_history.len(),
+            preview: self.next_preview(),
+        });
+    }
+
+    fn handle_hotkey(&mut self) {
+        self.pop_and_paste(
+            Some(self.settings.hotkey),
+            self.settings.plain_paste,
+            self.settings.mode,
+        );
+    }
+
+    fn handle_plain_paste_hotkey(&mut self) {
+        self.pop_and_paste(
+            Some(self.settings.plain_paste_hotkey),
+            true,
+            self.settings.mode,
+        );
+    }
+
+    /// Start timing a press of the tap key, to tell a tap from a long press
+    fn start_tap(&mut self) {
+        if self.tap_started.is_some() {
+            return;
+        }
+        self.tap_started = Some(Instant::now());
+        if let Err(error) = set_timer(self.h_wnd, TAP_TIMER_ID, TAP_CHECK_INTERVAL_MS) {
+            warn!("Could not time the tap key: {}", error);
+            self.tap_started = None;
+        }
+    }
+
+    /// Paste and pop the next item once the tap key is let go, or open the picker if it's held for
+    /// `LONG_PRESS`
+    fn check_tap(&mut self) {
+        let (started, tap_key) = match (self.tap_started, self.settings.tap_key) {
+            (Some(started), Some(tap_key)) => (started, tap_key),
+            _ => {
+                let _ = kill_timer(self.h_wnd, TAP_TIMER_ID);
+                return;
+            }
+        };
+        let long_press = started.elapsed() >= LONG_PRESS;
+        if key_held(tap_key.key) && !long_press {
+            return;
+        }
+        let _ = kill_timer(self.h_wnd, TAP_TIMER_ID);
+        self.tap_started = None;
+        if long_press {
+            self.handle_picker_hotkey();
+        } else {
+            self.pop_and_paste(
+                Some(tap_key.as_hotkey()),
+                self.settings.plain_paste,
+                self.settings.mode,
+            );
+        }
+    }
+
+    /// Paste the oldest item whatever the mode, then press `queue_keys`, so fields copied one after
+    /// another can be pasted into a form in the same order
+    fn handle_queue_hotkey(&mut self) {
+        let hotkey = self.settings.queue_hotkey;
+        if self.cb_history.is_empty()
+            || !self.pop_and_paste(Some(hotkey), self.settings.plain_paste, Order::Fifo)
+            || self.settings.queue_keys.is_empty()
+        {
+            return;
+        }
+        // The hotkey's modifiers are still held, and would change what the keys do
+        let (mut keys, mut events) = hotkey.release_sequence(key_held);
+        for keystroke in &self.settings.queue_keys {
+            let (press_keys, press_events) = keystroke.press_sequence();
+            keys.extend(press_keys);
+            events.extend(press_events);
+        }
+        let (hold_keys, hold_events) = hotkey.hold_sequence(key_held);
+        keys.extend(hold_keys);
+        events.extend(hold_events);
+        if let Err(error) = trigger_keys(&keys, &events) {
+            warn!("Could not press the queue keys: {}", error);
+            self.arm_watchdog();
+        }
+    }
+
+    /// Paste the next item in `order`, stripped down to its text if `plain` is set, then remove it
+    /// and put the front item on the clipboard. With `lazy_restore` the next item is only put there
+    /// when it's pasted in turn. Returns whether the paste was sent
+    fn pop_and_paste(&mut self, hotkey: Option<Hotkey>, plain: bool, order: Order) -> bool {
+        let lazy = self.settings.lazy_restore;
+        let index = self.next_index_in(order);
+        // Otherwise the clipboard holds every format of the front item until now
+        if (plain || lazy || index != 0 || self.pasting_into_safe_app())
+            && !self.restore_item(index, plain)
+        {
+            self.emit(Event::Error {
+                message: "Could not put the next item on the clipboard".to_owned(),
+            });
+            return false;
+        }
+        if !self.send_paste(hotkey) {
+            return false;
+        }
+        // Sleep for less time than the lowest possible automatic keystroke repeat ((1000ms / 30) * 0.8)
+        thread::sleep(Duration::from_millis(25));
+        self.last_internal_update = self.take_next_in(order);
+        if let Some(popped) = self.last_internal_update.clone() {
+            self.remember_popped(&popped);
+            self.note_exhaustion();
+        }
+        if !lazy && !self.restore_front() {
+            self.emit(Event::Error {
+                message: "Could not restore the previous item".to_owned(),
+            });
+        }
+        if let Some(fingerprint) = self.last_internal_update.as_ref().map(Entry::fingerprint) {
+            self.emit(Event::EntryPopped {
+                depth: self.cb_history.len(),
+                preview: self.next_preview(),
+            });
+            let action = if plain {
+                Action::PlainPaste
+            } else {
+                Action::Paste
+            };
+            self.audit(action, fingerprint, true);
+            if self
+                .last_internal_update
+                .as_ref()
+                .is_some_and(|entry| entry.burn)
+            {
+                self.burn(fingerprint);
+            }
+        }
+        true
+    }
+
+    /// Save the history and usage stats, hand over any promised formats and remove the listeners.
+    /// Only the first call does anything, so it's safe when the session ends and again on exit
+    fn shut_down(&mut self) {
+        if self.shut_down {
+            return;
+        }
+        self.shut_down = true;
+        self.flush_history();
+        // Anything still only promised to the clipboard has to be copied there before we exit
+        delayed_rendering::stop();
+        if let Some(path) = self.settings.config_path.as_deref().map(usage::stats_path) {
+            self.usage.end_session();
+            if let Err(error) = self.usage.save(&path) {
+                warn!("Could not save usage stats: {}", error);
+            }
+        }
+        self.note_read_formats();
+        let adaptive = self.settings.adaptive_formats;
+        if let Some(path) = self.settings.config_path.as_deref().filter(|_| adaptive) {
+            if let Err(error) = self.format_usage.save(&format_usage::usage_path(path)) {
+                warn!("Could not save which formats are read: {}", error);
+            }
+        }
+        let config_path = self.settings.config_path.as_deref();
+        if let (Some(telemetry), Some(path)) = (self.telemetry.as_mut(), config_path) {
+            telemetry.sessions += 1;
+            if let Err(error) = telemetry.save(&telemetry::telemetry_path(path)) {
+                warn!("Could not save telemetry: {}", error);
+            }
+        }
+        let _ = kill_timer(self.h_wnd, PERSIST_TIMER_ID);
+        let _ = kill_timer(self.h_wnd, RESTORE_RETRY_TIMER_ID);
+        let _ = kill_timer(self.h_wnd, EXPIRY_TIMER_ID);
+        let _ = kill_timer(self.h_wnd, AUTO_GROW_TIMER_ID);
+        let _ = kill_timer(self.h_wnd, CONFIG_RELOAD_TIMER_ID);
+        let _ = kill_timer(self.h_wnd, MAX_AGE_TIMER_ID);
+        let _ = kill_timer(self.h_wnd, QUIET_HOURS_TIMER_ID);
+        let _ = kill_timer(self.h_wnd, WATCHDOG_TIMER_ID);
+        let _ = kill_timer(self.h_wnd, DEBOUNCE_TIMER_ID);
+        let _ = remove_clipboard_format_listener(&mut self.h_wnd);
+        self.hotkeys.unregister_all(self.h_wnd);
+    }
+}
 
 impl Drop for Window<'_> {
     fn drop(&mut self) {
-        let _ = remove_clipboard_format_listener(&mut self.h_wnd);
-        let _ = unregister_hotkey(self.h_wnd, 1);
+        self.shut_down();
     }
 }